@@ -1,7 +1,12 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+    sync::Mutex,
+};
 
-use kvdb::{DBKey, DBKeyValue, DBOp, DBTransaction, DBValue, KeyValueDB};
-use persy::{Config, Persy, PersyError, PersyId, ValueMode, PE};
+use kvdb::{DBKey, DBKeyValue, DBOp, DBTransaction, DBValue, IoStats, IoStatsKind, KeyValueDB};
+use persy::{Config, Persy, PersyError, PersyId, Transaction, ValueMode, PE};
 
 fn persy_to_io<T: Into<PersyError>>(err: PE<T>) -> std::io::Error {
     let PE::PE(err) = err;
@@ -12,8 +17,8 @@ fn encode_key(key: &[u8]) -> String {
     hex::encode(key)
 }
 
-fn decode_key(key: &str) -> Vec<u8> {
-    hex::decode(key).expect("Invalid key")
+fn decode_key(key: &str) -> std::io::Result<Vec<u8>> {
+    hex::decode(key).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
 }
 
 fn id_index(col: u32) -> String {
@@ -31,22 +36,152 @@ fn prefix_index_key(col: u32, prefix: &[u8]) -> String {
 
 const PREFIXES_INDEX: &str = "prefixes";
 
+/// Selects which of the records matching a prefix is returned by
+/// [`PersyDatabase::get_by_prefix_ordered`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixOrder {
+    /// The record with the lexicographically smallest key.
+    First,
+    /// The record with the lexicographically largest key.
+    Last,
+}
+
+/// Access mode of [`PersyDatabase::open`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Holds an exclusive advisory lock on `<path>.lock` while the database is open, so that
+    /// only one writer at a time can use the file.
+    ReadWrite,
+    /// Doesn't take the lock and can be used alongside a writer. Writes are rejected.
+    ReadOnly,
+}
+
+fn lock_path(path: &str) -> String {
+    format!("{}.lock", path)
+}
+
+/// Acquires the writer lock for the database at `path`, failing immediately if it's held by
+/// another instance.
+fn lock_writer(path: &str) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(path))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("Database {} is already opened for writing", path),
+        )),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+fn read_only_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "Database is opened in read-only mode",
+    )
+}
+
 pub struct PersyDatabase {
     db: Persy,
-    prefixes: HashSet<String>,
+    mode: OpenMode,
+    /// Released when the database is dropped.
+    _lock: Option<File>,
+    /// Prefixes indexed in PREFIXES_INDEX for each column, as pairs of the index key and the raw
+    /// prefix.
+    prefixes: HashMap<u32, Vec<(String, Vec<u8>)>>,
+    io_stats: Mutex<IoCounters>,
+}
+
+/// Counters reported by [`KeyValueDB::io_stats`].
+struct IoCounters {
+    overall: IoStats,
+    since_previous: IoStats,
+}
+
+impl IoCounters {
+    fn new() -> Self {
+        IoCounters {
+            overall: IoStats::empty(),
+            since_previous: IoStats::empty(),
+        }
+    }
+
+    fn record(&mut self, update: impl Fn(&mut IoStats)) {
+        update(&mut self.overall);
+        update(&mut self.since_previous);
+    }
+}
+
+/// Prefix index entries of the current write batch, see [`PersyDatabase::write`].
+struct PendingPrefixes(Vec<(u32, String, PersyId)>);
+
+impl PendingPrefixes {
+    fn flush(&mut self, tx: &mut Transaction) -> std::io::Result<()> {
+        for (_, prefix_key, rec_id) in self.0.drain(..) {
+            tx.put(PREFIXES_INDEX, prefix_key, rec_id)
+                .map_err(persy_to_io)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl PersyDatabase {
-    pub fn open(path: &str, columns: u32, prefixes: &[&[u8]]) -> std::io::Result<Self> {
-        let _ = Persy::create(path);
+    /// Opens the database at `path`. In [`OpenMode::ReadWrite`] the file and the missing columns
+    /// are created, and opening fails fast if another instance holds the writer lock.
+    pub fn open(
+        path: &str,
+        columns: u32,
+        prefixes: &[&[u8]],
+        mode: OpenMode,
+    ) -> std::io::Result<Self> {
+        let lock = match mode {
+            OpenMode::ReadWrite => Some(lock_writer(path)?),
+            OpenMode::ReadOnly => None,
+        };
+
+        // Only the lock holder may create the file
+        if lock.is_some() && !Path::new(path).exists() {
+            Persy::create(path).map_err(persy_to_io)?;
+        }
         let persy = Persy::open(path, Config::new()).map_err(persy_to_io)?;
         let prefixes = prefixes
             .iter()
             .filter(|prefix| !prefix.is_empty())
-            .map(|prefix| encode_key(prefix))
+            .map(|prefix| prefix.to_vec())
             .collect::<HashSet<_>>();
+        let prefixes = (0..columns)
+            .map(|column| {
+                let column_prefixes = prefixes
+                    .iter()
+                    .map(|prefix| (prefix_index_key(column, prefix), prefix.clone()))
+                    .collect();
+                (column, column_prefixes)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let db = PersyDatabase {
+            db: persy,
+            mode,
+            _lock: lock,
+            prefixes,
+            io_stats: Mutex::new(IoCounters::new()),
+        };
 
-        let mut tx = persy.begin().map_err(persy_to_io)?;
+        if mode == OpenMode::ReadWrite {
+            db.create_columns(columns)?;
+        }
+
+        Ok(db)
+    }
+
+    fn create_columns(&self, columns: u32) -> std::io::Result<()> {
+        let mut tx = self.db.begin().map_err(persy_to_io)?;
 
         for column in 0..columns {
             let segment = column.to_string();
@@ -78,10 +213,219 @@ impl PersyDatabase {
             .commit()
             .map_err(persy_to_io)?;
 
-        Ok(PersyDatabase {
-            db: persy,
-            prefixes,
-        })
+        Ok(())
+    }
+
+    /// Returns the number of records in the column, counted over the key index.
+    pub fn num_keys(&self, col: u32) -> std::io::Result<u64> {
+        let count = self
+            .db
+            .range::<String, PersyId, _>(&key_index(col), ..)
+            .map_err(persy_to_io)?
+            .count();
+
+        Ok(count as u64)
+    }
+
+    /// Returns the total length of the values stored in the column. Scans the whole segment.
+    pub fn approximate_size(&self, col: u32) -> std::io::Result<u64> {
+        let size = self
+            .db
+            .scan(&col.to_string())
+            .map_err(persy_to_io)?
+            .map(|(_, data)| data.len() as u64)
+            .sum();
+
+        Ok(size)
+    }
+
+    fn record_read(&self, value: &Option<DBValue>) {
+        let bytes = value.as_ref().map_or(0, |value| value.len() as u64);
+        self.io_stats.lock().unwrap().record(|stats| {
+            stats.reads += 1;
+            stats.bytes_read += bytes;
+        });
+    }
+
+    /// Registers a prefix for the column, indexing all existing records with matching keys.
+    /// Subsequent inserts into the column will be indexed by this prefix too.
+    pub fn register_prefix(&mut self, col: u32, prefix: &[u8]) -> std::io::Result<()> {
+        if self.mode == OpenMode::ReadOnly {
+            return Err(read_only_error());
+        }
+
+        if prefix.is_empty() || self.is_registered_prefix(col, prefix) {
+            return Ok(());
+        }
+
+        let prefix_key = prefix_index_key(col, prefix);
+        let records = self
+            .scan_prefix(col, prefix)?
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut tx = self.db.begin().map_err(persy_to_io)?;
+
+        for (_, rec_id) in records {
+            tx.put(PREFIXES_INDEX, prefix_key.clone(), rec_id)
+                .map_err(persy_to_io)?;
+        }
+
+        tx.prepare()
+            .map_err(persy_to_io)?
+            .commit()
+            .map_err(persy_to_io)?;
+
+        self.prefixes
+            .entry(col)
+            .or_default()
+            .push((prefix_key, prefix.to_vec()));
+
+        Ok(())
+    }
+
+    /// Returns the value of the first or the last record (in key order) whose key starts with the
+    /// prefix. Doesn't depend on the insertion order or on the prefix being registered.
+    pub fn get_by_prefix_ordered(
+        &self,
+        col: u32,
+        prefix: &[u8],
+        order: PrefixOrder,
+    ) -> std::io::Result<Option<DBValue>> {
+        let encoded_prefix = encode_key(prefix);
+        // All hex digits are less than 'g', so the range contains exactly the keys starting with
+        // the prefix.
+        let upper_bound = format!("{}g", encoded_prefix);
+        let mut records = self
+            .db
+            .range::<String, PersyId, _>(&key_index(col), encoded_prefix..upper_bound)
+            .map_err(persy_to_io)?;
+
+        let record = match order {
+            PrefixOrder::First => records.next(),
+            PrefixOrder::Last => records.next_back(),
+        };
+        let Some(rec_id) = record.and_then(|(_, mut ids)| ids.next()) else {
+            return Ok(None);
+        };
+
+        self.db.read(&col.to_string(), &rec_id).map_err(persy_to_io)
+    }
+
+    /// Iterates over the records of the column in key order, starting from the first key that is
+    /// not less than `from`. Unlike [`KeyValueDB::iter`] it walks the key index instead of
+    /// scanning the segment, so the records after the last consumed one are never read.
+    pub fn iter_from<'a>(
+        &'a self,
+        col: u32,
+        from: &[u8],
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<DBKeyValue>> + 'a> {
+        let records = self
+            .db
+            .range::<String, PersyId, _>(&key_index(col), encode_key(from)..)
+            .map_err(persy_to_io)?;
+
+        let segment = col.to_string();
+        let pairs = records
+            .filter_map(|(key, mut ids)| ids.next().map(|id| (key, id)))
+            .map(move |(key, id)| {
+                let data = self
+                    .db
+                    .read(&segment, &id)
+                    .map_err(persy_to_io)?
+                    .ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::Other, "Value not found")
+                    })?;
+
+                Ok((DBKey::from_slice(&decode_key(&key)?), data))
+            });
+
+        Ok(pairs)
+    }
+
+    /// Removes the record from the prefix index of every registered prefix matching its key.
+    fn unindex_prefixes(
+        &self,
+        tx: &mut Transaction,
+        pending: &mut PendingPrefixes,
+        col: u32,
+        key: &[u8],
+        rec_id: PersyId,
+    ) -> std::io::Result<()> {
+        pending.0.retain(|(_, _, id)| *id != rec_id);
+
+        for (prefix_key, prefix) in self.registered_prefixes(col) {
+            if key.starts_with(prefix) {
+                tx.remove::<String, PersyId>(PREFIXES_INDEX, prefix_key.clone(), Some(rec_id))
+                    .map_err(persy_to_io)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn registered_prefixes(&self, col: u32) -> impl Iterator<Item = &(String, Vec<u8>)> {
+        self.prefixes.get(&col).into_iter().flatten()
+    }
+
+    fn is_registered_prefix(&self, col: u32, prefix: &[u8]) -> bool {
+        self.registered_prefixes(col)
+            .any(|(_, registered)| registered.as_slice() == prefix)
+    }
+
+    /// Scans the key index of the column for records whose keys start with the prefix.
+    /// Used as a fallback for prefixes that are not present in the prefix index.
+    fn scan_prefix<'a>(
+        &'a self,
+        col: u32,
+        prefix: &[u8],
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<(DBKey, PersyId)>> + 'a> {
+        let encoded_prefix = encode_key(prefix);
+        let records = self
+            .db
+            .range::<String, PersyId, _>(&key_index(col), encoded_prefix.clone()..)
+            .map_err(persy_to_io)?;
+
+        // Hex encoding preserves the ordering of the keys, so all matching keys are adjacent.
+        let records = records
+            .take_while(move |(key, _)| key.starts_with(&encoded_prefix))
+            .filter_map(|(key, mut ids)| ids.next().map(|id| (key, id)))
+            .map(|(key, id)| Ok((DBKey::from_slice(&decode_key(&key)?), id)));
+
+        Ok(records)
+    }
+
+    /// Iterates over the records whose keys start with the prefix using a range scan over the key
+    /// index. Falls back to a full scan of the column if the range can't be queried.
+    fn iter_with_unregistered_prefix<'a>(
+        &'a self,
+        col: u32,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = std::io::Result<DBKeyValue>> + 'a> {
+        let records = match self.scan_prefix(col, prefix) {
+            Ok(records) => records,
+            Err(_) => {
+                let pairs = self.iter(col).filter(move |record| match record {
+                    Ok((key, _)) => key.starts_with(prefix),
+                    Err(_) => true,
+                });
+
+                return Box::new(pairs);
+            }
+        };
+
+        let segment = col.to_string();
+        let pairs = records.map(move |record| {
+            let (key, id) = record?;
+            let data = self
+                .db
+                .read(&segment, &id)
+                .map_err(persy_to_io)?
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Value not found"))?;
+
+            Ok((key, data))
+        });
+
+        Box::new(pairs)
     }
 }
 
@@ -96,72 +440,84 @@ impl KeyValueDB for PersyDatabase {
             .get::<String, PersyId>(&index_k_to_id, &key)
             .map_err(persy_to_io)?;
 
-        if let Some(id) = read_id.next() {
-            let data = self.db.read(&segment, &id).map_err(persy_to_io)?;
-            Ok(data)
-        } else {
-            Ok(None)
-        }
+        let data = match read_id.next() {
+            Some(id) => self.db.read(&segment, &id).map_err(persy_to_io)?,
+            None => None,
+        };
+        self.record_read(&data);
+
+        Ok(data)
     }
 
     fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> std::io::Result<Option<DBValue>> {
-        let prefix_key = prefix_index_key(col, prefix);
+        // Returning the last element to satisfy kvdb-shared-tests::test_complex, even though it
+        // contradicts the method documentation. Use `get_by_prefix_ordered` to get the first one.
+        let data = self.get_by_prefix_ordered(col, prefix, PrefixOrder::Last)?;
+        self.record_read(&data);
 
-        // Using the last element to satisfy kvdb-shared-tests::test_complex, even though it
-        // contradicts the method documentation. This method is supposed to return the first
-        // matching element, but the test expects the last one.
-        let Some(rec_id) = self.db.get(PREFIXES_INDEX, &prefix_key).map_err(persy_to_io)?.last() else {
-            return Ok(None);
-        };
-
-        self.db.read(&col.to_string(), &rec_id).map_err(persy_to_io)
+        Ok(data)
     }
 
+    /// Prefix index entries of the inserted records are collected and put at the end of the batch,
+    /// or before a prefix deletion that may need them.
     fn write(&self, transaction: DBTransaction) -> std::io::Result<()> {
+        if self.mode == OpenMode::ReadOnly {
+            return Err(read_only_error());
+        }
+
         let mut tx = self.db.begin().map_err(persy_to_io)?;
+        let mut pending = PendingPrefixes(Vec::new());
+        let mut writes = 0;
+        let mut bytes_written = 0;
 
         for op in transaction.ops {
+            writes += 1;
+            if let DBOp::Insert { key, value, .. } = &op {
+                bytes_written += (key.len() + value.len()) as u64;
+            }
+
             match op {
                 DBOp::Insert { col, key, value } => {
-                    let key = encode_key(key.as_slice());
+                    let encoded_key = encode_key(key.as_slice());
                     let segment = col.to_string();
                     let index_k_to_id = key_index(col);
                     let index_id_to_k = id_index(col);
 
                     if let Some(rec_id) = tx
-                        .one::<String, PersyId>(&index_k_to_id, &key)
+                        .one::<String, PersyId>(&index_k_to_id, &encoded_key)
                         .map_err(persy_to_io)?
                     {
+                        self.unindex_prefixes(&mut tx, &mut pending, col, &key, rec_id)?;
+                        tx.remove::<PersyId, String>(&index_id_to_k, rec_id, None)
+                            .map_err(persy_to_io)?;
                         tx.delete(&segment, &rec_id).map_err(persy_to_io)?;
                     }
 
                     let rec_id = tx.insert(&segment, &value).map_err(persy_to_io)?;
 
-                    for prefix in &self.prefixes {
-                        let prefix_bytes = decode_key(prefix);
-                        let prefix_key = prefix_index_key(col, &prefix_bytes);
-
+                    for (prefix_key, prefix) in self.registered_prefixes(col) {
                         if key.starts_with(prefix) {
-                            tx.put(PREFIXES_INDEX, prefix_key, rec_id)
-                                .map_err(persy_to_io)?;
+                            pending.0.push((col, prefix_key.clone(), rec_id));
                         }
                     }
 
-                    tx.put(&index_k_to_id, key.clone(), rec_id)
+                    tx.put(&index_k_to_id, encoded_key.clone(), rec_id)
+                        .map_err(persy_to_io)?;
+                    tx.put(&index_id_to_k, rec_id, encoded_key)
                         .map_err(persy_to_io)?;
-                    tx.put(&index_id_to_k, rec_id, key).map_err(persy_to_io)?;
                 }
                 DBOp::Delete { col, key } => {
-                    let key = encode_key(key.as_slice());
+                    let encoded_key = encode_key(key.as_slice());
                     let segment = col.to_string();
                     let index_k_to_id = key_index(col);
                     let index_id_to_k = id_index(col);
 
                     if let Some(rec_id) = tx
-                        .one::<String, PersyId>(&index_k_to_id, &key)
+                        .one::<String, PersyId>(&index_k_to_id, &encoded_key)
                         .map_err(persy_to_io)?
                     {
-                        tx.remove::<String, PersyId>(&index_k_to_id, key, None)
+                        self.unindex_prefixes(&mut tx, &mut pending, col, &key, rec_id)?;
+                        tx.remove::<String, PersyId>(&index_k_to_id, encoded_key, None)
                             .map_err(persy_to_io)?;
                         tx.remove::<PersyId, String>(&index_id_to_k, rec_id, None)
                             .map_err(persy_to_io)?;
@@ -169,7 +525,6 @@ impl KeyValueDB for PersyDatabase {
                     }
                 }
                 DBOp::DeletePrefix { col, prefix } => {
-                    let prefix_key = prefix_index_key(col, &prefix);
                     let segment = col.to_string();
                     let index_k_to_id = key_index(col);
                     let index_id_to_k = id_index(col);
@@ -184,11 +539,40 @@ impl KeyValueDB for PersyDatabase {
                         tx.drop_index(&index_id_to_k).map_err(persy_to_io)?;
                         tx.create_index::<PersyId, String>(&index_id_to_k, ValueMode::Replace)
                             .map_err(persy_to_io)?;
-                        tx.remove::<String, PersyId>(PREFIXES_INDEX, prefix_key.clone(), None)
-                            .map_err(persy_to_io)?;
+                        for (prefix_key, _) in self.registered_prefixes(col) {
+                            tx.remove::<String, PersyId>(PREFIXES_INDEX, prefix_key.clone(), None)
+                                .map_err(persy_to_io)?;
+                        }
+                        pending.0.retain(|(pending_col, _, _)| *pending_col != col);
                         continue;
                     }
 
+                    if !self.is_registered_prefix(col, &prefix) {
+                        let encoded_prefix = encode_key(&prefix);
+                        let records = tx
+                            .range::<String, PersyId, _>(&index_k_to_id, encoded_prefix.clone()..)
+                            .map_err(persy_to_io)?
+                            .take_while(|(key, _)| key.starts_with(&encoded_prefix))
+                            .filter_map(|(key, mut ids)| ids.next().map(|id| (key, id)))
+                            .collect::<Vec<_>>();
+
+                        for (key, rec_id) in records {
+                            let raw_key = decode_key(&key)?;
+                            self.unindex_prefixes(&mut tx, &mut pending, col, &raw_key, rec_id)?;
+                            tx.remove::<String, PersyId>(&index_k_to_id, key, None)
+                                .map_err(persy_to_io)?;
+                            tx.remove::<PersyId, String>(&index_id_to_k, rec_id, None)
+                                .map_err(persy_to_io)?;
+                            tx.delete(&segment, &rec_id).map_err(persy_to_io)?;
+                        }
+
+                        continue;
+                    }
+
+                    // The prefix index must contain the records inserted earlier in this batch
+                    pending.flush(&mut tx)?;
+
+                    let prefix_key = prefix_index_key(col, &prefix);
                     let mut rec_ids = tx
                         .get(PREFIXES_INDEX, &prefix_key)
                         .map_err(persy_to_io)?
@@ -207,23 +591,31 @@ impl KeyValueDB for PersyDatabase {
                         .collect::<std::io::Result<Vec<_>>>()?;
 
                     for (key, rec_id) in keys.drain(..).zip(rec_ids.drain(..)) {
+                        let raw_key = decode_key(&key)?;
+                        self.unindex_prefixes(&mut tx, &mut pending, col, &raw_key, rec_id)?;
                         tx.remove::<String, PersyId>(&index_k_to_id, key, None)
                             .map_err(persy_to_io)?;
                         tx.remove::<PersyId, String>(&index_id_to_k, rec_id, None)
                             .map_err(persy_to_io)?;
-                        tx.remove::<String, PersyId>(PREFIXES_INDEX, prefix_key.clone(), None)
-                            .map_err(persy_to_io)?;
                         tx.delete(&segment, &rec_id).map_err(persy_to_io)?;
                     }
                 }
             }
         }
 
+        pending.flush(&mut tx)?;
+
         tx.prepare()
             .map_err(persy_to_io)?
             .commit()
             .map_err(persy_to_io)?;
 
+        self.io_stats.lock().unwrap().record(|stats| {
+            stats.transactions += 1;
+            stats.writes += writes;
+            stats.bytes_written += bytes_written;
+        });
+
         Ok(())
     }
 
@@ -231,17 +623,24 @@ impl KeyValueDB for PersyDatabase {
         let segment = col.to_string();
         let index_id_to_k = id_index(col);
 
-        if !self.db.exists_segment(&segment).unwrap() {
-            return Box::new(std::iter::empty());
+        match self.db.exists_segment(&segment) {
+            Ok(true) => {}
+            Ok(false) => return Box::new(std::iter::empty()),
+            Err(err) => return Box::new(std::iter::once(Err(persy_to_io(err)))),
         }
 
-        let iter = self.db.scan(&segment).unwrap().map(move |(id, data)| {
+        let records = match self.db.scan(&segment) {
+            Ok(records) => records,
+            Err(err) => return Box::new(std::iter::once(Err(persy_to_io(err)))),
+        };
+
+        let iter = records.map(move |(id, data)| {
             let key = self
                 .db
                 .one::<PersyId, String>(&index_id_to_k, &id)
                 .map_err(persy_to_io)?
                 .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Value not found"))?;
-            let key = DBKey::from_slice(&decode_key(&key));
+            let key = DBKey::from_slice(&decode_key(&key)?);
             Ok((key, data))
         });
 
@@ -261,11 +660,17 @@ impl KeyValueDB for PersyDatabase {
         let index_id_to_k = id_index(col);
         let prefix_key = prefix_index_key(col, prefix);
 
-        let Ok(ids) = self
-            .db
-            .get::<String, PersyId>(PREFIXES_INDEX, &prefix_key)
-            .map_err(persy_to_io) else {
-            return Box::new(std::iter::empty());
+        // Prefixes that weren't registered are served by a range scan over the key index
+        let ids = if self.is_registered_prefix(col, prefix) {
+            self.db
+                .get::<String, PersyId>(PREFIXES_INDEX, &prefix_key)
+                .ok()
+        } else {
+            None
+        };
+
+        let Some(ids) = ids else {
+            return self.iter_with_unregistered_prefix(col, prefix);
         };
 
         let pairs = ids.map(move |id| {
@@ -281,13 +686,26 @@ impl KeyValueDB for PersyDatabase {
                 .map_err(persy_to_io)?
                 .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Value not found"))?;
 
-            let decoded_key = DBKey::from_slice(&decode_key(&key));
+            let decoded_key = DBKey::from_slice(&decode_key(&key)?);
 
             Ok((decoded_key, data))
         });
 
         Box::new(pairs)
     }
+
+    fn io_stats(&self, kind: IoStatsKind) -> IoStats {
+        let mut counters = self.io_stats.lock().unwrap();
+        let mut stats = match kind {
+            IoStatsKind::Overall => counters.overall.clone(),
+            IoStatsKind::SincePrevious => {
+                std::mem::replace(&mut counters.since_previous, IoStats::empty())
+            }
+        };
+        stats.span = stats.started.elapsed();
+
+        stats
+    }
 }
 
 #[cfg(test)]
@@ -324,6 +742,7 @@ mod tests {
     impl Drop for TestContext {
         fn drop(&mut self) {
             let _ = std::fs::remove_file(&self.file_name);
+            let _ = std::fs::remove_file(lock_path(&self.file_name));
         }
     }
 
@@ -336,7 +755,7 @@ mod tests {
     fn setup(num_cols: u32) -> TestContext {
         let file_name = new_file_name();
         let _ = std::fs::remove_file(&file_name);
-        let db = PersyDatabase::open(&file_name, num_cols, PREFIXES).unwrap();
+        let db = PersyDatabase::open(&file_name, num_cols, PREFIXES, OpenMode::ReadWrite).unwrap();
 
         TestContext { file_name, db }
     }
@@ -357,6 +776,344 @@ mod tests {
         assert_eq!(results.len(), 2);
     }
 
+    #[test]
+    fn test_single_writer() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[1], &[1]);
+        ctx.db.write(tx).unwrap();
+
+        let err = PersyDatabase::open(&ctx.file_name, 1, PREFIXES, OpenMode::ReadWrite)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("already opened for writing"));
+
+        let reader = PersyDatabase::open(&ctx.file_name, 1, PREFIXES, OpenMode::ReadOnly).unwrap();
+        assert_eq!(reader.get(0, &[1]).unwrap(), Some(vec![1]));
+
+        let mut tx = reader.transaction();
+        tx.put(0, &[2], &[2]);
+        let err = reader.write(tx).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(ctx.db.get(0, &[2]).unwrap(), None);
+
+        // The lock is released along with the writer
+        let file_name = ctx.file_name.clone();
+        drop(ctx);
+        let db = PersyDatabase::open(&file_name, 1, PREFIXES, OpenMode::ReadWrite).unwrap();
+        drop(db);
+        let _ = std::fs::remove_file(&file_name);
+        let _ = std::fs::remove_file(lock_path(&file_name));
+    }
+
+    #[test]
+    fn test_iter_malformed_key() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.db.begin().unwrap();
+        let rec_id = tx.insert("0", &[1, 2, 3]).unwrap();
+        tx.put(&key_index(0), "not a hex key".to_string(), rec_id)
+            .unwrap();
+        tx.put(&id_index(0), rec_id, "not a hex key".to_string())
+            .unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+
+        let results = ctx.db.iter(0).collect::<Vec<_>>();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_delete_unregistered_prefix() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[5, 6, 7], &[1]);
+        tx.put(0, &[5, 6, 7, 1], &[2]);
+        tx.put(0, &[5, 6, 8], &[3]);
+        tx.put(0, &[5, 6], &[4]);
+        tx.put(0, &[1, 2, 3, 4], &[5]);
+        tx.put(0, &[1, 2, 4], &[6]);
+        ctx.db.write(tx).unwrap();
+
+        let keys_with_prefix = |prefix: &[u8]| {
+            let mut keys = ctx
+                .db
+                .iter_with_prefix(0, prefix)
+                .map(|res| res.map(|(key, _)| key.to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            keys_with_prefix(&[5, 6, 7]),
+            vec![vec![5, 6, 7], vec![5, 6, 7, 1]]
+        );
+        assert_eq!(ctx.db.get_by_prefix(0, &[5, 6, 8]).unwrap(), Some(vec![3]));
+
+        let mut tx = ctx.db.transaction();
+        tx.delete_prefix(0, &[5, 6, 7]);
+        tx.delete_prefix(0, &[1, 2, 3]);
+        ctx.db.write(tx).unwrap();
+
+        assert!(keys_with_prefix(&[5, 6, 7]).is_empty());
+        assert_eq!(ctx.db.get_by_prefix(0, &[5, 6, 7]).unwrap(), None);
+        assert_eq!(keys_with_prefix(&[5, 6]), vec![vec![5, 6], vec![5, 6, 8]]);
+        // [1, 2] is a registered prefix
+        assert_eq!(keys_with_prefix(&[1, 2]), vec![vec![1, 2, 4]]);
+        assert_eq!(ctx.db.iter(0).count(), 3);
+    }
+
+    #[test]
+    fn test_register_prefix() {
+        let mut ctx = setup(2);
+        let mut tx = ctx.db.transaction();
+        tx.put(1, &[7, 7, 1], &[1]);
+        tx.put(1, &[7, 7, 2], &[2]);
+        tx.put(1, &[7, 8], &[3]);
+        tx.put(0, &[7, 7, 3], &[4]);
+        ctx.db.write(tx).unwrap();
+
+        ctx.db.register_prefix(1, &[7, 7]).unwrap();
+
+        let mut tx = ctx.db.transaction();
+        tx.put(1, &[7, 7, 4], &[5]);
+        ctx.db.write(tx).unwrap();
+
+        let indexed = ctx
+            .db
+            .db
+            .get::<String, PersyId>(PREFIXES_INDEX, &prefix_index_key(1, &[7, 7]))
+            .unwrap()
+            .count();
+        assert_eq!(indexed, 3);
+        assert!(!ctx.db.is_registered_prefix(0, &[7, 7]));
+
+        let mut values = ctx
+            .db
+            .iter_with_prefix(1, &[7, 7])
+            .map(|res| res.map(|(_, value)| value))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        values.sort();
+        assert_eq!(values, vec![vec![1], vec![2], vec![5]]);
+    }
+
+    #[test]
+    fn test_get_by_prefix_ordered() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        // [1] is a registered prefix, [3] is not
+        for prefix in [1, 3] {
+            tx.put(0, &[prefix, 7], &[prefix, 7]);
+            tx.put(0, &[prefix, 2], &[prefix, 2]);
+            tx.put(0, &[prefix, 9], &[prefix, 9]);
+            tx.put(0, &[prefix, 5], &[prefix, 5]);
+        }
+        tx.put(0, &[2, 0], &[2, 0]);
+        ctx.db.write(tx).unwrap();
+
+        let get = |prefix: &[u8], order| ctx.db.get_by_prefix_ordered(0, prefix, order).unwrap();
+
+        for prefix in [1, 3] {
+            assert_eq!(get(&[prefix], PrefixOrder::First), Some(vec![prefix, 2]));
+            assert_eq!(get(&[prefix], PrefixOrder::Last), Some(vec![prefix, 9]));
+        }
+        assert_eq!(get(&[], PrefixOrder::First), Some(vec![1, 2]));
+        assert_eq!(get(&[], PrefixOrder::Last), Some(vec![3, 9]));
+        assert_eq!(get(&[4], PrefixOrder::First), None);
+
+        let mut tx = ctx.db.transaction();
+        for prefix in [1, 3] {
+            tx.delete(0, &[prefix, 2]);
+            tx.delete(0, &[prefix, 9]);
+            tx.put(0, &[prefix, 5], &[prefix, 5, 5]);
+        }
+        ctx.db.write(tx).unwrap();
+
+        for prefix in [1, 3] {
+            assert_eq!(get(&[prefix], PrefixOrder::First), Some(vec![prefix, 5, 5]));
+            assert_eq!(get(&[prefix], PrefixOrder::Last), Some(vec![prefix, 7]));
+            assert_eq!(
+                ctx.db.get_by_prefix(0, &[prefix]).unwrap(),
+                Some(vec![prefix, 7])
+            );
+        }
+
+        let mut tx = ctx.db.transaction();
+        tx.delete_prefix(0, &[1]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(get(&[1], PrefixOrder::First), None);
+        assert_eq!(get(&[1], PrefixOrder::Last), None);
+        assert_eq!(get(&[], PrefixOrder::First), Some(vec![2, 0]));
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        for key in [[0, 9], [1, 0], [0, 2], [2, 5], [0, 5]] {
+            tx.put(0, &key, &key);
+        }
+        tx.delete(0, &[1, 0]);
+        ctx.db.write(tx).unwrap();
+
+        let keys = |from: &[u8]| -> Vec<Vec<u8>> {
+            ctx.db
+                .iter_from(0, from)
+                .unwrap()
+                .map(|res| res.unwrap().0.to_vec())
+                .collect()
+        };
+
+        assert_eq!(
+            keys(&[]),
+            vec![vec![0, 2], vec![0, 5], vec![0, 9], vec![2, 5]]
+        );
+        assert_eq!(keys(&[0, 5]), vec![vec![0, 5], vec![0, 9], vec![2, 5]]);
+        assert_eq!(keys(&[0, 6]), vec![vec![0, 9], vec![2, 5]]);
+        assert!(keys(&[3]).is_empty());
+
+        let (key, value) = ctx.db.iter_from(0, &[1]).unwrap().next().unwrap().unwrap();
+        assert_eq!(key.to_vec(), vec![2, 5]);
+        assert_eq!(value, vec![2, 5]);
+    }
+
+    #[test]
+    fn test_write_indexes_large_batch() {
+        let ctx = setup(1);
+        let leads: &[&[u8]] = &[&[1, 2], &[1, 255, 255], &[255, 255], &[8], b"abc", &[3]];
+
+        let mut tx = ctx.db.transaction();
+        for i in 0..10_000u32 {
+            let lead = leads[i as usize % leads.len()];
+            let key = [lead, &i.to_be_bytes()[..]].concat();
+            tx.put(0, &key, &i.to_le_bytes());
+        }
+        // Overwritten and deleted in the same batch
+        tx.put(0, &[1, 2, 0, 0, 0, 0], &[0]);
+        tx.delete(0, &[8, 0, 0, 0, 3]);
+        ctx.db.write(tx).unwrap();
+
+        for prefix in PREFIXES.iter().filter(|prefix| !prefix.is_empty()) {
+            let expected = (0..10_000u32)
+                .filter(|i| {
+                    let lead = leads[*i as usize % leads.len()];
+                    let key = [lead, &i.to_be_bytes()[..]].concat();
+                    key.starts_with(prefix) && key != [8, 0, 0, 0, 3]
+                })
+                .count();
+
+            assert_eq!(ctx.db.iter_with_prefix(0, prefix).count(), expected);
+
+            let indexed = ctx
+                .db
+                .db
+                .get::<String, PersyId>(PREFIXES_INDEX, &prefix_index_key(0, prefix))
+                .unwrap()
+                .count();
+            assert_eq!(indexed, expected);
+        }
+    }
+
+    #[test]
+    fn test_num_keys_and_size() {
+        let ctx = setup(2);
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[1], &[1, 1]);
+        tx.put(0, &[2], &[2, 2, 2]);
+        tx.put(0, &[1, 2, 3], &[3]);
+        tx.put(1, &[1], &[4, 4, 4, 4]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(ctx.db.num_keys(0).unwrap(), 3);
+        assert_eq!(ctx.db.approximate_size(0).unwrap(), 6);
+        assert_eq!(ctx.db.num_keys(1).unwrap(), 1);
+        assert_eq!(ctx.db.approximate_size(1).unwrap(), 4);
+
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[2], &[2]);
+        tx.delete(0, &[1]);
+        tx.delete(1, &[5]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(ctx.db.num_keys(0).unwrap(), 2);
+        assert_eq!(ctx.db.approximate_size(0).unwrap(), 2);
+        assert_eq!(ctx.db.num_keys(1).unwrap(), 1);
+
+        // [1, 2] is a registered prefix, the empty prefix recreates the segment
+        let mut tx = ctx.db.transaction();
+        tx.delete_prefix(0, &[1, 2]);
+        tx.delete_prefix(1, &[]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(ctx.db.num_keys(0).unwrap(), 1);
+        assert_eq!(ctx.db.approximate_size(0).unwrap(), 1);
+        assert_eq!(ctx.db.num_keys(1).unwrap(), 0);
+        assert_eq!(ctx.db.approximate_size(1).unwrap(), 0);
+
+        let mut tx = ctx.db.transaction();
+        tx.put(1, &[9], &[9, 9]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(ctx.db.num_keys(1).unwrap(), 1);
+        assert_eq!(ctx.db.approximate_size(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_io_stats() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[1], &[1, 1]);
+        tx.put(0, &[2], &[2, 2, 2]);
+        tx.delete(0, &[3]);
+        ctx.db.write(tx).unwrap();
+
+        assert_eq!(ctx.db.get(0, &[1]).unwrap(), Some(vec![1, 1]));
+        assert_eq!(ctx.db.get(0, &[3]).unwrap(), None);
+
+        let stats = ctx.db.io_stats(IoStatsKind::SincePrevious);
+        assert_eq!(stats.transactions, 1);
+        assert_eq!(stats.writes, 3);
+        assert_eq!(stats.bytes_written, 7);
+        assert_eq!(stats.reads, 2);
+        assert_eq!(stats.bytes_read, 2);
+
+        ctx.db.get(0, &[2]).unwrap();
+
+        let stats = ctx.db.io_stats(IoStatsKind::SincePrevious);
+        assert_eq!(stats.transactions, 0);
+        assert_eq!(stats.reads, 1);
+        assert_eq!(stats.bytes_read, 3);
+
+        let stats = ctx.db.io_stats(IoStatsKind::Overall);
+        assert_eq!(stats.transactions, 1);
+        assert_eq!(stats.reads, 3);
+    }
+
+    #[test]
+    fn test_iter_with_prefix_is_byte_aligned() {
+        let ctx = setup(1);
+        let mut tx = ctx.db.transaction();
+        tx.put(0, &[0x10, 0x01], &[1]);
+        tx.put(0, &[0x00, 0x11], &[2]);
+        tx.put(0, &[0x01, 0x00], &[3]);
+        ctx.db.write(tx).unwrap();
+
+        let keys = ctx
+            .db
+            .iter_with_prefix(0, &[1])
+            .map(|res| res.map(|(key, _)| key.to_vec()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(keys, vec![vec![0x01, 0x00]]);
+    }
+
     #[test]
     pub fn test_put_and_get() {
         let ctx = setup(1);
@@ -21,7 +21,11 @@ use futures::prelude::*;
 use kvdb::{DBOp, DBTransaction};
 
 use log::{debug, warn};
+use std::cell::RefCell;
 use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use crate::error::Error;
 
@@ -143,11 +147,13 @@ fn try_create_missing_stores(req: &IdbOpenDbRequest, columns: u32, version: Opti
     on_upgradeneeded.forget();
 }
 
-/// Commit a transaction to the IndexedDB.
+/// Commit a transaction to the IndexedDB. The returned future resolves once the transaction is
+/// completed or aborted. If `pending` is provided, it's incremented until then.
 pub fn idb_commit_transaction(
     idb: &IdbDatabase,
     txn: &DBTransaction,
     columns: u32,
+    pending: Option<Arc<AtomicUsize>>,
 ) -> impl Future<Output = ()> {
     let store_names_js = store_names_js(columns);
 
@@ -207,11 +213,26 @@ pub fn idb_commit_transaction(
         }
     }
 
-    let (tx, rx) = channel::oneshot::channel::<()>();
+    if let Some(pending) = &pending {
+        pending.fetch_add(1, Ordering::SeqCst);
+    }
 
-    let on_complete = Closure::once(move || {
-        let _ = tx.send(());
+    // Either `complete` or `abort` is fired, finish only once just in case
+    let (tx, rx) = channel::oneshot::channel::<()>();
+    let tx = RefCell::new(Some(tx));
+    let finish = Rc::new(move || {
+        if let Some(tx) = tx.borrow_mut().take() {
+            if let Some(pending) = &pending {
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            let _ = tx.send(());
+        }
     });
+
+    let on_complete = {
+        let finish = finish.clone();
+        Closure::once(move || (*finish)())
+    };
     idb_txn.set_oncomplete(Some(on_complete.as_ref().unchecked_ref()));
     on_complete.forget();
 
@@ -221,6 +242,10 @@ pub fn idb_commit_transaction(
     idb_txn.set_onerror(Some(on_error.as_ref().unchecked_ref()));
     on_error.forget();
 
+    let on_abort = Closure::once(move || (*finish)());
+    idb_txn.set_onabort(Some(on_abort.as_ref().unchecked_ref()));
+    on_abort.forget();
+
     rx.map(|_| ())
 }
 
@@ -9,7 +9,8 @@
 //! A key-value database for use in browsers
 //!
 //! Writes data both into memory and IndexedDB, reads the whole database in memory
-//! from the IndexedDB on `open`.
+//! from the IndexedDB on `open`. Reads are always served from memory, so they reflect
+//! the latest writes even if those are not committed to IndexedDB yet, see `Database::flush`.
 
 #![deny(missing_docs)]
 
@@ -17,6 +18,8 @@ mod error;
 mod indexed_db;
 
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub use error::Error;
 use futures::prelude::*;
@@ -33,6 +36,7 @@ pub struct Database {
     columns: u32,
     in_memory: InMemory,
     indexed_db: SendWrapper<IdbDatabase>,
+    pending: Arc<AtomicUsize>,
 }
 
 // TODO: implement when web-based implementation need memory stats
@@ -79,6 +83,7 @@ impl Database {
             columns,
             in_memory,
             indexed_db: inner,
+            pending: Arc::new(AtomicUsize::new(0)),
         })
     }
 
@@ -91,6 +96,28 @@ impl Database {
     pub fn version(&self) -> u32 {
         self.version
     }
+
+    /// Number of written transactions that are not committed to IndexedDB yet.
+    ///
+    /// It's tracked here rather than by the users of the database since only the database sees
+    /// the completion of its IndexedDB transactions, the wasm bindings just add the counts of the
+    /// databases of a state up.
+    pub fn pending_writes(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once all the transactions written before the call are committed to IndexedDB.
+    ///
+    /// IndexedDB runs readwrite transactions with overlapping scopes in the order of their
+    /// creation, so it's enough to wait for an empty transaction over all the columns.
+    pub fn flush(&self) -> impl Future<Output = ()> {
+        indexed_db::idb_commit_transaction(
+            &*self.indexed_db,
+            &DBTransaction::new(),
+            self.columns,
+            None,
+        )
+    }
 }
 
 impl Drop for Database {
@@ -109,7 +136,12 @@ impl KeyValueDB for Database {
     }
 
     fn write(&self, transaction: DBTransaction) -> io::Result<()> {
-        let _ = indexed_db::idb_commit_transaction(&*self.indexed_db, &transaction, self.columns);
+        let _ = indexed_db::idb_commit_transaction(
+            &*self.indexed_db,
+            &transaction,
+            self.columns,
+            Some(self.pending.clone()),
+        );
         self.in_memory.write(transaction)
     }
 
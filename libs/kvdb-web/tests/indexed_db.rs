@@ -94,3 +94,29 @@ async fn reopen_the_database_with_more_columns() {
     // The version should be bumped
     assert_eq!(db.version(), 2);
 }
+
+#[wasm_bindgen_test]
+async fn flush_and_reopen() {
+    let db = open_db(1, "flush_and_reopen").await;
+
+    let mut batch = db.transaction();
+    batch.put(0, b"hello", b"world");
+    db.write(batch).unwrap();
+
+    // Reads are served from memory before the write is committed
+    assert_eq!(db.get(0, b"hello").unwrap().unwrap(), b"world");
+
+    let mut batch = db.transaction();
+    batch.put(0, b"hello", b"again");
+    db.write(batch).unwrap();
+    assert_eq!(db.get(0, b"hello").unwrap().unwrap(), b"again");
+    assert!(db.pending_writes() > 0);
+
+    db.flush().await;
+    assert_eq!(db.pending_writes(), 0);
+
+    drop(db);
+
+    let db = open_db(1, "flush_and_reopen").await;
+    assert_eq!(db.get(0, b"hello").unwrap().unwrap(), b"again");
+}
@@ -10,13 +10,16 @@ declare class MerkleTree {
 
     getRoot(): string;
     getNextIndex(): number;
+    getStats(): TreeStats;
     getNode(height: number, index: number): string;
     addHash(index: number, hash: Buffer): void;
     addCommitment(index: number, hash: Buffer): void;
     appendHash(hash: Buffer): number;
     getProof(index: number): MerkleProof;
     getCommitmentProof(index: number): MerkleProof;
+    prepareTreeUpdate(commitmentIndex: number, commitment: Buffer): { public: TreePub, secret: TreeSec };
     getAllNodes(): any;
+    getNodes(height: number, fromIndex: number, toIndex: number): [number, number][];
     getVirtualNode(
         height: number,
         index: number,
@@ -24,17 +27,144 @@ declare class MerkleTree {
         new_hashes_left_index: number,
         new_hashes_right_index: number,
     ): any;
+    getRootAfterVirtual(leafs: [number, string[]][], commitments: [number, string][]): string;
+    addLeafsAndCommitments(leafs: [number, string[]][], commitments: [number, string][]): void;
     rollback(index: number): void;
+    rollbackToRoot(expectedRoot: string, maxLookback: number): number | null;
+    checkIntegrity(): [number, number][];
+    repair(nodes: [number, number][]): void;
+    /** Checks a proof returned by `getProof` or `getCommitmentProof` against the root. */
+    static verifyProof(leaf: string, proof: MerkleProof, root: string): boolean;
+}
+
+export interface TreeStats {
+    nodes_per_height: number[];
+    temporary_subtrees: number;
+    next_index: number;
+    clean_index: number;
+    approximate_size: number;
 }
 
 declare class TxStorage {
     constructor(path: string);
     add(index: number, data: Buffer): void;
     get(index: number): Buffer | null;
+    /** Stores the items at consecutive indices in a single transaction. */
+    addBatch(startIndex: number, items: Buffer[]): void;
+    /** Stores the entries in a single transaction. */
+    setBatch(entries: [number, Buffer][]): void;
     delete(index: number): void;
+    /** Removes entries within `[from, to)` in a single transaction, returns their number. */
+    removeRange(from: number, to: number): number;
     count(): number;
+    /** Up to `limit` entries starting from `startIndex`, ascending by index. */
+    iter(startIndex: number, limit: number): { index: number, data: Buffer }[];
+    /** Entries within `[from, to)`, ascending by index. */
+    getRange(from: number, to: number): { index: number, data: Buffer }[];
+    lastIndex(): number | null;
+}
+
+/** Decimal or `0x`-prefixed hex string, or a safe integer. */
+export type Amount = string | number;
+
+export interface Output {
+    to: string;
+    amount: Amount;
+}
+
+export interface DepositData {
+    fee: Amount;
+    amount: Amount;
+    outputs?: Output[];
+}
+
+export interface TransferData {
+    fee: Amount;
+    outputs: Output[];
 }
 
+export interface WithdrawData {
+    fee: Amount;
+    amount: Amount;
+    /** Hex encoded receiver address */
+    to: string;
+    native_amount: Amount;
+    energy_amount: Amount;
+}
+
+export interface TransactionData {
+    public: TransferPub;
+    secret: TransferSec;
+    /** Hex encoded */
+    ciphertext: string;
+    /** Hex encoded */
+    memo: string;
+    commitment_root: string;
+    out_hashes: string[];
+    summary?: TxSummary;
+    warnings?: TxWarning[];
+}
+
+/** Reported when notes were already sent to the address `to`. */
+export interface TxWarning {
+    type: 'addressReuse';
+    to: string;
+}
+
+export interface TxSummary {
+    input_value: string;
+    output_value: string;
+    fee: string;
+    delta_value: string;
+    input_energy: string;
+    remaining_energy: string;
+    spent_note_indices: number[];
+}
+
+/** Thrown by the create* methods, amounts are decimal strings. */
+export interface CreateTxError extends Error {
+    code: string;
+    required?: string;
+    available?: string;
+    [field: string]: any;
+}
+
+declare class UserAccount {
+    /**
+     * sk is a little-endian encoded spending key, path is a prefix for the database files,
+     * poolId is 0 if omitted
+     */
+    constructor(sk: Buffer, path: string, poolId?: number);
+    /** Derives the spending key of the account `accountIndex` from a BIP-39 mnemonic */
+    static fromMnemonic(phrase: string, accountIndex: number, path: string): UserAccount;
+
+    generateAddress(): string;
+    totalBalance(): string;
+    addHashes(index: number, hashes: Buffer[]): void;
+    addAccount(index: number, account: Account): void;
+    addNotes(notes: { index: number, note: Note }[]): void;
+    earliestUsableIndex(): number;
+    /** Same as `earliestUsableIndex` but includes the optimistic accounts and notes */
+    earliestUsableIndexOptimistic(
+        newAccounts: [number, Account][],
+        newNotes: [number, Note][][],
+    ): number;
+    noteStatus(index: number): NoteStatus;
+    exportState(): Buffer;
+    importState(snapshot: Buffer): void;
+    createDeposit(deposit: DepositData): TransactionData;
+    createTransfer(transfer: TransferData): TransactionData;
+    createWithdraw(withdraw: WithdrawData): TransactionData;
+    createDepositAsync(deposit: DepositData): Promise<TransactionData>;
+    createTransferAsync(transfer: TransferData): Promise<TransactionData>;
+    createWithdrawAsync(withdraw: WithdrawData): Promise<TransactionData>;
+}
+
+export type NoteStatus =
+    | { status: 'spendable' }
+    | { status: 'lockedUntil'; accountIndex: number }
+    | { status: 'unknown' };
+
 export interface TransferPub {
     root: string;
     nullifier: string;
@@ -88,6 +218,8 @@ export interface MerkleProof {
     path: boolean[];
 }
 
+export type ProofStage = 'WitnessGeneration' | 'Proving' | 'Done';
+
 export interface SnarkProof {
     a: [string, string];
     b: [[string, string], [string, string]];
@@ -107,20 +239,49 @@ declare class Params {
     static fromFile(path: string): Params;
 }
 
+/** Cancels the proving started with `Proof.txAsync`, the promise is then rejected. */
+declare class CancelToken {
+    constructor();
+    cancel(): void;
+}
+
 declare class Proof {
     inputs: Array<string>;
     proof: SnarkProof;
 
     static tx(params: Params, tr_pub: TransferPub, tr_sec: TransferSec): Proof;
     static tree(params: Params, tr_pub: TreePub, tr_sec: TreeSec): Proof;
-    static txAsync(params: Params, tr_pub: TransferPub, tr_sec: TransferSec): Promise<Proof>;
+    static txAsync(params: Params, tr_pub: TransferPub, tr_sec: TransferSec, progress?: (stage: ProofStage) => void, cancel?: CancelToken): Promise<Proof>;
     static treeAsync(params: Params, tr_pub: TreePub, tr_sec: TreeSec): Promise<Proof>;
     static verify(vk: VK, proof: SnarkProof, inputs: Array<string>): boolean;
+    static verifyBatch(vk: VK, proofs: Array<{ inputs: Array<string>, proof: SnarkProof }>, seed?: Buffer): boolean;
+}
+
+// Available only if the module is built with the `plonk` feature.
+declare class PlonkParams {}
+
+export interface PlonkProof {
+    inputs: Array<string>;
+    proof: any;
+}
+
+declare class Plonk {
+    static readParams(data: Buffer): PlonkParams;
+    static proveTx(params: PlonkParams, tr_pub: TransferPub, tr_sec: TransferSec): PlonkProof;
+    static proveTxAsync(params: PlonkParams, tr_pub: TransferPub, tr_sec: TransferSec): Promise<PlonkProof>;
+    static verifyTx(params: PlonkParams, proof: PlonkProof): boolean;
 }
 
 declare class Helpers {
     static outCommitmentHash(hashes: Array<Buffer>): string
     static parseDelta(delta: string): { v: string, e: string, index: string, poolId: string }
+    static calculateNullifier(account: Account, eta: string, index: number): string
+    static txHash(inputHashes: Array<string | number>, outCommitment: string | number): string
+    static nullifier(accountHash: string | number, eta: string | number, index: string | number): string
+    static memoHash(memo: Buffer): string
+    static poseidonCompress(left: string | number, right: string | number): string
+    static hashAccount(account: Account): string
+    static hashNote(note: Note): string
     static numToStr(num: Buffer): string
     static strToNum(str: string): Buffer
 }
@@ -13,6 +13,10 @@ class MerkleTree {
         return zp.merkleGetNextIndex(this.inner)
     }
 
+    getStats() {
+        return zp.merkleGetStats(this.inner)
+    }
+
     getNode(height, index) {
         return zp.merkleGetNode(this.inner, height, index)
     }
@@ -37,10 +41,18 @@ class MerkleTree {
         return zp.merkleGetCommitmentProof(this.inner, index)
     }
 
+    prepareTreeUpdate(commitmentIndex, commitment) {
+        return zp.merklePrepareTreeUpdate(this.inner, commitmentIndex, commitment)
+    }
+
     getAllNodes() {
         return zp.merkleGetAllNodes(this.inner)
     }
 
+    getNodes(height, fromIndex, toIndex) {
+        return zp.merkleGetNodes(this.inner, height, fromIndex, toIndex)
+    }
+
     getVirtualNode(
         height,
         index,
@@ -58,9 +70,33 @@ class MerkleTree {
         )
     }
 
+    getRootAfterVirtual(leafs, commitments) {
+        return zp.merkleGetRootAfterVirtual(this.inner, leafs, commitments)
+    }
+
+    addLeafsAndCommitments(leafs, commitments) {
+        zp.merkleAddLeafsAndCommitments(this.inner, leafs, commitments)
+    }
+
     rollback(index) {
         return zp.merkleRollback(this.inner, index)
     }
+
+    rollbackToRoot(expectedRoot, maxLookback) {
+        return zp.merkleRollbackToRoot(this.inner, expectedRoot, maxLookback)
+    }
+
+    checkIntegrity() {
+        return zp.merkleCheckIntegrity(this.inner)
+    }
+
+    repair(nodes) {
+        zp.merkleRepair(this.inner, nodes)
+    }
+
+    static verifyProof(leaf, proof, root) {
+        return zp.merkleVerifyProof(leaf, proof, root)
+    }
 }
 
 class TxStorage {
@@ -76,13 +112,123 @@ class TxStorage {
         return zp.txStorageGet(this.inner, index);
     }
 
+    addBatch(startIndex, items) {
+        zp.txStorageAddBatch(this.inner, startIndex, items);
+    }
+
+    setBatch(entries) {
+        zp.txStorageSetBatch(this.inner, entries);
+    }
+
     delete(index) {
         return zp.txStorageDelete(this.inner, index);
     }
 
+    removeRange(from, to) {
+        return zp.txStorageRemoveRange(this.inner, from, to);
+    }
+
     count() {
         return zp.txStorageCount(this.inner);
     }
+
+    iter(startIndex, limit) {
+        return zp.txStorageIter(this.inner, startIndex, limit);
+    }
+
+    getRange(from, to) {
+        return zp.txStorageGetRange(this.inner, from, to);
+    }
+
+    lastIndex() {
+        return zp.txStorageLastIndex(this.inner);
+    }
+}
+
+class UserAccount {
+    constructor(sk, path, poolId) {
+        this.inner = zp.accountNew(sk, path, poolId);
+    }
+
+    static fromMnemonic(phrase, accountIndex, path) {
+        const account = Object.create(UserAccount.prototype);
+        account.inner = zp.accountFromMnemonic(phrase, accountIndex, path);
+        return account;
+    }
+
+    generateAddress() {
+        return zp.accountGenerateAddress(this.inner)
+    }
+
+    totalBalance() {
+        return zp.accountTotalBalance(this.inner)
+    }
+
+    addHashes(index, hashes) {
+        zp.accountAddHashes(this.inner, index, hashes)
+    }
+
+    addAccount(index, account) {
+        zp.accountAddAccount(this.inner, index, account)
+    }
+
+    addNotes(notes) {
+        zp.accountAddNotes(this.inner, notes)
+    }
+
+    earliestUsableIndex() {
+        return zp.accountEarliestUsableIndex(this.inner)
+    }
+
+    earliestUsableIndexOptimistic(newAccounts, newNotes) {
+        return zp.accountEarliestUsableIndexOptimistic(this.inner, newAccounts, newNotes)
+    }
+
+    noteStatus(index) {
+        return zp.accountNoteStatus(this.inner, index)
+    }
+
+    exportState() {
+        return zp.accountExportState(this.inner)
+    }
+
+    importState(snapshot) {
+        zp.accountImportState(this.inner, snapshot)
+    }
+
+    createDeposit(deposit) {
+        return zp.accountCreateDeposit(this.inner, deposit)
+    }
+
+    createTransfer(transfer) {
+        return zp.accountCreateTransfer(this.inner, transfer)
+    }
+
+    createWithdraw(withdraw) {
+        return zp.accountCreateWithdraw(this.inner, withdraw)
+    }
+
+    createDepositAsync(deposit) {
+        return zp.accountCreateDepositAsync(this.inner, deposit)
+    }
+
+    createTransferAsync(transfer) {
+        return zp.accountCreateTransferAsync(this.inner, transfer)
+    }
+
+    createWithdrawAsync(withdraw) {
+        return zp.accountCreateWithdrawAsync(this.inner, withdraw)
+    }
+}
+
+class CancelToken {
+    constructor() {
+        this.inner = zp.cancelTokenNew();
+    }
+
+    cancel() {
+        zp.cancelTokenCancel(this.inner);
+    }
 }
 
 const Params = {
@@ -93,9 +239,18 @@ const Params = {
 const Proof = {
     tx: zp.proveTx,
     tree: zp.proveTree,
-    txAsync: zp.proveTxAsync,
+    txAsync: (params, trPub, trSec, progress, cancel) =>
+        zp.proveTxAsync(params, trPub, trSec, progress, cancel && cancel.inner),
     treeAsync: zp.proveTreeAsync,
     verify: zp.verify,
+    verifyBatch: zp.verifyBatch,
+};
+
+const Plonk = {
+    readParams: zp.readPlonkParams,
+    proveTx: zp.provePlonkTx,
+    proveTxAsync: zp.provePlonkTxAsync,
+    verifyTx: zp.verifyPlonkTx,
 };
 
 class Helpers {
@@ -107,6 +262,34 @@ class Helpers {
         return zp.helpersParseDelta(delta)
     }
 
+    static calculateNullifier(account, eta, index) {
+        return zp.helpersCalculateNullifier(account, eta, index)
+    }
+
+    static txHash(inputHashes, outCommitment) {
+        return zp.helpersTxHash(inputHashes, outCommitment)
+    }
+
+    static nullifier(accountHash, eta, index) {
+        return zp.helpersNullifier(accountHash, eta, index)
+    }
+
+    static memoHash(memo) {
+        return zp.helpersMemoHash(memo)
+    }
+
+    static poseidonCompress(left, right) {
+        return zp.helpersPoseidonCompress(left, right)
+    }
+
+    static hashAccount(account) {
+        return zp.helpersPoseidonHashAccount(account)
+    }
+
+    static hashNote(note) {
+        return zp.helpersPoseidonHashNote(note)
+    }
+
     static numToStr(num) {
         return zp.helpersNumToStr(num)
     }
@@ -118,7 +301,10 @@ class Helpers {
 
 zp.MerkleTree = MerkleTree;
 zp.TxStorage = TxStorage;
+zp.UserAccount = UserAccount;
 zp.Params = Params;
 zp.Proof = Proof
+zp.CancelToken = CancelToken;
+zp.Plonk = Plonk;
 zp.Helpers = Helpers;
 module.exports = zp;
@@ -0,0 +1,454 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, RwLock},
+};
+
+use libzeropool_rs::{
+    client::{
+        amount,
+        state::{NativeState, StateSnapshot},
+        CreateTxError, NativeUserAccount, TokenAmount, TransactionData, TxOutput, TxSummary,
+        TxType as NativeTxType,
+    },
+    keys::derive_from_mnemonic,
+    libzeropool::{
+        constants,
+        fawkes_crypto::{
+            borsh::{BorshDeserialize, BorshSerialize},
+            core::sizedvec::SizedVec,
+            ff_uint::{Num, NumRepr, Uint},
+        },
+        native::{
+            account::Account as NativeAccount,
+            boundednum::BoundedNum,
+            note::Note as NativeNote,
+            tx::{TransferPub, TransferSec},
+        },
+        POOL_PARAMS,
+    },
+};
+use neon::{prelude::*, types::buffer::TypedArray};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Fr, Fs, PoolParams};
+
+pub struct UserAccount {
+    inner: Arc<RwLock<SharedUserAccount>>,
+}
+
+/// [`NativeUserAccount`] is neither `Send` nor `Sync` because its sign callback may be bound to
+/// the thread it was created on. The binding never sets one, so the account can be shared with
+/// the worker threads building the transactions.
+struct SharedUserAccount(NativeUserAccount<PoolParams>);
+
+// SAFETY: the sign callback is the only field that isn't `Send + Sync` and it's always `None`,
+// see `SharedUserAccount::new`.
+unsafe impl Send for SharedUserAccount {}
+unsafe impl Sync for SharedUserAccount {}
+
+impl SharedUserAccount {
+    fn new(account: NativeUserAccount<PoolParams>) -> Self {
+        assert!(account.sign_callback.is_none());
+
+        SharedUserAccount(account)
+    }
+}
+
+impl Deref for SharedUserAccount {
+    type Target = NativeUserAccount<PoolParams>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SharedUserAccount {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+pub type BoxedUserAccount = JsBox<UserAccount>;
+
+impl Finalize for UserAccount {}
+
+#[derive(Deserialize)]
+struct DepositData {
+    #[serde(deserialize_with = "amount::deserialize")]
+    fee: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    amount: TokenAmount<Fr>,
+    #[serde(default)]
+    outputs: Vec<TxOutput<Fr>>,
+}
+
+#[derive(Deserialize)]
+struct TransferData {
+    #[serde(deserialize_with = "amount::deserialize")]
+    fee: TokenAmount<Fr>,
+    outputs: Vec<TxOutput<Fr>>,
+}
+
+#[derive(Deserialize)]
+struct WithdrawData {
+    #[serde(deserialize_with = "amount::deserialize")]
+    fee: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    amount: TokenAmount<Fr>,
+    #[serde(with = "hex")]
+    to: Vec<u8>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    native_amount: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    energy_amount: TokenAmount<Fr>,
+}
+
+#[derive(Deserialize)]
+struct IndexedNote {
+    index: u64,
+    note: NativeNote<Fr>,
+}
+
+#[derive(Serialize)]
+struct TransactionDataSer {
+    public: TransferPub<Fr>,
+    secret: TransferSec<Fr>,
+    #[serde(with = "hex")]
+    ciphertext: Vec<u8>,
+    #[serde(with = "hex")]
+    memo: Vec<u8>,
+    commitment_root: Num<Fr>,
+    out_hashes: SizedVec<Num<Fr>, { constants::OUT + 1 }>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<TxSummary<Fr>>,
+}
+
+impl From<TransactionData<Fr>> for TransactionDataSer {
+    fn from(tx: TransactionData<Fr>) -> Self {
+        TransactionDataSer {
+            public: tx.public,
+            secret: tx.secret,
+            ciphertext: tx.ciphertext,
+            memo: tx.memo,
+            commitment_root: tx.commitment_root,
+            out_hashes: tx.out_hashes,
+            summary: tx.summary,
+        }
+    }
+}
+
+fn deposit_to_native(data: DepositData) -> NativeTxType<Fr> {
+    NativeTxType::Deposit {
+        fee: data.fee,
+        deposit_amount: data.amount,
+        outputs: data.outputs,
+    }
+}
+
+fn transfer_to_native(data: TransferData) -> NativeTxType<Fr> {
+    NativeTxType::Transfer {
+        fee: data.fee,
+        outputs: data.outputs,
+    }
+}
+
+fn withdraw_to_native(data: WithdrawData) -> NativeTxType<Fr> {
+    NativeTxType::Withdraw {
+        fee: data.fee,
+        withdraw_amount: data.amount,
+        to: data.to,
+        native_amount: data.native_amount,
+        energy_amount: data.energy_amount,
+    }
+}
+
+pub fn account_new(mut cx: FunctionContext) -> JsResult<BoxedUserAccount> {
+    let sk = {
+        let buffer = cx.argument::<JsBuffer>(0)?;
+        Num::<Fs>::from_uint(NumRepr(Uint::from_little_endian(buffer.as_slice(&cx))))
+    };
+    let sk = match sk {
+        Some(sk) => sk,
+        None => return cx.throw_error("Invalid spending key"),
+    };
+    let path = {
+        let path = cx.argument::<JsString>(1)?;
+        path.value(&mut cx)
+    };
+    let pool_id = match cx.argument_opt(2) {
+        Some(pool_id) if !pool_id.is_a::<JsUndefined, _>(&mut cx) => {
+            let pool_id = pool_id
+                .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                .value(&mut cx);
+            if pool_id < 0.0 || pool_id.fract() != 0.0 || pool_id > u32::MAX as f64 {
+                return cx.throw_error("Invalid pool id");
+            }
+            pool_id as u32
+        }
+        _ => 0,
+    };
+
+    boxed_account(&mut cx, sk, pool_id, &path)
+}
+
+pub fn account_from_mnemonic(mut cx: FunctionContext) -> JsResult<BoxedUserAccount> {
+    let phrase = cx.argument::<JsString>(0)?.value(&mut cx);
+    let account_index = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    if account_index < 0.0 || account_index.fract() != 0.0 || account_index > u32::MAX as f64 {
+        return cx.throw_error("Invalid account index");
+    }
+    let path = cx.argument::<JsString>(2)?.value(&mut cx);
+
+    let sk = match derive_from_mnemonic::<Fs>(&phrase, account_index as u32) {
+        Ok(sk) => sk,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+
+    boxed_account(&mut cx, sk, 0, &path)
+}
+
+fn boxed_account<'a>(
+    cx: &mut FunctionContext<'a>,
+    sk: Num<Fs>,
+    pool_id: u32,
+    path: &str,
+) -> JsResult<'a, BoxedUserAccount> {
+    let state = match NativeState::init_native(path, POOL_PARAMS.clone()) {
+        Ok(state) => state,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+    let pool_id = BoundedNum::new(Num::from(pool_id as u64));
+    let inner = NativeUserAccount::new_with_pool(sk, pool_id, state, POOL_PARAMS.clone());
+
+    Ok(cx.boxed(UserAccount {
+        inner: Arc::new(RwLock::new(SharedUserAccount::new(inner))),
+    }))
+}
+
+pub fn account_generate_address(mut cx: FunctionContext) -> JsResult<JsString> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let address = account.inner.read().unwrap().generate_address();
+
+    Ok(cx.string(address))
+}
+
+pub fn account_total_balance(mut cx: FunctionContext) -> JsResult<JsString> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let balance = account.inner.read().unwrap().state.total_balance();
+
+    Ok(cx.string(balance.to_string()))
+}
+
+pub fn account_add_hashes(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+    let hashes = cx.argument::<JsArray>(2)?.to_vec(&mut cx)?;
+
+    let hashes = hashes
+        .into_iter()
+        .map(|hash| {
+            let buffer = hash.downcast_or_throw::<JsBuffer, _>(&mut cx)?;
+            match Num::try_from_slice(buffer.as_slice(&cx)) {
+                Ok(hash) => Ok(hash),
+                Err(err) => cx.throw_error(err.to_string()),
+            }
+        })
+        .collect::<NeonResult<Vec<Num<Fr>>>>()?;
+
+    account
+        .inner
+        .write()
+        .unwrap()
+        .state
+        .add_hashes(index, &hashes);
+
+    Ok(cx.undefined())
+}
+
+pub fn account_add_account(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+    let acc_js = cx.argument::<JsValue>(2)?;
+    let acc: NativeAccount<Fr> =
+        neon_serde::from_value(&mut cx, acc_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    account.inner.write().unwrap().state.add_account(index, acc);
+
+    Ok(cx.undefined())
+}
+
+pub fn account_add_notes(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let notes_js = cx.argument::<JsValue>(1)?;
+    let notes: Vec<IndexedNote> =
+        neon_serde::from_value(&mut cx, notes_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let mut account = account.inner.write().unwrap();
+    for IndexedNote { index, note } in notes {
+        account.state.add_note(index, note);
+    }
+
+    Ok(cx.undefined())
+}
+
+pub fn account_earliest_usable_index(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let index = account.inner.read().unwrap().state.earliest_usable_index();
+
+    Ok(cx.number(index as f64))
+}
+
+pub fn account_earliest_usable_index_optimistic(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let accounts_js = cx.argument::<JsValue>(1)?;
+    let new_accounts: Vec<(u64, NativeAccount<Fr>)> = neon_serde::from_value(&mut cx, accounts_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+    let notes_js = cx.argument::<JsValue>(2)?;
+    let new_notes: Vec<Vec<(u64, NativeNote<Fr>)>> =
+        neon_serde::from_value(&mut cx, notes_js).or_else(|err| cx.throw_error(err.to_string()))?;
+    let new_notes: Vec<_> = new_notes.into_iter().flatten().collect();
+
+    let index = account
+        .inner
+        .read()
+        .unwrap()
+        .state
+        .earliest_usable_index_optimistic(&new_accounts, &new_notes);
+
+    Ok(cx.number(index as f64))
+}
+
+pub fn account_note_status(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+
+    let status = account.inner.read().unwrap().state.note_status(index);
+    let result = neon_serde::to_value(&mut cx, &status).unwrap();
+
+    Ok(result)
+}
+
+pub fn account_export_state(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let snapshot = account.inner.read().unwrap().state.export();
+    let data = snapshot
+        .try_to_vec()
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let mut buffer = cx.buffer(data.len())?;
+    buffer.as_mut_slice(&mut cx).copy_from_slice(&data);
+
+    Ok(buffer)
+}
+
+pub fn account_import_state(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let snapshot = {
+        let buffer = cx.argument::<JsBuffer>(1)?;
+        StateSnapshot::try_from_slice(buffer.as_slice(&cx))
+    };
+    let snapshot = snapshot.or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let result = account.inner.write().unwrap().state.restore(&snapshot);
+    result.or_else(|err| cx.throw_error(err.to_string()))?;
+
+    Ok(cx.undefined())
+}
+
+/// Throws an `Error` that also carries the `code` and the data of the variant as properties.
+fn throw_create_tx_error<'a, C: Context<'a>, T>(cx: &mut C, err: CreateTxError) -> NeonResult<T> {
+    let js_err = cx.error(err.to_string())?;
+    let fields = neon_serde::to_value(cx, &err).or_else(|err| cx.throw_error(err.to_string()))?;
+    let fields = fields
+        .downcast::<JsObject, _>(cx)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    for key in fields.get_own_property_names(cx)?.to_vec(cx)? {
+        let value: Handle<JsValue> = fields.get(cx, key)?;
+        js_err.set(cx, key, value)?;
+    }
+
+    cx.throw(js_err)
+}
+
+fn create_tx<T: DeserializeOwned>(
+    mut cx: FunctionContext,
+    to_native: fn(T) -> NativeTxType<Fr>,
+) -> JsResult<JsValue> {
+    let account = cx.argument::<BoxedUserAccount>(0)?;
+    let data_js = cx.argument::<JsValue>(1)?;
+    let data: T =
+        neon_serde::from_value(&mut cx, data_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let tx = account
+        .inner
+        .read()
+        .unwrap()
+        .create_tx(to_native(data), None, None);
+    let tx = tx.or_else(|err| throw_create_tx_error(&mut cx, err))?;
+
+    neon_serde::to_value(&mut cx, &TransactionDataSer::from(tx))
+        .or_else(|err| cx.throw_error(err.to_string()))
+}
+
+fn create_tx_async<T: DeserializeOwned + Send + 'static>(
+    mut cx: FunctionContext,
+    to_native: fn(T) -> NativeTxType<Fr>,
+) -> JsResult<JsPromise> {
+    let account = cx.argument::<BoxedUserAccount>(0)?.inner.clone();
+    let data_js = cx.argument::<JsValue>(1)?;
+    let data: T =
+        neon_serde::from_value(&mut cx, data_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+
+    rayon::spawn(move || {
+        let tx = account
+            .read()
+            .unwrap()
+            .create_tx(to_native(data), None, None);
+
+        deferred.settle_with(&channel, move |mut cx| {
+            let tx = tx.or_else(|err| throw_create_tx_error(&mut cx, err))?;
+
+            neon_serde::to_value(&mut cx, &TransactionDataSer::from(tx))
+                .or_else(|err| cx.throw_error(err.to_string()))
+        });
+    });
+
+    Ok(promise)
+}
+
+pub fn account_create_deposit(cx: FunctionContext) -> JsResult<JsValue> {
+    create_tx(cx, deposit_to_native)
+}
+
+pub fn account_create_transfer(cx: FunctionContext) -> JsResult<JsValue> {
+    create_tx(cx, transfer_to_native)
+}
+
+pub fn account_create_withdraw(cx: FunctionContext) -> JsResult<JsValue> {
+    create_tx(cx, withdraw_to_native)
+}
+
+pub fn account_create_deposit_async(cx: FunctionContext) -> JsResult<JsPromise> {
+    create_tx_async(cx, deposit_to_native)
+}
+
+pub fn account_create_transfer_async(cx: FunctionContext) -> JsResult<JsPromise> {
+    create_tx_async(cx, transfer_to_native)
+}
+
+pub fn account_create_withdraw_async(cx: FunctionContext) -> JsResult<JsPromise> {
+    create_tx_async(cx, withdraw_to_native)
+}
@@ -1,13 +1,23 @@
-use std::{convert::TryInto, str::FromStr};
+use std::str::FromStr;
 
-use libzeropool_rs::libzeropool::{
-    constants::OUT,
-    fawkes_crypto::{
-        borsh::{BorshDeserialize, BorshSerialize},
-        ff_uint::Num,
+use libzeropool_rs::{
+    delta::decode_delta,
+    libzeropool::{
+        constants::{IN, OUT},
+        fawkes_crypto::{
+            borsh::{BorshDeserialize, BorshSerialize},
+            ff_uint::{Num, NumRepr, Uint},
+            native::poseidon::poseidon,
+        },
+        native::{
+            account::Account,
+            note::Note,
+            params::PoolParams,
+            tx::{nullifier, out_commitment_hash, tx_hash},
+        },
+        POOL_PARAMS,
     },
-    native::tx::{out_commitment_hash, parse_delta},
-    POOL_PARAMS,
+    utils::keccak256,
 };
 use neon::{prelude::*, types::buffer::TypedArray};
 
@@ -35,22 +45,109 @@ pub fn out_commitment(mut cx: FunctionContext) -> JsResult<JsValue> {
     Ok(res)
 }
 
+pub fn calculate_nullifier(mut cx: FunctionContext) -> JsResult<JsString> {
+    let account_js = cx.argument::<JsValue>(0)?;
+    let account: Account<Fr> = neon_serde::from_value(&mut cx, account_js).unwrap();
+    let eta: Num<Fr> = {
+        let eta = cx.argument::<JsString>(1)?;
+        Num::from_str(eta.value(&mut cx).as_str()).unwrap()
+    };
+    let index = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as u64
+    };
+
+    let nullifier = nullifier(
+        account.hash(&*POOL_PARAMS),
+        eta,
+        index.into(),
+        &*POOL_PARAMS,
+    );
+
+    Ok(cx.string(nullifier.to_string()))
+}
+
+pub fn tx_hash_str(mut cx: FunctionContext) -> JsResult<JsString> {
+    let input_hashes = cx.argument::<JsArray>(0)?.to_vec(&mut cx)?;
+    if input_hashes.len() != IN + 1 {
+        return cx.throw_error(format!(
+            "Expected {} input hashes, got {}",
+            IN + 1,
+            input_hashes.len()
+        ));
+    }
+
+    let input_hashes = input_hashes
+        .into_iter()
+        .map(|val| num_from_js(&mut cx, val))
+        .collect::<NeonResult<Vec<_>>>()?;
+    let out_commit = cx.argument::<JsValue>(1)?;
+    let out_commit = num_from_js(&mut cx, out_commit)?;
+
+    Ok(cx.string(compute_tx_hash(&input_hashes, out_commit).to_string()))
+}
+
+pub fn nullifier_str(mut cx: FunctionContext) -> JsResult<JsString> {
+    let account_hash = cx.argument::<JsValue>(0)?;
+    let account_hash = num_from_js(&mut cx, account_hash)?;
+    let eta = cx.argument::<JsValue>(1)?;
+    let eta = num_from_js(&mut cx, eta)?;
+    let index = cx.argument::<JsValue>(2)?;
+    let index = num_from_js(&mut cx, index)?;
+
+    Ok(cx.string(compute_nullifier(account_hash, eta, index).to_string()))
+}
+
+pub fn poseidon_compress_str(mut cx: FunctionContext) -> JsResult<JsString> {
+    let left = cx.argument::<JsValue>(0)?;
+    let left = num_from_js(&mut cx, left)?;
+    let right = cx.argument::<JsValue>(1)?;
+    let right = num_from_js(&mut cx, right)?;
+
+    Ok(cx.string(compute_poseidon_compress(left, right).to_string()))
+}
+
+pub fn poseidon_hash_account(mut cx: FunctionContext) -> JsResult<JsString> {
+    let account_js = cx.argument::<JsValue>(0)?;
+    let account: Account<Fr> = neon_serde::from_value(&mut cx, account_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    Ok(cx.string(account.hash(&*POOL_PARAMS).to_string()))
+}
+
+pub fn poseidon_hash_note(mut cx: FunctionContext) -> JsResult<JsString> {
+    let note_js = cx.argument::<JsValue>(0)?;
+    let note: Note<Fr> =
+        neon_serde::from_value(&mut cx, note_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    Ok(cx.string(note.hash(&*POOL_PARAMS).to_string()))
+}
+
+pub fn memo_hash_str(mut cx: FunctionContext) -> JsResult<JsString> {
+    let memo = cx.argument::<JsBuffer>(0)?;
+    let memo_hash = compute_memo_hash(memo.as_slice(&cx));
+
+    Ok(cx.string(memo_hash.to_string()))
+}
+
 pub fn parse_delta_string(mut cx: FunctionContext) -> JsResult<JsObject> {
     let delta_str_js = cx.argument::<JsString>(0)?;
     let delta_str = delta_str_js.value(&mut cx);
 
-    let delta: Num<Fr> = Num::from_str(delta_str.as_str()).unwrap();
+    let delta: Num<Fr> = match Num::from_str(delta_str.as_str()) {
+        Ok(delta) => delta,
+        Err(_) => return cx.throw_error("Invalid delta"),
+    };
 
-    let delta_params = parse_delta(delta);
-    let value_int: i64 = delta_params.0.try_into().unwrap();
-    let energy_int: i64 = delta_params.1.try_into().unwrap();
-    let index_uint: u64 = delta_params.2.try_into().unwrap();
-    let pool_id_uint: u64 = delta_params.3.try_into().unwrap();
+    let delta = match decode_delta(delta) {
+        Ok(delta) => delta,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
 
-    let v = neon_serde::to_value(&mut cx, &value_int).unwrap();
-    let e = neon_serde::to_value(&mut cx, &energy_int).unwrap();
-    let index = neon_serde::to_value(&mut cx, &index_uint).unwrap();
-    let pool_id = neon_serde::to_value(&mut cx, &pool_id_uint).unwrap();
+    let v = cx.string(delta.v.to_string());
+    let e = cx.string(delta.e.to_string());
+    let index = cx.string(delta.index.to_string());
+    let pool_id = cx.string(delta.pool_id.to_string());
 
     let js_object = JsObject::new(&mut cx);
     js_object.set(&mut cx, "v", v)?;
@@ -82,3 +179,128 @@ pub fn str_to_num(mut cx: FunctionContext) -> JsResult<JsBuffer> {
 
     Ok(buf)
 }
+
+fn compute_tx_hash(input_hashes: &[Num<Fr>], out_commit: Num<Fr>) -> Num<Fr> {
+    tx_hash(input_hashes, out_commit, &*POOL_PARAMS)
+}
+
+fn compute_nullifier(account_hash: Num<Fr>, eta: Num<Fr>, index: Num<Fr>) -> Num<Fr> {
+    nullifier(account_hash, eta, index, &*POOL_PARAMS)
+}
+
+fn compute_poseidon_compress(left: Num<Fr>, right: Num<Fr>) -> Num<Fr> {
+    poseidon(&[left, right], POOL_PARAMS.compress())
+}
+
+/// Same reduction of the memo keccak256 hash as the one used for `TransferPub::memo`.
+fn compute_memo_hash(memo: &[u8]) -> Num<Fr> {
+    let hash = keccak256(memo);
+    Num::from_uint_reduced(NumRepr(Uint::from_big_endian(&hash)))
+}
+
+/// Accepts a JS number or a decimal or `0x`-prefixed hex string.
+fn num_from_js<'a, C: Context<'a>>(cx: &mut C, val: Handle<'a, JsValue>) -> NeonResult<Num<Fr>> {
+    let res = if let Ok(num) = val.downcast::<JsNumber, _>(cx) {
+        let num = num.value(cx);
+        if num < 0.0 || num.fract() != 0.0 || num > u64::MAX as f64 {
+            return cx.throw_error(format!("Invalid number: {}", num));
+        }
+        Ok(Num::from(num as u64))
+    } else if let Ok(s) = val.downcast::<JsString, _>(cx) {
+        parse_num(&s.value(cx))
+    } else {
+        Err("Expected a string or a number".to_owned())
+    };
+
+    res.or_else(|err| cx.throw_error(err))
+}
+
+fn parse_num(s: &str) -> Result<Num<Fr>, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex_str) => {
+            let hex_str = if hex_str.len() % 2 == 1 {
+                format!("0{}", hex_str)
+            } else {
+                hex_str.to_owned()
+            };
+            let bytes = hex::decode(&hex_str).map_err(|err| err.to_string())?;
+            if bytes.len() > 32 {
+                return Err(format!("Hex value is too long: {}", s));
+            }
+
+            let mut buf = [0u8; 32];
+            buf[32 - bytes.len()..].copy_from_slice(&bytes);
+            Num::from_uint(NumRepr(Uint::from_big_endian(&buf)))
+                .ok_or_else(|| format!("Value is not a field element: {}", s))
+        }
+        None => Num::from_str(s).map_err(|_| format!("Invalid number: {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::{
+        client::{state::State, TxType, UserAccount},
+        libzeropool::native::boundednum::BoundedNum,
+        merkle::MerkleTree,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_num() {
+        assert_eq!(parse_num("255").unwrap(), Num::from(255u64));
+        assert_eq!(parse_num("0xff").unwrap(), Num::from(255u64));
+        assert_eq!(parse_num("0x0ff").unwrap(), Num::from(255u64));
+        assert!(parse_num("0xzz").is_err());
+        assert!(parse_num(&format!("0x{}", "ff".repeat(32))).is_err());
+        assert!(parse_num("abc").is_err());
+    }
+
+    #[test]
+    fn test_helpers_match_create_tx() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::ONE),
+                    outputs: vec![],
+                },
+                None,
+                None,
+            )
+            .unwrap();
+
+        let account_hash = tx.secret.tx.input.0.hash(&*POOL_PARAMS);
+        let eta = acc.keys.eta;
+        let nullifier = compute_nullifier(account_hash, eta, Num::ZERO);
+        assert_eq!(nullifier, tx.public.nullifier);
+
+        let account_hash_hex = {
+            let mut bytes = account_hash.try_to_vec().unwrap();
+            bytes.reverse();
+            format!("0x{}", hex::encode(bytes))
+        };
+        let nullifier = compute_nullifier(
+            parse_num(&account_hash_hex).unwrap(),
+            parse_num(&eta.to_string()).unwrap(),
+            parse_num("0").unwrap(),
+        );
+        assert_eq!(nullifier, tx.public.nullifier);
+
+        assert_eq!(compute_memo_hash(&tx.memo), tx.public.memo);
+    }
+
+    #[test]
+    fn test_poseidon_compress_matches_default_hashes() {
+        let tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        assert_eq!(
+            compute_poseidon_compress(Num::ZERO, Num::ZERO),
+            tree.get(1, 0)
+        );
+    }
+}
@@ -1,20 +1,27 @@
+#[cfg(feature = "groth16")]
+use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::engines::Bn256;
 use libzeropool_rs::libzeropool::{
     constants,
-    fawkes_crypto::backend::bellman_groth16::engines::Bn256,
     native::params::{PoolBN256, PoolParams as PoolParamsTrait},
 };
 use neon::prelude::*;
 use serde::Serialize;
 
+mod account;
 mod helpers;
 mod merkle;
+#[cfg(feature = "groth16")]
 mod params;
+#[cfg(feature = "plonk")]
+mod plonk;
+#[cfg(feature = "groth16")]
 mod proof;
 mod storage;
 
 pub type PoolParams = PoolBN256;
 pub type Fr = <PoolParams as PoolParamsTrait>::Fr;
 pub type Fs = <PoolParams as PoolParamsTrait>::Fs;
+#[cfg(feature = "groth16")]
 pub type Engine = Bn256;
 
 #[allow(non_snake_case)]
@@ -41,18 +48,37 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
 
     cx.export_value("Constants", constants)?;
 
-    cx.export_function("readParamsFromBinary", params::from_binary)?;
-    cx.export_function("readParamsFromFile", params::from_file)?;
+    #[cfg(feature = "groth16")]
+    {
+        cx.export_function("readParamsFromBinary", params::from_binary)?;
+        cx.export_function("readParamsFromFile", params::from_file)?;
 
-    cx.export_function("proveTx", proof::prove_tx)?;
-    cx.export_function("proveTree", proof::prove_tree)?;
-    cx.export_function("proveTxAsync", proof::prove_tx_async)?;
-    cx.export_function("proveTreeAsync", proof::prove_tree_async)?;
-    cx.export_function("verify", proof::verify_proof)?;
+        cx.export_function("proveTx", proof::prove_tx)?;
+        cx.export_function("proveTree", proof::prove_tree)?;
+        cx.export_function("proveTxAsync", proof::prove_tx_async)?;
+        cx.export_function("proveTreeAsync", proof::prove_tree_async)?;
+        cx.export_function("cancelTokenNew", proof::cancel_token_new)?;
+        cx.export_function("cancelTokenCancel", proof::cancel_token_cancel)?;
+        cx.export_function("verify", proof::verify_proof)?;
+        cx.export_function("verifyBatch", proof::verify_batch_proofs)?;
+    }
+
+    #[cfg(feature = "plonk")]
+    {
+        cx.export_function("readPlonkParams", plonk::read_plonk_params)?;
+        cx.export_function("provePlonkTx", plonk::prove_plonk_tx)?;
+        cx.export_function("provePlonkTxAsync", plonk::prove_plonk_tx_async)?;
+        cx.export_function("verifyPlonkTx", plonk::verify_plonk_tx)?;
+    }
 
     cx.export_function("merkleNew", merkle::merkle_new)?;
     cx.export_function("merkleGetRoot", merkle::merkle_get_root)?;
     cx.export_function("merkleGetNextIndex", merkle::merkle_get_next_index)?;
+    cx.export_function("merkleGetStats", merkle::merkle_get_stats)?;
+    cx.export_function(
+        "merklePrepareTreeUpdate",
+        merkle::merkle_prepare_tree_update,
+    )?;
     cx.export_function("merkleGetNode", merkle::merkle_get_node)?;
     cx.export_function("merkleAddHash", merkle::merkle_add_hash)?;
     cx.export_function("merkleAddCommitment", merkle::merkle_add_commitment)?;
@@ -63,17 +89,77 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
         merkle::merkle_get_commitment_proof,
     )?;
     cx.export_function("merkleGetAllNodes", merkle::merkle_get_all_nodes)?;
+    cx.export_function("merkleGetNodes", merkle::merkle_get_nodes)?;
     cx.export_function("merkleGetVirtualNode", merkle::merkle_get_virtual_node)?;
+    cx.export_function(
+        "merkleGetRootAfterVirtual",
+        merkle::merkle_get_root_after_virtual,
+    )?;
+    cx.export_function(
+        "merkleAddLeafsAndCommitments",
+        merkle::merkle_add_leafs_and_commitments,
+    )?;
     cx.export_function("merkleRollback", merkle::merkle_rollback)?;
+    cx.export_function("merkleRollbackToRoot", merkle::merkle_rollback_to_root)?;
+    cx.export_function("merkleCheckIntegrity", merkle::merkle_check_integrity)?;
+    cx.export_function("merkleRepair", merkle::merkle_repair)?;
+    cx.export_function("merkleVerifyProof", merkle::merkle_verify_proof)?;
 
     cx.export_function("txStorageNew", storage::tx_storage_new)?;
     cx.export_function("txStorageAdd", storage::tx_storage_add)?;
+    cx.export_function("txStorageAddBatch", storage::tx_storage_add_batch)?;
+    cx.export_function("txStorageSetBatch", storage::tx_storage_set_batch)?;
+    cx.export_function("txStorageRemoveRange", storage::tx_storage_remove_range)?;
     cx.export_function("txStorageDelete", storage::tx_storage_delete)?;
     cx.export_function("txStorageGet", storage::tx_storage_get)?;
     cx.export_function("txStorageCount", storage::tx_storage_count)?;
+    cx.export_function("txStorageIter", storage::tx_storage_iter)?;
+    cx.export_function("txStorageGetRange", storage::tx_storage_get_range)?;
+    cx.export_function("txStorageLastIndex", storage::tx_storage_last_index)?;
+
+    cx.export_function("accountNew", account::account_new)?;
+    cx.export_function("accountFromMnemonic", account::account_from_mnemonic)?;
+    cx.export_function("accountGenerateAddress", account::account_generate_address)?;
+    cx.export_function("accountTotalBalance", account::account_total_balance)?;
+    cx.export_function("accountAddHashes", account::account_add_hashes)?;
+    cx.export_function("accountAddAccount", account::account_add_account)?;
+    cx.export_function("accountAddNotes", account::account_add_notes)?;
+    cx.export_function(
+        "accountEarliestUsableIndex",
+        account::account_earliest_usable_index,
+    )?;
+    cx.export_function(
+        "accountEarliestUsableIndexOptimistic",
+        account::account_earliest_usable_index_optimistic,
+    )?;
+    cx.export_function("accountNoteStatus", account::account_note_status)?;
+    cx.export_function("accountExportState", account::account_export_state)?;
+    cx.export_function("accountImportState", account::account_import_state)?;
+    cx.export_function("accountCreateDeposit", account::account_create_deposit)?;
+    cx.export_function("accountCreateTransfer", account::account_create_transfer)?;
+    cx.export_function("accountCreateWithdraw", account::account_create_withdraw)?;
+    cx.export_function(
+        "accountCreateDepositAsync",
+        account::account_create_deposit_async,
+    )?;
+    cx.export_function(
+        "accountCreateTransferAsync",
+        account::account_create_transfer_async,
+    )?;
+    cx.export_function(
+        "accountCreateWithdrawAsync",
+        account::account_create_withdraw_async,
+    )?;
 
     cx.export_function("helpersOutCommitment", helpers::out_commitment)?;
     cx.export_function("helpersParseDelta", helpers::parse_delta_string)?;
+    cx.export_function("helpersCalculateNullifier", helpers::calculate_nullifier)?;
+    cx.export_function("helpersTxHash", helpers::tx_hash_str)?;
+    cx.export_function("helpersNullifier", helpers::nullifier_str)?;
+    cx.export_function("helpersMemoHash", helpers::memo_hash_str)?;
+    cx.export_function("helpersPoseidonCompress", helpers::poseidon_compress_str)?;
+    cx.export_function("helpersPoseidonHashAccount", helpers::poseidon_hash_account)?;
+    cx.export_function("helpersPoseidonHashNote", helpers::poseidon_hash_note)?;
     cx.export_function("helpersNumToStr", helpers::num_to_str)?;
     cx.export_function("helpersStrToNum", helpers::str_to_num)?;
 
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::RwLock, vec::Vec};
+use std::{collections::HashMap, str::FromStr, sync::RwLock, vec::Vec};
 
 use libzeropool_rs::{
     libzeropool::{
@@ -7,10 +7,12 @@ use libzeropool_rs::{
         POOL_PARAMS,
     },
     merkle::NativeMerkleTree,
+    utils::verify_merkle_proof_parts,
 };
 use neon::{prelude::*, types::buffer::TypedArray};
+use serde::Deserialize;
 
-use crate::PoolParams;
+use crate::{Fr, PoolParams};
 
 pub struct MerkleTree {
     inner: NativeMerkleTree<PoolParams>,
@@ -54,13 +56,14 @@ pub fn merkle_add_commitment(mut cx: FunctionContext) -> JsResult<JsUndefined> {
 
     let hash = {
         let buffer = cx.argument::<JsBuffer>(2)?;
-        Num::try_from_slice(buffer.as_slice(&cx)).unwrap()
+        Num::try_from_slice(buffer.as_slice(&cx)).or_else(|err| cx.throw_error(err.to_string()))?
     };
 
     tree.write()
         .unwrap()
         .inner
-        .add_hash_at_height(OUTPLUSONELOG as u32, index, hash, false);
+        .add_commitment(index, hash)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
 
     Ok(cx.undefined())
 }
@@ -152,6 +155,43 @@ pub fn merkle_get_next_index(mut cx: FunctionContext) -> JsResult<JsValue> {
     Ok(result)
 }
 
+pub fn merkle_get_stats(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+
+    let stats = tree.read().unwrap().inner.stats();
+
+    let result = neon_serde::to_value(&mut cx, &stats).unwrap();
+
+    Ok(result)
+}
+
+pub fn merkle_prepare_tree_update(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let commitment_index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+    let commitment = {
+        let buffer = cx.argument::<JsBuffer>(2)?;
+        Num::try_from_slice(buffer.as_slice(&cx)).unwrap()
+    };
+
+    let (tree_pub, tree_sec) = tree
+        .read()
+        .unwrap()
+        .inner
+        .prepare_tree_update(commitment, commitment_index);
+
+    let tree_pub = neon_serde::to_value(&mut cx, &tree_pub).unwrap();
+    let tree_sec = neon_serde::to_value(&mut cx, &tree_sec).unwrap();
+
+    let js_object = JsObject::new(&mut cx);
+    js_object.set(&mut cx, "public", tree_pub)?;
+    js_object.set(&mut cx, "secret", tree_sec)?;
+
+    Ok(js_object)
+}
+
 pub fn merkle_get_all_nodes(mut cx: FunctionContext) -> JsResult<JsValue> {
     let tree = cx.argument::<BoxedMerkleTree>(0)?;
 
@@ -159,8 +199,35 @@ pub fn merkle_get_all_nodes(mut cx: FunctionContext) -> JsResult<JsValue> {
         .read()
         .unwrap()
         .inner
-        .get_all_nodes()
-        .iter()
+        .iter_nodes()
+        .map(|n| (n.index, n.height))
+        .collect();
+
+    let result = neon_serde::to_value(&mut cx, &nodes).unwrap();
+
+    Ok(result)
+}
+
+pub fn merkle_get_nodes(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let height = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u32
+    };
+    let from = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as u64
+    };
+    let to = {
+        let num = cx.argument::<JsNumber>(3)?;
+        num.value(&mut cx) as u64
+    };
+
+    let nodes: Vec<(u64, u32)> = tree
+        .read()
+        .unwrap()
+        .inner
+        .iter_nodes_in(height, from..to)
         .map(|n| (n.index, n.height))
         .collect();
 
@@ -206,6 +273,101 @@ pub fn merkle_get_virtual_node(mut cx: FunctionContext) -> JsResult<JsValue> {
     Ok(result)
 }
 
+pub fn merkle_get_root_after_virtual(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let leafs: Vec<(u64, Vec<Num<_>>)> = {
+        let leafs = cx.argument::<JsValue>(1)?;
+        neon_serde::from_value(&mut cx, leafs).unwrap()
+    };
+    let commitments: Vec<(u64, Num<_>)> = {
+        let commitments = cx.argument::<JsValue>(2)?;
+        neon_serde::from_value(&mut cx, commitments).unwrap()
+    };
+
+    let root = tree
+        .read()
+        .unwrap()
+        .inner
+        .get_root_after_state(&leafs, &commitments)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let result = neon_serde::to_value(&mut cx, &root).unwrap();
+
+    Ok(result)
+}
+
+pub fn merkle_add_leafs_and_commitments(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let leafs: Vec<(u64, Vec<Num<_>>)> = {
+        let leafs = cx.argument::<JsValue>(1)?;
+        neon_serde::from_value(&mut cx, leafs).or_else(|err| cx.throw_error(err.to_string()))?
+    };
+    let commitments: Vec<(u64, Num<_>)> = {
+        let commitments = cx.argument::<JsValue>(2)?;
+        neon_serde::from_value(&mut cx, commitments)
+            .or_else(|err| cx.throw_error(err.to_string()))?
+    };
+
+    tree.write()
+        .unwrap()
+        .inner
+        .add_leafs_and_commitments(leafs, commitments);
+
+    Ok(cx.undefined())
+}
+
+pub fn merkle_rollback_to_root(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let expected_root = {
+        let root = cx.argument::<JsString>(1)?.value(&mut cx);
+        Num::from_str(&root).or_else(|err| cx.throw_error(err.to_string()))?
+    };
+    let max_lookback = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as u64
+    };
+
+    let index = tree
+        .write()
+        .unwrap()
+        .inner
+        .rollback_to_root(expected_root, max_lookback);
+
+    let result = match index {
+        Some(index) => cx.number(index as f64).upcast(),
+        None => cx.null().upcast(),
+    };
+
+    Ok(result)
+}
+
+/// Takes the leaf and the root as decimal strings and the proof in the shape returned by
+/// `merkleGetProof` or `merkleGetCommitmentProof`.
+pub fn merkle_verify_proof(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    #[derive(Deserialize)]
+    struct SerMerkleProof {
+        sibling: Vec<Num<Fr>>,
+        path: Vec<bool>,
+    }
+
+    let leaf = {
+        let leaf = cx.argument::<JsString>(0)?.value(&mut cx);
+        Num::from_str(&leaf).or_else(|err| cx.throw_error(err.to_string()))?
+    };
+    let proof: SerMerkleProof = {
+        let proof_js = cx.argument::<JsValue>(1)?;
+        neon_serde::from_value(&mut cx, proof_js).or_else(|err| cx.throw_error(err.to_string()))?
+    };
+    let root = {
+        let root = cx.argument::<JsString>(2)?.value(&mut cx);
+        Num::from_str(&root).or_else(|err| cx.throw_error(err.to_string()))?
+    };
+
+    let valid = verify_merkle_proof_parts(leaf, &proof.sibling, &proof.path, root, &*POOL_PARAMS);
+
+    Ok(cx.boolean(valid))
+}
+
 pub fn merkle_rollback(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let tree = cx.argument::<BoxedMerkleTree>(0)?;
     let rollback_index = {
@@ -218,3 +380,31 @@ pub fn merkle_rollback(mut cx: FunctionContext) -> JsResult<JsUndefined> {
 
     Ok(cx.undefined())
 }
+
+pub fn merkle_check_integrity(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+
+    let bad = tree
+        .read()
+        .unwrap()
+        .inner
+        .check_integrity()
+        .err()
+        .unwrap_or_default();
+
+    let result = neon_serde::to_value(&mut cx, &bad).unwrap();
+
+    Ok(result)
+}
+
+pub fn merkle_repair(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let tree = cx.argument::<BoxedMerkleTree>(0)?;
+    let bad: Vec<(u32, u64)> = {
+        let nodes = cx.argument::<JsValue>(1)?;
+        neon_serde::from_value(&mut cx, nodes).unwrap()
+    };
+
+    tree.write().unwrap().inner.repair(&bad);
+
+    Ok(cx.undefined())
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use libzeropool_rs::{
+    libzeropool::{
+        circuit::tx::c_transfer,
+        fawkes_crypto::{
+            backend::plonk::{
+                engines::Bn256,
+                prover::Proof,
+                setup::{setup, ProvingKey, VerifyingKey},
+                verifier::verify,
+                Parameters,
+            },
+            ff_uint::Num,
+        },
+        native::tx::{TransferPub, TransferSec},
+        POOL_PARAMS,
+    },
+    proof_plonk::prove_tx,
+};
+use neon::{prelude::*, types::buffer::TypedArray};
+use serde::{Deserialize, Serialize};
+
+use crate::Fr;
+
+pub type BoxedPlonkParams = JsBox<Arc<PlonkParams>>;
+
+pub struct PlonkParams {
+    pub inner: Parameters<Bn256>,
+    pub tx_pk: ProvingKey<Bn256>,
+    pub tx_vk: VerifyingKey<Bn256>,
+}
+
+impl Finalize for PlonkParams {}
+
+impl From<Parameters<Bn256>> for PlonkParams {
+    fn from(params: Parameters<Bn256>) -> Self {
+        let circuit = |public, secret| {
+            c_transfer(&public, &secret, &*POOL_PARAMS);
+        };
+
+        let (tx_vk, tx_pk) = setup(&params, circuit);
+
+        PlonkParams {
+            inner: params,
+            tx_pk,
+            tx_vk,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlonkProof {
+    inputs: Vec<Num<Fr>>,
+    proof: Proof,
+}
+
+impl PlonkParams {
+    fn prove_tx(&self, tr_pub: TransferPub<Fr>, tr_sec: TransferSec<Fr>) -> PlonkProof {
+        let (inputs, proof) = prove_tx(&self.inner, &self.tx_pk, &*POOL_PARAMS, tr_pub, tr_sec);
+
+        PlonkProof { inputs, proof }
+    }
+
+    fn verify_tx(&self, proof: &PlonkProof) -> bool {
+        verify(&self.inner, &self.tx_vk, &proof.proof, &proof.inputs)
+    }
+}
+
+/// Reads the plonk parameters and runs the setup for the transfer circuit.
+pub fn read_plonk_params(mut cx: FunctionContext) -> JsResult<BoxedPlonkParams> {
+    let input = cx.argument::<JsBuffer>(0)?;
+
+    let mut data = input.as_slice(&cx);
+    let inner = match Parameters::read(&mut data) {
+        Ok(params) => params,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+
+    Ok(cx.boxed(Arc::new(inner.into())))
+}
+
+pub fn prove_plonk_tx(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let params = cx.argument::<BoxedPlonkParams>(0)?;
+    let tr_pub_js = cx.argument::<JsValue>(1)?;
+    let tr_sec_js = cx.argument::<JsValue>(2)?;
+    let tr_pub = neon_serde::from_value(&mut cx, tr_pub_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+    let tr_sec = neon_serde::from_value(&mut cx, tr_sec_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let proof = params.prove_tx(tr_pub, tr_sec);
+
+    neon_serde::to_value(&mut cx, &proof).or_else(|err| cx.throw_error(err.to_string()))
+}
+
+pub fn prove_plonk_tx_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let params: Arc<PlonkParams> = (*cx.argument::<BoxedPlonkParams>(0)?).clone();
+    let tr_pub_js = cx.argument::<JsValue>(1)?;
+    let tr_sec_js = cx.argument::<JsValue>(2)?;
+    let tr_pub = neon_serde::from_value(&mut cx, tr_pub_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+    let tr_sec = neon_serde::from_value(&mut cx, tr_sec_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+
+    rayon::spawn(move || {
+        let proof = params.prove_tx(tr_pub, tr_sec);
+
+        deferred.settle_with(&channel, move |mut cx| {
+            neon_serde::to_value(&mut cx, &proof).or_else(|err| cx.throw_error(err.to_string()))
+        });
+    });
+
+    Ok(promise)
+}
+
+/// Verifies a transfer proof with the verifying key obtained during the setup.
+pub fn verify_plonk_tx(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let params = cx.argument::<BoxedPlonkParams>(0)?;
+    let proof_js = cx.argument::<JsValue>(1)?;
+    let proof: PlonkProof =
+        neon_serde::from_value(&mut cx, proof_js).or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let result = params.verify_tx(&proof);
+
+    Ok(cx.boolean(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::{
+        client::{state::State, TxType, UserAccount},
+        libzeropool::native::boundednum::BoundedNum,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_prove_and_verify_zero_deposit() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::ZERO),
+                    outputs: vec![],
+                },
+                None,
+                None,
+            )
+            .unwrap();
+
+        let params: PlonkParams = Parameters::<Bn256>::setup(18).into();
+        let proof = params.prove_tx(tx.public, tx.secret);
+
+        assert!(params.verify_tx(&proof));
+    }
+}
@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use libzeropool_rs::{
     libzeropool::{
@@ -8,12 +11,17 @@ use libzeropool_rs::{
                 verifier::{verify, VK},
             },
             ff_uint::Num,
+            rand::RngCore,
         },
         POOL_PARAMS,
     },
-    proof_groth16::{prove_tree as prove_tree_native, prove_tx as prove_tx_native},
+    proof_groth16::{
+        prove_tree as prove_tree_native, prove_tx as prove_tx_native, prove_tx_with_progress,
+        verify_batch, ProofStage,
+    },
+    random::CustomRng,
 };
-use neon::prelude::*;
+use neon::{prelude::*, types::buffer::TypedArray};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -29,24 +37,75 @@ pub struct SnarkProof {
 
 impl Finalize for SnarkProof {}
 
+pub type BoxedCancelToken = JsBox<CancelToken>;
+
+/// Shared with the proving thread, cancels the proving started with `proveTxAsync`.
+#[derive(Default)]
+pub struct CancelToken {
+    inner: Arc<AtomicBool>,
+}
+
+impl Finalize for CancelToken {}
+
+pub fn cancel_token_new(mut cx: FunctionContext) -> JsResult<BoxedCancelToken> {
+    Ok(cx.boxed(CancelToken::default()))
+}
+
+pub fn cancel_token_cancel(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let token = cx.argument::<BoxedCancelToken>(0)?;
+    token.inner.store(true, Ordering::Relaxed);
+
+    Ok(cx.undefined())
+}
+
 pub fn prove_tx_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
     let params: Arc<Params> = (*cx.argument::<BoxedParams>(0)?).clone();
     let tr_pub_js = cx.argument::<JsValue>(1)?;
     let tr_sec_js = cx.argument::<JsValue>(2)?;
     let tr_pub = neon_serde::from_value(&mut cx, tr_pub_js).unwrap();
     let tr_sec = neon_serde::from_value(&mut cx, tr_sec_js).unwrap();
+    let progress = cx
+        .argument_opt(3)
+        .and_then(|arg| arg.downcast::<JsFunction, _>(&mut cx).ok())
+        .map(|callback| Arc::new(callback.root(&mut cx)));
+    let cancel = match cx.argument_opt(4) {
+        Some(token) if !token.is_a::<JsUndefined, _>(&mut cx) => token
+            .downcast_or_throw::<BoxedCancelToken, _>(&mut cx)?
+            .inner
+            .clone(),
+        _ => Arc::default(),
+    };
 
     let channel = cx.channel();
     let (deferred, promise) = cx.promise();
 
     rayon::spawn(move || {
-        let pair = prove_tx_native(&params.inner, &*POOL_PARAMS, tr_pub, tr_sec);
-        let proof = SnarkProof {
-            inputs: pair.0,
-            proof: pair.1,
+        let report = |stage: ProofStage| {
+            if let Some(callback) = &progress {
+                let callback = callback.clone();
+                channel.send(move |mut cx| {
+                    let stage = neon_serde::to_value(&mut cx, &stage)
+                        .or_else(|err| cx.throw_error(err.to_string()))?;
+                    callback
+                        .to_inner(&mut cx)
+                        .call_with(&cx)
+                        .arg(stage)
+                        .exec(&mut cx)
+                });
+            }
         };
+        let proof = prove_tx_with_progress(
+            &params.inner,
+            &*POOL_PARAMS,
+            tr_pub,
+            tr_sec,
+            report,
+            &cancel,
+        )
+        .map(|(inputs, proof)| SnarkProof { inputs, proof });
 
         deferred.settle_with(&channel, move |mut cx| {
+            let proof = proof.or_else(|err| cx.throw_error(err.to_string()))?;
             neon_serde::to_value(&mut cx, &proof).or_else(|err| cx.throw_error(err.to_string()))
         });
     });
@@ -134,3 +193,36 @@ pub fn verify_proof(mut cx: FunctionContext) -> JsResult<JsValue> {
 
     Ok(result)
 }
+
+/// Verifies an array of `{ inputs, proof }` objects at once, an empty array is rejected. The
+/// optional third argument is a 32-byte seed for the batch randomizers, which must be
+/// unpredictable to whoever produced the proofs. A random one is used if it's omitted.
+pub fn verify_batch_proofs(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let vk_js = cx.argument::<JsValue>(0)?;
+    let proofs_js = cx.argument::<JsValue>(1)?;
+
+    let vk: VK<Engine> =
+        neon_serde::from_value(&mut cx, vk_js).or_else(|err| cx.throw_error(err.to_string()))?;
+    let proofs: Vec<SnarkProof> = neon_serde::from_value(&mut cx, proofs_js)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    let mut seed = [0u8; 32];
+    match cx.argument_opt(2) {
+        Some(seed_js) => {
+            let seed_js = seed_js.downcast_or_throw::<JsBuffer, _>(&mut cx)?;
+            let data = seed_js.as_slice(&cx);
+            if data.len() != seed.len() {
+                return cx.throw_error("Seed must be 32 bytes long");
+            }
+            seed.copy_from_slice(data);
+        }
+        None => CustomRng.fill_bytes(&mut seed),
+    }
+
+    let batch: Vec<_> = proofs
+        .iter()
+        .map(|proof| (&proof.proof, proof.inputs.as_slice()))
+        .collect();
+
+    Ok(cx.boolean(verify_batch(&vk, &batch, seed)))
+}
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use libzeropool_rs::sparse_array::NativeSparseArray;
 use neon::{prelude::*, types::buffer::TypedArray};
 
@@ -35,6 +37,80 @@ pub fn tx_storage_add(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+/// Stores the buffers at consecutive indices starting from `startIndex` in a single transaction.
+pub fn tx_storage_add_batch(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let start_index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+
+    let items = cx.argument::<JsArray>(2)?.to_vec(&mut cx)?;
+    let entries = items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let buffer = item.downcast_or_throw::<JsBuffer, _>(&mut cx)?;
+            Ok((start_index + i as u64, buffer.as_slice(&cx).to_vec()))
+        })
+        .collect::<NeonResult<Vec<_>>>()?;
+
+    this.inner
+        .try_set_multiple(&entries)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    Ok(cx.undefined())
+}
+
+/// Stores `[index, buffer]` pairs in a single transaction.
+pub fn tx_storage_set_batch(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let items = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+    let entries = items
+        .into_iter()
+        .map(|item| {
+            let pair = item.downcast_or_throw::<JsArray, _>(&mut cx)?;
+            let index = pair.get::<JsNumber, _, _>(&mut cx, 0)?.value(&mut cx) as u64;
+            let buffer = pair.get::<JsBuffer, _, _>(&mut cx, 1)?;
+            Ok((index, buffer.as_slice(&cx).to_vec()))
+        })
+        .collect::<NeonResult<Vec<_>>>()?;
+
+    this.inner
+        .try_set_multiple(&entries)
+        .or_else(|err| cx.throw_error(err.to_string()))?;
+
+    Ok(cx.undefined())
+}
+
+/// Removes all entries within `from..to` (`to` excluded) in a single transaction. Returns the
+/// number of removed entries.
+pub fn tx_storage_remove_range(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let from = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+
+    let to = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as u64
+    };
+
+    let removed = match to.checked_sub(1) {
+        Some(last) if from <= last => this
+            .inner
+            .try_remove_range(from..=last)
+            .or_else(|err| cx.throw_error(err.to_string()))?,
+        _ => 0,
+    };
+
+    Ok(cx.number(removed as f64))
+}
+
 pub fn tx_storage_delete(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let this = cx.argument::<BoxedTxStorage>(0)?;
 
@@ -71,3 +147,121 @@ pub fn tx_storage_count(mut cx: FunctionContext) -> JsResult<JsValue> {
 
     Ok(len)
 }
+
+/// Returns up to `limit` entries starting from `startIndex`, ordered by index. Holes are skipped.
+pub fn tx_storage_iter(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let start_index = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+
+    let limit = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as usize
+    };
+
+    let entries = this.inner.iter_from(start_index).take(limit).collect();
+
+    entries_to_js(&mut cx, entries)
+}
+
+/// Returns all entries within `from..to` (`to` excluded), ordered by index.
+pub fn tx_storage_get_range(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let from = {
+        let num = cx.argument::<JsNumber>(1)?;
+        num.value(&mut cx) as u64
+    };
+
+    let to = {
+        let num = cx.argument::<JsNumber>(2)?;
+        num.value(&mut cx) as u64
+    };
+
+    let entries = range_entries(&this.inner, from..to);
+
+    entries_to_js(&mut cx, entries)
+}
+
+pub fn tx_storage_last_index(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let this = cx.argument::<BoxedTxStorage>(0)?;
+
+    let result = match this.inner.iter().map(|(index, _)| index).max() {
+        Some(index) => cx.number(index as f64).upcast(),
+        None => cx.null().upcast(),
+    };
+
+    Ok(result)
+}
+
+/// Entries within the range ordered by index, the ones after it are never read.
+fn range_entries(storage: &NativeSparseArray<Vec<u8>>, range: Range<u64>) -> Vec<(u64, Vec<u8>)> {
+    storage
+        .iter_from(range.start)
+        .take_while(|(index, _)| *index < range.end)
+        .collect()
+}
+
+fn entries_to_js<'a>(
+    cx: &mut FunctionContext<'a>,
+    entries: Vec<(u64, Vec<u8>)>,
+) -> JsResult<'a, JsArray> {
+    let js_array = JsArray::new(cx, entries.len() as u32);
+
+    for (i, (index, data)) in entries.into_iter().enumerate() {
+        let index = cx.number(index as f64);
+        let data = JsBuffer::external(cx, data);
+
+        let js_object = JsObject::new(cx);
+        js_object.set(cx, "index", index)?;
+        js_object.set(cx, "data", data)?;
+        js_array.set(cx, i as u32, js_object)?;
+    }
+
+    Ok(js_array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordered_entries_with_holes() {
+        let path = std::env::temp_dir().join("zp-node-tx-storage-test.persy");
+        let _ = std::fs::remove_file(&path);
+        let storage = NativeSparseArray::<Vec<u8>>::new_native(path.to_str().unwrap()).unwrap();
+
+        for &index in &[300u64, 1, 128, 5, 129] {
+            storage.set(index, &vec![index as u8]);
+        }
+        storage.remove(128);
+
+        let indices = |entries: Vec<(u64, Vec<u8>)>| -> Vec<u64> {
+            entries.into_iter().map(|(index, _)| index).collect()
+        };
+
+        let page = |from: u64, limit: usize| -> Vec<(u64, Vec<u8>)> {
+            storage.iter_from(from).take(limit).collect()
+        };
+
+        let all = page(0, usize::MAX);
+        assert_eq!(indices(all.clone()), vec![1, 5, 129, 300]);
+        assert!(all.iter().all(|(index, data)| data == &vec![*index as u8]));
+
+        // iteration continues past the holes
+        assert_eq!(indices(page(2, 2)), vec![5, 129]);
+        assert_eq!(indices(page(6, 10)), vec![129, 300]);
+        assert!(page(0, 0).is_empty());
+
+        // ranges exclude the upper bound
+        assert_eq!(indices(range_entries(&storage, 5..300)), vec![5, 129]);
+        assert!(range_entries(&storage, 130..300).is_empty());
+        assert!(range_entries(&storage, 300..5).is_empty());
+
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+// Usage: node test-account.js <transfer params file> <transfer vk json>
+const assert = require('assert');
+const fs = require('fs');
+const os = require('os');
+const path = require('path');
+const zp = require('./index.js');
+
+async function main() {
+    const [paramsPath, vkPath] = process.argv.slice(2);
+    const dbPath = fs.mkdtempSync(path.join(os.tmpdir(), 'zp-account-')) + '/account';
+    const account = new zp.UserAccount(Buffer.alloc(32, 1), dbPath);
+    const address = account.generateAddress();
+
+    // Deposit to own account
+    const deposit = account.createDeposit({ fee: '0', amount: '100', outputs: [] });
+    const depositHashes = deposit.out_hashes.slice(0, 1).map(zp.Helpers.strToNum);
+    account.addHashes(0, depositHashes);
+    account.addAccount(0, deposit.secret.tx.output[0]);
+    assert.strictEqual(account.totalBalance(), '100');
+
+    // Transfer to own address
+    const transfer = await account.createTransferAsync({
+        fee: '0',
+        outputs: [{ to: address, amount: '40' }],
+    });
+    const [outAccount, outNotes] = transfer.secret.tx.output;
+    assert.strictEqual(outAccount.b, '60');
+    assert.strictEqual(outNotes[0].b, '40');
+
+    if (paramsPath && vkPath) {
+        const params = zp.Params.fromFile(paramsPath);
+        const vk = JSON.parse(fs.readFileSync(vkPath, 'utf8'));
+        const proof = await zp.Proof.txAsync(params, transfer.public, transfer.secret);
+        assert(zp.Proof.verify(vk, proof.proof, proof.inputs));
+        console.log('Transfer proof verified');
+    } else {
+        console.log('Skipping proving, no params provided');
+    }
+}
+
+main().catch((err) => {
+    console.error(err);
+    process.exit(1);
+});
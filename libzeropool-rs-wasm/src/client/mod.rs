@@ -1,35 +1,47 @@
-use std::{cell::RefCell, collections::HashMap, convert::TryInto, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, str::FromStr};
 
-use js_sys::Array;
+use js_sys::{Array, Object};
 use libzeropool_rs::{
-    client::{StateFragment, TxType as NativeTxType, UserAccount as NativeUserAccount},
+    client::{
+        amount::parse_token_amount, state::StateSnapshot, AddressInfo, CreateTxError,
+        StateFragment, TransactionData as NativeTransactionData, TxDestination, TxSummary,
+        TxType as NativeTxType, TxWarning, UserAccount as NativeUserAccount, UserAccountBuilder,
+    },
+    delta::{decode_delta, Delta},
+    keys::{zeroize, SecretKey},
     libzeropool::{
         constants,
         fawkes_crypto::{
-            borsh::BorshDeserialize,
+            borsh::{BorshDeserialize, BorshSerialize},
             core::sizedvec::SizedVec,
-            ff_uint::{Num, NumRepr, Uint},
+            ff_uint::Num,
         },
         native::{
             account::Account as NativeAccount,
+            boundednum::BoundedNum,
             note::Note as NativeNote,
-            tx::{parse_delta, TransferPub as NativeTransferPub, TransferSec as NativeTransferSec},
+            tree::{TreePub as NativeTreePub, TreeSec as NativeTreeSec},
+            tx::{TransferPub as NativeTransferPub, TransferSec as NativeTransferSec},
         },
     },
     merkle::{Hash, Node},
+    utils::commitment_from_leaves,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::Serializer;
 use wasm_bindgen::{prelude::*, JsCast};
 
 use crate::{
-    database::Database, keys::reduce_sk, ts_types::Hash as JsHash, Account, Fr, Fs, Hashes,
-    IDepositData, IDepositPermittableData, ITransferData, IWithdrawData, IndexedNote, IndexedNotes,
-    MerkleProof, Pair, PoolParams, Transaction, TransactionData, UserState, POOL_PARAMS,
+    database::Database, keys::reduce_sk, ts_types::Hash as JsHash, Account, ConsolidationAdvice,
+    Fr, Fs, Hashes, HistoryEntries, IAddressInfo, IDepositData, IDepositPermittableData,
+    IGeneratedAddress, IMultiTransferData, ITransferData, IUserAccountConfig, IWithdrawData,
+    IndexedNote, IndexedNotes, MerkleProof, NoteStatus, NotesPage, NullifierRecord,
+    OutgoingAddresses, Pair, PoolParams, Transaction, TransactionData, TransactionDataList,
+    TreeNodes, TreeStats, TreeUpdateInputs, UsableNotesPage, UserState, POOL_PARAMS,
 };
 
 mod tx_types;
-use tx_types::JsTxType;
+use tx_types::{JsMultiTxType, JsTxType, TxType};
 
 use self::tx_parser::StateUpdate;
 
@@ -45,6 +57,25 @@ pub struct ParsedDelta {
     pub pool_id: String,
 }
 
+impl From<Delta<Fr>> for ParsedDelta {
+    fn from(delta: Delta<Fr>) -> Self {
+        ParsedDelta {
+            v: delta.v.to_string(),
+            e: delta.e.to_string(),
+            index: delta.index.to_string(),
+            pool_id: delta.pool_id.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum AddressInfoSer {
+    Invalid { error: String },
+    Foreign { d: String, p_d: String },
+    Own { d: String, p_d: String },
+}
+
 #[derive(Serialize)]
 struct TransactionDataSer {
     public: NativeTransferPub<Fr>,
@@ -56,6 +87,78 @@ struct TransactionDataSer {
     commitment_root: Num<Fr>,
     out_hashes: SizedVec<Num<Fr>, { constants::OUT + 1 }>,
     parsed_delta: ParsedDelta,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<TxSummary<Fr>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<TxWarning>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    destinations: Vec<TxDestination<Fr>>,
+}
+
+/// The part of `TransactionData` read by `recordOutgoingAddresses`.
+#[derive(Deserialize)]
+struct TransactionDestinations {
+    #[serde(default)]
+    destinations: Vec<TxDestination<Fr>>,
+}
+
+/// Converts [`CreateTxError`] into a JS `Error` that also carries the `code` and the data of
+/// the variant, see `ICreateTxError`.
+struct JsCreateTxError(CreateTxError);
+
+impl From<JsCreateTxError> for JsValue {
+    fn from(JsCreateTxError(err): JsCreateTxError) -> Self {
+        let js_err = js_sys::Error::new(&err.to_string());
+        if let Ok(fields) = serde_wasm_bindgen::to_value(&err) {
+            Object::assign(&js_err, &fields.unchecked_into::<Object>());
+        }
+
+        js_err.into()
+    }
+}
+
+fn transaction_data_to_js(tx: NativeTransactionData<Fr>) -> Result<TransactionData, JsValue> {
+    let parsed_delta = decode_delta(tx.public.delta)
+        .map_err(|err| js_err!(&err.to_string()))?
+        .into();
+
+    let tx = TransactionDataSer {
+        public: tx.public,
+        secret: tx.secret,
+        ciphertext: tx.ciphertext,
+        memo: tx.memo,
+        out_hashes: tx.out_hashes,
+        commitment_root: tx.commitment_root,
+        parsed_delta,
+        summary: tx.summary,
+        warnings: tx.warnings,
+        destinations: tx.destinations,
+    };
+
+    let serializer = Serializer::new().serialize_large_number_types_as_bigints(true);
+    let value: JsValue = tx.serialize(&serializer).unwrap();
+
+    Ok(value.unchecked_into::<TransactionData>())
+}
+
+fn state_fragment(state_update: StateUpdate) -> StateFragment<Fr> {
+    state_update.into()
+}
+
+/// Accepts either a single `StateUpdate` or an array of them, which are merged into one fragment.
+fn merged_state_fragment(new_state: JsValue) -> Result<StateFragment<Fr>, JsValue> {
+    let updates: Vec<StateUpdate> = if Array::is_array(&new_state) {
+        serde_wasm_bindgen::from_value(new_state)
+    } else {
+        serde_wasm_bindgen::from_value::<StateUpdate>(new_state).map(|update| vec![update])
+    }
+    .map_err(|err| js_err!(&err.to_string()))?;
+
+    updates
+        .into_iter()
+        .map(state_fragment)
+        .try_fold(StateFragment::default(), StateFragment::merge)
+        .map_err(|err| js_err!(&err.to_string()))
 }
 
 #[wasm_bindgen]
@@ -63,28 +166,142 @@ pub struct UserAccount {
     inner: Rc<RefCell<NativeUserAccount<Database, PoolParams>>>,
 }
 
+/// Options of the `UserAccount` constructor, the omitted ones keep their defaults.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct UserAccountConfig {
+    pool_id: Option<u32>,
+    address_length: Option<usize>,
+    allow_fresh_account: Option<bool>,
+    track_outgoing: Option<bool>,
+    override_double_spend_check: Option<bool>,
+}
+
+impl UserAccountConfig {
+    /// Also accepts the pool id alone, as the constructor used to.
+    fn from_js(config: Option<IUserAccountConfig>) -> Result<Self, JsValue> {
+        let config: JsValue = match config {
+            Some(config) => config.into(),
+            None => return Ok(Self::default()),
+        };
+
+        if let Some(pool_id) = config.as_f64() {
+            return Ok(UserAccountConfig {
+                pool_id: Some(pool_id as u32),
+                ..Default::default()
+            });
+        }
+        if config.is_undefined() || config.is_null() {
+            return Ok(Self::default());
+        }
+
+        serde_wasm_bindgen::from_value(config).map_err(|err| js_err!(&err.to_string()))
+    }
+
+    fn into_builder(self) -> UserAccountBuilder<PoolParams> {
+        let mut builder = UserAccountBuilder::new();
+        if let Some(pool_id) = self.pool_id {
+            builder = builder.pool_id(BoundedNum::new(Num::from(pool_id as u64)));
+        }
+        if let Some(address_length) = self.address_length {
+            builder = builder.address_length(address_length);
+        }
+        if let Some(allow) = self.allow_fresh_account {
+            builder = builder.allow_fresh_account(allow);
+        }
+        if let Some(track) = self.track_outgoing {
+            builder = builder.track_outgoing(track);
+        }
+        if let Some(allow) = self.override_double_spend_check {
+            builder = builder.override_double_spend_check(allow);
+        }
+
+        builder
+    }
+}
+
 #[wasm_bindgen]
 impl UserAccount {
     #[wasm_bindgen(constructor)]
     /// Initializes UserAccount with a spending key that has to be an element of the prime field Fs (p = 6554484396890773809930967563523245729705921265872317281365359162392183254199).
-    pub fn new(sk: &[u8], state: UserState) -> Result<UserAccount, JsValue> {
+    /// `config` is either a `UserAccountConfig` or the pool id alone, pool id is 0 if omitted.
+    /// The `sk` buffer is overwritten with zeros after use.
+    pub fn new(
+        sk: &mut [u8],
+        state: UserState,
+        config: Option<IUserAccountConfig>,
+    ) -> Result<UserAccount, JsValue> {
+        crate::utils::set_panic_hook();
+
+        let parsed_sk = SecretKey::<Fs>::from_bytes(sk);
+        zeroize(sk);
+        let sk = parsed_sk.ok_or_else(|| js_err!("Invalid spending key"))?;
+        let account = UserAccountConfig::from_js(config)?.into_builder().build(
+            sk,
+            state.inner,
+            POOL_PARAMS.clone(),
+        );
+
+        Ok(UserAccount {
+            inner: Rc::new(RefCell::new(account)),
+        })
+    }
+
+    #[wasm_bindgen(js_name = watchOnly)]
+    /// Initializes a watch-only UserAccount from the decryption key (decimal string). It can
+    /// decrypt notes and track the balance, but cannot create transactions.
+    pub fn watch_only(eta: &str, state: UserState) -> Result<UserAccount, JsValue> {
         crate::utils::set_panic_hook();
 
-        let sk = Num::<Fs>::from_uint(NumRepr(Uint::from_little_endian(sk)))
-            .ok_or_else(|| js_err!("Invalid spending key"))?;
-        let account = NativeUserAccount::new(sk, state.inner, POOL_PARAMS.clone());
+        let eta = Num::from_str(eta).map_err(|_| js_err!("Invalid decryption key"))?;
+        let account = NativeUserAccount::watch_only(eta, state.inner, POOL_PARAMS.clone());
 
         Ok(UserAccount {
             inner: Rc::new(RefCell::new(account)),
         })
     }
 
+    #[wasm_bindgen(js_name = forgetSk)]
+    /// Wipes the spending key, turning the account into a watch-only one.
+    pub fn forget_sk(&self) {
+        self.inner.borrow_mut().forget_sk();
+    }
+
+    #[wasm_bindgen(js_name = getEta)]
+    /// Returns the decryption key that can be used to create a watch-only account.
+    pub fn get_eta(&self) -> String {
+        self.inner.borrow().keys.eta.to_string()
+    }
+
     // TODO: Is this safe?
     #[wasm_bindgen(js_name = fromSeed)]
     /// Same as constructor but accepts arbitrary data as spending key.
     pub fn from_seed(seed: &[u8], state: UserState) -> Result<UserAccount, JsValue> {
-        let sk = reduce_sk(seed);
-        Self::new(&sk, state)
+        let mut sk = reduce_sk(seed);
+        Self::new(&mut sk, state, None)
+    }
+
+    #[wasm_bindgen(js_name = fromMnemonic)]
+    /// Same as constructor but derives the spending key of the account `account_index` from
+    /// a BIP-39 mnemonic.
+    pub fn from_mnemonic(
+        phrase: &str,
+        account_index: u32,
+        state: UserState,
+    ) -> Result<UserAccount, JsValue> {
+        crate::utils::set_panic_hook();
+
+        let account = NativeUserAccount::from_mnemonic(
+            phrase,
+            account_index,
+            state.inner,
+            POOL_PARAMS.clone(),
+        )
+        .map_err(|err| js_err!(&err.to_string()))?;
+
+        Ok(UserAccount {
+            inner: Rc::new(RefCell::new(account)),
+        })
     }
 
     #[wasm_bindgen(js_name = generateAddress)]
@@ -93,14 +310,31 @@ impl UserAccount {
         self.inner.borrow().generate_address()
     }
 
+    #[wasm_bindgen(js_name = generateAddressAt)]
+    /// Generates the deterministic address number `index`, it can be regenerated after
+    /// restoring the account.
+    pub fn generate_address_at(&self, index: u64) -> Result<IGeneratedAddress, JsValue> {
+        #[derive(Serialize)]
+        struct GeneratedAddress {
+            address: String,
+            d: String,
+        }
+
+        let (address, d) = self.inner.borrow().generate_address_deterministic(index);
+        let generated = GeneratedAddress {
+            address,
+            d: d.to_num().to_string(),
+        };
+
+        Ok(serde_wasm_bindgen::to_value(&generated)?.unchecked_into::<IGeneratedAddress>())
+    }
+
     #[wasm_bindgen(js_name = decryptNotes)]
     /// Attempts to decrypt notes.
-    pub fn decrypt_notes(&self, data: Vec<u8>) -> Result<IndexedNotes, JsValue> {
-        let notes = self
-            .inner
-            .borrow()
-            .decrypt_notes(data)
-            .into_iter()
+    pub fn decrypt_notes(&self, data: &[u8]) -> Result<IndexedNotes, JsValue> {
+        let inner = self.inner.borrow();
+        let notes = inner
+            .decrypt_notes_iter(data)
             .enumerate()
             .filter_map(|(index, note)| {
                 let note = IndexedNote {
@@ -118,7 +352,7 @@ impl UserAccount {
 
     #[wasm_bindgen(js_name = decryptPair)]
     /// Attempts to decrypt account and notes.
-    pub fn decrypt_pair(&self, data: Vec<u8>) -> Result<Option<Pair>, JsValue> {
+    pub fn decrypt_pair(&self, data: &[u8]) -> Result<Option<Pair>, JsValue> {
         #[derive(Serialize)]
         struct SerPair {
             account: NativeAccount<Fr>,
@@ -143,63 +377,27 @@ impl UserAccount {
     fn construct_tx_data(
         &self,
         native_tx: NativeTxType<Fr>,
-        new_state: Option<StateUpdate>,
+        extra_state: Option<StateFragment<Fr>>,
+        note_indices: Option<Vec<u64>>,
     ) -> Result<TransactionData, JsValue> {
         let account = self.inner.clone();
 
-        let extra_state = new_state.map(|s| {
-            let mut joined_notes = vec![];
-            s.new_notes.into_iter().for_each(|notes| {
-                notes.into_iter().for_each(|one_note| {
-                    joined_notes.push(one_note);
-                });
-            });
-
-            StateFragment {
-                new_leafs: s.new_leafs,
-                new_commitments: s.new_commitments,
-                new_accounts: s.new_accounts,
-                new_notes: joined_notes,
-            }
-        });
-
-        let tx = account
-            .borrow()
-            .create_tx(native_tx, None, extra_state)
-            .map_err(|err| js_err!("{}", err))?;
-
-        let (v, e, index, pool_id) = parse_delta(tx.public.delta);
-        let parsed_delta = {
-            let v: i64 = v.try_into().unwrap();
-            let e: i64 = e.try_into().unwrap();
-
-            ParsedDelta {
-                v: v.to_string(),
-                e: e.to_string(),
-                index: index.to_string(),
-                pool_id: pool_id.to_string(),
+        let tx = match note_indices {
+            Some(note_indices) => {
+                account
+                    .borrow()
+                    .create_tx_with_notes(native_tx, None, extra_state, &note_indices)
             }
-        };
-
-        let tx = TransactionDataSer {
-            public: tx.public,
-            secret: tx.secret,
-            ciphertext: tx.ciphertext,
-            memo: tx.memo,
-            out_hashes: tx.out_hashes,
-            commitment_root: tx.commitment_root,
-            parsed_delta,
-        };
-
-        let serializer = Serializer::new().serialize_large_number_types_as_bigints(true);
-        let value: JsValue = tx.serialize(&serializer).unwrap();
+            None => account.borrow().create_tx(native_tx, None, extra_state),
+        }
+        .map_err(JsCreateTxError)?;
 
-        Ok(value.unchecked_into::<TransactionData>())
+        transaction_data_to_js(tx)
     }
 
     #[wasm_bindgen(js_name = "createDeposit")]
     pub fn create_deposit(&self, deposit: IDepositData) -> Result<TransactionData, JsValue> {
-        self.construct_tx_data(deposit.to_native()?, None)
+        self.construct_tx_data(deposit.to_native()?, None, None)
     }
 
     #[wasm_bindgen(js_name = "createDepositOptimistic")]
@@ -210,7 +408,7 @@ impl UserAccount {
     ) -> Result<TransactionData, JsValue> {
         let new_state: StateUpdate =
             serde_wasm_bindgen::from_value(new_state).map_err(|err| js_err!(&err.to_string()))?;
-        self.construct_tx_data(deposit.to_native()?, Some(new_state))
+        self.construct_tx_data(deposit.to_native()?, Some(state_fragment(new_state)), None)
     }
 
     #[wasm_bindgen(js_name = "createDepositPermittable")]
@@ -218,28 +416,62 @@ impl UserAccount {
         &self,
         deposit: IDepositPermittableData,
     ) -> Result<TransactionData, JsValue> {
-        self.construct_tx_data(deposit.to_native()?, None)
+        self.construct_tx_data(deposit.to_native()?, None, None)
     }
 
     #[wasm_bindgen(js_name = "createTransfer")]
     pub fn create_transfer(&self, transfer: ITransferData) -> Result<TransactionData, JsValue> {
-        self.construct_tx_data(transfer.to_native()?, None)
+        self.construct_tx_data(transfer.to_native()?, None, transfer.note_indices()?)
     }
 
     #[wasm_bindgen(js_name = "createTransferOptimistic")]
+    /// `new_state` is either a single `StateUpdate` or an array of them, e.g. collected from
+    /// several relayer responses. They are merged, failing on conflicting indices.
     pub fn create_transfer_optimistic(
         &self,
         transfer: ITransferData,
         new_state: JsValue,
     ) -> Result<TransactionData, JsValue> {
-        let new_state: StateUpdate =
-            serde_wasm_bindgen::from_value(new_state).map_err(|err| js_err!(&err.to_string()))?;
-        self.construct_tx_data(transfer.to_native()?, Some(new_state))
+        self.construct_tx_data(
+            transfer.to_native()?,
+            Some(merged_state_fragment(new_state)?),
+            transfer.note_indices()?,
+        )
+    }
+
+    #[wasm_bindgen(js_name = "createMultiTransfer")]
+    /// Creates a chain of transfers, every subsequent one spends the optimistic output of the
+    /// previous ones.
+    pub fn create_multi_transfer(
+        &self,
+        transfers: IMultiTransferData,
+        new_state: JsValue,
+    ) -> Result<TransactionDataList, JsValue> {
+        let extra_state = if new_state.is_undefined() || new_state.is_null() {
+            None
+        } else {
+            let new_state: StateUpdate = serde_wasm_bindgen::from_value(new_state)
+                .map_err(|err| js_err!(&err.to_string()))?;
+            Some(state_fragment(new_state))
+        };
+
+        let txs = self
+            .inner
+            .borrow()
+            .create_txs(transfers.to_native_array()?, None, extra_state)
+            .map_err(JsCreateTxError)?;
+
+        let js_txs = Array::new();
+        for tx in txs {
+            js_txs.push(&transaction_data_to_js(tx)?);
+        }
+
+        Ok(js_txs.unchecked_into::<TransactionDataList>())
     }
 
     #[wasm_bindgen(js_name = "createWithdraw")]
     pub fn create_withdraw(&self, withdraw: IWithdrawData) -> Result<TransactionData, JsValue> {
-        self.construct_tx_data(withdraw.to_native()?, None)
+        self.construct_tx_data(withdraw.to_native()?, None, None)
     }
 
     #[wasm_bindgen(js_name = "createWithdrawalOptimistic")]
@@ -250,25 +482,126 @@ impl UserAccount {
     ) -> Result<TransactionData, JsValue> {
         let new_state: StateUpdate =
             serde_wasm_bindgen::from_value(new_state).map_err(|err| js_err!(&err.to_string()))?;
-        self.construct_tx_data(withdraw.to_native()?, Some(new_state))
+        self.construct_tx_data(withdraw.to_native()?, Some(state_fragment(new_state)), None)
+    }
+
+    #[wasm_bindgen(js_name = "estimateTx")]
+    /// Estimates the outcome of a transaction: the number of spent notes, the new balance,
+    /// the accumulated energy, and whether the transaction would fail.
+    pub fn estimate_tx(
+        &self,
+        tx_type: TxType,
+        tx_data: JsValue,
+        new_state: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let native_tx = match tx_type {
+            TxType::Deposit => tx_data.unchecked_into::<IDepositData>().to_native()?,
+            TxType::DepositPermittable => tx_data
+                .unchecked_into::<IDepositPermittableData>()
+                .to_native()?,
+            TxType::Transfer => tx_data.unchecked_into::<ITransferData>().to_native()?,
+            TxType::Withdraw => tx_data.unchecked_into::<IWithdrawData>().to_native()?,
+            _ => return Err(js_err!("Unknown tx type")),
+        };
+
+        let extra_state = if new_state.is_null() || new_state.is_undefined() {
+            None
+        } else {
+            let new_state: StateUpdate = serde_wasm_bindgen::from_value(new_state)
+                .map_err(|err| js_err!(&err.to_string()))?;
+            Some(state_fragment(new_state))
+        };
+
+        let estimate = self
+            .inner
+            .borrow()
+            .estimate_tx(&native_tx, extra_state.as_ref());
+
+        Ok(serde_wasm_bindgen::to_value(&estimate)?)
     }
 
     #[wasm_bindgen(js_name = "isOwnAddress")]
     pub fn is_own_address(&self, address: &str) -> bool {
-        self.inner.borrow().is_own_address(address)
+        matches!(
+            self.inner.borrow().address_info(address),
+            AddressInfo::Own { .. }
+        )
+    }
+
+    #[wasm_bindgen(js_name = "parseOwnAddress")]
+    /// Returns whether the address is invalid, foreign or belongs to the account, along with its
+    /// diversifier and public key.
+    pub fn parse_own_address(&self, address: &str) -> Result<IAddressInfo, JsValue> {
+        let info = match self.inner.borrow().address_info(address) {
+            AddressInfo::Invalid(err) => AddressInfoSer::Invalid {
+                error: err.to_string(),
+            },
+            AddressInfo::Foreign { d, p_d } => AddressInfoSer::Foreign {
+                d: d.to_num().to_string(),
+                p_d: p_d.to_string(),
+            },
+            AddressInfo::Own { d, p_d } => AddressInfoSer::Own {
+                d: d.to_num().to_string(),
+                p_d: p_d.to_string(),
+            },
+        };
+
+        Ok(serde_wasm_bindgen::to_value(&info)?.unchecked_into::<IAddressInfo>())
+    }
+
+    #[wasm_bindgen(js_name = "deriveAddressForD")]
+    /// Restores the address generated for the diversifier (a decimal number).
+    pub fn derive_address_for_d(&self, d: &str) -> Result<String, JsValue> {
+        self.inner
+            .borrow()
+            .derive_address_for_d(d)
+            .map_err(|err| js_err!(&err.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = "calculateNullifier")]
+    /// Calculates the nullifier of an account located at the specified index.
+    pub fn calculate_nullifier(&self, account: Account, index: u64) -> Result<String, JsValue> {
+        let account: NativeAccount<Fr> = serde_wasm_bindgen::from_value(account.into())?;
+        let nullifier = self.inner.borrow().account_nullifier(&account, index);
+
+        Ok(nullifier.to_string())
     }
 
     #[wasm_bindgen(js_name = "addCommitment")]
-    /// Add out commitment hash to the tree.
-    pub fn add_commitment(&mut self, index: u64, commitment: Vec<u8>) -> Result<(), JsValue> {
-        self.inner.borrow_mut().state.tree.add_hash_at_height(
-            constants::OUTPLUSONELOG as u32,
-            index,
-            Num::try_from_slice(commitment.as_slice()).unwrap(),
-            false,
-        );
+    /// Add out commitment hash to the tree. If the leafs of the transaction (account hash followed
+    /// by the note hashes) are provided, the commitment is checked against them first.
+    pub fn add_commitment(
+        &mut self,
+        index: u64,
+        commitment: Vec<u8>,
+        hashes: Option<Hashes>,
+    ) -> Result<(), JsValue> {
+        let commitment = Num::try_from_slice(commitment.as_slice())
+            .map_err(|err| js_err!("Invalid commitment: {}", err))?;
 
-        Ok(())
+        if let Some(hashes) = hashes {
+            let hashes: Vec<_> = serde_wasm_bindgen::from_value(hashes.unchecked_into())?;
+            if hashes.len() > constants::OUT + 1 {
+                return Err(js_err!("Too many hashes: {}", hashes.len()));
+            }
+
+            let computed = commitment_from_leaves(&hashes, &*POOL_PARAMS);
+            if computed != commitment {
+                return Err(js_err!(
+                    "Commitment mismatch at {}: expected {}, computed {}",
+                    index,
+                    commitment,
+                    computed
+                ));
+            }
+        }
+
+        self.inner
+            .borrow_mut()
+            .state
+            .tree
+            .add_commitment(index, commitment)
+            .map_err(|err| js_err!(&err.to_string()))
     }
 
     #[wasm_bindgen(js_name = "addAccount")]
@@ -330,34 +663,150 @@ impl UserAccount {
     }
 
     #[wasm_bindgen(js_name = "updateState")]
-    pub fn update_state(&mut self, state_update: JsValue) -> Result<(), JsValue> {
+    /// Applies the state update and moves the sync cursor to `cursor` (left unchanged if not
+    /// set). If `return_changes` is set, returns the tree nodes written by the update ordered by
+    /// height and index.
+    pub fn update_state(
+        &mut self,
+        state_update: JsValue,
+        return_changes: Option<bool>,
+        cursor: Option<u64>,
+    ) -> Result<TreeNodes, JsValue> {
         let state_update: StateUpdate = serde_wasm_bindgen::from_value(state_update)
             .map_err(|err| js_err!(&err.to_string()))?;
 
-        if !state_update.new_leafs.is_empty() || !state_update.new_commitments.is_empty() {
-            self.inner
-                .borrow_mut()
-                .state
-                .tree
-                .add_leafs_and_commitments(state_update.new_leafs, state_update.new_commitments);
-        }
+        // Nothing is applied if any of the leafs don't match the expected commitments
+        self.inner
+            .borrow()
+            .state
+            .tree
+            .check_leafs(&state_update.new_leafs, &state_update.expected_commitments)
+            .map_err(|err| js_err!(&err.to_string()))?;
 
-        state_update
-            .new_accounts
-            .into_iter()
-            .for_each(|(at_index, account)| {
-                self.inner.borrow_mut().state.add_account(at_index, account);
-            });
+        let mut inner = self.inner.borrow_mut();
+        let cursor = cursor.unwrap_or_else(|| inner.state.sync_cursor());
+        let fragment = state_fragment(state_update);
 
-        state_update.new_notes.into_iter().for_each(|notes| {
-            notes.into_iter().for_each(|(at_index, note)| {
-                self.inner.borrow_mut().state.add_note(at_index, note);
-            });
-        });
+        let changes = if return_changes.unwrap_or(false) {
+            let changes = inner.state.apply_update_with_changes(fragment, cursor);
+            serde_wasm_bindgen::to_value(&changes).unwrap()
+        } else {
+            inner.state.apply_update(fragment, cursor);
+            JsValue::UNDEFINED
+        };
+
+        Ok(changes.unchecked_into::<TreeNodes>())
+    }
+
+    #[wasm_bindgen(js_name = "getSyncCursor")]
+    /// Returns the index up to which the state updates were applied.
+    pub fn get_sync_cursor(&self) -> u64 {
+        self.inner.borrow().state.sync_cursor()
+    }
+
+    #[wasm_bindgen(js_name = "checkDeltaPoolId")]
+    /// Decodes a delta (decimal string) provided by the relayer and checks that it was made for
+    /// the pool of this account.
+    pub fn check_delta_pool_id(&self, delta: &str) -> Result<(), JsValue> {
+        let delta = Num::from_str(delta).map_err(|_| js_err!("Invalid delta"))?;
+        self.inner
+            .borrow()
+            .check_delta_pool_id(delta)
+            .map_err(|err| js_err!(&err.to_string()))?;
+
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "isStateBootstrapped")]
+    /// Returns `false` until the state has been synced at least once. Transactions can't be
+    /// created before that unless `setAllowFreshAccount(true)` was called.
+    pub fn is_state_bootstrapped(&self) -> bool {
+        self.inner.borrow().state.is_bootstrapped()
+    }
+
+    #[wasm_bindgen(js_name = "setAllowFreshAccount")]
+    /// Allows creating transactions from a fresh account before the state is synced. Only safe
+    /// for users who have never transacted before.
+    pub fn set_allow_fresh_account(&self, allow: bool) {
+        self.inner.borrow_mut().allow_fresh_account = allow;
+    }
+
+    #[wasm_bindgen(js_name = "setTrackOutgoing")]
+    /// Reports the destinations recorded with `recordOutgoingAddresses` in
+    /// `TransactionData.warnings` when notes are sent to them again.
+    pub fn set_track_outgoing(&self, track: bool) {
+        self.inner.borrow_mut().track_outgoing = track;
+    }
+
+    #[wasm_bindgen(js_name = "recordOutgoingAddresses")]
+    /// Records the destinations of a submitted transaction in the address book if tracking is
+    /// enabled. Creating a transaction doesn't record them.
+    pub fn record_outgoing_addresses(&self, tx: TransactionData) -> Result<(), JsValue> {
+        let TransactionDestinations { destinations } =
+            serde_wasm_bindgen::from_value(tx.into()).map_err(|err| js_err!(&err.to_string()))?;
+        self.inner
+            .borrow_mut()
+            .record_outgoing_addresses(&destinations);
+
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "setOverrideDoubleSpendCheck")]
+    /// Allows creating a transaction whose nullifier was recorded with `recordPendingNullifier`,
+    /// e.g. to resubmit a transaction the relayer has dropped.
+    pub fn set_override_double_spend_check(&self, allow: bool) {
+        self.inner.borrow_mut().override_double_spend_check = allow;
+    }
+
+    #[wasm_bindgen(js_name = "recordPendingNullifier")]
+    /// Records the nullifier (decimal string) of a transaction submitted to the relayer. Creating
+    /// a transaction with the same nullifier fails with `NULLIFIER_ALREADY_USED` afterwards.
+    pub fn record_pending_nullifier(
+        &self,
+        nullifier: &str,
+        job_ref: String,
+    ) -> Result<(), JsValue> {
+        let nullifier = Num::from_str(nullifier).map_err(|_| js_err!("Invalid nullifier"))?;
+        self.inner
+            .borrow_mut()
+            .state
+            .record_pending_nullifier(nullifier, job_ref);
+
+        Ok(())
+    }
+
+    #[wasm_bindgen(js_name = "markNullifierSpent")]
+    /// Marks the nullifier (decimal string) as spent once its transaction is mined.
+    pub fn mark_nullifier_spent(&self, nullifier: &str) -> Result<(), JsValue> {
+        let nullifier = Num::from_str(nullifier).map_err(|_| js_err!("Invalid nullifier"))?;
+        self.inner
+            .borrow_mut()
+            .state
+            .mark_nullifier_spent(nullifier);
 
         Ok(())
     }
 
+    #[wasm_bindgen(js_name = "getNullifierRecord")]
+    pub fn get_nullifier_record(&self, nullifier: &str) -> Result<NullifierRecord, JsValue> {
+        let nullifier = Num::from_str(nullifier).map_err(|_| js_err!("Invalid nullifier"))?;
+        let record = self.inner.borrow().state.nullifier_record(nullifier);
+
+        Ok(serde_wasm_bindgen::to_value(&record)
+            .unwrap()
+            .unchecked_into::<NullifierRecord>())
+    }
+
+    #[wasm_bindgen(js_name = "listOutgoingAddresses")]
+    /// Returns the addresses recorded while tracking was enabled.
+    pub fn list_outgoing_addresses(&self) -> OutgoingAddresses {
+        let addresses = self.inner.borrow().state.list_outgoing_addresses();
+
+        serde_wasm_bindgen::to_value(&addresses)
+            .unwrap()
+            .unchecked_into::<OutgoingAddresses>()
+    }
+
     #[wasm_bindgen(js_name = "getRoot")]
     pub fn get_root(&mut self) -> String {
         let root = self.inner.borrow_mut().state.tree.get_root().to_string();
@@ -365,6 +814,30 @@ impl UserAccount {
         root
     }
 
+    #[wasm_bindgen(js_name = "getRootAfterState")]
+    /// Returns the root the tree would have after adding the leafs and commitments (in the
+    /// `StateUpdate` format), without modifying the tree.
+    pub fn get_root_after_state(
+        &self,
+        new_leafs: JsValue,
+        new_commitments: JsValue,
+    ) -> Result<String, JsValue> {
+        let new_leafs: Vec<(u64, Vec<Hash<Fr>>)> =
+            serde_wasm_bindgen::from_value(new_leafs).map_err(|err| js_err!(&err.to_string()))?;
+        let new_commitments: Vec<(u64, Hash<Fr>)> = serde_wasm_bindgen::from_value(new_commitments)
+            .map_err(|err| js_err!(&err.to_string()))?;
+
+        let root = self
+            .inner
+            .borrow()
+            .state
+            .tree
+            .get_root_after_state(&new_leafs, &new_commitments)
+            .map_err(|err| js_err!(&err.to_string()))?;
+
+        Ok(root.to_string())
+    }
+
     #[wasm_bindgen(js_name = "totalBalance")]
     /// Returns user's total balance (account + available notes).
     pub fn total_balance(&self) -> String {
@@ -383,6 +856,43 @@ impl UserAccount {
         self.inner.borrow().state.note_balance().to_string()
     }
 
+    #[wasm_bindgen(js_name = "spendableBalance")]
+    /// Returns the balance that can be spent by a single transaction, excluding the notes below
+    /// `min_amount`.
+    pub fn spendable_balance(&self, min_amount: String) -> Result<String, JsValue> {
+        let min_amount = Num::from_str(&min_amount).map_err(|_| js_err!("Invalid amount"))?;
+        let balance = self
+            .inner
+            .borrow()
+            .state
+            .spendable_balance(BoundedNum::new(min_amount));
+
+        Ok(balance.to_string())
+    }
+
+    #[wasm_bindgen(js_name = "getDustNotes")]
+    /// Returns usable notes below `min_amount`
+    pub fn get_dust_notes(&self, min_amount: String) -> Result<JsValue, JsValue> {
+        let min_amount = Num::from_str(&min_amount).map_err(|_| js_err!("Invalid amount"))?;
+        let data = self
+            .inner
+            .borrow()
+            .state
+            .dust_notes(BoundedNum::new(min_amount));
+
+        Ok(serde_wasm_bindgen::to_value(&data)?)
+    }
+
+    #[wasm_bindgen(js_name = "getConsolidationAdvice")]
+    /// Returns whether it's worth to consolidate the usable notes now at the specified fee per
+    /// transaction.
+    pub fn get_consolidation_advice(&self, fee: String) -> Result<ConsolidationAdvice, JsValue> {
+        let fee = parse_token_amount(&fee).map_err(|err| js_err!(&err.to_string()))?;
+        let advice = self.inner.borrow().state.consolidation_advice(fee);
+
+        Ok(serde_wasm_bindgen::to_value(&advice)?.unchecked_into::<ConsolidationAdvice>())
+    }
+
     #[wasm_bindgen(js_name = "getUsableNotes")]
     /// Returns all notes available for spending
     pub fn get_usable_notes(&self) -> JsValue {
@@ -391,11 +901,107 @@ impl UserAccount {
         serde_wasm_bindgen::to_value(&data).unwrap()
     }
 
+    #[wasm_bindgen(js_name = "getUsableNotesPage")]
+    /// Returns `limit` usable notes starting from `offset`, sorted by index, and the total number
+    /// of usable notes.
+    pub fn get_usable_notes_page(&self, offset: usize, limit: usize) -> UsableNotesPage {
+        let (notes, total) = self.inner.borrow().state.usable_notes_page(offset, limit);
+        let page = NotesPage {
+            notes: notes
+                .into_iter()
+                .map(|(index, note)| IndexedNote { index, note })
+                .collect(),
+            total,
+        };
+
+        serde_wasm_bindgen::to_value(&page)
+            .unwrap()
+            .unchecked_into::<UsableNotesPage>()
+    }
+
+    #[wasm_bindgen(js_name = "estimateEnergy")]
+    /// Returns the energy a transaction with the specified delta index would receive.
+    pub fn estimate_energy(&self, at_index: u64) -> String {
+        self.inner
+            .borrow()
+            .state
+            .energy_estimate(at_index)
+            .to_string()
+    }
+
+    #[wasm_bindgen(js_name = "getBalanceBreakdown")]
+    /// Returns usable notes with the energy they would yield if spent at the next index.
+    pub fn get_balance_breakdown(&self) -> JsValue {
+        let data = self.inner.borrow().state.balance_breakdown();
+
+        serde_wasm_bindgen::to_value(&data).unwrap()
+    }
+
+    #[wasm_bindgen(js_name = "getHistory")]
+    /// Returns deposits, incoming and outgoing transfers reconstructed from the cached accounts
+    /// and notes.
+    pub fn get_history(&self) -> HistoryEntries {
+        let history = self.inner.borrow().state.history();
+
+        serde_wasm_bindgen::to_value(&history)
+            .unwrap()
+            .unchecked_into::<HistoryEntries>()
+    }
+
+    #[wasm_bindgen(js_name = "earliestUsableIndex")]
+    /// Returns the index of the earliest note that can be spent, notes below it are locked by the
+    /// latest account.
+    pub fn earliest_usable_index(&self) -> u64 {
+        self.inner.borrow().state.earliest_usable_index()
+    }
+
+    #[wasm_bindgen(js_name = "earliestUsableIndexOptimistic")]
+    /// Same as `earliestUsableIndex`, but also takes into account the optimistic accounts and
+    /// notes in the `StateUpdate` format (`newAccounts` and `newNotes`).
+    pub fn earliest_usable_index_optimistic(
+        &self,
+        new_accounts: JsValue,
+        new_notes: JsValue,
+    ) -> Result<u64, JsValue> {
+        let new_accounts: Vec<(u64, NativeAccount<Fr>)> =
+            serde_wasm_bindgen::from_value(new_accounts)?;
+        let new_notes: Vec<Vec<(u64, NativeNote<Fr>)>> = serde_wasm_bindgen::from_value(new_notes)?;
+        let new_notes: Vec<_> = new_notes.into_iter().flatten().collect();
+
+        Ok(self
+            .inner
+            .borrow()
+            .state
+            .earliest_usable_index_optimistic(&new_accounts, &new_notes))
+    }
+
+    #[wasm_bindgen(js_name = "noteStatus")]
+    /// Returns `{ status: "spendable" | "unknown" }` or
+    /// `{ status: "lockedUntil", accountIndex }` for the note at the index.
+    pub fn note_status(&self, index: u64) -> NoteStatus {
+        let status = self.inner.borrow().state.note_status(index);
+
+        serde_wasm_bindgen::to_value(&status)
+            .unwrap()
+            .unchecked_into::<NoteStatus>()
+    }
+
     #[wasm_bindgen(js_name = "nextTreeIndex")]
     pub fn next_tree_index(&self) -> u64 {
         self.inner.borrow().state.tree.next_index()
     }
 
+    #[wasm_bindgen(js_name = "getTreeStats")]
+    /// Returns node counts and the approximate storage size of the local tree.
+    pub fn get_tree_stats(&self) -> TreeStats {
+        let stats = self.inner.borrow().state.tree.stats();
+
+        let serializer = Serializer::new().serialize_large_number_types_as_bigints(true);
+        let value: JsValue = stats.serialize(&serializer).unwrap();
+
+        value.unchecked_into::<TreeStats>()
+    }
+
     // TODO: Temporary method, try to expose the whole tree
     #[wasm_bindgen(js_name = "getLastLeaf")]
     pub fn get_last_leaf(&self) -> String {
@@ -447,6 +1053,35 @@ impl UserAccount {
         Ok(node.to_string())
     }
 
+    #[wasm_bindgen(js_name = "prepareTreeUpdate")]
+    /// Returns the inputs of the tree update circuit for adding the commitment at the specified
+    /// commitment index. The tree is not modified.
+    pub fn prepare_tree_update(
+        &self,
+        commitment_index: u64,
+        commitment: JsHash,
+    ) -> Result<TreeUpdateInputs, JsValue> {
+        #[derive(Serialize)]
+        struct SerTreeUpdate {
+            public: NativeTreePub<Fr>,
+            secret: NativeTreeSec<Fr>,
+        }
+
+        let hash: Hash<Fr> = serde_wasm_bindgen::from_value(commitment.unchecked_into())?;
+        let (public, secret) = self
+            .inner
+            .borrow()
+            .state
+            .tree
+            .prepare_tree_update(hash, commitment_index);
+
+        Ok(
+            serde_wasm_bindgen::to_value(&SerTreeUpdate { public, secret })
+                .unwrap()
+                .unchecked_into::<TreeUpdateInputs>(),
+        )
+    }
+
     #[wasm_bindgen(js_name = "getMerkleProofAfter")]
     /// Returns merkle proofs for the specified leafs (hashes) as if they were appended to the tree.
     pub fn get_merkle_proof_after(&self, hashes: Hashes) -> Result<Vec<MerkleProof>, JsValue> {
@@ -470,17 +1105,34 @@ impl UserAccount {
     }
 
     #[wasm_bindgen(js_name = "getCommitmentMerkleProof")]
-    pub fn get_commitment_merkle_proof(&self, index: u64) -> MerkleProof {
+    /// Returns the proof of the commitment at the specified commitment index. Fails if the
+    /// commitment is not in the tree yet.
+    pub fn get_commitment_merkle_proof(&self, index: u64) -> Result<MerkleProof, JsValue> {
         let proof = self
             .inner
             .borrow()
             .state
             .tree
-            .get_proof_unchecked::<{ constants::HEIGHT - constants::OUTPLUSONELOG }>(index);
+            .get_commitment_proof(index)
+            .ok_or_else(|| js_err!("Commitment {} is not in the tree", index))?;
 
-        serde_wasm_bindgen::to_value(&proof)
+        Ok(serde_wasm_bindgen::to_value(&proof)
             .unwrap()
-            .unchecked_into::<MerkleProof>()
+            .unchecked_into::<MerkleProof>())
+    }
+
+    #[wasm_bindgen(js_name = "getMerkleNodes")]
+    /// Returns stored merkle nodes at the specified height with indices in [from, to).
+    pub fn get_merkle_nodes(&self, height: u32, from: u64, to: u64) -> JsValue {
+        let nodes: Vec<Node<Fr>> = self
+            .inner
+            .borrow()
+            .state
+            .tree
+            .iter_nodes_in(height, from..to)
+            .collect();
+
+        serde_wasm_bindgen::to_value(&nodes).unwrap()
     }
 
     #[wasm_bindgen(js_name = "getWholeState")]
@@ -504,8 +1156,125 @@ impl UserAccount {
         serde_wasm_bindgen::to_value(&data).unwrap()
     }
 
+    #[wasm_bindgen(js_name = "exportState")]
+    /// Serializes the tree and the cached accounts and notes into a portable snapshot.
+    pub fn export_state(&self) -> Result<Vec<u8>, JsValue> {
+        let snapshot = self.inner.borrow().state.export();
+
+        snapshot
+            .try_to_vec()
+            .map_err(|err| js_err!(&err.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = "importState")]
+    /// Replaces the whole state with a snapshot produced by `exportState`.
+    pub fn import_state(&mut self, snapshot: &[u8]) -> Result<(), JsValue> {
+        let snapshot =
+            StateSnapshot::try_from_slice(snapshot).map_err(|err| js_err!(&err.to_string()))?;
+
+        self.inner
+            .borrow_mut()
+            .state
+            .restore(&snapshot)
+            .map_err(|err| js_err!(&err.to_string()))
+    }
+
     #[wasm_bindgen(js_name = "rollback")]
-    pub fn rollback(&mut self, index: u64) {
-        self.inner.borrow_mut().state.rollback(index);
+    /// Removes all cached data at or after `index`. Returns the number of removed accounts and
+    /// notes and the index starting from which the tree nodes must be re-requested, if any.
+    pub fn rollback(&mut self, index: u64) -> Result<JsValue, JsValue> {
+        let report = self.inner.borrow_mut().state.rollback(index);
+
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+
+    #[wasm_bindgen(js_name = "pruneHistory")]
+    /// Removes cached accounts and notes below `index` that are no longer needed to calculate the
+    /// balance. Returns the number of removed entries.
+    pub fn prune_history(&mut self, index: u64) -> u64 {
+        self.inner.borrow_mut().state.prune_history(index)
+    }
+
+    #[wasm_bindgen(js_name = "redecryptRange")]
+    /// Re-runs decryption over the [from, to) index range. The callback receives a memo index
+    /// and should return the raw memo as a hex string or Uint8Array, or null if it's unavailable.
+    pub fn redecrypt_range(
+        &mut self,
+        from: u64,
+        to: u64,
+        fetch_memo_callback: js_sys::Function,
+    ) -> Result<JsValue, JsValue> {
+        let fetch_memo = |index: u64| -> Option<Vec<u8>> {
+            let memo = fetch_memo_callback
+                .call1(&JsValue::NULL, &JsValue::from_f64(index as f64))
+                .ok()?;
+
+            if memo.is_null() || memo.is_undefined() {
+                None
+            } else if let Some(memo) = memo.as_string() {
+                hex::decode(memo).ok()
+            } else {
+                Some(js_sys::Uint8Array::new(&memo).to_vec())
+            }
+        };
+
+        let account = &mut *self.inner.borrow_mut();
+        let report = account
+            .state
+            .redecrypt_range(from..to, &account.keys, &fetch_memo);
+
+        Ok(serde_wasm_bindgen::to_value(&report)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    async fn init_account() -> UserAccount {
+        let state = UserState::init("test_account".to_string()).await.unwrap();
+        let mut sk = Num::<Fs>::from(1u64).try_to_vec().unwrap();
+
+        UserAccount::new(&mut sk, state, None).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_add_commitment_rejects_invalid_input() {
+        let mut account = init_account().await;
+
+        assert!(account.add_commitment(0, vec![0; 31], None).is_err());
+        assert!(account.get_commitment_merkle_proof(0).is_err());
+
+        let commitment = Num::<Fr>::from(1u64).try_to_vec().unwrap();
+        account.add_commitment(0, commitment, None).unwrap();
+        assert!(account.get_commitment_merkle_proof(0).is_ok());
+        assert!(account.get_commitment_merkle_proof(1).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_new_with_config() {
+        let new_account = |config: JsValue| async move {
+            let state = UserState::init("test_account_config".to_string())
+                .await
+                .unwrap();
+            let mut sk = Num::<Fs>::from(1u64).try_to_vec().unwrap();
+            UserAccount::new(&mut sk, state, Some(config.unchecked_into()))
+        };
+
+        let config = js_sys::JSON::parse(r#"{"poolId": 7, "trackOutgoing": true}"#).unwrap();
+        let account = new_account(config).await.unwrap();
+        {
+            let inner = account.inner.borrow();
+            assert_eq!(inner.pool_id.to_num(), Num::from(7u64));
+            assert!(inner.track_outgoing);
+            assert!(!inner.allow_fresh_account);
+        }
+
+        let account = new_account(JsValue::from(3)).await.unwrap();
+        assert_eq!(account.inner.borrow().pool_id.to_num(), Num::from(3u64));
+
+        assert!(new_account(JsValue::from("7")).await.is_err());
     }
 }
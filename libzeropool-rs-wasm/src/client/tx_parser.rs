@@ -1,49 +1,19 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::convert::TryInto;
+
 use libzeropool_rs::{
-    keys::Keys,
-    libzeropool::{
-        fawkes_crypto::ff_uint::{Num, NumRepr, Uint},
-        native::{account::Account, cipher, key, note::Note},
+    keys::{Keys, SecretKey},
+    libzeropool::fawkes_crypto::ff_uint::{Num, NumRepr, Uint},
+    tx_parser::{
+        self, IndexedTx as NativeIndexedTx, ParseError, ParseErrorCode, ParseResult, ParseSession,
     },
-    merkle::Hash,
 };
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
 use wasm_bindgen::{prelude::*, JsCast};
 
-use crate::{Fr, Fs, IndexedNote, IndexedTx, ParseTxsResult, PoolParams, POOL_PARAMS};
-
-#[derive(Serialize, Deserialize, Clone, Default)]
-pub struct StateUpdate {
-    #[serde(rename = "newLeafs")]
-    pub new_leafs: Vec<(u64, Vec<Hash<Fr>>)>,
-    #[serde(rename = "newCommitments")]
-    pub new_commitments: Vec<(u64, Hash<Fr>)>,
-    #[serde(rename = "newAccounts")]
-    pub new_accounts: Vec<(u64, Account<Fr>)>,
-    #[serde(rename = "newNotes")]
-    pub new_notes: Vec<Vec<(u64, Note<Fr>)>>,
-}
+use crate::{Fr, Fs, IndexedTx, ParseTxsResult, PoolParams, POOL_PARAMS};
 
-#[derive(Serialize, Deserialize, Clone, Default)]
-struct DecMemo {
-    index: u64,
-    acc: Option<Account<Fr>>,
-    #[serde(rename = "inNotes")]
-    in_notes: Vec<IndexedNote>,
-    #[serde(rename = "outNotes")]
-    out_notes: Vec<IndexedNote>,
-    #[serde(rename = "txHash")]
-    tx_hash: Option<String>,
-}
+pub type StateUpdate = tx_parser::StateUpdate<Fr>;
 
-#[derive(Serialize, Default)]
-struct ParseResult {
-    #[serde(rename = "decryptedMemos")]
-    decrypted_memos: Vec<DecMemo>,
-    #[serde(rename = "stateUpdate")]
-    state_update: StateUpdate,
-}
+const COMMITMENT_SIZE: usize = 32;
 
 #[wasm_bindgen]
 pub struct TxParser {
@@ -60,159 +30,319 @@ impl TxParser {
         })
     }
 
+    /// `memo` and `commitment` of every tx can be either a hex string or a `Uint8Array`.
+    /// Transactions with invalid hex are skipped and reported in `errors`.
     #[wasm_bindgen(js_name = "parseTxs")]
     pub fn parse_txs(&self, sk: &[u8], txs: JsValue) -> Result<ParseTxsResult, JsValue> {
-        let sk = Num::<Fs>::from_uint(NumRepr(Uint::from_little_endian(sk)))
-            .ok_or_else(|| js_err!("Invalid spending key"))?;
+        let (txs, errors) = decode_indexed_txs(txs)?;
+        let parse_result = self.parse_native_txs(sk, &txs)?;
+
+        Ok(to_parse_txs_result(with_errors(parse_result, errors)))
+    }
+
+    /// Same as `parseTxs`, but takes all transactions packed into a single buffer.
+    /// Every tx is encoded as `index: u64 LE, memo_len: u32 LE, memo, commitment: [u8; 32] BE`.
+    #[wasm_bindgen(js_name = "parseTxsBinary")]
+    pub fn parse_txs_binary(&self, sk: &[u8], packed: &[u8]) -> Result<ParseTxsResult, JsValue> {
+        let txs = unpack_txs(packed)?;
+        self.parse_native_txs(sk, &txs).map(to_parse_txs_result)
+    }
+}
+
+impl TxParser {
+    fn parse_native_txs(
+        &self,
+        sk: &[u8],
+        txs: &[NativeIndexedTx<Fr>],
+    ) -> Result<ParseResult<Fr>, JsValue> {
         let params = &self.params;
-        let eta = Keys::derive(sk, params).eta;
-
-        let txs: Vec<IndexedTx> =
-            serde_wasm_bindgen::from_value(txs).map_err(|err| js_err!(&err.to_string()))?;
-        let parse_results: Vec<_> = txs
-            .into_par_iter()
-            .map(|tx| -> ParseResult {
-                let IndexedTx {
-                    index,
-                    memo,
-                    commitment,
-                } = tx;
-                let memo = hex::decode(memo).unwrap();
-                let commitment = hex::decode(commitment).unwrap();
-                let num_hashes = (&memo[0..4]).read_u32::<LittleEndian>().unwrap();
-                let hashes: Vec<_> = (&memo[4..])
-                    .chunks(32)
-                    .take(num_hashes as usize)
-                    .map(|bytes| Num::from_uint_reduced(NumRepr(Uint::from_little_endian(bytes))))
-                    .collect();
-
-                let pair = cipher::decrypt_out(eta, &memo.clone(), params);
-
-                match pair {
-                    Some((account, notes)) => {
-                        let mut in_notes = Vec::new();
-                        let mut out_notes = Vec::new();
-                        notes.into_iter().enumerate().for_each(|(i, note)| {
-                            out_notes.push((index + 1 + (i as u64), note));
-
-                            if note.p_d == key::derive_key_p_d(note.d.to_num(), eta, params).x {
-                                in_notes.push((index + 1 + (i as u64), note));
-                            }
-                        });
-
-                        ParseResult {
-                            decrypted_memos: vec![DecMemo {
-                                index,
-                                acc: Some(account),
-                                in_notes: in_notes
-                                    .clone()
-                                    .into_iter()
-                                    .map(|(index, note)| IndexedNote { index, note })
-                                    .collect(),
-                                out_notes: out_notes
-                                    .into_iter()
-                                    .map(|(index, note)| IndexedNote { index, note })
-                                    .collect(),
-                                ..Default::default()
-                            }],
-                            state_update: StateUpdate {
-                                new_leafs: vec![(index, hashes)],
-                                new_accounts: vec![(index, account)],
-                                new_notes: vec![in_notes],
-                                ..Default::default()
-                            },
-                        }
-                    }
-                    None => {
-                        let in_notes: Vec<(_, _)> = cipher::decrypt_in(eta, &memo, params)
-                            .into_iter()
-                            .enumerate()
-                            .filter_map(|(i, note)| match note {
-                                Some(note)
-                                    if note.p_d
-                                        == key::derive_key_p_d(note.d.to_num(), eta, params).x =>
-                                {
-                                    Some((index + 1 + (i as u64), note))
-                                }
-                                _ => None,
-                            })
-                            .collect();
-
-                        if !in_notes.is_empty() {
-                            ParseResult {
-                                decrypted_memos: vec![DecMemo {
-                                    index,
-                                    in_notes: in_notes
-                                        .clone()
-                                        .into_iter()
-                                        .map(|(index, note)| IndexedNote { index, note })
-                                        .collect(),
-                                    ..Default::default()
-                                }],
-                                state_update: StateUpdate {
-                                    new_leafs: vec![(index, hashes)],
-                                    new_notes: vec![in_notes],
-                                    ..Default::default()
-                                },
-                            }
-                        } else {
-                            ParseResult {
-                                state_update: StateUpdate {
-                                    new_commitments: vec![(
-                                        index,
-                                        Num::from_uint_reduced(NumRepr(Uint::from_big_endian(
-                                            &commitment,
-                                        ))),
-                                    )],
-                                    ..Default::default()
-                                },
-                                ..Default::default()
-                            }
-                        }
-                    }
-                }
+        let eta = derive_eta(sk, params)?;
+
+        Ok(tx_parser::parse_txs(eta, txs, params))
+    }
+}
+
+/// Stateful parser for transactions fetched in pages. Already processed indices are skipped,
+/// the merged state update is returned by `finalize`.
+#[wasm_bindgen]
+pub struct TxParserSession {
+    eta: Num<Fr>,
+    params: PoolParams,
+    session: ParseSession<Fr>,
+}
+
+#[wasm_bindgen]
+impl TxParserSession {
+    #[wasm_bindgen(js_name = "new")]
+    pub fn new(sk: &[u8]) -> Result<TxParserSession, JsValue> {
+        let params = POOL_PARAMS.clone();
+        let eta = derive_eta(sk, &params)?;
+        let session = ParseSession::new(&params);
+
+        Ok(TxParserSession {
+            eta,
+            params,
+            session,
+        })
+    }
+
+    /// Highest transaction index processed so far.
+    #[wasm_bindgen(js_name = "lastIndex")]
+    pub fn last_index(&self) -> Option<u64> {
+        self.session.last_index()
+    }
+
+    /// Parses the transactions that haven't been processed by this session yet. Transactions
+    /// with invalid hex are reported in `errors` and can be fed again later.
+    pub fn feed(&mut self, txs: JsValue) -> Result<ParseTxsResult, JsValue> {
+        let (txs, errors) = decode_indexed_txs(txs)?;
+        let parse_result = self.session.feed(self.eta, &txs, &self.params);
+
+        Ok(to_parse_txs_result(with_errors(parse_result, errors)))
+    }
+
+    /// Returns the cumulative state update with adjacent leaf batches merged.
+    pub fn finalize(&self) -> crate::StateUpdate {
+        serde_wasm_bindgen::to_value(&self.session.finalize())
+            .unwrap()
+            .unchecked_into::<crate::StateUpdate>()
+    }
+}
+
+fn derive_eta(sk: &[u8], params: &PoolParams) -> Result<Num<Fr>, JsValue> {
+    let sk = SecretKey::<Fs>::from_bytes(sk).ok_or_else(|| js_err!("Invalid spending key"))?;
+
+    Ok(Keys::derive(sk, params).eta)
+}
+
+fn with_errors(mut parse_result: ParseResult<Fr>, errors: Vec<ParseError>) -> ParseResult<Fr> {
+    parse_result.errors.extend(errors);
+    parse_result.errors.sort_by_key(|err| err.index);
+    parse_result
+}
+
+fn to_parse_txs_result(parse_result: ParseResult<Fr>) -> ParseTxsResult {
+    serde_wasm_bindgen::to_value(&parse_result)
+        .unwrap()
+        .unchecked_into::<ParseTxsResult>()
+}
+
+/// Returns the decoded transactions and the errors for the ones with invalid hex.
+fn decode_indexed_txs(
+    txs: JsValue,
+) -> Result<(Vec<NativeIndexedTx<Fr>>, Vec<ParseError>), JsValue> {
+    let txs: Vec<IndexedTx> =
+        serde_wasm_bindgen::from_value(txs).map_err(|err| js_err!(&err.to_string()))?;
+
+    let mut native_txs = Vec::with_capacity(txs.len());
+    let mut errors = Vec::new();
+    for tx in txs {
+        match (bytes_from_js(&tx.memo)?, bytes_from_js(&tx.commitment)?) {
+            (Some(memo), Some(commitment)) => native_txs.push(NativeIndexedTx {
+                index: tx.index,
+                memo,
+                commitment: commitment_from_bytes(&commitment),
+            }),
+            _ => errors.push(ParseError {
+                index: tx.index,
+                code: ParseErrorCode::BadHex,
+            }),
+        }
+    }
+
+    Ok((native_txs, errors))
+}
+
+/// Returns `None` if the value is a string that is not valid hex.
+fn bytes_from_js(value: &JsValue) -> Result<Option<Vec<u8>>, JsValue> {
+    if let Some(bytes) = value.dyn_ref::<js_sys::Uint8Array>() {
+        Ok(Some(bytes.to_vec()))
+    } else if let Some(hex_str) = value.as_string() {
+        Ok(hex::decode(hex_str).ok())
+    } else {
+        Err(js_err!("Expected a hex string or Uint8Array"))
+    }
+}
+
+fn commitment_from_bytes(bytes: &[u8]) -> Num<Fr> {
+    Num::from_uint_reduced(NumRepr(Uint::from_big_endian(bytes)))
+}
+
+fn unpack_txs(mut data: &[u8]) -> Result<Vec<NativeIndexedTx<Fr>>, JsValue> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], JsValue> {
+        if data.len() < len {
+            return Err(js_err!("Unexpected end of packed transactions"));
+        }
+
+        let (head, tail) = data.split_at(len);
+        *data = tail;
+        Ok(head)
+    }
+
+    let mut txs = Vec::new();
+    while !data.is_empty() {
+        let index = u64::from_le_bytes(take(&mut data, 8)?.try_into().unwrap());
+        let memo_len = u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap()) as usize;
+        let memo = take(&mut data, memo_len)?.to_vec();
+        let commitment = commitment_from_bytes(take(&mut data, COMMITMENT_SIZE)?);
+
+        txs.push(NativeIndexedTx {
+            index,
+            memo,
+            commitment,
+        });
+    }
+
+    Ok(txs)
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::{
+        client::{state::State, TxOutput, TxType, UserAccount},
+        libzeropool::{fawkes_crypto::borsh::BorshSerialize, native::boundednum::BoundedNum},
+    };
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    fn commitment_bytes(commitment: Num<Fr>) -> Vec<u8> {
+        let mut bytes = commitment.try_to_vec().unwrap();
+        bytes.reverse();
+        bytes
+    }
+
+    #[wasm_bindgen_test]
+    fn test_parse_txs_binary_matches_parse_txs() {
+        let sk = Num::<Fs>::from(1u64);
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(sk, state, POOL_PARAMS.clone());
+
+        let txs: Vec<(u64, Vec<u8>, Vec<u8>)> = (0..3u64)
+            .map(|i| {
+                let tx = TxType::Transfer {
+                    fee: BoundedNum::new(Num::ZERO),
+                    outputs: vec![TxOutput {
+                        to: acc.generate_address(),
+                        amount: BoundedNum::new(Num::ZERO),
+                    }],
+                };
+                let data = acc.create_tx(tx, None, None).unwrap();
+                let commitment = commitment_bytes(data.public.out_commit);
+
+                (i * 128, data.ciphertext, commitment)
             })
             .collect();
 
-        let mut parse_result =
-            parse_results
-                .into_iter()
-                .fold(Default::default(), |acc: ParseResult, parse_result| {
-                    ParseResult {
-                        decrypted_memos: vec![acc.decrypted_memos, parse_result.decrypted_memos]
-                            .concat(),
-                        state_update: StateUpdate {
-                            new_leafs: vec![
-                                acc.state_update.new_leafs,
-                                parse_result.state_update.new_leafs,
-                            ]
-                            .concat(),
-                            new_commitments: vec![
-                                acc.state_update.new_commitments,
-                                parse_result.state_update.new_commitments,
-                            ]
-                            .concat(),
-                            new_accounts: vec![
-                                acc.state_update.new_accounts,
-                                parse_result.state_update.new_accounts,
-                            ]
-                            .concat(),
-                            new_notes: vec![
-                                acc.state_update.new_notes,
-                                parse_result.state_update.new_notes,
-                            ]
-                            .concat(),
-                        },
-                    }
-                });
-
-        parse_result
-            .decrypted_memos
-            .sort_by(|a, b| a.index.cmp(&b.index));
-
-        let parse_result = serde_wasm_bindgen::to_value(&parse_result)
-            .unwrap()
-            .unchecked_into::<ParseTxsResult>();
-        Ok(parse_result)
+        // Mix both field representations
+        let js_txs = js_sys::Array::new();
+        let mut packed = Vec::new();
+        for (i, (index, memo, commitment)) in txs.iter().enumerate() {
+            let (memo_js, commitment_js) = if i % 2 == 0 {
+                (
+                    JsValue::from(hex::encode(memo)),
+                    JsValue::from(js_sys::Uint8Array::from(commitment.as_slice())),
+                )
+            } else {
+                (
+                    JsValue::from(js_sys::Uint8Array::from(memo.as_slice())),
+                    JsValue::from(hex::encode(commitment)),
+                )
+            };
+            let tx = IndexedTx {
+                index: *index,
+                memo: memo_js,
+                commitment: commitment_js,
+            };
+            js_txs.push(&serde_wasm_bindgen::to_value(&tx).unwrap());
+
+            packed.extend_from_slice(&index.to_le_bytes());
+            packed.extend_from_slice(&(memo.len() as u32).to_le_bytes());
+            packed.extend_from_slice(memo);
+            packed.extend_from_slice(commitment);
+        }
+
+        let parser = TxParser::new().unwrap();
+        let sk_bytes = sk.try_to_vec().unwrap();
+
+        let from_js = parser
+            .parse_native_txs(&sk_bytes, &decode_indexed_txs(js_txs.into()).unwrap().0)
+            .unwrap();
+        let from_binary = parser
+            .parse_native_txs(&sk_bytes, &unpack_txs(&packed).unwrap())
+            .unwrap();
+
+        assert_eq!(from_js.decrypted_memos.len(), txs.len());
+        assert_eq!(
+            serde_json::to_string(&from_js).unwrap(),
+            serde_json::to_string(&from_binary).unwrap()
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_parse_txs_reports_errors() {
+        let sk = Num::<Fs>::from(1u64);
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(sk, state, POOL_PARAMS.clone());
+        let tx = TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::ZERO),
+            }],
+        };
+        let data = acc.create_tx(tx, None, None).unwrap();
+        let commitment = hex::encode(commitment_bytes(data.public.out_commit));
+
+        let memos = [
+            hex::encode(&data.ciphertext),
+            "abc".to_string(),
+            hex::encode(&data.ciphertext[..8]),
+        ];
+        let js_txs = js_sys::Array::new();
+        for (i, memo) in memos.iter().enumerate() {
+            let tx = IndexedTx {
+                index: i as u64 * 128,
+                memo: JsValue::from(memo),
+                commitment: JsValue::from(&commitment),
+            };
+            js_txs.push(&serde_wasm_bindgen::to_value(&tx).unwrap());
+        }
+
+        let parser = TxParser::new().unwrap();
+        let result = parser
+            .parse_txs(&sk.try_to_vec().unwrap(), js_txs.into())
+            .unwrap();
+        let result: ParseResult<Fr> = serde_wasm_bindgen::from_value(result.into()).unwrap();
+
+        assert_eq!(
+            result.errors,
+            vec![
+                ParseError {
+                    index: 128,
+                    code: ParseErrorCode::BadHex,
+                },
+                ParseError {
+                    index: 256,
+                    code: ParseErrorCode::TruncatedMemo,
+                },
+            ]
+        );
+        assert_eq!(result.decrypted_memos.len(), 1);
+        assert_eq!(result.decrypted_memos[0].index, 0);
+        assert_eq!(result.state_update.new_accounts.len(), 1);
+        assert_eq!(result.state_update.new_commitments.len(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_unpack_txs_truncated() {
+        let mut packed = Vec::new();
+        packed.extend_from_slice(&0u64.to_le_bytes());
+        packed.extend_from_slice(&4u32.to_le_bytes());
+        packed.extend_from_slice(&[1, 2, 3, 4]);
+        packed.extend_from_slice(&[0; COMMITMENT_SIZE - 1]);
+
+        assert!(unpack_txs(&packed).is_err());
+        assert!(unpack_txs(&[]).unwrap().is_empty());
     }
 }
@@ -1,5 +1,10 @@
-use libzeropool_rs::client::{TokenAmount, TxOutput, TxType as NativeTxType};
-use serde::Deserialize;
+use std::convert::TryFrom;
+
+use libzeropool_rs::client::{
+    dto::{DepositDto, DepositPermittableDto, TransferDto, WithdrawDto},
+    TxType as NativeTxType,
+};
+use serde::{de::DeserializeOwned, Deserialize};
 use wasm_bindgen::prelude::*;
 
 use crate::{
@@ -18,272 +23,97 @@ pub enum TxType {
 
 pub trait JsTxType {
     fn to_native(&self) -> Result<NativeTxType<Fr>, JsValue>;
+
+    /// Indices of the notes that should be spent, if specified by the caller.
+    fn note_indices(&self) -> Result<Option<Vec<u64>>, JsValue> {
+        Ok(None)
+    }
 }
 
 pub trait JsMultiTxType {
     fn to_native_array(&self) -> Result<Vec<NativeTxType<Fr>>, JsValue>;
 }
 
-#[wasm_bindgen]
-#[derive(Deserialize)]
-pub struct TxBaseFields {
-    fee: TokenAmount<Fr>,
+/// Deserializes a JS object into the core tx DTO and converts it into the native tx type.
+fn dto_to_native<T>(value: &JsValue) -> Result<NativeTxType<Fr>, JsValue>
+where
+    T: DeserializeOwned,
+    NativeTxType<Fr>: TryFrom<T>,
+    <NativeTxType<Fr> as TryFrom<T>>::Error: ToString,
+{
+    let dto: T = serde_wasm_bindgen::from_value(value.clone())?;
+    NativeTxType::try_from(dto).map_err(|err| js_err!(&err.to_string()))
 }
 
-#[wasm_bindgen]
-#[derive(Deserialize)]
-pub struct DepositData {
-    #[serde(flatten)]
-    base_fields: TxBaseFields,
-    amount: TokenAmount<Fr>,
-    outputs: Option<Vec<Output>>,
+fn dto_array_to_native<T>(value: &JsValue) -> Result<Vec<NativeTxType<Fr>>, JsValue>
+where
+    T: DeserializeOwned,
+    NativeTxType<Fr>: TryFrom<T>,
+    <NativeTxType<Fr> as TryFrom<T>>::Error: ToString,
+{
+    let array: Vec<T> = serde_wasm_bindgen::from_value(value.clone())?;
+    array
+        .into_iter()
+        .map(|dto| NativeTxType::try_from(dto).map_err(|err| js_err!(&err.to_string())))
+        .collect()
 }
 
 impl JsTxType for IDepositData {
     fn to_native(&self) -> Result<NativeTxType<Fr>, JsValue> {
-        let DepositData {
-            base_fields,
-            amount,
-            outputs,
-        } = serde_wasm_bindgen::from_value(self.into())?;
-
-        let outputs = outputs
-            .map(|outputs| {
-                outputs
-                    .into_iter()
-                    .map(|out| TxOutput {
-                        to: out.to,
-                        amount: out.amount,
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        Ok(NativeTxType::Deposit {
-            fee: base_fields.fee,
-            deposit_amount: amount,
-            outputs,
-        })
+        dto_to_native::<DepositDto<Fr>>(self)
     }
 }
 
 impl JsMultiTxType for IMultiDepositData {
     fn to_native_array(&self) -> Result<Vec<NativeTxType<Fr>>, JsValue> {
-        let array: Vec<DepositData> = serde_wasm_bindgen::from_value(self.into())?;
-
-        let tx_array = array
-            .into_iter()
-            .map(|tx| {
-                let outputs = tx
-                    .outputs
-                    .map(|outputs| {
-                        outputs
-                            .into_iter()
-                            .map(|out| TxOutput {
-                                to: out.to,
-                                amount: out.amount,
-                            })
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or_default();
-
-                NativeTxType::Deposit {
-                    fee: tx.base_fields.fee,
-                    deposit_amount: tx.amount,
-                    outputs,
-                }
-            })
-            .collect();
-
-        Ok(tx_array)
+        dto_array_to_native::<DepositDto<Fr>>(self)
     }
 }
 
-#[wasm_bindgen]
-#[derive(Deserialize)]
-pub struct DepositPermittableData {
-    #[serde(flatten)]
-    base_fields: TxBaseFields,
-    amount: TokenAmount<Fr>,
-    deadline: String,
-    holder: Vec<u8>,
-    outputs: Option<Vec<Output>>,
-}
-
 impl JsTxType for IDepositPermittableData {
     fn to_native(&self) -> Result<NativeTxType<Fr>, JsValue> {
-        let DepositPermittableData {
-            base_fields,
-            amount,
-            deadline,
-            holder,
-            outputs,
-        } = serde_wasm_bindgen::from_value(self.into())?;
-
-        let outputs = outputs
-            .map(|outputs| {
-                outputs
-                    .into_iter()
-                    .map(|out| TxOutput {
-                        to: out.to,
-                        amount: out.amount,
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-
-        Ok(NativeTxType::DepositPermittable {
-            fee: base_fields.fee,
-            deposit_amount: amount,
-            deadline: deadline.parse::<u64>().unwrap_or(0),
-            holder,
-            outputs,
-        })
+        dto_to_native::<DepositPermittableDto<Fr>>(self)
     }
 }
 
 impl JsMultiTxType for IMultiDepositPermittableData {
     fn to_native_array(&self) -> Result<Vec<NativeTxType<Fr>>, JsValue> {
-        let array: Vec<DepositPermittableData> = serde_wasm_bindgen::from_value(self.into())?;
-
-        let tx_array = array
-            .into_iter()
-            .map(|tx| {
-                let outputs = tx
-                    .outputs
-                    .map(|outputs| {
-                        outputs
-                            .into_iter()
-                            .map(|out| TxOutput {
-                                to: out.to,
-                                amount: out.amount,
-                            })
-                            .collect::<Vec<_>>()
-                    })
-                    .unwrap_or_default();
-                NativeTxType::DepositPermittable {
-                    fee: tx.base_fields.fee,
-                    deposit_amount: tx.amount,
-                    deadline: tx.deadline.parse::<u64>().unwrap_or(0),
-                    holder: tx.holder,
-                    outputs,
-                }
-            })
-            .collect();
-
-        Ok(tx_array)
+        dto_array_to_native::<DepositPermittableDto<Fr>>(self)
     }
 }
 
 #[derive(Deserialize)]
-pub struct Output {
-    to: String,
-    amount: TokenAmount<Fr>,
-}
-
-#[wasm_bindgen]
-#[derive(Deserialize)]
-pub struct TransferData {
-    #[serde(flatten)]
-    base_fields: TxBaseFields,
-    outputs: Vec<Output>,
+struct NoteIndices {
+    #[serde(rename = "noteIndices")]
+    note_indices: Option<Vec<u64>>,
 }
 
 impl JsTxType for ITransferData {
     fn to_native(&self) -> Result<NativeTxType<Fr>, JsValue> {
-        let TransferData {
-            base_fields,
-            outputs,
-        } = serde_wasm_bindgen::from_value(self.into())?;
+        dto_to_native::<TransferDto<Fr>>(self)
+    }
 
-        let outputs = outputs
-            .into_iter()
-            .map(|out| TxOutput {
-                to: out.to,
-                amount: out.amount,
-            })
-            .collect::<Vec<_>>();
+    fn note_indices(&self) -> Result<Option<Vec<u64>>, JsValue> {
+        let NoteIndices { note_indices } = serde_wasm_bindgen::from_value(self.into())?;
 
-        Ok(NativeTxType::Transfer {
-            fee: base_fields.fee,
-            outputs,
-        })
+        Ok(note_indices)
     }
 }
 
 impl JsMultiTxType for IMultiTransferData {
     fn to_native_array(&self) -> Result<Vec<NativeTxType<Fr>>, JsValue> {
-        let array: Vec<TransferData> = serde_wasm_bindgen::from_value(self.into())?;
-
-        let tx_array = array
-            .into_iter()
-            .map(|tx| {
-                let outputs = tx
-                    .outputs
-                    .into_iter()
-                    .map(|out| TxOutput {
-                        to: out.to,
-                        amount: out.amount,
-                    })
-                    .collect::<Vec<_>>();
-
-                NativeTxType::Transfer {
-                    fee: tx.base_fields.fee,
-                    outputs,
-                }
-            })
-            .collect();
-
-        Ok(tx_array)
+        dto_array_to_native::<TransferDto<Fr>>(self)
     }
 }
 
-#[wasm_bindgen]
-#[derive(Deserialize)]
-pub struct WithdrawData {
-    #[serde(flatten)]
-    base_fields: TxBaseFields,
-    amount: TokenAmount<Fr>,
-    to: Vec<u8>,
-    native_amount: TokenAmount<Fr>,
-    energy_amount: TokenAmount<Fr>,
-}
-
 impl JsTxType for IWithdrawData {
     fn to_native(&self) -> Result<NativeTxType<Fr>, JsValue> {
-        let WithdrawData {
-            base_fields,
-            amount,
-            to,
-            native_amount,
-            energy_amount,
-        } = serde_wasm_bindgen::from_value(self.into())?;
-
-        Ok(NativeTxType::Withdraw {
-            fee: base_fields.fee,
-            withdraw_amount: amount,
-            to,
-            native_amount,
-            energy_amount,
-        })
+        dto_to_native::<WithdrawDto<Fr>>(self)
     }
 }
 
 impl JsMultiTxType for IMultiWithdrawData {
     fn to_native_array(&self) -> Result<Vec<NativeTxType<Fr>>, JsValue> {
-        let array: Vec<WithdrawData> = serde_wasm_bindgen::from_value(self.into())?;
-
-        let tx_array = array
-            .into_iter()
-            .map(|tx| NativeTxType::Withdraw {
-                fee: tx.base_fields.fee,
-                withdraw_amount: tx.amount,
-                to: tx.to,
-                native_amount: tx.native_amount,
-                energy_amount: tx.energy_amount,
-            })
-            .collect();
-
-        Ok(tx_array)
+        dto_array_to_native::<WithdrawDto<Fr>>(self)
     }
 }
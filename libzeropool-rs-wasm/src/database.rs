@@ -1,4 +1,36 @@
+//! The database backing [`UserState`](crate::UserState). With the `web` and `bundler` features
+//! it's the IndexedDB-backed `kvdb_web::Database`: the whole database is loaded into memory when
+//! the state is initialized, all reads are served from memory, and writes are committed to
+//! IndexedDB in the background. Otherwise it's an in-memory database that never has pending writes.
+
+use std::future::Future;
+
 #[cfg(not(any(feature = "bundler", feature = "web")))]
 pub use kvdb_memorydb::InMemory as Database;
 #[cfg(any(feature = "bundler", feature = "web"))]
 pub use kvdb_web::Database;
+use libzeropool_rs::client::state::State;
+
+use crate::PoolParams;
+
+/// Number of writes that are not committed to the persistent storage yet.
+#[cfg(any(feature = "bundler", feature = "web"))]
+pub fn pending_writes(state: &State<Database, PoolParams>) -> usize {
+    state.pending_writes()
+}
+
+#[cfg(not(any(feature = "bundler", feature = "web")))]
+pub fn pending_writes(_state: &State<Database, PoolParams>) -> usize {
+    0
+}
+
+/// Resolves once all the previous writes are committed to the persistent storage.
+#[cfg(any(feature = "bundler", feature = "web"))]
+pub fn flush(state: &State<Database, PoolParams>) -> impl Future<Output = ()> + 'static {
+    state.flush()
+}
+
+#[cfg(not(any(feature = "bundler", feature = "web")))]
+pub fn flush(_state: &State<Database, PoolParams>) -> impl Future<Output = ()> + 'static {
+    async {}
+}
@@ -1,16 +1,26 @@
 use std::str::FromStr;
 
-use libzeropool_rs::libzeropool::{
-    fawkes_crypto::{
-        borsh::{BorshDeserialize, BorshSerialize},
-        ff_uint::Num,
+use libzeropool_rs::{
+    client::amount::{self, parse_token_amount},
+    libzeropool::{
+        fawkes_crypto::{
+            borsh::{BorshDeserialize, BorshSerialize},
+            ff_uint::Num,
+            native::poseidon::poseidon,
+        },
+        native::{
+            account::Account as NativeAccount, note::Note as NativeNote, params::PoolParams as _,
+            tx::out_commitment_hash,
+        },
+        POOL_PARAMS,
     },
-    native::tx::out_commitment_hash,
-    POOL_PARAMS,
 };
 use wasm_bindgen::prelude::*;
 
-use crate::{ts_types::RawHashes, Fr};
+use crate::{
+    ts_types::{Account, Amount, Note, RawHashes},
+    Fr,
+};
 
 #[wasm_bindgen]
 pub struct Helpers {}
@@ -33,6 +43,25 @@ impl Helpers {
         num.to_string()
     }
 
+    /// Parses a decimal or `0x`-prefixed hex amount and returns it as a decimal string. Fails if
+    /// the amount doesn't fit into the balance size.
+    #[wasm_bindgen(js_name = "amountFromString")]
+    pub fn amount_from_string(s: &str) -> Result<String, JsValue> {
+        let amount = parse_token_amount::<Fr>(s).map_err(|err| js_err!(&err.to_string()))?;
+
+        Ok(amount.to_num().to_string())
+    }
+
+    /// Converts an amount accepted by the transaction constructors (a string or a number) into a
+    /// decimal string, applying the same validation.
+    #[wasm_bindgen(js_name = "amountToString")]
+    pub fn amount_to_string(amount: Amount) -> Result<String, JsValue> {
+        let deserializer = serde_wasm_bindgen::Deserializer::from(JsValue::from(amount));
+        let amount = amount::deserialize::<_, Fr>(deserializer)?;
+
+        Ok(amount.to_num().to_string())
+    }
+
     #[wasm_bindgen(js_name = "outCommitmentHash")]
     pub fn out_commitment(hashes: RawHashes) -> String {
         let hashes = serde_wasm_bindgen::from_value::<Vec<Vec<u8>>>(hashes.into()).unwrap();
@@ -44,4 +73,72 @@ impl Helpers {
 
         commitment.to_string()
     }
+
+    /// Poseidon hash of a pair of merkle tree nodes, given as decimal strings.
+    #[wasm_bindgen(js_name = "poseidonCompress")]
+    pub fn poseidon_compress(left: &str, right: &str) -> Result<String, JsValue> {
+        let left = Num::<Fr>::from_str(left).map_err(|_| js_err!("Invalid number: {}", left))?;
+        let right = Num::<Fr>::from_str(right).map_err(|_| js_err!("Invalid number: {}", right))?;
+
+        Ok(poseidon(&[left, right], POOL_PARAMS.compress()).to_string())
+    }
+
+    #[wasm_bindgen(js_name = "hashAccount")]
+    pub fn hash_account(account: Account) -> Result<String, JsValue> {
+        let account: NativeAccount<Fr> = serde_wasm_bindgen::from_value(account.into())?;
+
+        Ok(account.hash(&*POOL_PARAMS).to_string())
+    }
+
+    #[wasm_bindgen(js_name = "hashNote")]
+    pub fn hash_note(note: Note) -> Result<String, JsValue> {
+        let note: NativeNote<Fr> = serde_wasm_bindgen::from_value(note.into())?;
+
+        Ok(note.hash(&*POOL_PARAMS).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::{merkle::MerkleTree, utils::zero_note};
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn test_poseidon_compress_matches_default_hashes() {
+        let tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        assert_eq!(
+            Helpers::poseidon_compress("0", "0").unwrap(),
+            tree.get(1, 0).to_string()
+        );
+        assert!(Helpers::poseidon_compress("abc", "0").is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_amount_conversions() {
+        let max = u64::MAX.to_string();
+
+        assert_eq!(Helpers::amount_from_string(&max).unwrap(), max);
+        assert_eq!(Helpers::amount_from_string("0xff").unwrap(), "255");
+        assert!(Helpers::amount_from_string("18446744073709551616").is_err());
+
+        let amount = |value: JsValue| Helpers::amount_to_string(value.unchecked_into::<Amount>());
+        assert_eq!(amount(JsValue::from(255)).unwrap(), "255");
+        assert_eq!(amount(JsValue::from("0xff")).unwrap(), "255");
+        assert!(amount(JsValue::from(-1)).is_err());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_hash_note_matches_zero_note() {
+        let note = zero_note::<Fr>();
+        let js_note = serde_wasm_bindgen::to_value(&note).unwrap();
+
+        assert_eq!(
+            Helpers::hash_note(js_note.unchecked_into::<Note>()).unwrap(),
+            note.hash(&*POOL_PARAMS).to_string()
+        );
+    }
 }
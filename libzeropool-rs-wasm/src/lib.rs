@@ -1,4 +1,4 @@
-use std::{convert::TryInto, str::FromStr};
+use std::str::FromStr;
 
 #[cfg(feature = "groth16")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::{
@@ -9,23 +9,27 @@ use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::{
     engines::Bn256, prover::Proof as PlonkProof,
 };
 use libzeropool_rs::{
-    address::{format_address, parse_address},
+    address::{format_address, parse_address, parse_address_for_pool},
+    delta::decode_delta,
     libzeropool::{
         constants,
         fawkes_crypto::ff_uint::Num,
         native::{
             boundednum::BoundedNum,
             params::{PoolBN256, PoolParams as PoolParamsTrait},
-            tx::parse_delta,
+            tx::{TransferPub as NativeTransferPub, TransferSec as NativeTransferSec},
         },
         POOL_PARAMS,
     },
+    utils::verify_merkle_proof_parts,
+    witness,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::{prelude::*, JsCast};
 #[cfg(feature = "multicore")]
 pub use wasm_bindgen_rayon::init_thread_pool;
 
+use crate::ts_types::Hash as JsHash;
 pub use crate::{
     client::*,
     proof::*,
@@ -65,6 +69,10 @@ pub struct SerConstants {
     pub OUT: usize,
     pub OUTLOG: usize,
     pub HEIGHT: usize,
+    pub BALANCE_SIZE_BITS: usize,
+    pub DIVERSIFIER_SIZE_BITS: usize,
+    pub ENERGY_SIZE_BITS: usize,
+    pub DELEGATED_DEPOSITS_NUM: usize,
 }
 
 impl SerConstants {
@@ -74,6 +82,10 @@ impl SerConstants {
             OUT: constants::OUT,
             OUTLOG: constants::OUTPLUSONELOG,
             HEIGHT: constants::HEIGHT,
+            BALANCE_SIZE_BITS: constants::BALANCE_SIZE_BITS,
+            DIVERSIFIER_SIZE_BITS: constants::DIVERSIFIER_SIZE_BITS,
+            ENERGY_SIZE_BITS: constants::ENERGY_SIZE_BITS,
+            DELEGATED_DEPOSITS_NUM: constants::DELEGATED_DEPOSITS_NUM,
         }
     }
 }
@@ -90,6 +102,14 @@ pub fn validate_address(address: &str) -> bool {
     parse_address::<PoolParams>(address).is_ok()
 }
 
+#[wasm_bindgen(js_name = "validateAddressForPool")]
+/// Checks that the address is either a legacy one or was formatted for the pool.
+pub fn validate_address_for_pool(address: &str, pool_id: &str) -> Result<bool, JsValue> {
+    let pool_id = Num::from_str(pool_id).map_err(|_| js_err!("Invalid pool id"))?;
+
+    Ok(parse_address_for_pool::<PoolParams>(address, BoundedNum::new(pool_id)).is_ok())
+}
+
 #[wasm_bindgen(js_name = "assembleAddress")]
 pub fn assemble_address(d: &str, p_d: &str) -> String {
     let d = Num::from_str(d).unwrap();
@@ -120,22 +140,59 @@ pub fn parse_address_(address: &str) -> IAddressComponents {
 }
 
 #[wasm_bindgen(js_name = "parseDelta")]
-pub fn parse_delta_(delta: &str) -> IParsedDelta {
-    let delta = Num::<Fr>::from_str(delta).unwrap();
+pub fn parse_delta_(delta: &str) -> Result<IParsedDelta, JsValue> {
+    let delta = Num::<Fr>::from_str(delta).map_err(|_| js_err!("Invalid delta"))?;
+    let parsed_delta: ParsedDelta = decode_delta(delta)
+        .map_err(|err| js_err!(&err.to_string()))?
+        .into();
 
-    let (token_amount, energy_amount, transfer_index, pool_id) = parse_delta(delta);
+    Ok(serde_wasm_bindgen::to_value(&parsed_delta)
+        .unwrap()
+        .unchecked_into::<IParsedDelta>())
+}
 
-    let token_amount: i64 = token_amount.try_into().unwrap();
-    let energy_amount: i64 = energy_amount.try_into().unwrap();
+#[wasm_bindgen(js_name = "transferPubToBytes")]
+/// Compact binary encoding of the public inputs, cheaper to pass to a worker than the object
+/// itself. See `Proof.txFromBytes`.
+pub fn transfer_pub_to_bytes(transfer_pub: TransferPub) -> Result<Vec<u8>, JsValue> {
+    let public: NativeTransferPub<Fr> =
+        serde_wasm_bindgen::from_value(transfer_pub.unchecked_into::<JsValue>())?;
 
-    let parsed_delta = ParsedDelta {
-        v: token_amount.to_string(),
-        e: energy_amount.to_string(),
-        index: transfer_index.to_string(),
-        pool_id: pool_id.to_string(),
-    };
+    Ok(witness::encode_pub(&public))
+}
 
-    serde_wasm_bindgen::to_value(&parsed_delta)
-        .unwrap()
-        .unchecked_into::<IParsedDelta>()
+#[wasm_bindgen(js_name = "transferSecToBytes")]
+/// Compact binary encoding of the secret inputs, see `transferPubToBytes`.
+pub fn transfer_sec_to_bytes(transfer_sec: TransferSec) -> Result<Vec<u8>, JsValue> {
+    let secret: NativeTransferSec<Fr> =
+        serde_wasm_bindgen::from_value(transfer_sec.unchecked_into::<JsValue>())?;
+
+    Ok(witness::encode(&secret))
+}
+
+#[wasm_bindgen(js_name = "verifyMerkleProof")]
+/// Checks that the proof (as returned by `getMerkleProof` or `getCommitmentMerkleProof`) leads
+/// from the leaf to the root.
+pub fn verify_merkle_proof(
+    leaf: JsHash,
+    proof: MerkleProof,
+    root: JsHash,
+) -> Result<bool, JsValue> {
+    #[derive(Deserialize)]
+    struct SerMerkleProof {
+        sibling: Vec<Num<Fr>>,
+        path: Vec<bool>,
+    }
+
+    let leaf: Num<Fr> = serde_wasm_bindgen::from_value(leaf.unchecked_into())?;
+    let proof: SerMerkleProof = serde_wasm_bindgen::from_value(proof.unchecked_into())?;
+    let root: Num<Fr> = serde_wasm_bindgen::from_value(root.unchecked_into())?;
+
+    Ok(verify_merkle_proof_parts(
+        leaf,
+        &proof.sibling,
+        &proof.path,
+        root,
+        &*POOL_PARAMS,
+    ))
 }
@@ -1,4 +1,9 @@
-use libzeropool_rs::libzeropool::circuit::tx::c_transfer;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
 #[cfg(feature = "groth16")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::Parameters;
 #[cfg(feature = "plonk")]
@@ -6,24 +11,42 @@ use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::{
     setup::{setup, ProvingKey},
     Parameters,
 };
+use libzeropool_rs::{libzeropool::circuit::tx::c_transfer, utils::keccak256};
 use wasm_bindgen::prelude::*;
 
 use crate::{Engine, POOL_PARAMS};
 
+thread_local! {
+    /// Parsed parameters shared between all the `Params` handles created for the same key.
+    static PARAMS_CACHE: RefCell<HashMap<String, CachedParams>> = RefCell::new(HashMap::new());
+    /// Number of times the cache had to parse its input.
+    static CACHE_LOADS: Cell<usize> = Cell::new(0);
+}
+
+struct CachedParams {
+    hash: [u8; 32],
+    params: Params,
+}
+
+/// Handles are cheap to clone: the parsed parameters are shared, so multiple handles to the same
+/// cache entry don't duplicate them in memory.
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Params {
     #[wasm_bindgen(skip)]
-    pub inner: Parameters<Engine>,
+    pub inner: Rc<Parameters<Engine>>,
 
     #[cfg(feature = "plonk")]
     #[wasm_bindgen(skip)]
-    pub tx_pk: ProvingKey<Engine>,
+    pub tx_pk: Rc<ProvingKey<Engine>>,
 }
 
 #[cfg(feature = "groth16")]
 impl From<Parameters<Engine>> for Params {
     fn from(params: Parameters<Engine>) -> Self {
-        Params { inner: params }
+        Params {
+            inner: Rc::new(params),
+        }
     }
 }
 
@@ -37,18 +60,74 @@ impl From<Parameters<Engine>> for Params {
         let (_, tx_pk) = setup(&params, circuit);
 
         Params {
-            inner: params,
-            tx_pk,
+            inner: Rc::new(params),
+            tx_pk: Rc::new(tx_pk),
         }
     }
 }
 
-impl From<Params> for Parameters<Engine> {
-    fn from(params: Params) -> Self {
-        params.inner
+#[wasm_bindgen]
+impl Params {
+    /// Parses `data` and stores the result under `key`. Calling it again with the same key and
+    /// the same data returns the cached parameters without parsing them again, while different
+    /// data replaces the cache entry.
+    #[wasm_bindgen(js_name = "fromBinaryCached")]
+    pub fn from_binary_cached(key: &str, data: &[u8]) -> Result<Params, JsValue> {
+        let hash = keccak256(data);
+
+        let cached = PARAMS_CACHE.with(|cache| {
+            cache
+                .borrow()
+                .get(key)
+                .filter(|entry| entry.hash == hash)
+                .map(|entry| entry.params.clone())
+        });
+
+        if let Some(params) = cached {
+            return Ok(params);
+        }
+
+        // Release the old entry before parsing, so that both versions are never kept in memory
+        // at the same time.
+        Self::drop_cache(key);
+
+        let params = Self::from_binary(data)?;
+        CACHE_LOADS.with(|loads| loads.set(loads.get() + 1));
+
+        PARAMS_CACHE.with(|cache| {
+            cache.borrow_mut().insert(
+                key.to_owned(),
+                CachedParams {
+                    hash,
+                    params: params.clone(),
+                },
+            )
+        });
+
+        Ok(params)
+    }
+
+    /// Returns a handle to the parameters previously loaded with `fromBinaryCached`.
+    #[wasm_bindgen(js_name = "fromCache")]
+    pub fn from_cache(key: &str) -> Result<Params, JsValue> {
+        PARAMS_CACHE
+            .with(|cache| cache.borrow().get(key).map(|entry| entry.params.clone()))
+            .ok_or_else(|| js_err!("No parameters cached under key '{}'", key))
+    }
+
+    /// Removes the cache entry. The parameters are freed once all of the existing handles are
+    /// freed as well. Returns `false` if there was nothing cached under `key`.
+    #[wasm_bindgen(js_name = "dropCache")]
+    pub fn drop_cache(key: &str) -> bool {
+        PARAMS_CACHE.with(|cache| cache.borrow_mut().remove(key).is_some())
     }
 }
 
+#[cfg(test)]
+pub(crate) fn cache_loads() -> usize {
+    CACHE_LOADS.with(|loads| loads.get())
+}
+
 #[cfg(feature = "groth16")]
 #[wasm_bindgen]
 impl Params {
@@ -68,7 +147,7 @@ impl Params {
         let inner = Parameters::read(&mut input, disallow_points_at_infinity, checked)
             .map_err(|err| js_err!("{}", err))?;
 
-        Ok(Params { inner })
+        Ok(inner.into())
     }
 }
 
@@ -82,7 +161,10 @@ impl Params {
         let mut pk_reader = pk;
         let tx_pk = ProvingKey::<Engine>::read(&mut pk_reader).map_err(|err| js_err!("{}", err))?;
 
-        Ok(Params { inner, tx_pk })
+        Ok(Params {
+            inner: Rc::new(inner),
+            tx_pk: Rc::new(tx_pk),
+        })
     }
 
     #[wasm_bindgen(js_name = "fromBinary")]
@@ -101,3 +183,50 @@ impl Params {
         Ok(inner.into())
     }
 }
+
+#[cfg(all(test, feature = "groth16"))]
+mod tests {
+    use libzeropool_rs::libzeropool::fawkes_crypto::{
+        backend::bellman_groth16::setup::setup,
+        circuit::{cs::CS, num::CNum},
+    };
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    fn square<C: CS>(public: CNum<C>, secret: CNum<C>) {
+        (&secret * &secret).assert_eq(&public);
+    }
+
+    fn params_bytes() -> Vec<u8> {
+        let params = setup::<Engine, _, _, _>(square);
+        let mut bytes = Vec::new();
+        params.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[wasm_bindgen_test]
+    fn test_from_binary_cached_parses_once() {
+        let key = "test_from_binary_cached_parses_once";
+        let bytes = params_bytes();
+
+        let loads = cache_loads();
+        let first = Params::from_binary_cached(key, &bytes).unwrap();
+        assert_eq!(cache_loads(), loads + 1);
+
+        let second = Params::from_binary_cached(key, &bytes).unwrap();
+        assert_eq!(cache_loads(), loads + 1);
+        assert!(Rc::ptr_eq(&first.inner, &second.inner));
+
+        let from_cache = Params::from_cache(key).unwrap();
+        assert!(Rc::ptr_eq(&first.inner, &from_cache.inner));
+
+        assert!(Params::drop_cache(key));
+        assert!(!Params::drop_cache(key));
+        assert!(Params::from_cache(key).is_err());
+
+        Params::from_binary_cached(key, &bytes).unwrap();
+        assert_eq!(cache_loads(), loads + 2);
+        Params::drop_cache(key);
+    }
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "groth16")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
 #[cfg(feature = "groth16")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::{
     prover::prove,
@@ -16,11 +19,44 @@ use libzeropool_rs::libzeropool::{
     },
     POOL_PARAMS,
 };
+use libzeropool_rs::witness;
+#[cfg(feature = "groth16")]
+use libzeropool_rs::{
+    libzeropool::fawkes_crypto::rand::RngCore,
+    proof_groth16::{prove_tx_with_progress, verify_batch, ProofStage},
+    random::CustomRng,
+};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::{prelude::*, JsCast};
 
 use crate::{params::Params, ts_types, Engine, Fr, SnarkProof};
 
+/// Cancels the proving started with `Proof.txWithProgress`, e.g. from its progress callback.
+#[cfg(feature = "groth16")]
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct CancelToken {
+    inner: AtomicBool,
+}
+
+#[cfg(feature = "groth16")]
+#[wasm_bindgen]
+impl CancelToken {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.store(true, Ordering::Relaxed);
+    }
+
+    #[wasm_bindgen(getter, js_name = "isCancelled")]
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.load(Ordering::Relaxed)
+    }
+}
+
 #[wasm_bindgen]
 #[derive(Serialize, Deserialize)]
 pub struct Proof {
@@ -72,14 +108,63 @@ impl Proof {
         let secret: NativeTransferSec<_> =
             serde_wasm_bindgen::from_value(transfer_sec.unchecked_into::<JsValue>())?;
 
-        let circuit = |public, secret| {
-            c_transfer(&public, &secret, &*POOL_PARAMS);
-        };
+        prove_transfer(params, public, secret)
+    }
+
+    #[wasm_bindgen(js_name = "txFromBytes")]
+    /// Same as `tx`, but takes the inputs encoded with `transferPubToBytes` and
+    /// `transferSecToBytes`.
+    pub fn tx_from_bytes(
+        params: &Params,
+        pub_bytes: &[u8],
+        sec_bytes: &[u8],
+    ) -> Result<crate::ts_types::Proof, JsValue> {
+        let public = witness::decode_pub(pub_bytes).map_err(|err| js_err!(&err.to_string()))?;
+        let secret = witness::decode(sec_bytes).map_err(|err| js_err!(&err.to_string()))?;
+
+        prove_transfer(params, public, secret)
+    }
+
+    #[wasm_bindgen(js_name = "txCached")]
+    /// Same as `tx`, but uses the parameters loaded with `Params.fromBinaryCached` under `key`.
+    pub fn tx_cached(
+        key: &str,
+        transfer_pub: ts_types::TransferPub,
+        transfer_sec: ts_types::TransferSec,
+    ) -> Result<crate::ts_types::Proof, JsValue> {
+        Self::tx(&Params::from_cache(key)?, transfer_pub, transfer_sec)
+    }
+
+    #[cfg(feature = "groth16")]
+    #[wasm_bindgen(js_name = "txWithProgress")]
+    /// Same as `tx`, but calls `progress` with the current `ProofStage`. Fails with "Proving was
+    /// cancelled" if `cancel` is cancelled before the proof is ready.
+    pub fn tx_with_progress(
+        params: &Params,
+        transfer_pub: ts_types::TransferPub,
+        transfer_sec: ts_types::TransferSec,
+        progress: js_sys::Function,
+        cancel: &CancelToken,
+    ) -> Result<crate::ts_types::Proof, JsValue> {
+        let public: NativeTransferPub<_> =
+            serde_wasm_bindgen::from_value(transfer_pub.unchecked_into::<JsValue>())?;
+        let secret: NativeTransferSec<_> =
+            serde_wasm_bindgen::from_value(transfer_sec.unchecked_into::<JsValue>())?;
 
-        #[cfg(feature = "groth16")]
-        let (inputs, snark_proof) = prove(&params.inner, &public, &secret, circuit);
-        #[cfg(feature = "plonk")]
-        let (inputs, snark_proof) = prove(&params.inner, &params.tx_pk, &public, &secret, circuit);
+        let report = |stage: ProofStage| {
+            if let Ok(stage) = serde_wasm_bindgen::to_value(&stage) {
+                let _ = progress.call1(&JsValue::NULL, &stage);
+            }
+        };
+        let (inputs, snark_proof) = prove_tx_with_progress(
+            &*params.inner,
+            &*POOL_PARAMS,
+            public,
+            secret,
+            report,
+            &cancel.inner,
+        )
+        .map_err(|err| js_err!(&err.to_string()))?;
 
         let proof = Proof {
             inputs,
@@ -89,6 +174,26 @@ impl Proof {
         Ok(serde_wasm_bindgen::to_value(&proof)?.unchecked_into::<crate::ts_types::Proof>())
     }
 
+    #[cfg(feature = "groth16")]
+    #[wasm_bindgen(js_name = "txWithProgressCached")]
+    /// Same as `txWithProgress`, but uses the parameters loaded with `Params.fromBinaryCached`
+    /// under `key`.
+    pub fn tx_with_progress_cached(
+        key: &str,
+        transfer_pub: ts_types::TransferPub,
+        transfer_sec: ts_types::TransferSec,
+        progress: js_sys::Function,
+        cancel: &CancelToken,
+    ) -> Result<crate::ts_types::Proof, JsValue> {
+        Self::tx_with_progress(
+            &Params::from_cache(key)?,
+            transfer_pub,
+            transfer_sec,
+            progress,
+            cancel,
+        )
+    }
+
     // #[cfg(feature = "groth16")]
     // #[wasm_bindgen(js_name = "tree")]
     // pub fn tree(
@@ -117,3 +222,52 @@ impl Proof {
     //     Ok(serde_wasm_bindgen::to_value(&proof)?.unchecked_into::<crate::ts_types::Proof>())
     // }
 }
+
+fn prove_transfer(
+    params: &Params,
+    public: NativeTransferPub<Fr>,
+    secret: NativeTransferSec<Fr>,
+) -> Result<crate::ts_types::Proof, JsValue> {
+    let circuit = |public, secret| {
+        c_transfer(&public, &secret, &*POOL_PARAMS);
+    };
+
+    #[cfg(feature = "groth16")]
+    let (inputs, snark_proof) = prove(&*params.inner, &public, &secret, circuit);
+    #[cfg(feature = "plonk")]
+    let (inputs, snark_proof) = prove(&*params.inner, &*params.tx_pk, &public, &secret, circuit);
+
+    let proof = Proof {
+        inputs,
+        proof: snark_proof,
+    };
+
+    Ok(serde_wasm_bindgen::to_value(&proof)?.unchecked_into::<crate::ts_types::Proof>())
+}
+
+#[cfg(feature = "groth16")]
+#[wasm_bindgen(js_name = "verifyBatchProofs")]
+/// Verifies all the proofs at once, an empty array is rejected. `seed` must be 32 bytes long and
+/// unpredictable to whoever produced the proofs, a random one is used if it's omitted.
+pub fn verify_batch_proofs(
+    vk: ts_types::VK,
+    proofs: ts_types::Proofs,
+    seed: Option<Vec<u8>>,
+) -> Result<bool, JsValue> {
+    let vk: VK<Engine> = serde_wasm_bindgen::from_value(vk.unchecked_into::<JsValue>())?;
+    let proofs: Vec<Proof> = serde_wasm_bindgen::from_value(proofs.unchecked_into::<JsValue>())?;
+
+    let mut rng_seed = [0u8; 32];
+    match seed {
+        Some(seed) if seed.len() == rng_seed.len() => rng_seed.copy_from_slice(&seed),
+        Some(_) => return Err(js_err!("Seed must be 32 bytes long")),
+        None => CustomRng.fill_bytes(&mut rng_seed),
+    }
+
+    let batch: Vec<_> = proofs
+        .iter()
+        .map(|proof| (&proof.proof, proof.inputs.as_slice()))
+        .collect();
+
+    Ok(verify_batch(&vk, &batch, rng_seed))
+}
@@ -8,7 +8,7 @@ use libzeropool_rs::{
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
-use crate::{database::Database, utils, Fr, PoolParams, POOL_PARAMS};
+use crate::{database, database::Database, utils, Fr, PoolParams, POOL_PARAMS};
 
 #[derive(Debug, PartialEq, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 pub enum Transaction {
@@ -35,16 +35,18 @@ pub struct UserState {
 impl UserState {
     #[allow(unused_variables)]
     #[wasm_bindgen]
-    pub async fn init(db_id: String) -> Self {
+    pub async fn init(db_id: String) -> Result<UserState, JsValue> {
         utils::set_panic_hook();
 
         #[cfg(any(feature = "bundler", feature = "web"))]
-        let state = State::init_web(db_id, POOL_PARAMS.clone()).await;
+        let state = State::init_web(db_id, POOL_PARAMS.clone())
+            .await
+            .map_err(|err| js_err!(&err.to_string()))?;
 
         #[cfg(not(any(feature = "bundler", feature = "web")))]
         let state = State::init_test(POOL_PARAMS.clone());
 
-        UserState { inner: state }
+        Ok(UserState { inner: state })
     }
 
     #[wasm_bindgen(js_name = "earliestUsableIndex")]
@@ -58,4 +60,53 @@ impl UserState {
     pub fn total_balance(&self) -> String {
         self.inner.total_balance().to_string()
     }
+
+    #[wasm_bindgen(js_name = "pendingWrites")]
+    /// Returns the number of writes that are not persisted yet.
+    pub fn pending_writes(&self) -> usize {
+        database::pending_writes(&self.inner)
+    }
+
+    #[wasm_bindgen]
+    /// Resolves once all the previous writes are persisted. Should be awaited before closing the app.
+    pub fn flush(&self) -> js_sys::Promise {
+        let flush = database::flush(&self.inner);
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            flush.await;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use wasm_bindgen_futures::JsFuture;
+    use wasm_bindgen_test::*;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    async fn test_flush_and_reopen() {
+        let db_id = "test_flush_and_reopen".to_owned();
+        let hash = Num::from(42u64);
+
+        let mut state = UserState::init(db_id.clone()).await.unwrap();
+        state.inner.tree.add_hash(0, hash, false);
+
+        // Reads reflect the write before it's persisted
+        assert_eq!(state.inner.tree.get(0, 0), hash);
+
+        JsFuture::from(state.flush()).await.unwrap();
+        assert_eq!(state.pending_writes(), 0);
+        drop(state);
+
+        // Only the IndexedDB-backed state survives reopening
+        #[cfg(any(feature = "bundler", feature = "web"))]
+        {
+            let state = UserState::init(db_id).await.unwrap();
+            assert_eq!(state.inner.tree.get(0, 0), hash);
+        }
+    }
 }
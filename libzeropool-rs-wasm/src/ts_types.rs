@@ -11,6 +11,10 @@ export class Constants {
   IN: number;
   OUT: number;
   OUTLOG: number;
+  BALANCE_SIZE_BITS: number;
+  DIVERSIFIER_SIZE_BITS: number;
+  ENERGY_SIZE_BITS: number;
+  DELEGATED_DEPOSITS_NUM: number;
 }
 
 export interface Note {
@@ -59,6 +63,57 @@ export interface TransactionData {
     out_hashes: string[];
     commitment_root: string;
     parsed_delta: ParsedDelta;
+    summary?: TxSummary;
+    warnings?: TxWarning[];
+    /** Parsed addresses of the out notes, see `recordOutgoingAddresses`. */
+    destinations?: TxDestination[];
+}
+
+export interface TxDestination {
+    d: string;
+    p_d: string;
+}
+
+/** Reported when notes were already sent to the address `to`, see `setTrackOutgoing`. */
+export interface TxWarning {
+    type: "addressReuse";
+    to: string;
+}
+
+export type NoteStatus =
+    | { status: "spendable" }
+    | { status: "lockedUntil"; accountIndex: number }
+    | { status: "unknown" };
+
+export interface OutgoingAddress {
+    d: string;
+    p_d: string;
+    count: number;
+    label?: string;
+}
+
+export interface UserAccountConfig {
+    poolId?: number;
+    /** Length of the withdraw and permittable deposit holder addresses, 20 by default. */
+    addressLength?: number;
+    allowFreshAccount?: boolean;
+    trackOutgoing?: boolean;
+    overrideDoubleSpendCheck?: boolean;
+}
+
+export interface NullifierRecord {
+    jobRef?: string;
+    spent: boolean;
+}
+
+export interface TxSummary {
+    input_value: string;
+    output_value: string;
+    fee: string;
+    delta_value: string;
+    input_energy: string;
+    remaining_energy: string;
+    spent_note_indices: bigint[];
 }
 
 export interface TreePub {
@@ -73,14 +128,22 @@ export interface TreeSec {
     prev_leaf: string;
 }
 
+export interface TreeUpdateInputs {
+    public: TreePub;
+    secret: TreeSec;
+}
+
 export interface Tx {
     input: [Account, Note[]];
     output: [Account, Note[]];
 }
 
+/** Decimal or `0x`-prefixed hex string, or a safe integer. */
+export type Amount = string | number;
+
 export interface Output {
     to: string;
-    amount: string;
+    amount: Amount;
 }
 
 export interface MerkleProof {
@@ -93,6 +156,8 @@ export interface Proof {
     proof: SnarkProof;
 }
 
+export type ProofStage = 'WitnessGeneration' | 'Proving' | 'Done';
+
 export interface SnarkProof {
     a: [string, string];
     b: [[string, string], [string, string]];
@@ -106,36 +171,50 @@ export interface VK {
     ic: string[][];    // G1[]
 }
 
+export type IAddressInfo =
+    | { type: 'invalid'; error: string }
+    | { type: 'foreign'; d: string; p_d: string }
+    | { type: 'own'; d: string; p_d: string };
+
 export interface IAddressComponents {
     d: string;
     p_d: string;
 }
 
+export interface IGeneratedAddress {
+    address: string;
+    d: string;
+}
+
 export interface ITxBaseFields {
-    fee: string;
+    fee: Amount;
 }
 
 export interface IDepositData extends ITxBaseFields {
-    amount: string;
+    amount: Amount;
     outputs?: Output[];
 }
 
 export interface IDepositPermittableData extends ITxBaseFields {
-    amount: string;
+    amount: Amount;
     deadline: string;
-    holder: Uint8Array;
+    holder: Uint8Array | string;
     outputs?: Output[];
 }
 
 export interface ITransferData extends ITxBaseFields {
     outputs: Output[];
+    noteIndices?: number[];
+    // Encrypted for the owners of the outputs, at most 255 bytes. Transfers without it keep the
+    // original memo layout.
+    encrypted_data?: Uint8Array | string;
 }
 
 export interface IWithdrawData extends ITxBaseFields {
-    amount: string;
-    to: Uint8Array;
-    native_amount: string;
-    energy_amount: string;
+    amount: Amount;
+    to: Uint8Array | string;
+    native_amount: Amount;
+    energy_amount: Amount;
 }
 
 export interface DecryptedMemo {
@@ -144,6 +223,7 @@ export interface DecryptedMemo {
     inNotes:  { note: Note, index: number }[];
     outNotes: { note: Note, index: number }[];
     txHash: string | undefined;
+    memoText?: number[];
 }
 
 export interface StateUpdate {
@@ -151,17 +231,82 @@ export interface StateUpdate {
     newCommitments: any[];
     newAccounts: any[];
     newNotes: any[][];
+    expectedCommitments?: any[];
 }
 
 export interface IndexedTx {
     index: number;
-    memo: string;
-    commitment: string;
+    memo: string | Uint8Array;
+    commitment: string | Uint8Array;
+}
+
+export interface ParseError {
+    index: number;
+    code: 'BadHex' | 'TruncatedMemo';
 }
 
 export interface ParseTxsResult {
     decryptedMemos: DecryptedMemo[];
     stateUpdate: StateUpdate;
+    latestOwnAccount: [number, Account] | undefined;
+    errors: ParseError[];
+}
+
+export interface TreeNode {
+    index: number;
+    height: number;
+    value: string;
+}
+
+export type HistoryEntry =
+    | { type: 'deposit'; index: number; amount: string; spent_notes: number[] }
+    | { type: 'transferIn'; index: number; amount: string; diversifier: string }
+    | { type: 'transferOut'; index: number; amount: string; spent_notes: number[] };
+
+export type CreateTxErrorCode =
+    | 'TOO_MANY_OUTPUTS'
+    | 'PROOF_NOT_FOUND'
+    | 'INVALID_ADDRESS'
+    | 'INSUFFICIENT_BALANCE'
+    | 'INSUFFICIENT_ENERGY'
+    | 'INVALID_NOTE_INDEX'
+    | 'TOO_MANY_AGGREGATION_TXS'
+    | 'INVALID_WITHDRAW_ADDRESS'
+    | 'INVALID_HOLDER_ADDRESS'
+    | 'NATIVE_AMOUNT_EXCEEDS_WITHDRAW'
+    | 'ENERGY_AMOUNT_TOO_LARGE'
+    | 'BALANCE_OVERFLOW'
+    | 'ENERGY_OVERFLOW'
+    | 'AMOUNT_OVERFLOW'
+    | 'WATCH_ONLY';
+
+/** Thrown by the transaction constructors, amounts are decimal strings. */
+export interface ICreateTxError extends Error {
+    code: CreateTxErrorCode;
+    required?: string;
+    available?: string;
+    [field: string]: any;
+}
+
+export interface UsableNotesPage {
+    notes: { note: Note, index: number }[];
+    total: number;
+}
+
+export interface ConsolidationAdvice {
+    usableNotes: number;
+    txsNeeded: number;
+    totalFee: string;
+    feeSaved: string;
+    recommended: boolean;
+}
+
+export interface TreeStats {
+    nodes_per_height: bigint[];
+    temporary_subtrees: bigint;
+    next_index: bigint;
+    clean_index: bigint;
+    approximate_size: bigint;
 }
 
 "#;
@@ -207,9 +352,15 @@ extern "C" {
     #[wasm_bindgen(typescript_type = "TreeSec")]
     pub type TreeSec;
 
+    #[wasm_bindgen(typescript_type = "TreeUpdateInputs")]
+    pub type TreeUpdateInputs;
+
     #[wasm_bindgen(typescript_type = "Proof")]
     pub type Proof;
 
+    #[wasm_bindgen(typescript_type = "Proof[]")]
+    pub type Proofs;
+
     #[wasm_bindgen(typescript_type = "SnarkProof")]
     pub type SnarkProof;
 
@@ -237,6 +388,9 @@ extern "C" {
     #[wasm_bindgen(typescript_type = "string")]
     pub type Hash;
 
+    #[wasm_bindgen(typescript_type = "Amount")]
+    pub type Amount;
+
     #[wasm_bindgen(typescript_type = "Array<Uint8Array>")]
     pub type RawHashes;
 
@@ -273,8 +427,41 @@ extern "C" {
     #[wasm_bindgen(typescript_type = "ParseTxsResult")]
     pub type ParseTxsResult;
 
+    #[wasm_bindgen(typescript_type = "TreeNode[] | undefined")]
+    pub type TreeNodes;
+
+    #[wasm_bindgen(typescript_type = "TreeStats")]
+    pub type TreeStats;
+
+    #[wasm_bindgen(typescript_type = "UsableNotesPage")]
+    pub type UsableNotesPage;
+
+    #[wasm_bindgen(typescript_type = "ConsolidationAdvice")]
+    pub type ConsolidationAdvice;
+
+    #[wasm_bindgen(typescript_type = "OutgoingAddress[]")]
+    pub type OutgoingAddresses;
+
+    #[wasm_bindgen(typescript_type = "UserAccountConfig | number")]
+    pub type IUserAccountConfig;
+
+    #[wasm_bindgen(typescript_type = "NullifierRecord | undefined")]
+    pub type NullifierRecord;
+
+    #[wasm_bindgen(typescript_type = "NoteStatus")]
+    pub type NoteStatus;
+
+    #[wasm_bindgen(typescript_type = "HistoryEntry[]")]
+    pub type HistoryEntries;
+
     #[wasm_bindgen(typescript_type = "IAddressComponents")]
     pub type IAddressComponents;
+
+    #[wasm_bindgen(typescript_type = "IAddressInfo")]
+    pub type IAddressInfo;
+
+    #[wasm_bindgen(typescript_type = "IGeneratedAddress")]
+    pub type IGeneratedAddress;
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -283,9 +470,19 @@ pub struct IndexedNote {
     pub note: NativeNote<Fr>,
 }
 
+#[derive(Serialize)]
+pub struct NotesPage {
+    pub notes: Vec<IndexedNote>,
+    pub total: usize,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct IndexedTx {
     pub index: u64,
-    pub memo: String,
-    pub commitment: String,
+    /// Hex string or `Uint8Array`
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub memo: JsValue,
+    /// Hex string or `Uint8Array`
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub commitment: JsValue,
 }
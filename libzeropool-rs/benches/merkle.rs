@@ -0,0 +1,46 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libzeropool::{
+    constants,
+    fawkes_crypto::{ff_uint::Num, rand::Rng},
+    native::params::{PoolBN256, PoolParams},
+    POOL_PARAMS,
+};
+use libzeropool_rs::{merkle::MerkleTree, random::CustomRng};
+
+type Fr = <PoolBN256 as PoolParams>::Fr;
+
+const COMMITMENTS: u64 = 1024;
+
+fn commitments() -> Vec<(u64, Num<Fr>)> {
+    let mut rng = CustomRng;
+
+    (0..COMMITMENTS)
+        .map(|i| (i << constants::OUTPLUSONELOG, rng.gen()))
+        .collect()
+}
+
+fn add_commitments_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_leafs_and_commitments");
+    group.sample_size(10);
+
+    group.bench_function("sequential", |b| {
+        b.iter_batched(
+            || (MerkleTree::new_test(POOL_PARAMS.clone()), commitments()),
+            |(mut tree, commitments)| tree.add_leafs_and_commitments(vec![], commitments),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("by_level", |b| {
+        b.iter_batched(
+            || (MerkleTree::new_test(POOL_PARAMS.clone()), commitments()),
+            |(mut tree, commitments)| tree.add_leafs_and_commitments_par(vec![], commitments),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, add_commitments_benchmark);
+criterion_main!(benches);
@@ -20,8 +20,16 @@ pub enum AddressParseError {
     Base58DecodeError(#[from] bs58::decode::Error),
     #[error("Deserialization error: {0}")]
     DeserializationError(#[from] std::io::Error),
+    #[error("Invalid diversifier: {0}")]
+    InvalidDiversifier(String),
+    #[error("Address was formatted for another pool")]
+    WrongPool,
 }
 
+/// Pool identifier that can be bound to an address checksum.
+pub type PoolId<Fr> = BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>;
+
+/// Parses a legacy address, i.e. an address whose checksum is not bound to a pool.
 pub fn parse_address<P: PoolParams>(
     address: &str,
 ) -> Result<
@@ -31,34 +39,160 @@ pub fn parse_address<P: PoolParams>(
     ),
     AddressParseError,
 > {
-    let mut bytes = [0; ADDR_LEN];
-    bs58::decode(address).into(&mut bytes)?;
-
-    let checksum = &bytes[42..=45];
-
-    let hash = keccak256(&bytes[0..=41]);
+    let bytes = decode_address(address)?;
 
-    if &hash[0..=3] != checksum {
+    if checksum(&bytes[0..42], None) != bytes[42..ADDR_LEN] {
         return Err(AddressParseError::InvalidChecksum);
     }
 
-    let d = BoundedNum::try_from_slice(&bytes[0..10])?;
-    let p_d = Num::try_from_slice(&bytes[10..42])?;
+    parse_components::<P>(&bytes)
+}
 
-    Ok((d, p_d))
+/// Parses an address formatted for the pool with [`format_address_for_pool`]. Legacy addresses
+/// are accepted as well, since they are not bound to any pool. Any other checksum is reported as
+/// [`AddressParseError::WrongPool`], a corrupted address can't be told apart from an address of
+/// another pool.
+pub fn parse_address_for_pool<P: PoolParams>(
+    address: &str,
+    pool_id: PoolId<P::Fr>,
+) -> Result<
+    (
+        BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        Num<P::Fr>,
+    ),
+    AddressParseError,
+> {
+    let bytes = decode_address(address)?;
+    let pool_id_bytes = pool_id.try_to_vec()?;
+
+    let actual = &bytes[42..ADDR_LEN];
+    if checksum(&bytes[0..42], Some(&pool_id_bytes)) != actual
+        && checksum(&bytes[0..42], None) != actual
+    {
+        return Err(AddressParseError::WrongPool);
+    }
+
+    parse_components::<P>(&bytes)
 }
 
+/// Formats a legacy address which is not bound to a pool.
 pub fn format_address<P: PoolParams>(
     d: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
     p_d: Num<P::Fr>,
+) -> String {
+    encode_address::<P>(d, p_d, None)
+}
+
+/// Formats an address whose checksum is bound to the pool, so that it is rejected by
+/// [`parse_address_for_pool`] for other pools.
+pub fn format_address_for_pool<P: PoolParams>(
+    d: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    p_d: Num<P::Fr>,
+    pool_id: PoolId<P::Fr>,
+) -> String {
+    let pool_id_bytes = pool_id.try_to_vec().unwrap();
+
+    encode_address::<P>(d, p_d, Some(&pool_id_bytes))
+}
+
+fn encode_address<P: PoolParams>(
+    d: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    p_d: Num<P::Fr>,
+    pool_id: Option<&[u8]>,
 ) -> String {
     let mut buf: [u8; ADDR_LEN] = [0; ADDR_LEN];
 
     d.serialize(&mut &mut buf[0..10]).unwrap();
     p_d.serialize(&mut &mut buf[10..42]).unwrap();
 
-    let hash = keccak256(&buf[0..42]);
-    buf[42..ADDR_LEN].clone_from_slice(&hash[0..4]);
+    let checksum = checksum(&buf[0..42], pool_id);
+    buf[42..ADDR_LEN].clone_from_slice(&checksum);
 
     bs58::encode(buf).into_string()
 }
+
+fn decode_address(address: &str) -> Result<[u8; ADDR_LEN], AddressParseError> {
+    let mut bytes = [0; ADDR_LEN];
+    bs58::decode(address).into(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn parse_components<P: PoolParams>(
+    bytes: &[u8; ADDR_LEN],
+) -> Result<
+    (
+        BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        Num<P::Fr>,
+    ),
+    AddressParseError,
+> {
+    let d = BoundedNum::try_from_slice(&bytes[0..10])?;
+    let p_d = Num::try_from_slice(&bytes[10..42])?;
+
+    Ok((d, p_d))
+}
+
+/// The pool identifier, if any, is prepended to the hashed data.
+fn checksum(data: &[u8], pool_id: Option<&[u8]>) -> [u8; 4] {
+    let hash = match pool_id {
+        Some(pool_id) => keccak256(&[pool_id, data].concat()),
+        None => keccak256(data),
+    };
+
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&hash[0..4]);
+
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::native::params::PoolBN256;
+
+    use super::*;
+
+    fn components() -> (
+        BoundedNum<<PoolBN256 as PoolParams>::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        Num<<PoolBN256 as PoolParams>::Fr>,
+    ) {
+        (BoundedNum::new(Num::from(12345u64)), Num::from(67890u64))
+    }
+
+    #[test]
+    fn test_pool_address() {
+        let (d, p_d) = components();
+        let pool_0 = BoundedNum::new(Num::ZERO);
+        let pool_1 = BoundedNum::new(Num::ONE);
+
+        let address = format_address_for_pool::<PoolBN256>(d, p_d, pool_0);
+        let (parsed_d, parsed_p_d) = parse_address_for_pool::<PoolBN256>(&address, pool_0).unwrap();
+        assert_eq!(parsed_d.to_num(), d.to_num());
+        assert_eq!(parsed_p_d, p_d);
+
+        assert!(matches!(
+            parse_address_for_pool::<PoolBN256>(&address, pool_1),
+            Err(AddressParseError::WrongPool)
+        ));
+        assert!(matches!(
+            parse_address::<PoolBN256>(&address),
+            Err(AddressParseError::InvalidChecksum)
+        ));
+    }
+
+    #[test]
+    fn test_legacy_address() {
+        let (d, p_d) = components();
+
+        let address = format_address::<PoolBN256>(d, p_d);
+        let (parsed_d, parsed_p_d) = parse_address::<PoolBN256>(&address).unwrap();
+        assert_eq!(parsed_d.to_num(), d.to_num());
+        assert_eq!(parsed_p_d, p_d);
+
+        for pool_id in vec![Num::ZERO, Num::ONE] {
+            assert!(
+                parse_address_for_pool::<PoolBN256>(&address, BoundedNum::new(pool_id)).is_ok()
+            );
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Construction of [`TokenAmount`]s from user input. Unlike [`BoundedNum::new`], the values are
+//! checked against `constants::BALANCE_SIZE_BITS`.
+
+use std::{convert::TryFrom, fmt, marker::PhantomData};
+
+use libzeropool::{
+    constants,
+    fawkes_crypto::ff_uint::{Num, PrimeField},
+    native::boundednum::BoundedNum,
+};
+use serde::{
+    de::{self, Visitor},
+    Deserializer,
+};
+use thiserror::Error;
+
+use super::TokenAmount;
+
+/// Largest integer that JS numbers represent exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AmountError {
+    #[error("Invalid amount: {0}")]
+    InvalidAmount(String),
+    #[error("Amount {0} does not fit into {} bits", constants::BALANCE_SIZE_BITS)]
+    AmountOutOfRange(String),
+}
+
+/// Largest value that fits into a [`TokenAmount`].
+pub fn max_token_amount() -> u128 {
+    (1u128 << constants::BALANCE_SIZE_BITS) - 1
+}
+
+pub fn token_amount_from_u128<Fr: PrimeField>(value: u128) -> Result<TokenAmount<Fr>, AmountError> {
+    if value > max_token_amount() {
+        return Err(AmountError::AmountOutOfRange(value.to_string()));
+    }
+
+    let value =
+        u64::try_from(value).map_err(|_| AmountError::AmountOutOfRange(value.to_string()))?;
+
+    Ok(BoundedNum::new(Num::from(value)))
+}
+
+/// Parses a decimal or a `0x`-prefixed hex string.
+pub fn parse_token_amount<Fr: PrimeField>(s: &str) -> Result<TokenAmount<Fr>, AmountError> {
+    let s = s.trim();
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex_digits) => (hex_digits, 16),
+        None => (s, 10),
+    };
+
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(AmountError::InvalidAmount(s.to_owned()));
+    }
+
+    // The digits are valid, so only an overflow is possible
+    u128::from_str_radix(digits, radix)
+        .ok()
+        .and_then(|value| token_amount_from_u128(value).ok())
+        .ok_or_else(|| AmountError::AmountOutOfRange(s.to_owned()))
+}
+
+/// Deserializes a [`TokenAmount`] from a decimal or `0x`-prefixed hex string or a non-negative
+/// integer, to be used with `#[serde(deserialize_with)]`.
+pub fn deserialize<'de, D, Fr>(deserializer: D) -> Result<TokenAmount<Fr>, D::Error>
+where
+    D: Deserializer<'de>,
+    Fr: PrimeField,
+{
+    deserializer.deserialize_any(AmountVisitor(PhantomData))
+}
+
+struct AmountVisitor<Fr>(PhantomData<Fr>);
+
+impl<'de, Fr: PrimeField> Visitor<'de> for AmountVisitor<Fr> {
+    type Value = TokenAmount<Fr>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal or hex string or a non-negative integer")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        parse_token_amount(v).map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        token_amount_from_u128(v.into()).map_err(E::custom)
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        token_amount_from_u128(v).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        match u64::try_from(v) {
+            Ok(v) => self.visit_u64(v),
+            Err(_) => Err(E::custom(AmountError::InvalidAmount(v.to_string()))),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        if v < 0.0 || v.fract() != 0.0 || v > MAX_SAFE_INTEGER {
+            return Err(E::custom(AmountError::InvalidAmount(v.to_string())));
+        }
+
+        self.visit_u64(v as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::native::params::{PoolBN256, PoolParams};
+    use serde::Deserialize;
+
+    use super::*;
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    #[derive(Deserialize)]
+    struct Amount {
+        #[serde(deserialize_with = "deserialize")]
+        amount: TokenAmount<Fr>,
+    }
+
+    fn from_json(json: &str) -> Result<TokenAmount<Fr>, serde_json::Error> {
+        serde_json::from_str::<Amount>(json).map(|a| a.amount)
+    }
+
+    #[test]
+    fn test_token_amount_range() {
+        let max = max_token_amount();
+
+        let amount = token_amount_from_u128::<Fr>(max).unwrap();
+        assert_eq!(amount.to_num().to_string(), max.to_string());
+        assert_eq!(
+            parse_token_amount::<Fr>(&max.to_string()).unwrap().to_num(),
+            amount.to_num()
+        );
+
+        assert_eq!(
+            token_amount_from_u128::<Fr>(max + 1).unwrap_err(),
+            AmountError::AmountOutOfRange((max + 1).to_string())
+        );
+        assert_eq!(
+            parse_token_amount::<Fr>(&(max + 1).to_string()).unwrap_err(),
+            AmountError::AmountOutOfRange((max + 1).to_string())
+        );
+        assert!(matches!(
+            parse_token_amount::<Fr>(&format!("0x{}", "f".repeat(40))),
+            Err(AmountError::AmountOutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_token_amount() {
+        assert_eq!(
+            parse_token_amount::<Fr>("1000000000").unwrap().to_num(),
+            Num::from(1_000_000_000u64)
+        );
+        assert_eq!(
+            parse_token_amount::<Fr>("0x3b9aca00").unwrap().to_num(),
+            Num::from(1_000_000_000u64)
+        );
+        assert!(matches!(
+            parse_token_amount::<Fr>("0x"),
+            Err(AmountError::InvalidAmount(_))
+        ));
+        assert!(matches!(
+            parse_token_amount::<Fr>("-1"),
+            Err(AmountError::InvalidAmount(_))
+        ));
+        assert!(matches!(
+            parse_token_amount::<Fr>("1.5"),
+            Err(AmountError::InvalidAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_amount() {
+        let expected = Num::from(255u64);
+        assert_eq!(from_json(r#"{"amount":"255"}"#).unwrap().to_num(), expected);
+        assert_eq!(
+            from_json(r#"{"amount":"0xff"}"#).unwrap().to_num(),
+            expected
+        );
+        assert_eq!(from_json(r#"{"amount":255}"#).unwrap().to_num(), expected);
+        assert!(from_json(r#"{"amount":-1}"#).is_err());
+        assert!(from_json(r#"{"amount":1.5}"#).is_err());
+        assert!(from_json(r#"{"amount":"18446744073709551616"}"#).is_err());
+    }
+}
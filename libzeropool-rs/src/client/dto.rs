@@ -0,0 +1,438 @@
+//! Simplified serde representation of [`TxType`] for CLI and config-driven flows. Amounts are
+//! decimal strings (hex strings and numbers are accepted as well), byte fields are `0x`-prefixed
+//! hex strings, and [`TxTypeDto`] is tagged with `type` using the same names as the wasm `TxType`
+//! enum.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use libzeropool::fawkes_crypto::ff_uint::PrimeField;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{amount, TokenAmount, TxOutput, TxType};
+
+#[derive(Debug, Error)]
+pub enum TxTypeDtoError {
+    #[error("Failed to parse tx type: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid deadline: {0}")]
+    InvalidDeadline(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferDto<Fr: PrimeField> {
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub fee: TokenAmount<Fr>,
+    pub outputs: Vec<TxOutput<Fr>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "hex_bytes_opt"
+    )]
+    pub encrypted_data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DepositDto<Fr: PrimeField> {
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub fee: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub amount: TokenAmount<Fr>,
+    pub outputs: Option<Vec<TxOutput<Fr>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DepositPermittableDto<Fr: PrimeField> {
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub fee: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub amount: TokenAmount<Fr>,
+    /// Unix timestamp as a decimal string.
+    pub deadline: String,
+    #[serde(with = "hex_bytes")]
+    pub holder: Vec<u8>,
+    pub outputs: Option<Vec<TxOutput<Fr>>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WithdrawDto<Fr: PrimeField> {
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub fee: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub amount: TokenAmount<Fr>,
+    #[serde(with = "hex_bytes")]
+    pub to: Vec<u8>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub native_amount: TokenAmount<Fr>,
+    #[serde(deserialize_with = "amount::deserialize")]
+    pub energy_amount: TokenAmount<Fr>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TxTypeDto<Fr: PrimeField> {
+    Transfer(TransferDto<Fr>),
+    Deposit(DepositDto<Fr>),
+    DepositPermittable(DepositPermittableDto<Fr>),
+    Withdraw(WithdrawDto<Fr>),
+}
+
+impl<Fr: PrimeField> From<TransferDto<Fr>> for TxType<Fr> {
+    fn from(dto: TransferDto<Fr>) -> Self {
+        match dto.encrypted_data {
+            Some(encrypted_data) => TxType::TransferV2 {
+                fee: dto.fee,
+                outputs: dto.outputs,
+                encrypted_data,
+            },
+            None => TxType::Transfer {
+                fee: dto.fee,
+                outputs: dto.outputs,
+            },
+        }
+    }
+}
+
+impl<Fr: PrimeField> From<DepositDto<Fr>> for TxType<Fr> {
+    fn from(dto: DepositDto<Fr>) -> Self {
+        TxType::Deposit {
+            fee: dto.fee,
+            deposit_amount: dto.amount,
+            outputs: dto.outputs.unwrap_or_default(),
+        }
+    }
+}
+
+impl<Fr: PrimeField> TryFrom<DepositPermittableDto<Fr>> for TxType<Fr> {
+    type Error = TxTypeDtoError;
+
+    fn try_from(dto: DepositPermittableDto<Fr>) -> Result<Self, Self::Error> {
+        let deadline = dto
+            .deadline
+            .parse::<u64>()
+            .map_err(|_| TxTypeDtoError::InvalidDeadline(dto.deadline.clone()))?;
+
+        Ok(TxType::DepositPermittable {
+            fee: dto.fee,
+            deposit_amount: dto.amount,
+            deadline,
+            holder: dto.holder,
+            outputs: dto.outputs.unwrap_or_default(),
+        })
+    }
+}
+
+impl<Fr: PrimeField> From<WithdrawDto<Fr>> for TxType<Fr> {
+    fn from(dto: WithdrawDto<Fr>) -> Self {
+        TxType::Withdraw {
+            fee: dto.fee,
+            withdraw_amount: dto.amount,
+            to: dto.to,
+            native_amount: dto.native_amount,
+            energy_amount: dto.energy_amount,
+        }
+    }
+}
+
+impl<Fr: PrimeField> TryFrom<TxTypeDto<Fr>> for TxType<Fr> {
+    type Error = TxTypeDtoError;
+
+    fn try_from(dto: TxTypeDto<Fr>) -> Result<Self, Self::Error> {
+        match dto {
+            TxTypeDto::Transfer(dto) => Ok(dto.into()),
+            TxTypeDto::Deposit(dto) => Ok(dto.into()),
+            TxTypeDto::DepositPermittable(dto) => TxType::try_from(dto),
+            TxTypeDto::Withdraw(dto) => Ok(dto.into()),
+        }
+    }
+}
+
+impl<Fr: PrimeField> From<TxType<Fr>> for TxTypeDto<Fr> {
+    fn from(tx: TxType<Fr>) -> Self {
+        match tx {
+            TxType::Transfer { fee, outputs } => TxTypeDto::Transfer(TransferDto { fee, outputs }),
+            TxType::TransferV2 {
+                fee,
+                outputs,
+                encrypted_data,
+            } => TxTypeDto::Transfer(TransferDto {
+                fee,
+                outputs,
+                encrypted_data: Some(encrypted_data),
+            }),
+            TxType::Deposit {
+                fee,
+                deposit_amount,
+                outputs,
+            } => TxTypeDto::Deposit(DepositDto {
+                fee,
+                amount: deposit_amount,
+                outputs: Some(outputs),
+            }),
+            TxType::DepositPermittable {
+                fee,
+                deposit_amount,
+                deadline,
+                holder,
+                outputs,
+            } => TxTypeDto::DepositPermittable(DepositPermittableDto {
+                fee,
+                amount: deposit_amount,
+                deadline: deadline.to_string(),
+                holder,
+                outputs: Some(outputs),
+            }),
+            TxType::Withdraw {
+                fee,
+                withdraw_amount,
+                to,
+                native_amount,
+                energy_amount,
+            } => TxTypeDto::Withdraw(WithdrawDto {
+                fee,
+                amount: withdraw_amount,
+                to,
+                native_amount,
+                energy_amount,
+            }),
+        }
+    }
+}
+
+impl<Fr: PrimeField> TxType<Fr> {
+    /// Parses the [`TxTypeDto`] JSON representation.
+    pub fn from_json(json: &str) -> Result<Self, TxTypeDtoError> {
+        let dto: TxTypeDto<Fr> = serde_json::from_str(json)?;
+        TxType::try_from(dto)
+    }
+
+    /// Serializes into the [`TxTypeDto`] JSON representation.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&TxTypeDto::from(self.clone())).unwrap()
+    }
+}
+
+impl<Fr: PrimeField> FromStr for TxType<Fr> {
+    type Err = TxTypeDtoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_json(s)
+    }
+}
+
+impl<Fr: PrimeField> Display for TxType<Fr> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_json())
+    }
+}
+
+/// Byte fields are serialized as `0x`-prefixed hex. Deserialization also accepts unprefixed hex
+/// and raw bytes, so that `Uint8Array`s coming from JS can be used as is.
+mod hex_bytes {
+    use std::fmt;
+
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a hex string or a byte array")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            let v = v.strip_prefix("0x").unwrap_or(v);
+            hex::decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+mod hex_bytes_opt {
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::hex_bytes::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        super::hex_bytes::deserialize(deserializer).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::{
+        fawkes_crypto::ff_uint::Num,
+        native::{
+            boundednum::BoundedNum,
+            params::{PoolBN256, PoolParams},
+        },
+    };
+
+    use super::*;
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    fn amount(value: u64) -> TokenAmount<Fr> {
+        BoundedNum::new(Num::from(value))
+    }
+
+    fn output() -> TxOutput<Fr> {
+        TxOutput {
+            to: "some_address".to_string(),
+            amount: amount(5),
+        }
+    }
+
+    #[test]
+    fn test_tx_type_json_roundtrip() {
+        let txs = vec![
+            TxType::Transfer {
+                fee: amount(1),
+                outputs: vec![output()],
+            },
+            TxType::TransferV2 {
+                fee: amount(1),
+                outputs: vec![output()],
+                encrypted_data: vec![1, 2, 3],
+            },
+            TxType::Deposit {
+                fee: amount(1),
+                deposit_amount: amount(10),
+                outputs: vec![output()],
+            },
+            TxType::DepositPermittable {
+                fee: amount(1),
+                deposit_amount: amount(10),
+                deadline: 1_700_000_000,
+                holder: vec![0xab; 20],
+                outputs: vec![],
+            },
+            TxType::Withdraw {
+                fee: amount(1),
+                withdraw_amount: amount(10),
+                to: vec![0xcd; 20],
+                native_amount: amount(2),
+                energy_amount: amount(3),
+            },
+        ];
+
+        for tx in txs {
+            let json = tx.to_json();
+            let parsed = TxType::<Fr>::from_json(&json).unwrap();
+            assert_eq!(parsed.to_json(), json);
+            assert_eq!(
+                tx.to_string().parse::<TxType<Fr>>().unwrap().to_json(),
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn test_tx_type_json_format() {
+        let tx = TxType::<Fr>::from_json(
+            r#"{
+                "type": "withdraw",
+                "fee": "1",
+                "amount": "10",
+                "to": "0xabcd",
+                "native_amount": "2",
+                "energy_amount": "0"
+            }"#,
+        )
+        .unwrap();
+
+        match &tx {
+            TxType::Withdraw {
+                withdraw_amount,
+                to,
+                ..
+            } => {
+                assert_eq!(withdraw_amount.to_num(), Num::from(10));
+                assert_eq!(to, &vec![0xab, 0xcd]);
+            }
+            _ => panic!("expected a withdraw"),
+        }
+        assert!(tx.to_json().contains(r#""to":"0xabcd""#));
+
+        let deposit = TxType::<Fr>::from_json(r#"{"type":"deposit","fee":"0","amount":"7"}"#);
+        assert!(matches!(deposit, Ok(TxType::Deposit { outputs, .. }) if outputs.is_empty()));
+
+        let deposit = TxType::<Fr>::from_json(r#"{"type":"deposit","fee":0,"amount":"0x07"}"#);
+        match deposit {
+            Ok(TxType::Deposit { deposit_amount, .. }) => {
+                assert_eq!(deposit_amount.to_num(), Num::from(7))
+            }
+            _ => panic!("expected a deposit"),
+        }
+    }
+
+    #[test]
+    fn test_tx_type_json_rejects_invalid() {
+        let invalid_to = r#"{
+            "type": "withdraw",
+            "fee": "1",
+            "amount": "10",
+            "to": "0xnothex",
+            "native_amount": "2",
+            "energy_amount": "0"
+        }"#;
+        assert!(matches!(
+            TxType::<Fr>::from_json(invalid_to),
+            Err(TxTypeDtoError::Json(_))
+        ));
+
+        let invalid_deadline = r#"{
+            "type": "deposit_permittable",
+            "fee": "1",
+            "amount": "10",
+            "deadline": "soon",
+            "holder": "0xabcd"
+        }"#;
+        assert!(matches!(
+            TxType::<Fr>::from_json(invalid_deadline),
+            Err(TxTypeDtoError::InvalidDeadline(_))
+        ));
+    }
+}
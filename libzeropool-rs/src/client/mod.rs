@@ -1,12 +1,22 @@
-use std::{convert::TryInto, io::Write};
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{self, Write},
+    ops::Range,
+    str::FromStr,
+};
 
 use kvdb::KeyValueDB;
+#[cfg(feature = "native")]
+use kvdb_persy::PersyDatabase as NativeDatabase;
 use libzeropool::{
     constants,
     fawkes_crypto::{
+        borsh::{BorshDeserialize, BorshSerialize},
         core::sizedvec::SizedVec,
         ff_uint::{Num, NumRepr, PrimeField, Uint},
-        rand::Rng,
+        native::poseidon::MerkleProof,
+        rand::{Rng, RngCore},
     },
     native::{
         account::Account,
@@ -21,18 +31,23 @@ use libzeropool::{
         },
     },
 };
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use thiserror::Error;
 
 use self::state::{State, Transaction};
 use crate::{
     address::{format_address, parse_address, AddressParseError},
-    keys::{reduce_sk, Keys},
+    delta::{check_pool_id, decode_delta, Delta, DeltaError},
+    keys::{derive_from_mnemonic, reduce_sk, KeyError, Keys, SecretKey},
+    memo::{self, parse_memo, split_memo_text, MemoTextError, TxTypeId},
     merkle::Hash,
     random::CustomRng,
+    tx_parser::StateUpdate,
     utils::{keccak256, zero_note, zero_proof},
 };
 
+pub mod amount;
+pub mod dto;
 pub mod state;
 
 #[derive(Debug, Error)]
@@ -43,10 +58,131 @@ pub enum CreateTxError {
     ProofNotFound(u64),
     #[error("Failed to parse address: {0}")]
     AddressParseError(#[from] AddressParseError),
-    #[error("Insufficient balance: sum of outputs is greater than sum of inputs: {0} > {1}")]
-    InsufficientBalance(String, String),
-    #[error("Insufficient energy: available {0}, received {1}")]
-    InsufficientEnergy(String, String),
+    #[error(
+        "Insufficient balance: sum of outputs is greater than sum of inputs: {required} > {available}"
+    )]
+    InsufficientBalance { required: u128, available: u128 },
+    #[error("Insufficient energy: available {available}, received {required}")]
+    InsufficientEnergy { required: u128, available: u128 },
+    #[error("Note {0} does not exist or cannot be spent")]
+    InvalidNoteIndex(u64),
+    #[error("Too many aggregation transactions required: expected {max} max got {required}")]
+    TooManyAggregationTxs { max: usize, required: usize },
+    #[error("Invalid withdraw address length: expected {expected} bytes, got {got}")]
+    InvalidWithdrawAddress { expected: usize, got: usize },
+    #[error("Invalid holder address length: expected {expected} bytes, got {got}")]
+    InvalidHolderAddress { expected: usize, got: usize },
+    #[error("Native amount {0} exceeds withdraw amount {1}")]
+    NativeAmountExceedsWithdraw(String, String),
+    #[error("Energy amount {0} does not fit into the balance size")]
+    EnergyAmountTooLarge(String),
+    #[error("Account balance {0} does not fit into the balance size")]
+    BalanceOverflow(String),
+    #[error("Account energy {0} does not fit into the energy size")]
+    EnergyOverflow(String),
+    #[error("Amount {0} does not fit into 128 bits")]
+    AmountOverflow(String),
+    #[error("Watch-only account cannot create transactions")]
+    WatchOnly,
+    #[error("State is not synced: the latest account is unknown")]
+    StateNotSynced,
+    #[error("Key rotation address belongs to the current account")]
+    RotationToOwnAddress,
+    #[error("Failed to encrypt the data: {0}")]
+    EncryptedData(#[from] MemoTextError),
+    #[error("Nullifier of the input account is already used (pending: {pending})")]
+    NullifierAlreadyUsed { pending: bool },
+}
+
+impl CreateTxError {
+    /// Stable identifier of the error kind, meant to be matched by the JS front-ends instead of
+    /// the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CreateTxError::TooManyOutputs { .. } => "TOO_MANY_OUTPUTS",
+            CreateTxError::ProofNotFound(_) => "PROOF_NOT_FOUND",
+            CreateTxError::AddressParseError(_) => "INVALID_ADDRESS",
+            CreateTxError::InsufficientBalance { .. } => "INSUFFICIENT_BALANCE",
+            CreateTxError::InsufficientEnergy { .. } => "INSUFFICIENT_ENERGY",
+            CreateTxError::InvalidNoteIndex(_) => "INVALID_NOTE_INDEX",
+            CreateTxError::TooManyAggregationTxs { .. } => "TOO_MANY_AGGREGATION_TXS",
+            CreateTxError::InvalidWithdrawAddress { .. } => "INVALID_WITHDRAW_ADDRESS",
+            CreateTxError::InvalidHolderAddress { .. } => "INVALID_HOLDER_ADDRESS",
+            CreateTxError::NativeAmountExceedsWithdraw(..) => "NATIVE_AMOUNT_EXCEEDS_WITHDRAW",
+            CreateTxError::EnergyAmountTooLarge(_) => "ENERGY_AMOUNT_TOO_LARGE",
+            CreateTxError::BalanceOverflow(_) => "BALANCE_OVERFLOW",
+            CreateTxError::EnergyOverflow(_) => "ENERGY_OVERFLOW",
+            CreateTxError::AmountOverflow(_) => "AMOUNT_OVERFLOW",
+            CreateTxError::WatchOnly => "WATCH_ONLY",
+            CreateTxError::StateNotSynced => "STATE_NOT_SYNCED",
+            CreateTxError::RotationToOwnAddress => "ROTATION_TO_OWN_ADDRESS",
+            CreateTxError::EncryptedData(_) => "INVALID_ENCRYPTED_DATA",
+            CreateTxError::NullifierAlreadyUsed { .. } => "NULLIFIER_ALREADY_USED",
+        }
+    }
+}
+
+/// Serialized as a flat object: `code`, the variant data (amounts as decimal strings) and
+/// `message`.
+impl Serialize for CreateTxError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CreateTxError", 4)?;
+        state.serialize_field("code", self.code())?;
+
+        match self {
+            CreateTxError::TooManyOutputs { max, got } => {
+                state.serialize_field("max", max)?;
+                state.serialize_field("got", got)?;
+            }
+            CreateTxError::ProofNotFound(index) | CreateTxError::InvalidNoteIndex(index) => {
+                state.serialize_field("index", index)?;
+            }
+            CreateTxError::InsufficientBalance {
+                required,
+                available,
+            }
+            | CreateTxError::InsufficientEnergy {
+                required,
+                available,
+            } => {
+                state.serialize_field("required", &required.to_string())?;
+                state.serialize_field("available", &available.to_string())?;
+            }
+            CreateTxError::TooManyAggregationTxs { max, required } => {
+                state.serialize_field("max", max)?;
+                state.serialize_field("required", required)?;
+            }
+            CreateTxError::InvalidWithdrawAddress { expected, got }
+            | CreateTxError::InvalidHolderAddress { expected, got } => {
+                state.serialize_field("expected", expected)?;
+                state.serialize_field("got", got)?;
+            }
+            CreateTxError::NativeAmountExceedsWithdraw(native_amount, withdraw_amount) => {
+                state.serialize_field("nativeAmount", native_amount)?;
+                state.serialize_field("withdrawAmount", withdraw_amount)?;
+            }
+            CreateTxError::EnergyAmountTooLarge(amount) | CreateTxError::AmountOverflow(amount) => {
+                state.serialize_field("amount", amount)?;
+            }
+            CreateTxError::BalanceOverflow(balance) => {
+                state.serialize_field("balance", balance)?;
+            }
+            CreateTxError::EnergyOverflow(energy) => {
+                state.serialize_field("energy", energy)?;
+            }
+            CreateTxError::NullifierAlreadyUsed { pending } => {
+                state.serialize_field("pending", pending)?;
+            }
+            CreateTxError::AddressParseError(_)
+            | CreateTxError::EncryptedData(_)
+            | CreateTxError::WatchOnly
+            | CreateTxError::StateNotSynced
+            | CreateTxError::RotationToOwnAddress => {}
+        }
+
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -57,6 +193,138 @@ pub struct StateFragment<Fr: PrimeField> {
     pub new_notes: Vec<(u64, Note<Fr>)>,
 }
 
+impl<Fr: PrimeField> From<StateUpdate<Fr>> for StateFragment<Fr> {
+    fn from(update: StateUpdate<Fr>) -> Self {
+        StateFragment {
+            new_leafs: update.new_leafs,
+            new_commitments: update.new_commitments,
+            new_accounts: update.new_accounts,
+            new_notes: update.new_notes.into_iter().flatten().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentItemKind {
+    Leaf,
+    Commitment,
+    Account,
+    Note,
+}
+
+impl fmt::Display for FragmentItemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            FragmentItemKind::Leaf => "leaf",
+            FragmentItemKind::Commitment => "commitment",
+            FragmentItemKind::Account => "account",
+            FragmentItemKind::Note => "note",
+        };
+        f.write_str(kind)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MergeError {
+    #[error("Conflicting {kind} at index {index}")]
+    Conflict { kind: FragmentItemKind, index: u64 },
+}
+
+impl<Fr: PrimeField> StateFragment<Fr> {
+    pub fn is_empty(&self) -> bool {
+        self.new_leafs.iter().all(|(_, leafs)| leafs.is_empty())
+            && self.new_commitments.is_empty()
+            && self.new_accounts.is_empty()
+            && self.new_notes.is_empty()
+    }
+
+    /// The highest tree index the fragment touches, the last leaf of every run included.
+    pub fn max_index(&self) -> Option<u64> {
+        let leafs = self.new_leafs.iter().filter_map(|(index, leafs)| {
+            (leafs.len() as u64)
+                .checked_sub(1)
+                .map(|offset| index + offset)
+        });
+        let commitments = self.new_commitments.iter().map(|(index, _)| *index);
+        let accounts = self.new_accounts.iter().map(|(index, _)| *index);
+        let notes = self.new_notes.iter().map(|(index, _)| *index);
+
+        leafs.chain(commitments).chain(accounts).chain(notes).max()
+    }
+
+    /// Joins two fragments, sorting every component by index. Fails if an index is present more
+    /// than once, or if a run of leafs overlaps the slot of a transaction that is already
+    /// represented by its commitment.
+    pub fn merge(self, other: StateFragment<Fr>) -> Result<StateFragment<Fr>, MergeError> {
+        let new_leafs = merge_sorted(self.new_leafs, other.new_leafs);
+        let mut leafs_end = None;
+        for (index, leafs) in new_leafs.iter().filter(|(_, leafs)| !leafs.is_empty()) {
+            if matches!(leafs_end, Some(end) if *index < end) {
+                return Err(MergeError::Conflict {
+                    kind: FragmentItemKind::Leaf,
+                    index: *index,
+                });
+            }
+            leafs_end = Some(index + leafs.len() as u64);
+        }
+
+        let new_commitments = merge_unique(
+            self.new_commitments,
+            other.new_commitments,
+            FragmentItemKind::Commitment,
+        )?;
+        let new_accounts = merge_unique(
+            self.new_accounts,
+            other.new_accounts,
+            FragmentItemKind::Account,
+        )?;
+        let new_notes = merge_unique(self.new_notes, other.new_notes, FragmentItemKind::Note)?;
+
+        let slot_size = 1u64 << constants::OUTPLUSONELOG;
+        for (start, leafs) in new_leafs.iter().filter(|(_, leafs)| !leafs.is_empty()) {
+            let end = start + leafs.len() as u64;
+            let next = new_commitments.partition_point(|(index, _)| index + slot_size <= *start);
+            match new_commitments.get(next) {
+                Some((index, _)) if *index < end => {
+                    return Err(MergeError::Conflict {
+                        kind: FragmentItemKind::Leaf,
+                        index: *start.max(index),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(StateFragment {
+            new_leafs,
+            new_commitments,
+            new_accounts,
+            new_notes,
+        })
+    }
+}
+
+fn merge_sorted<T>(mut left: Vec<(u64, T)>, right: Vec<(u64, T)>) -> Vec<(u64, T)> {
+    left.extend(right);
+    left.sort_by_key(|(index, _)| *index);
+    left
+}
+
+fn merge_unique<T>(
+    left: Vec<(u64, T)>,
+    right: Vec<(u64, T)>,
+    kind: FragmentItemKind,
+) -> Result<Vec<(u64, T)>, MergeError> {
+    let items = merge_sorted(left, right);
+    match items.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        Some(pair) => Err(MergeError::Conflict {
+            kind,
+            index: pair[0].0,
+        }),
+        None => Ok(items),
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TransactionData<Fr: PrimeField> {
     pub public: TransferPub<Fr>,
@@ -65,6 +333,184 @@ pub struct TransactionData<Fr: PrimeField> {
     pub memo: Vec<u8>,
     pub commitment_root: Num<Fr>,
     pub out_hashes: SizedVec<Num<Fr>, { constants::OUT + 1 }>,
+    /// Balance and energy figures of the transaction. Not included in the binary encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<TxSummary<Fr>>,
+    /// Non-fatal issues of the transaction that should be shown to the user. Not included in the
+    /// binary encoding.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<TxWarning>,
+    /// Addresses of the out notes, recorded by [`UserAccount::record_outgoing_addresses`] once
+    /// the transaction is submitted. Not included in the binary encoding.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<TxDestination<Fr>>,
+}
+
+/// Parsed address of an out note of a transaction.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TxDestination<Fr: PrimeField> {
+    pub d: BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    pub p_d: Num<Fr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TxWarning {
+    /// Notes were already sent to the address, which links the transactions. Only reported if
+    /// [`UserAccount::track_outgoing`] is set.
+    AddressReuse { to: String },
+}
+
+/// Amounts a transaction was built from, so that they can be shown to the user without
+/// re-deriving them from the delta and the notes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TxSummary<Fr: PrimeField> {
+    /// Balance of the input account and the spent notes.
+    pub input_value: Num<Fr>,
+    /// Sum of the output notes.
+    pub output_value: Num<Fr>,
+    pub fee: Num<Fr>,
+    pub delta_value: Num<Fr>,
+    /// Energy accumulated by the input account and the spent notes.
+    pub input_energy: Num<Fr>,
+    /// Energy of the output account.
+    pub remaining_energy: Num<Fr>,
+    pub spent_note_indices: Vec<u64>,
+}
+
+/// Version of the binary encoding produced by [`TransactionData::to_bytes`].
+const TRANSACTION_DATA_VERSION: u8 = 1;
+
+impl<Fr: PrimeField> TransactionData<Fr> {
+    /// Checks that `commitment_root` is the out commitment of `out_hashes`, e.g. before sending
+    /// the transaction to the relayer.
+    pub fn verify_out_commitment<P: PoolParams<Fr = Fr>>(&self, params: &P) -> bool {
+        out_commitment_hash(self.out_hashes.as_slice(), params) == self.commitment_root
+    }
+
+    /// Serializes the transaction data into a compact binary format prefixed with a version byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    /// Deserializes transaction data produced by [`TransactionData::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let buf = &mut &bytes[..];
+
+        let version = u8::deserialize(buf)?;
+        if version != TRANSACTION_DATA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported transaction data version: {}", version),
+            ));
+        }
+
+        let public = TransferPub {
+            root: Num::deserialize(buf)?,
+            nullifier: Num::deserialize(buf)?,
+            out_commit: Num::deserialize(buf)?,
+            delta: Num::deserialize(buf)?,
+            memo: Num::deserialize(buf)?,
+        };
+
+        let tx = Tx {
+            input: (Account::deserialize(buf)?, read_sized_vec(buf)?),
+            output: (Account::deserialize(buf)?, read_sized_vec(buf)?),
+        };
+        let account_proof = read_merkle_proof(buf)?;
+        let note_proofs = (0..constants::IN)
+            .map(|_| read_merkle_proof(buf))
+            .collect::<io::Result<_>>()?;
+        let secret = TransferSec {
+            tx,
+            in_proof: (account_proof, note_proofs),
+            eddsa_s: Num::deserialize(buf)?,
+            eddsa_r: Num::deserialize(buf)?,
+            eddsa_a: Num::deserialize(buf)?,
+        };
+
+        let data = TransactionData {
+            public,
+            secret,
+            ciphertext: Vec::deserialize(buf)?,
+            memo: Vec::deserialize(buf)?,
+            commitment_root: Num::deserialize(buf)?,
+            out_hashes: read_sized_vec(buf)?,
+            summary: None,
+            warnings: vec![],
+            destinations: vec![],
+        };
+
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected trailing bytes",
+            ));
+        }
+
+        Ok(data)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        TRANSACTION_DATA_VERSION.serialize(w)?;
+
+        let public = &self.public;
+        public.root.serialize(w)?;
+        public.nullifier.serialize(w)?;
+        public.out_commit.serialize(w)?;
+        public.delta.serialize(w)?;
+        public.memo.serialize(w)?;
+
+        let secret = &self.secret;
+        secret.tx.input.0.serialize(w)?;
+        write_sized_vec(&secret.tx.input.1, w)?;
+        secret.tx.output.0.serialize(w)?;
+        write_sized_vec(&secret.tx.output.1, w)?;
+        write_merkle_proof(&secret.in_proof.0, w)?;
+        for proof in secret.in_proof.1.iter() {
+            write_merkle_proof(proof, w)?;
+        }
+        secret.eddsa_s.serialize(w)?;
+        secret.eddsa_r.serialize(w)?;
+        secret.eddsa_a.serialize(w)?;
+
+        self.ciphertext.serialize(w)?;
+        self.memo.serialize(w)?;
+        self.commitment_root.serialize(w)?;
+        write_sized_vec(&self.out_hashes, w)
+    }
+}
+
+pub(crate) fn write_sized_vec<T: BorshSerialize, W: Write, const N: usize>(
+    items: &SizedVec<T, N>,
+    w: &mut W,
+) -> io::Result<()> {
+    items.iter().try_for_each(|item| item.serialize(w))
+}
+
+pub(crate) fn read_sized_vec<T: BorshDeserialize, const N: usize>(
+    buf: &mut &[u8],
+) -> io::Result<SizedVec<T, N>> {
+    (0..N).map(|_| T::deserialize(buf)).collect()
+}
+
+fn write_merkle_proof<Fr: PrimeField, W: Write, const H: usize>(
+    proof: &MerkleProof<Fr, H>,
+    w: &mut W,
+) -> io::Result<()> {
+    write_sized_vec(&proof.sibling, w)?;
+    write_sized_vec(&proof.path, w)
+}
+
+fn read_merkle_proof<Fr: PrimeField, const H: usize>(
+    buf: &mut &[u8],
+) -> io::Result<MerkleProof<Fr, H>> {
+    Ok(MerkleProof {
+        sibling: read_sized_vec(buf)?,
+        path: read_sized_vec(buf)?,
+    })
 }
 
 pub type TokenAmount<Fr> = BoundedNum<Fr, { constants::BALANCE_SIZE_BITS }>;
@@ -72,6 +518,7 @@ pub type TokenAmount<Fr> = BoundedNum<Fr, { constants::BALANCE_SIZE_BITS }>;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxOutput<Fr: PrimeField> {
     pub to: String,
+    #[serde(deserialize_with = "amount::deserialize")]
     pub amount: TokenAmount<Fr>,
 }
 
@@ -81,6 +528,14 @@ pub enum TxType<Fr: PrimeField> {
         fee: TokenAmount<Fr>,
         outputs: Vec<TxOutput<Fr>>,
     },
+    /// Same as [`TxType::Transfer`], but the ciphertext is followed by `encrypted_data`, which
+    /// only the owners of the output notes can read. At most [`memo::MAX_MEMO_TEXT_SIZE`] bytes,
+    /// see [`memo::encrypt_memo_text`].
+    TransferV2 {
+        fee: TokenAmount<Fr>,
+        outputs: Vec<TxOutput<Fr>>,
+        encrypted_data: Vec<u8>,
+    },
     Deposit {
         fee: TokenAmount<Fr>,
         deposit_amount: TokenAmount<Fr>,
@@ -102,15 +557,247 @@ pub enum TxType<Fr: PrimeField> {
     },
 }
 
+/// Expected outcome of a transaction, see [`UserAccount::estimate_tx`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TxEstimate<Fr: PrimeField> {
+    /// Number of notes that would be spent by the transaction.
+    pub in_notes_count: usize,
+    /// Account balance after the transaction, `None` if the transaction would fail.
+    pub new_balance: Option<Num<Fr>>,
+    /// Energy accumulated by the input account and notes.
+    pub energy: Num<Fr>,
+    pub insufficient_balance: bool,
+    pub insufficient_energy: bool,
+}
+
+impl<Fr: PrimeField> TxType<Fr> {
+    fn fee(&self) -> TokenAmount<Fr> {
+        match self {
+            TxType::Transfer { fee, .. }
+            | TxType::TransferV2 { fee, .. }
+            | TxType::Deposit { fee, .. }
+            | TxType::DepositPermittable { fee, .. }
+            | TxType::Withdraw { fee, .. } => *fee,
+        }
+    }
+
+    fn output_value(&self) -> Num<Fr> {
+        match self {
+            TxType::Transfer { outputs, .. }
+            | TxType::TransferV2 { outputs, .. }
+            | TxType::Deposit { outputs, .. }
+            | TxType::DepositPermittable { outputs, .. } => outputs
+                .iter()
+                .fold(Num::ZERO, |sum, output| sum + output.amount.to_num()),
+            TxType::Withdraw { .. } => Num::ZERO,
+        }
+    }
+
+    /// Length of the memo of this transaction with `num_outputs` out notes (0 for withdrawals),
+    /// so that a calldata based fee can be calculated before the transaction is built.
+    pub fn memo_size_hint(&self, num_outputs: usize) -> usize {
+        let tx_data_size = match self {
+            TxType::DepositPermittable { holder, .. } => {
+                memo::tx_data_size(TxTypeId::DepositPermittable) - memo::ADDRESS_SIZE + holder.len()
+            }
+            TxType::Withdraw { to, .. } => {
+                memo::tx_data_size(TxTypeId::Withdraw) - memo::ADDRESS_SIZE + to.len()
+            }
+            tx => memo::tx_data_size(tx.into()),
+        };
+
+        tx_data_size + memo::ciphertext_size::<Fr>(num_outputs)
+    }
+
+    fn outputs(&self) -> &[TxOutput<Fr>] {
+        match self {
+            TxType::Transfer { outputs, .. }
+            | TxType::TransferV2 { outputs, .. }
+            | TxType::Deposit { outputs, .. }
+            | TxType::DepositPermittable { outputs, .. } => outputs,
+            TxType::Withdraw { .. } => &[],
+        }
+    }
+}
+
+/// Result of checking an address against the account keys, see [`UserAccount::address_info`].
+#[derive(Debug)]
+pub enum AddressInfo<Fr: PrimeField> {
+    Invalid(AddressParseError),
+    Foreign {
+        d: BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        p_d: Num<Fr>,
+    },
+    Own {
+        d: BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        p_d: Num<Fr>,
+    },
+}
+
+/// Input account and notes selected from the (optimistic) state.
+struct TxInputs<Fr: PrimeField> {
+    account: Account<Fr>,
+    account_index: Option<u64>,
+    notes: Vec<(u64, Note<Fr>)>,
+    spend_interval_index: u64,
+    delta_index: Num<Fr>,
+    value: Num<Fr>,
+    energy: Num<Fr>,
+}
+
+struct TxBalance<Fr: PrimeField> {
+    new_balance: Num<Fr>,
+    delta_value: Num<Fr>,
+    delta_energy: Num<Fr>,
+}
+
+#[cfg(feature = "native")]
+pub type NativeUserAccount<P> = UserAccount<NativeDatabase, P>;
+
+/// Length of the withdraw and holder addresses of the EVM pools.
+pub const EVM_ADDRESS_LENGTH: usize = 20;
+
+/// Domain separator of the deterministic address diversifiers.
+const DETERMINISTIC_ADDRESS_DOMAIN: &[u8] = b"zp_addr";
+
 pub struct UserAccount<D: KeyValueDB, P: PoolParams> {
     pub pool_id: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
     pub keys: Keys<P>,
     pub params: P,
     // TODO: Separate state from UserAccount, pass it as an argument to create_tx
     pub state: State<D, P>,
+    /// Expected length of the external addresses in withdraw and permittable deposit
+    /// transactions, [`EVM_ADDRESS_LENGTH`] by default.
+    pub address_length: usize,
+    /// Allows building transactions from a fresh account while the state is not bootstrapped
+    /// (see [`State::is_bootstrapped`]) and the pool already has transactions. Only safe if the
+    /// user has never transacted before.
+    pub allow_fresh_account: bool,
+    /// Warns about out notes sent to the addresses that are already in the address book of the
+    /// state, see [`UserAccount::record_outgoing_addresses`].
+    pub track_outgoing: bool,
+    /// Allows building a transaction whose nullifier is already in the nullifier ledger of the
+    /// state (see [`State::record_pending_nullifier`]), e.g. to resubmit a transaction that was
+    /// dropped by the relayer.
+    pub override_double_spend_check: bool,
+    /// Derives the owned zero notes that fill the unused input slots of a transaction from a
+    /// single diversifier instead of a fresh one for every note, which saves a scalar
+    /// multiplication per padding note. The padding notes of a transaction are then linkable to
+    /// each other inside the encrypted witness only, they never leave the prover.
+    pub fast_padding: bool,
     pub sign_callback: Option<Box<dyn Fn(&[u8]) -> Vec<u8>>>, // TODO: Find a way to make it async
 }
 
+/// Configures a [`UserAccount`] before it's created. The defaults are the ones of
+/// [`UserAccount::new`].
+pub struct UserAccountBuilder<P: PoolParams> {
+    pool_id: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    address_length: usize,
+    allow_fresh_account: bool,
+    track_outgoing: bool,
+    override_double_spend_check: bool,
+    fast_padding: bool,
+    sign_callback: Option<Box<dyn Fn(&[u8]) -> Vec<u8>>>,
+}
+
+impl<P: PoolParams> Default for UserAccountBuilder<P> {
+    fn default() -> Self {
+        UserAccountBuilder {
+            pool_id: BoundedNum::new(Num::ZERO),
+            address_length: EVM_ADDRESS_LENGTH,
+            allow_fresh_account: false,
+            track_outgoing: false,
+            override_double_spend_check: false,
+            fast_padding: false,
+            sign_callback: None,
+        }
+    }
+}
+
+impl<P: PoolParams> UserAccountBuilder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`UserAccount::new_with_pool`].
+    pub fn pool_id(
+        mut self,
+        pool_id: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    ) -> Self {
+        self.pool_id = pool_id;
+        self
+    }
+
+    /// See [`UserAccount::address_length`].
+    pub fn address_length(mut self, address_length: usize) -> Self {
+        self.address_length = address_length;
+        self
+    }
+
+    /// See [`UserAccount::allow_fresh_account`].
+    pub fn allow_fresh_account(mut self, allow: bool) -> Self {
+        self.allow_fresh_account = allow;
+        self
+    }
+
+    /// See [`UserAccount::track_outgoing`].
+    pub fn track_outgoing(mut self, track: bool) -> Self {
+        self.track_outgoing = track;
+        self
+    }
+
+    /// See [`UserAccount::override_double_spend_check`].
+    pub fn override_double_spend_check(mut self, allow: bool) -> Self {
+        self.override_double_spend_check = allow;
+        self
+    }
+
+    /// See [`UserAccount::fast_padding`].
+    pub fn fast_padding(mut self, fast: bool) -> Self {
+        self.fast_padding = fast;
+        self
+    }
+
+    pub fn sign_callback(mut self, callback: Box<dyn Fn(&[u8]) -> Vec<u8>>) -> Self {
+        self.sign_callback = Some(callback);
+        self
+    }
+
+    /// Creates the account from the spending key, see [`UserAccount::from_secret_key`].
+    pub fn build<D: KeyValueDB>(
+        self,
+        sk: impl Into<SecretKey<P::Fs>>,
+        state: State<D, P>,
+        params: P,
+    ) -> UserAccount<D, P> {
+        let keys = Keys::derive(sk.into(), &params);
+
+        UserAccount {
+            pool_id: self.pool_id,
+            keys,
+            state,
+            params,
+            address_length: self.address_length,
+            allow_fresh_account: self.allow_fresh_account,
+            track_outgoing: self.track_outgoing,
+            override_double_spend_check: self.override_double_spend_check,
+            fast_padding: self.fast_padding,
+            sign_callback: self.sign_callback,
+        }
+    }
+
+    /// Same as [`UserAccountBuilder::build`] but accepts arbitrary data as spending key, see
+    /// [`UserAccount::from_seed`].
+    pub fn build_from_seed<D: KeyValueDB>(
+        self,
+        seed: &[u8],
+        state: State<D, P>,
+        params: P,
+    ) -> UserAccount<D, P> {
+        self.build(reduce_sk::<P::Fs>(seed), state, params)
+    }
+}
+
 impl<'p, D, P> UserAccount<D, P>
 where
     D: KeyValueDB,
@@ -119,32 +806,92 @@ where
 {
     /// Initializes UserAccount with a spending key that has to be an element of the prime field Fs (p = 6554484396890773809930967563523245729705921265872317281365359162392183254199).
     pub fn new(sk: Num<P::Fs>, state: State<D, P>, params: P) -> Self {
-        let keys = Keys::derive(sk, &params);
+        Self::new_with_pool(sk, BoundedNum::new(Num::ZERO), state, params)
+    }
+
+    /// Same as constructor but for the pool with the specified id. The pool id is embedded into
+    /// the transaction deltas and is the diversifier of the initial account, so that transactions
+    /// can't be replayed in another pool.
+    pub fn new_with_pool(
+        sk: Num<P::Fs>,
+        pool_id: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        state: State<D, P>,
+        params: P,
+    ) -> Self {
+        Self::from_secret_key(sk.into(), pool_id, state, params)
+    }
+
+    /// Same as [`UserAccount::new_with_pool`], but takes the ownership of the spending key, so
+    /// that it's wiped from memory once the keys are derived.
+    pub fn from_secret_key(
+        sk: SecretKey<P::Fs>,
+        pool_id: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        state: State<D, P>,
+        params: P,
+    ) -> Self {
+        UserAccountBuilder::new()
+            .pool_id(pool_id)
+            .build(sk, state, params)
+    }
 
+    /// Initializes a watch-only UserAccount from the decryption key. Such an account can decrypt
+    /// notes and track the balance, but cannot create transactions.
+    pub fn watch_only(eta: Num<P::Fr>, state: State<D, P>, params: P) -> Self {
         UserAccount {
-            // For now it is constant, but later should be provided by user
             pool_id: BoundedNum::new(Num::ZERO),
-            keys,
+            keys: Keys::watch_only(eta),
             state,
             params,
+            address_length: EVM_ADDRESS_LENGTH,
+            allow_fresh_account: false,
+            track_outgoing: false,
+            override_double_spend_check: false,
+            fast_padding: false,
             sign_callback: None,
         }
     }
 
+    /// Wipes the spending key, turning the account into a watch-only one. The account can still
+    /// decrypt notes and track the balance, but [`UserAccount::create_tx`] fails with
+    /// [`CreateTxError::WatchOnly`].
+    pub fn forget_sk(&mut self) {
+        self.keys.forget_sk();
+    }
+
     /// Same as constructor but accepts arbitrary data as spending key.
     pub fn from_seed(seed: &[u8], state: State<D, P>, params: P) -> Self {
         let sk = reduce_sk(seed);
         Self::new(sk, state, params)
     }
 
+    /// Same as constructor but derives the spending key from a BIP-39 mnemonic, see
+    /// [`derive_from_mnemonic`].
+    pub fn from_mnemonic(
+        phrase: &str,
+        account_index: u32,
+        state: State<D, P>,
+        params: P,
+    ) -> Result<Self, KeyError> {
+        let sk = derive_from_mnemonic(phrase, account_index)?;
+        Ok(Self::new(sk, state, params))
+    }
+
     fn generate_address_components(
         &self,
     ) -> (
         BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
         Num<P::Fr>,
     ) {
-        let mut rng = CustomRng;
+        self.generate_address_components_with_rng(&mut CustomRng)
+    }
 
+    fn generate_address_components_with_rng<R: RngCore>(
+        &self,
+        rng: &mut R,
+    ) -> (
+        BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        Num<P::Fr>,
+    ) {
         let d: BoundedNum<_, { constants::DIVERSIFIER_SIZE_BITS }> = rng.gen();
         let pk_d = derive_key_p_d(d.to_num(), self.keys.eta, &self.params);
         (d, pk_d.x)
@@ -157,44 +904,150 @@ where
         format_address::<P>(d, p_d)
     }
 
+    /// Generates the address number `index` of the deterministic sequence and returns it along
+    /// with its diversifier. The diversifier is derived as
+    /// `keccak256(eta || "zp_addr" || index as u64 LE)` truncated to
+    /// [`constants::DIVERSIFIER_SIZE_BITS`], `eta` being derived from the spending key, so the
+    /// addresses can be regenerated after restoring the account (also by a watch-only one).
+    pub fn generate_address_deterministic(
+        &self,
+        index: u64,
+    ) -> (
+        String,
+        BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    ) {
+        let eta = self.keys.eta.try_to_vec().unwrap();
+        let data = [&eta, DETERMINISTIC_ADDRESS_DOMAIN, &index.to_le_bytes()].concat();
+        let hash = keccak256(&data);
+
+        let d_bytes = constants::DIVERSIFIER_SIZE_BITS / 8;
+        let d = BoundedNum::new(Num::from_uint_reduced(NumRepr(Uint::from_little_endian(
+            &hash[..d_bytes],
+        ))));
+        let p_d = derive_key_p_d(d.to_num(), self.keys.eta, &self.params).x;
+
+        (format_address::<P>(d, p_d), d)
+    }
+
+    /// Generates the deterministic addresses for every index of the range, see
+    /// [`UserAccount::generate_address_deterministic`].
+    pub fn list_deterministic_addresses(
+        &self,
+        range: Range<u64>,
+    ) -> Vec<(
+        String,
+        BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    )> {
+        range
+            .map(|index| self.generate_address_deterministic(index))
+            .collect()
+    }
+
     /// Attempts to decrypt notes.
-    pub fn decrypt_notes(&self, data: Vec<u8>) -> Vec<Option<Note<P::Fr>>> {
-        cipher::decrypt_in(self.keys.eta, &data, &self.params)
+    pub fn decrypt_notes(&self, data: &[u8]) -> Vec<Option<Note<P::Fr>>> {
+        let (data, _) = split_memo_text::<P::Fr>(data);
+        cipher::decrypt_in(self.keys.eta, data, &self.params)
+    }
+
+    /// Lazy version of [`UserAccount::decrypt_notes`] that borrows the memo. Yields one entry per
+    /// note declared in the memo header, notes that cannot be decrypted (including the ones cut
+    /// off by a truncated memo) are `None`. Nothing is yielded if the header itself is truncated.
+    pub fn decrypt_notes_iter<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = Option<Note<P::Fr>>> + 'a {
+        let (data, _) = split_memo_text::<P::Fr>(data);
+        let num_notes = parse_memo::<P::Fr>(data, None)
+            .map(|memo| memo.hashes.len().saturating_sub(1))
+            .unwrap_or(0);
+
+        // libzeropool decrypts all notes of a memo at once, which is bounded by OUT notes
+        std::iter::once_with(move || {
+            if num_notes == 0 {
+                vec![]
+            } else {
+                cipher::decrypt_in(self.keys.eta, data, &self.params)
+            }
+        })
+        .flatten()
+        .chain(std::iter::repeat(None))
+        .take(num_notes)
     }
 
     /// Attempts to decrypt account and notes.
-    pub fn decrypt_pair(&self, data: Vec<u8>) -> Option<(Account<P::Fr>, Vec<Note<P::Fr>>)> {
-        cipher::decrypt_out(self.keys.eta, &data, &self.params)
+    pub fn decrypt_pair(&self, data: &[u8]) -> Option<(Account<P::Fr>, Vec<Note<P::Fr>>)> {
+        let (data, _) = split_memo_text::<P::Fr>(data);
+        cipher::decrypt_out(self.keys.eta, data, &self.params)
     }
 
     pub fn is_own_address(&self, address: &str) -> bool {
-        let mut result = false;
-        if let Ok((d, p_d)) = parse_address::<P>(address) {
-            let own_p_d = derive_key_p_d(d.to_num(), self.keys.eta, &self.params).x;
-            result = own_p_d == p_d;
+        matches!(self.address_info(address), AddressInfo::Own { .. })
+    }
+
+    /// Parses the address and checks whether its public key is derived from the account keys.
+    pub fn address_info(&self, address: &str) -> AddressInfo<P::Fr> {
+        let (d, p_d) = match parse_address::<P>(address) {
+            Ok(components) => components,
+            Err(err) => return AddressInfo::Invalid(err),
+        };
+
+        let own_p_d = derive_key_p_d(d.to_num(), self.keys.eta, &self.params).x;
+        if own_p_d == p_d {
+            AddressInfo::Own { d, p_d }
+        } else {
+            AddressInfo::Foreign { d, p_d }
         }
+    }
+
+    /// Derives the address for the diversifier (a decimal number), so that a previously
+    /// generated address can be restored from its stored diversifier.
+    pub fn derive_address_for_d(&self, d: &str) -> Result<String, AddressParseError> {
+        let d = Num::<P::Fr>::from_str(d)
+            .ok()
+            .filter(|d| fits_in_bits(*d, constants::DIVERSIFIER_SIZE_BITS))
+            .ok_or_else(|| AddressParseError::InvalidDiversifier(d.to_owned()))?;
+        let p_d = derive_key_p_d(d, self.keys.eta, &self.params).x;
 
-        result
+        Ok(format_address::<P>(BoundedNum::new(d), p_d))
     }
 
-    /// Constructs a transaction.
-    pub fn create_tx(
+    /// Decodes a delta provided by the relayer, checking that it was made for the pool of this
+    /// account.
+    pub fn check_delta_pool_id(&self, delta: Num<P::Fr>) -> Result<Delta<P::Fr>, DeltaError> {
+        let delta = decode_delta(delta)?;
+        check_pool_id(&delta, self.pool_id.to_num())?;
+
+        Ok(delta)
+    }
+
+    /// Calculates the nullifier of an account located at the specified index.
+    pub fn account_nullifier(&self, account: &Account<P::Fr>, index: u64) -> Num<P::Fr> {
+        nullifier(
+            account.hash(&self.params),
+            self.keys.eta,
+            index.into(),
+            &self.params,
+        )
+    }
+
+    /// Calculates nullifiers for a batch of indexed accounts.
+    pub fn nullifiers_for_accounts(&self, accounts: &[(u64, Account<P::Fr>)]) -> Vec<Num<P::Fr>> {
+        accounts
+            .iter()
+            .map(|(index, account)| self.account_nullifier(account, *index))
+            .collect()
+    }
+
+    /// Selects the input account and notes for a transaction, including the optimistic state.
+    /// Notes are selected automatically unless `selected_notes` is provided.
+    fn select_inputs(
         &self,
-        tx: TxType<P::Fr>,
         delta_index: Option<u64>,
-        extra_state: Option<StateFragment<P::Fr>>,
-    ) -> Result<TransactionData<P::Fr>, CreateTxError> {
-        let mut rng = CustomRng;
-        let keys = self.keys.clone();
+        extra_state: &StateFragment<P::Fr>,
+        selected_notes: Option<Vec<(u64, Note<P::Fr>)>>,
+    ) -> TxInputs<P::Fr> {
         let state = &self.state;
 
-        let extra_state = extra_state.unwrap_or(StateFragment {
-            new_leafs: [].to_vec(),
-            new_commitments: [].to_vec(),
-            new_accounts: [].to_vec(),
-            new_notes: [].to_vec(),
-        });
-
         // initial input account (from optimistic state)
         let (in_account_optimistic_index, in_account_optimistic) = {
             let last_acc = extra_state.new_accounts.last();
@@ -220,8 +1073,6 @@ where
             })
         });
 
-        let tree = &state.tree;
-
         let in_account_index = in_account_optimistic_index.or(state.latest_account_index);
 
         // initial usable note index
@@ -236,84 +1087,25 @@ where
 
         // Should be provided by relayer together with note proofs, but as a fallback
         // take the next index of the tree (optimistic part included).
-        let delta_index = Num::from(delta_index.unwrap_or_else(|| {
-            let next_by_optimistic_leaf = extra_state.new_leafs.last().map(|leafs| {
-                (((leafs.0 + (leafs.1.len() as u64)) >> constants::OUTPLUSONELOG) + 1)
-                    << constants::OUTPLUSONELOG
-            });
-            let next_by_optimistic_commitment =
-                extra_state.new_commitments.last().map(|commitment| {
-                    ((commitment.0 >> constants::OUTPLUSONELOG) + 1) << constants::OUTPLUSONELOG
-                });
-            next_by_optimistic_leaf
+        let delta_index =
+            Num::from(delta_index.unwrap_or_else(|| self.next_tx_index_optimistic(extra_state)));
+
+        let in_notes_original: Vec<(u64, Note<P::Fr>)> = selected_notes.unwrap_or_else(|| {
+            // Optimistic available notes
+            let optimistic_available_notes = extra_state
+                .new_notes
+                .iter()
+                .copied()
+                .filter(|indexed_note| indexed_note.0 >= next_usable_index);
+
+            // Fetch constants::IN usable notes from state
+            state
+                .notes_from(next_usable_index, constants::IN)
                 .into_iter()
-                .chain(next_by_optimistic_commitment)
-                .max()
-                .unwrap_or(state.tree.next_index())
-        }));
-
-        let (fee, tx_data) = {
-            let mut tx_data: Vec<u8> = vec![];
-            match &tx {
-                TxType::Deposit { fee, .. } => {
-                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
-                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
-                    (fee, tx_data)
-                }
-                TxType::DepositPermittable {
-                    fee,
-                    deadline,
-                    holder,
-                    ..
-                } => {
-                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
-
-                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
-                    tx_data.write_all(&deadline.to_be_bytes()).unwrap();
-                    tx_data.append(&mut holder.clone());
-
-                    (fee, tx_data)
-                }
-                TxType::Transfer { fee, .. } => {
-                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
-                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
-                    (fee, tx_data)
-                }
-                TxType::Withdraw {
-                    fee,
-                    to,
-                    native_amount,
-                    ..
-                } => {
-                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
-                    let raw_native_amount: u64 = native_amount.to_num().try_into().unwrap();
-
-                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
-                    tx_data.write_all(&raw_native_amount.to_be_bytes()).unwrap();
-                    tx_data.append(&mut to.clone());
-
-                    (fee, tx_data)
-                }
-            }
-        };
-
-        // Optimistic available notes
-        let optimistic_available_notes = extra_state
-            .new_notes
-            .into_iter()
-            .filter(|indexed_note| indexed_note.0 >= next_usable_index);
-
-        // Fetch constants::IN usable notes from state
-        let in_notes_original: Vec<(u64, Note<P::Fr>)> = state
-            .txs
-            .iter_slice(next_usable_index..=state.latest_note_index)
-            .filter_map(|(index, tx)| match tx {
-                Transaction::Note(note) => Some((index, note)),
-                _ => None,
-            })
-            .chain(optimistic_available_notes)
-            .take(constants::IN)
-            .collect();
+                .chain(optimistic_available_notes)
+                .take(constants::IN)
+                .collect()
+        });
 
         let spend_interval_index = in_notes_original
             .last()
@@ -330,64 +1122,110 @@ where
             input_value += note.b.to_num();
         }
 
-        let mut output_value = Num::ZERO;
+        let in_account_pos = in_account_index.unwrap_or(0);
+
+        let mut input_energy = in_account.e.to_num();
+        input_energy += in_account.b.to_num() * (delta_index - Num::from(in_account_pos));
+
+        for (note_index, note) in &in_notes_original {
+            input_energy += note.b.to_num() * (delta_index - Num::from(*note_index));
+        }
+
+        TxInputs {
+            account: in_account,
+            account_index: in_account_index,
+            notes: in_notes_original,
+            spend_interval_index,
+            delta_index,
+            value: input_value,
+            energy: input_energy,
+        }
+    }
 
-        let (num_real_out_notes, out_notes) = match &tx {
-            TxType::Transfer { outputs, .. }
-            | TxType::Deposit { outputs, .. }
-            | TxType::DepositPermittable { outputs, .. } => {
-                if outputs.len() >= constants::OUT {
-                    return Err(CreateTxError::TooManyOutputs {
-                        max: constants::OUT,
-                        got: outputs.len(),
-                    });
+    /// Index of the slot the next transaction is expected to land in: the next index of the tree,
+    /// optimistic part included.
+    fn next_tx_index_optimistic(&self, extra_state: &StateFragment<P::Fr>) -> u64 {
+        let next_by_optimistic_leaf = extra_state.new_leafs.last().map(|leafs| {
+            (((leafs.0 + (leafs.1.len() as u64)) >> constants::OUTPLUSONELOG) + 1)
+                << constants::OUTPLUSONELOG
+        });
+        let next_by_optimistic_commitment = extra_state.new_commitments.last().map(|commitment| {
+            ((commitment.0 >> constants::OUTPLUSONELOG) + 1) << constants::OUTPLUSONELOG
+        });
+        next_by_optimistic_leaf
+            .into_iter()
+            .chain(next_by_optimistic_commitment)
+            .max()
+            .unwrap_or_else(|| self.state.tree.next_index())
+    }
+
+    /// Looks up notes with the specified indices (including the optimistic state), checking
+    /// that they can be spent. The result is sorted by index.
+    fn find_notes(
+        &self,
+        indices: &[u64],
+        extra_state: &StateFragment<P::Fr>,
+    ) -> Result<Vec<(u64, Note<P::Fr>)>, CreateTxError> {
+        if let Some(&index) = indices.get(constants::IN) {
+            return Err(CreateTxError::InvalidNoteIndex(index));
+        }
+
+        let next_usable_index = self
+            .state
+            .earliest_usable_index_optimistic(&extra_state.new_accounts, &extra_state.new_notes);
+
+        let mut notes = indices
+            .iter()
+            .map(|&index| {
+                if index < next_usable_index {
+                    return Err(CreateTxError::InvalidNoteIndex(index));
                 }
 
-                let out_notes = outputs
-                    .iter()
-                    .map(|dest| {
-                        let (to_d, to_p_d) = parse_address::<P>(&dest.to)?;
+                let note = match self.state.txs.get(index) {
+                    Some(Transaction::Note(note)) => Some(note),
+                    _ => extra_state
+                        .new_notes
+                        .iter()
+                        .find(|(note_index, _)| *note_index == index)
+                        .map(|(_, note)| *note),
+                };
+
+                note.map(|note| (index, note))
+                    .ok_or(CreateTxError::InvalidNoteIndex(index))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
-                        output_value += dest.amount.to_num();
+        notes.sort_by_key(|(index, _)| *index);
+        if let Some(pair) = notes.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(CreateTxError::InvalidNoteIndex(pair[0].0));
+        }
 
-                        Ok(Note {
-                            d: to_d,
-                            p_d: to_p_d,
-                            b: dest.amount,
-                            t: rng.gen(),
-                        })
-                    })
-                    // fill out remaining output notes with zeroes
-                    .chain((0..).map(|_| Ok(zero_note())))
-                    .take(constants::OUT)
-                    .collect::<Result<SizedVec<_, { constants::OUT }>, AddressParseError>>()?;
+        Ok(notes)
+    }
 
-                (outputs.len(), out_notes)
-            }
-            _ => (0, (0..).map(|_| zero_note()).take(constants::OUT).collect()),
-        };
+    /// Calculates the new account balance and the value and energy deltas of a transaction.
+    fn calc_balance(
+        tx: &TxType<P::Fr>,
+        inputs: &TxInputs<P::Fr>,
+    ) -> Result<TxBalance<P::Fr>, CreateTxError> {
+        let input_value = inputs.value;
+        let input_energy = inputs.energy;
+        let fee = tx.fee();
+        let output_value = tx.output_value();
 
         let mut delta_value = -fee.as_num();
         // By default all account energy will be withdrawn on withdraw tx
         let mut delta_energy = Num::ZERO;
 
-        let in_account_pos = in_account_index.unwrap_or(0);
-
-        let mut input_energy = in_account.e.to_num();
-        input_energy += in_account.b.to_num() * (delta_index - Num::from(in_account_pos));
-
-        for (note_index, note) in &in_notes_original {
-            input_energy += note.b.to_num() * (delta_index - Num::from(*note_index));
-        }
-        let new_balance = match &tx {
-            TxType::Transfer { .. } => {
+        let new_balance = match tx {
+            TxType::Transfer { .. } | TxType::TransferV2 { .. } => {
                 if input_value.to_uint() >= (output_value + fee.as_num()).to_uint() {
                     input_value - output_value - fee.as_num()
                 } else {
-                    return Err(CreateTxError::InsufficientBalance(
-                        (output_value + fee.as_num()).to_string(),
-                        input_value.to_string(),
-                    ));
+                    return Err(CreateTxError::InsufficientBalance {
+                        required: num_to_u128(output_value + fee.as_num())?,
+                        available: num_to_u128(input_value)?,
+                    });
                 }
             }
             TxType::Withdraw {
@@ -399,10 +1237,10 @@ where
                 let energy = energy_amount.to_num();
 
                 if energy.to_uint() > input_energy.to_uint() {
-                    return Err(CreateTxError::InsufficientEnergy(
-                        input_energy.to_string(),
-                        energy.to_string(),
-                    ));
+                    return Err(CreateTxError::InsufficientEnergy {
+                        required: num_to_u128(energy)?,
+                        available: num_to_u128(input_energy)?,
+                    });
                 }
 
                 delta_energy -= energy;
@@ -411,10 +1249,10 @@ where
                 if input_value.to_uint() >= amount.to_uint() {
                     input_value + delta_value
                 } else {
-                    return Err(CreateTxError::InsufficientBalance(
-                        delta_value.to_string(),
-                        input_value.to_string(),
-                    ));
+                    return Err(CreateTxError::InsufficientBalance {
+                        required: num_to_u128(-delta_value)?,
+                        available: num_to_u128(input_value)?,
+                    });
                 }
             }
             TxType::Deposit { deposit_amount, .. }
@@ -424,52 +1262,338 @@ where
                 if new_total_balance.to_uint() >= output_value.to_uint() {
                     new_total_balance - output_value
                 } else {
-                    return Err(CreateTxError::InsufficientBalance(
-                        output_value.to_string(),
-                        new_total_balance.to_string(),
+                    return Err(CreateTxError::InsufficientBalance {
+                        required: num_to_u128(output_value)?,
+                        available: num_to_u128(new_total_balance)?,
+                    });
+                }
+            }
+        };
+
+        Ok(TxBalance {
+            new_balance,
+            delta_value,
+            delta_energy,
+        })
+    }
+
+    /// Estimates the outcome of a transaction without constructing it. Uses the same input
+    /// selection as [`Self::create_tx`].
+    pub fn estimate_tx(
+        &self,
+        tx: &TxType<P::Fr>,
+        extra_state: Option<&StateFragment<P::Fr>>,
+    ) -> TxEstimate<P::Fr> {
+        let empty_state = StateFragment {
+            new_leafs: vec![],
+            new_commitments: vec![],
+            new_accounts: vec![],
+            new_notes: vec![],
+        };
+        let inputs = self.select_inputs(None, extra_state.unwrap_or(&empty_state), None);
+        let balance = Self::calc_balance(tx, &inputs);
+
+        TxEstimate {
+            in_notes_count: inputs.notes.len(),
+            new_balance: balance.as_ref().ok().map(|balance| balance.new_balance),
+            energy: inputs.energy,
+            insufficient_balance: matches!(balance, Err(CreateTxError::InsufficientBalance { .. })),
+            insufficient_energy: matches!(balance, Err(CreateTxError::InsufficientEnergy { .. })),
+        }
+    }
+
+    /// Constructs a transaction.
+    pub fn create_tx(
+        &self,
+        tx: TxType<P::Fr>,
+        delta_index: Option<u64>,
+        extra_state: Option<StateFragment<P::Fr>>,
+    ) -> Result<TransactionData<P::Fr>, CreateTxError> {
+        self.build_tx(tx, delta_index, extra_state, None, &mut CustomRng)
+    }
+
+    /// Constructs a transaction using the provided RNG for all random values (note salts,
+    /// diversifiers and encryption entropy). Given the same state and a deterministic RNG with
+    /// the same seed, the resulting transaction data is identical.
+    pub fn create_tx_with_rng<R: RngCore>(
+        &self,
+        tx: TxType<P::Fr>,
+        delta_index: Option<u64>,
+        extra_state: Option<StateFragment<P::Fr>>,
+        rng: &mut R,
+    ) -> Result<TransactionData<P::Fr>, CreateTxError> {
+        self.build_tx(tx, delta_index, extra_state, None, rng)
+    }
+
+    /// Constructs a transaction spending only the notes with the specified indices
+    /// (at most constants::IN).
+    pub fn create_tx_with_notes(
+        &self,
+        tx: TxType<P::Fr>,
+        delta_index: Option<u64>,
+        extra_state: Option<StateFragment<P::Fr>>,
+        note_indices: &[u64],
+    ) -> Result<TransactionData<P::Fr>, CreateTxError> {
+        self.build_tx(
+            tx,
+            delta_index,
+            extra_state,
+            Some(note_indices),
+            &mut CustomRng,
+        )
+    }
+
+    /// Checks the external fields of the transaction that are not verified by the circuit, so that
+    /// a transaction rejected by the contract is not proven.
+    fn validate_tx(&self, tx: &TxType<P::Fr>) -> Result<(), CreateTxError> {
+        match tx {
+            TxType::Withdraw {
+                withdraw_amount,
+                to,
+                native_amount,
+                energy_amount,
+                ..
+            } => {
+                if to.len() != self.address_length {
+                    return Err(CreateTxError::InvalidWithdrawAddress {
+                        expected: self.address_length,
+                        got: to.len(),
+                    });
+                }
+
+                if native_amount.to_num().to_uint() > withdraw_amount.to_num().to_uint() {
+                    return Err(CreateTxError::NativeAmountExceedsWithdraw(
+                        native_amount.to_num().to_string(),
+                        withdraw_amount.to_num().to_string(),
                     ));
                 }
+
+                if !fits_in_bits(energy_amount.to_num(), constants::BALANCE_SIZE_BITS) {
+                    return Err(CreateTxError::EnergyAmountTooLarge(
+                        energy_amount.to_num().to_string(),
+                    ));
+                }
+            }
+            TxType::DepositPermittable { holder, .. } => {
+                if holder.len() != self.address_length {
+                    return Err(CreateTxError::InvalidHolderAddress {
+                        expected: self.address_length,
+                        got: holder.len(),
+                    });
+                }
             }
+            TxType::Transfer { .. } | TxType::TransferV2 { .. } | TxType::Deposit { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    fn build_tx<R: RngCore>(
+        &self,
+        tx: TxType<P::Fr>,
+        delta_index: Option<u64>,
+        extra_state: Option<StateFragment<P::Fr>>,
+        note_indices: Option<&[u64]>,
+        rng: &mut R,
+    ) -> Result<TransactionData<P::Fr>, CreateTxError> {
+        let (sk, a) = match (self.keys.sk, self.keys.a) {
+            (Some(sk), Some(a)) => (sk, a),
+            _ => return Err(CreateTxError::WatchOnly),
         };
+        self.validate_tx(&tx)?;
 
-        let (d, p_d) = self.generate_address_components();
+        let keys = &self.keys;
+        let state = &self.state;
+
+        let extra_state = extra_state.unwrap_or(StateFragment {
+            new_leafs: [].to_vec(),
+            new_commitments: [].to_vec(),
+            new_accounts: [].to_vec(),
+            new_notes: [].to_vec(),
+        });
+
+        // A fresh account spent after the user has already transacted produces an invalid
+        // nullifier, and so does an outdated one if the local tree is behind the transaction
+        let local_index = self.next_tx_index_optimistic(&extra_state);
+        let pool_index = delta_index.unwrap_or(local_index);
+        if !self.allow_fresh_account
+            && pool_index > 0
+            && extra_state.new_accounts.is_empty()
+            && (!state.is_bootstrapped() || local_index < pool_index)
+        {
+            return Err(CreateTxError::StateNotSynced);
+        }
+
+        let selected_notes = note_indices
+            .map(|indices| self.find_notes(indices, &extra_state))
+            .transpose()?;
+        let inputs = self.select_inputs(delta_index, &extra_state, selected_notes);
+        let in_account = inputs.account;
+        let in_account_index = inputs.account_index;
+        let delta_index = inputs.delta_index;
+
+        let tree = &state.tree;
+
+        let tx_data = {
+            let mut tx_data: Vec<u8> = vec![];
+            match &tx {
+                TxType::Deposit { fee, .. } => {
+                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
+                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
+                    tx_data
+                }
+                TxType::DepositPermittable {
+                    fee,
+                    deadline,
+                    holder,
+                    ..
+                } => {
+                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
+
+                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
+                    tx_data.write_all(&deadline.to_be_bytes()).unwrap();
+                    tx_data.append(&mut holder.clone());
+
+                    tx_data
+                }
+                TxType::Transfer { fee, .. } | TxType::TransferV2 { fee, .. } => {
+                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
+                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
+                    tx_data
+                }
+                TxType::Withdraw {
+                    fee,
+                    to,
+                    native_amount,
+                    ..
+                } => {
+                    let raw_fee: u64 = fee.to_num().try_into().unwrap();
+                    let raw_native_amount: u64 = native_amount.to_num().try_into().unwrap();
+
+                    tx_data.write_all(&raw_fee.to_be_bytes()).unwrap();
+                    tx_data.write_all(&raw_native_amount.to_be_bytes()).unwrap();
+                    tx_data.append(&mut to.clone());
+
+                    tx_data
+                }
+            }
+        };
+
+        let (destinations, out_notes) = match &tx {
+            TxType::Transfer { outputs, .. }
+            | TxType::TransferV2 { outputs, .. }
+            | TxType::Deposit { outputs, .. }
+            | TxType::DepositPermittable { outputs, .. } => {
+                if outputs.len() >= constants::OUT {
+                    return Err(CreateTxError::TooManyOutputs {
+                        max: constants::OUT,
+                        got: outputs.len(),
+                    });
+                }
+
+                let destinations = outputs
+                    .iter()
+                    .map(|dest| {
+                        let (d, p_d) = parse_address::<P>(&dest.to)?;
+                        Ok(TxDestination { d, p_d })
+                    })
+                    .collect::<Result<Vec<_>, AddressParseError>>()?;
+
+                let out_notes: SizedVec<_, { constants::OUT }> = outputs
+                    .iter()
+                    .zip(&destinations)
+                    .map(|(dest, to)| Note {
+                        d: to.d,
+                        p_d: to.p_d,
+                        b: dest.amount,
+                        t: rng.gen(),
+                    })
+                    // fill out remaining output notes with zeroes
+                    .chain((0..).map(|_| zero_note()))
+                    .take(constants::OUT)
+                    .collect();
+
+                (destinations, out_notes)
+            }
+            _ => (
+                vec![],
+                (0..).map(|_| zero_note()).take(constants::OUT).collect(),
+            ),
+        };
+
+        let TxBalance {
+            new_balance,
+            delta_value,
+            delta_energy,
+        } = Self::calc_balance(&tx, &inputs)?;
+
+        // Adversarial state (e.g. notes at huge indices) can push the output account out of range
+        if !fits_in_bits(new_balance, constants::BALANCE_SIZE_BITS) {
+            return Err(CreateTxError::BalanceOverflow(new_balance.to_string()));
+        }
+
+        let new_energy = delta_energy + inputs.energy;
+        if !fits_in_bits(new_energy, constants::ENERGY_SIZE_BITS) {
+            return Err(CreateTxError::EnergyOverflow(new_energy.to_string()));
+        }
+
+        let summary = TxSummary {
+            input_value: inputs.value,
+            output_value: tx.output_value(),
+            fee: tx.fee().to_num(),
+            delta_value,
+            input_energy: inputs.energy,
+            remaining_energy: new_energy,
+            spent_note_indices: inputs.notes.iter().map(|(index, _)| *index).collect(),
+        };
+
+        let (d, p_d) = self.generate_address_components_with_rng(rng);
         let out_account = Account {
             d,
             p_d,
-            i: BoundedNum::new(Num::from(spend_interval_index)),
+            i: BoundedNum::new(Num::from(inputs.spend_interval_index)),
             b: BoundedNum::new(new_balance),
-            e: BoundedNum::new(delta_energy + input_energy),
+            e: BoundedNum::new(new_energy),
         };
 
         let in_account_hash = in_account.hash(&self.params);
-        let nullifier = nullifier(
-            in_account_hash,
-            keys.eta,
-            in_account_pos.into(),
-            &self.params,
-        );
+        let nullifier = self.account_nullifier(&in_account, in_account_index.unwrap_or(0));
+        if !self.override_double_spend_check {
+            if let Some(record) = state.nullifier_record(nullifier) {
+                return Err(CreateTxError::NullifierAlreadyUsed {
+                    pending: !record.spent,
+                });
+            }
+        }
 
         let ciphertext = {
             let entropy: [u8; 32] = rng.gen();
 
             // No need to include all the zero notes in the encrypted transaction
-            let out_notes = &out_notes[0..num_real_out_notes];
+            let out_notes = &out_notes[0..destinations.len()];
+
+            let mut ciphertext =
+                cipher::encrypt(&entropy, keys.eta, out_account, out_notes, &self.params);
+            if let TxType::TransferV2 {
+                encrypted_data: data,
+                ..
+            } = &tx
+            {
+                ciphertext.extend(memo::encrypt_memo_text(data, out_notes, rng, &self.params)?);
+            }
 
-            cipher::encrypt(&entropy, keys.eta, out_account, out_notes, &self.params)
+            ciphertext
         };
 
         // Hash input account + notes filling remaining space with non-hashed zeroes
-        let owned_zero_notes = (0..).map(|_| {
-            let d: BoundedNum<_, { constants::DIVERSIFIER_SIZE_BITS }> = rng.gen();
-            let p_d = derive_key_p_d::<P, P::Fr>(d.to_num(), keys.eta, &self.params).x;
-            Note {
-                d,
-                p_d,
-                b: BoundedNum::new(Num::ZERO),
-                t: rng.gen(),
-            }
-        });
-        let in_notes: SizedVec<Note<P::Fr>, { constants::IN }> = in_notes_original
+        let owned_zero_notes = padding_notes(
+            constants::IN.saturating_sub(inputs.notes.len()),
+            self.fast_padding,
+            rng,
+            |d| derive_key_p_d::<P, P::Fr>(d, keys.eta, &self.params).x,
+        );
+        let in_notes: SizedVec<Note<P::Fr>, { constants::IN }> = inputs
+            .notes
             .iter()
             .map(|(_, note)| note)
             .cloned()
@@ -531,12 +1655,18 @@ where
             memo,
         };
 
+        let warnings = if self.track_outgoing {
+            self.address_reuse_warnings(&tx, &destinations)
+        } else {
+            vec![]
+        };
+
         let tx = Tx {
             input: (in_account, in_notes),
             output: (out_account, out_notes),
         };
 
-        let (eddsa_s, eddsa_r) = tx_sign(keys.sk, tx_hash, &self.params);
+        let (eddsa_s, eddsa_r) = tx_sign(sk, tx_hash, &self.params);
 
         let account_proof = in_account_index.map_or_else(
             || Ok(zero_proof()),
@@ -545,23 +1675,20 @@ where
                     .ok_or(CreateTxError::ProofNotFound(i))
             },
         )?;
-        let note_proofs = in_notes_original
-            .iter()
-            .copied()
-            .map(|(index, _note)| {
-                tree.get_proof_optimistic_index(index, &mut virtual_nodes, &update_boundaries)
-                    .ok_or(CreateTxError::ProofNotFound(index))
-            })
-            .chain((0..).map(|_| Ok(zero_proof())))
+        let note_indices: Vec<_> = inputs.notes.iter().map(|(index, _)| *index).collect();
+        let note_proofs = tree
+            .get_proofs_optimistic_index(&note_indices, &mut virtual_nodes, &update_boundaries)
+            .into_iter()
+            .chain((0..).map(|_| zero_proof()))
             .take(constants::IN)
-            .collect::<Result<_, _>>()?;
+            .collect();
 
         let secret = TransferSec::<P::Fr> {
             tx,
             in_proof: (account_proof, note_proofs),
             eddsa_s: eddsa_s.to_other().unwrap(),
             eddsa_r,
-            eddsa_a: keys.a,
+            eddsa_a: a,
         };
 
         Ok(TransactionData {
@@ -571,15 +1698,248 @@ where
             memo: memo_data,
             commitment_root: out_commit,
             out_hashes,
+            summary: Some(summary),
+            warnings,
+            destinations,
+        })
+    }
+
+    /// Returns the warnings about the destinations that are already in the address book or
+    /// repeat an earlier output of the transaction.
+    fn address_reuse_warnings(
+        &self,
+        tx: &TxType<P::Fr>,
+        destinations: &[TxDestination<P::Fr>],
+    ) -> Vec<TxWarning> {
+        tx.outputs()
+            .iter()
+            .zip(destinations)
+            .enumerate()
+            .filter(|(i, (_, dest))| {
+                destinations[..*i].contains(dest)
+                    || self.state.outgoing_address_seen(dest.d, dest.p_d).is_some()
+            })
+            .map(|(_, (output, _))| TxWarning::AddressReuse {
+                to: output.to.clone(),
+            })
+            .collect()
+    }
+
+    /// Records [`TransactionData::destinations`] of a submitted transaction in the address book of
+    /// the state if [`UserAccount::track_outgoing`] is set. Building a transaction doesn't record
+    /// them, so that retries and failed transaction chains don't leave stale records.
+    pub fn record_outgoing_addresses(&mut self, destinations: &[TxDestination<P::Fr>]) {
+        if !self.track_outgoing {
+            return;
+        }
+
+        for dest in destinations {
+            self.state.record_outgoing_address(dest.d, dest.p_d, None);
+        }
+    }
+
+    /// Constructs a chain of transactions, every subsequent one is built on top of the optimistic
+    /// state of the previous ones: their output accounts, own output notes and leafs are added to
+    /// `extra_state`. The transactions are placed in consecutive slots starting at `delta_index`
+    /// (or the next index of the tree, optimistic part included).
+    pub fn create_txs(
+        &self,
+        txs: Vec<TxType<P::Fr>>,
+        delta_index: Option<u64>,
+        extra_state: Option<StateFragment<P::Fr>>,
+    ) -> Result<Vec<TransactionData<P::Fr>>, CreateTxError> {
+        let mut extra_state = extra_state.unwrap_or_default();
+        let mut tx_index =
+            delta_index.unwrap_or_else(|| self.next_tx_index_optimistic(&extra_state));
+        let mut tx_datas = Vec::with_capacity(txs.len());
+
+        for tx in txs {
+            let tx_data = self.create_tx(tx, Some(tx_index), Some(extra_state.clone()))?;
+
+            let (out_account, out_notes) = &tx_data.secret.tx.output;
+            let own_notes = out_notes
+                .iter()
+                .enumerate()
+                .filter(|(_, note)| note.b.to_num() != Num::ZERO)
+                .filter(|(_, note)| {
+                    derive_key_p_d(note.d.to_num(), self.keys.eta, &self.params).x == note.p_d
+                })
+                .map(|(i, note)| (tx_index + 1 + i as u64, *note));
+
+            extra_state.new_notes.extend(own_notes);
+            extra_state
+                .new_leafs
+                .push((tx_index, tx_data.out_hashes.as_slice().to_vec()));
+            extra_state.new_accounts.push((tx_index, *out_account));
+
+            tx_index += constants::OUT as u64 + 1;
+            tx_datas.push(tx_data);
+        }
+
+        Ok(tx_datas)
+    }
+
+    /// Constructs a chain of self-transfers that spend all usable notes into the account balance.
+    /// Every subsequent transaction is built on top of the optimistic state of the previous ones.
+    /// Returns no transactions if there are no usable notes.
+    pub fn create_aggregation_txs(
+        &self,
+        fee_per_tx: TokenAmount<P::Fr>,
+        max_txs: usize,
+    ) -> Result<Vec<TransactionData<P::Fr>>, CreateTxError> {
+        let num_notes = self.state.get_usable_notes().len();
+        let required = (num_notes + constants::IN - 1) / constants::IN;
+
+        if required > max_txs {
+            return Err(CreateTxError::TooManyAggregationTxs {
+                max: max_txs,
+                required,
+            });
+        }
+
+        let txs = (0..required)
+            .map(|_| TxType::Transfer {
+                fee: fee_per_tx,
+                outputs: vec![],
+            })
+            .collect();
+
+        self.create_txs(txs, None, None)
+    }
+
+    /// Plans the transfers that move the whole spendable balance to `new_account_address`, e.g. an
+    /// address of a fresh spending key. Every transfer spends up to `constants::IN` usable notes
+    /// (the first one also the account balance) and sends everything except `fee_per_tx` to the
+    /// address, in the order they are selected by [`Self::create_txs`].
+    pub fn plan_key_rotation(
+        &self,
+        new_account_address: &str,
+        fee_per_tx: TokenAmount<P::Fr>,
+    ) -> Result<Vec<TxType<P::Fr>>, CreateTxError> {
+        match self.address_info(new_account_address) {
+            AddressInfo::Invalid(err) => return Err(err.into()),
+            AddressInfo::Own { .. } => return Err(CreateTxError::RotationToOwnAddress),
+            AddressInfo::Foreign { .. } => {}
+        }
+
+        let account_balance = self
+            .state
+            .latest_account
+            .map_or(Num::ZERO, |account| account.b.to_num());
+        let notes = self.state.get_usable_notes();
+        if account_balance == Num::ZERO && notes.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut chunks: Vec<&[(u64, Note<P::Fr>)]> = notes.chunks(constants::IN).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let fee = fee_per_tx.to_num();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let initial = if i == 0 { account_balance } else { Num::ZERO };
+                let available = chunk
+                    .iter()
+                    .fold(initial, |sum, (_, note)| sum + note.b.to_num());
+
+                if available.to_uint() < fee.to_uint() {
+                    return Err(CreateTxError::InsufficientBalance {
+                        required: num_to_u128(fee)?,
+                        available: num_to_u128(available)?,
+                    });
+                }
+
+                Ok(TxType::Transfer {
+                    fee: fee_per_tx,
+                    outputs: vec![TxOutput {
+                        to: new_account_address.to_string(),
+                        amount: BoundedNum::new(available - fee),
+                    }],
+                })
+            })
+            .collect()
+    }
+}
+
+/// Owned zero notes that fill the unused input slots. Every note gets its own diversifier unless
+/// `shared` is set, in which case `derive_p_d` is evaluated at most once and the random `t` keeps
+/// the hashes of the notes distinct.
+fn padding_notes<Fr: PrimeField, R: RngCore>(
+    count: usize,
+    shared: bool,
+    rng: &mut R,
+    mut derive_p_d: impl FnMut(Num<Fr>) -> Num<Fr>,
+) -> Vec<Note<Fr>> {
+    let mut address = None;
+
+    (0..count)
+        .map(|_| {
+            let (d, p_d) = match address {
+                Some(address) if shared => address,
+                _ => {
+                    let d: BoundedNum<_, { constants::DIVERSIFIER_SIZE_BITS }> = rng.gen();
+                    let p_d = derive_p_d(d.to_num());
+                    address = Some((d, p_d));
+                    (d, p_d)
+                }
+            };
+
+            Note {
+                d,
+                p_d,
+                b: BoundedNum::new(Num::ZERO),
+                t: rng.gen(),
+            }
         })
+        .collect()
+}
+
+/// Converts the amount to `u128`. Only the amounts of an adversarial state don't fit.
+fn num_to_u128<Fr: PrimeField>(num: Num<Fr>) -> Result<u128, CreateTxError> {
+    let bytes = num.to_uint().0.to_little_endian();
+    let (low, high) = bytes.split_at(16);
+
+    if high.iter().any(|&b| b != 0) {
+        Err(CreateTxError::AmountOverflow(num.to_string()))
+    } else {
+        Ok(u128::from_le_bytes(low.try_into().unwrap()))
     }
 }
 
+fn fits_in_bits<Fr: PrimeField>(num: Num<Fr>, bits: usize) -> bool {
+    let bytes = num.to_uint().0.to_little_endian();
+
+    bytes.iter().enumerate().all(|(i, &byte)| {
+        let low_bit = i * 8;
+        if low_bit >= bits {
+            byte == 0
+        } else if low_bit + 8 > bits {
+            byte >> (bits - low_bit) == 0
+        } else {
+            true
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use libzeropool::POOL_PARAMS;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use libzeropool::{
+        fawkes_crypto::native::poseidon::PoseidonParams, native::params::PoolBN256, POOL_PARAMS,
+    };
 
     use super::*;
+    use crate::{client::state::NullifierRecord, random::SeededRng};
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
 
     #[test]
     fn test_create_tx_deposit_zero() {
@@ -664,25 +2024,1782 @@ mod tests {
     }
 
     #[test]
-    fn test_user_account_is_own_address() {
-        let acc_1 = UserAccount::new(
-            Num::ZERO,
+    fn test_create_tx_transfer_encrypted_data() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let transfer = |encrypted_data: Vec<u8>| TxType::TransferV2 {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::ZERO),
+            }],
+            encrypted_data,
+        };
+
+        let data = acc
+            .create_tx(transfer(vec![1; memo::MAX_MEMO_TEXT_SIZE]), None, None)
+            .unwrap();
+        let (ciphertext, memo_text) = split_memo_text::<Fr>(&data.ciphertext);
+        assert_eq!(ciphertext.len(), memo::ciphertext_size::<Fr>(1));
+        assert!(memo_text.is_some());
+
+        assert!(matches!(
+            acc.create_tx(transfer(vec![1; memo::MAX_MEMO_TEXT_SIZE + 1]), None, None),
+            Err(CreateTxError::EncryptedData(MemoTextError::TooLong { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_create_aggregation_txs() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let num_notes = 2 * constants::IN + 3;
+        let notes: Vec<(u64, Note<_>)> = (1..=num_notes as u64)
+            .map(|index| {
+                let (d, p_d) = acc.generate_address_components();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(10u64)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = [Num::ZERO]
+            .iter()
+            .copied()
+            .chain(notes.iter().map(|(_, note)| note.hash(&acc.params)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+
+        assert!(matches!(
+            acc.create_aggregation_txs(BoundedNum::new(Num::ONE), 2),
+            Err(CreateTxError::TooManyAggregationTxs {
+                max: 2,
+                required: 3
+            })
+        ));
+
+        let txs = acc
+            .create_aggregation_txs(BoundedNum::new(Num::ONE), 5)
+            .unwrap();
+        assert_eq!(txs.len(), 3);
+
+        let spent_notes: usize = txs
+            .iter()
+            .map(|tx| {
+                tx.secret
+                    .tx
+                    .input
+                    .1
+                    .iter()
+                    .filter(|note| note.b.to_num() != Num::ZERO)
+                    .count()
+            })
+            .sum();
+        assert_eq!(spent_notes, num_notes);
+
+        let final_account = txs.last().unwrap().secret.tx.output.0;
+        assert_eq!(
+            final_account.b.to_num(),
+            Num::from(10 * num_notes as u64 - txs.len() as u64)
+        );
+    }
+
+    #[test]
+    fn test_plan_key_rotation() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let new_acc = UserAccount::new(
+            Num::ONE,
             State::init_test(POOL_PARAMS.clone()),
             POOL_PARAMS.clone(),
         );
-        let acc_2 = UserAccount::new(
+
+        let num_notes = 2 * constants::IN;
+        let notes: Vec<(u64, Note<_>)> = (1..=num_notes as u64)
+            .map(|index| {
+                let (d, p_d) = acc.generate_address_components();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(index * 10)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = [Num::ZERO]
+            .iter()
+            .copied()
+            .chain(notes.iter().map(|(_, note)| note.hash(&acc.params)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+        let balance: u64 = (1..=num_notes as u64).map(|index| index * 10).sum();
+
+        let fee = BoundedNum::new(Num::from(3u64));
+        let address = new_acc.generate_address();
+        let plan = acc.plan_key_rotation(&address, fee).unwrap();
+        assert_eq!(plan.len(), 2);
+
+        let total = plan.iter().fold(Num::ZERO, |sum, tx| match tx {
+            TxType::Transfer {
+                fee: tx_fee,
+                outputs,
+                ..
+            } => {
+                assert_eq!(tx_fee.to_num(), fee.to_num());
+                assert_eq!(outputs.len(), 1);
+                assert_eq!(outputs[0].to, address);
+                sum + outputs[0].amount.to_num()
+            }
+            _ => panic!("expected a transfer"),
+        });
+        assert_eq!(total, Num::from(balance - 2 * 3));
+
+        let txs = acc.create_txs(plan, None, None).unwrap();
+        assert_eq!(txs.len(), 2);
+
+        assert!(matches!(
+            acc.plan_key_rotation(&acc.generate_address(), fee),
+            Err(CreateTxError::RotationToOwnAddress)
+        ));
+        assert!(matches!(
+            acc.plan_key_rotation("invalid", fee),
+            Err(CreateTxError::AddressParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_aggregation_txs_no_notes() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let txs = acc
+            .create_aggregation_txs(BoundedNum::new(Num::ZERO), 0)
+            .unwrap();
+        assert!(txs.is_empty());
+    }
+
+    #[test]
+    fn test_create_txs_chained() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let foreign_acc = UserAccount::new(
             Num::ONE,
             State::init_test(POOL_PARAMS.clone()),
             POOL_PARAMS.clone(),
         );
 
-        let address_1 = acc_1.generate_address();
-        let address_2 = acc_2.generate_address();
+        let (d, p_d) = acc.generate_address_components();
+        let note = Note {
+            d,
+            p_d,
+            b: BoundedNum::new(Num::from(10u64)),
+            t: CustomRng.gen(),
+        };
+        acc.state
+            .add_full_tx(0, &[Num::ZERO, note.hash(&acc.params)], None, &[(1, note)]);
+        let root_before = acc.state.tree.get_root();
 
-        assert!(acc_1.is_own_address(&address_1));
-        assert!(acc_2.is_own_address(&address_2));
+        let output = |acc: &UserAccount<_, _>, amount: u64| TxOutput {
+            to: acc.generate_address(),
+            amount: BoundedNum::new(Num::from(amount)),
+        };
+        let txs = vec![
+            // change of 4 stays in the account, 2 are sent back to self as a note
+            TxType::Transfer {
+                fee: BoundedNum::new(Num::ONE),
+                outputs: vec![output(&foreign_acc, 3), output(&acc, 2)],
+            },
+            // spends the account and the note created by the first transaction
+            TxType::Transfer {
+                fee: BoundedNum::new(Num::ONE),
+                outputs: vec![output(&foreign_acc, 5)],
+            },
+        ];
+        let txs = acc.create_txs(txs, None, None).unwrap();
+        assert_eq!(txs.len(), 2);
+
+        let first_account = txs[0].secret.tx.output.0;
+        assert_eq!(first_account.b.to_num(), Num::from(4u64));
+        assert_eq!(txs[0].public.root, root_before);
+        assert_eq!(txs[0].summary.as_ref().unwrap().spent_note_indices, vec![1]);
+
+        let second = &txs[1];
+        assert_eq!(
+            second.secret.tx.input.0.hash(&acc.params),
+            first_account.hash(&acc.params)
+        );
+        assert_eq!(
+            second.summary.as_ref().unwrap().spent_note_indices,
+            vec![130]
+        );
+        assert_eq!(second.secret.tx.output.0.b.to_num(), Num::ZERO);
+        let delta = crate::delta::decode_delta(second.public.delta).unwrap();
+        assert_eq!(delta.index, 256);
+
+        // the proofs of the second transaction were built from the virtual subtree
+        acc.state.add_full_tx(
+            128,
+            txs[0].out_hashes.as_slice(),
+            Some(first_account),
+            &[(130, txs[0].secret.tx.output.1[1])],
+        );
+        assert_eq!(second.public.root, acc.state.tree.get_root());
+
+        let (account_proof, note_proofs) = &second.secret.in_proof;
+        let expected = acc.state.tree.get_leaf_proof(128).unwrap();
+        assert!(account_proof.sibling.iter().eq(expected.sibling.iter()));
+        assert!(account_proof.path.iter().eq(expected.path.iter()));
+        let expected = acc.state.tree.get_leaf_proof(130).unwrap();
+        assert!(note_proofs[0].sibling.iter().eq(expected.sibling.iter()));
+        assert!(note_proofs[0].path.iter().eq(expected.path.iter()));
+    }
 
-        assert!(!acc_1.is_own_address(&address_2));
-        assert!(!acc_2.is_own_address(&address_1));
+    #[test]
+    fn test_estimate_tx_balance_boundary() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let (d, p_d) = acc.generate_address_components();
+        let note = Note {
+            d,
+            p_d,
+            b: BoundedNum::new(Num::from(10u64)),
+            t: CustomRng.gen(),
+        };
+        acc.state
+            .add_full_tx(0, &[Num::ZERO, note.hash(&acc.params)], None, &[(1, note)]);
+
+        let transfer = |amount: u64| TxType::Transfer {
+            fee: BoundedNum::new(Num::ONE),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::from(amount)),
+            }],
+        };
+
+        let tx = transfer(9);
+        let estimate = acc.estimate_tx(&tx, None);
+        assert_eq!(estimate.in_notes_count, 1);
+        assert_eq!(estimate.new_balance, Some(Num::ZERO));
+        assert!(!estimate.insufficient_balance);
+        assert!(!estimate.insufficient_energy);
+        assert_eq!(
+            acc.create_tx(tx, None, None)
+                .unwrap()
+                .secret
+                .tx
+                .output
+                .0
+                .e
+                .to_num(),
+            estimate.energy
+        );
+
+        let tx = transfer(10);
+        let estimate = acc.estimate_tx(&tx, None);
+        assert_eq!(estimate.in_notes_count, 1);
+        assert_eq!(estimate.new_balance, None);
+        assert!(estimate.insufficient_balance);
+        assert!(matches!(
+            acc.create_tx(tx, None, None),
+            Err(CreateTxError::InsufficientBalance {
+                required: 11,
+                available: 10,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_create_tx_with_rng_deterministic() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::from(10u64)),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::from(5u64)),
+            }],
+        };
+        let create = |seed: u64| {
+            acc.create_tx_with_rng(deposit(), None, None, &mut SeededRng(seed))
+                .unwrap()
+        };
+
+        let first = create(1);
+        let second = create(1);
+        assert_eq!(first.memo, second.memo);
+        assert_eq!(first.ciphertext, second.ciphertext);
+        assert_eq!(first.public.root, second.public.root);
+        assert_eq!(first.public.nullifier, second.public.nullifier);
+        assert_eq!(first.public.out_commit, second.public.out_commit);
+        assert_eq!(first.public.delta, second.public.delta);
+        assert_eq!(first.public.memo, second.public.memo);
+        assert_eq!(first.out_hashes.as_slice(), second.out_hashes.as_slice());
+        assert_eq!(first.secret.eddsa_s, second.secret.eddsa_s);
+        assert_eq!(first.secret.eddsa_r, second.secret.eddsa_r);
+
+        let other = create(2);
+        assert_ne!(first.ciphertext, other.ciphertext);
+        assert_ne!(first.public.out_commit, other.public.out_commit);
+        assert_ne!(first.out_hashes.as_slice(), other.out_hashes.as_slice());
+    }
+
+    #[test]
+    fn test_spendable_balance_excludes_dust() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let notes: Vec<(u64, Note<_>)> = [(1u64, 1u64), (2, 5), (3, 10)]
+            .iter()
+            .map(|&(index, amount)| {
+                let (d, p_d) = acc.generate_address_components();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(amount)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = [Num::ZERO]
+            .iter()
+            .copied()
+            .chain(notes.iter().map(|(_, note)| note.hash(&acc.params)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+
+        for &(min_amount, expected_balance, expected_dust) in &[
+            (0u64, 16u64, 0usize),
+            (1, 16, 0),
+            (5, 15, 1),
+            (10, 10, 2),
+            (11, 0, 3),
+        ] {
+            let min_amount = BoundedNum::new(Num::from(min_amount));
+            let balance = acc.state.spendable_balance(min_amount);
+            assert_eq!(balance, Num::from(expected_balance));
+            assert_eq!(acc.state.dust_notes(min_amount).len(), expected_dust);
+
+            let tx = TxType::Transfer {
+                fee: BoundedNum::new(Num::ZERO),
+                outputs: vec![TxOutput {
+                    to: acc.generate_address(),
+                    amount: BoundedNum::new(balance),
+                }],
+            };
+            assert!(acc.create_tx(tx, None, None).is_ok());
+        }
+
+        let dust: Vec<_> = acc
+            .state
+            .dust_notes(BoundedNum::new(Num::from(10u64)))
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(dust, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_create_tx_with_notes() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let notes: Vec<(u64, Note<_>)> = [1u64, 2]
+            .iter()
+            .map(|&index| {
+                let (d, p_d) = acc.generate_address_components();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(index * 10)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = [Num::ZERO]
+            .iter()
+            .copied()
+            .chain(notes.iter().map(|(_, note)| note.hash(&acc.params)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+
+        let transfer = || TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![],
+        };
+
+        assert!(matches!(
+            acc.create_tx_with_notes(transfer(), None, None, &[3]),
+            Err(CreateTxError::InvalidNoteIndex(3))
+        ));
+        assert!(matches!(
+            acc.create_tx_with_notes(transfer(), None, None, &[1, 1]),
+            Err(CreateTxError::InvalidNoteIndex(1))
+        ));
+
+        // spend interval is based on the highest selected note
+        let tx = acc
+            .create_tx_with_notes(transfer(), None, None, &[2])
+            .unwrap();
+        let out_account = tx.secret.tx.output.0;
+        assert_eq!(
+            tx.secret.tx.input.1[0].hash(&acc.params),
+            notes[1].1.hash(&acc.params)
+        );
+        assert_eq!(out_account.b.to_num(), Num::from(20u64));
+        assert_eq!(out_account.i.to_num(), Num::from(3u64));
+
+        // the unselected note stays usable after the tx is applied
+        let tx = acc
+            .create_tx_with_notes(transfer(), None, None, &[1])
+            .unwrap();
+        let out_account = tx.secret.tx.output.0;
+        assert_eq!(out_account.b.to_num(), Num::from(10u64));
+        acc.state
+            .add_full_tx(128, tx.out_hashes.as_slice(), Some(out_account), &[]);
+        let usable_indices: Vec<_> = acc
+            .state
+            .get_usable_notes()
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(usable_indices, vec![2]);
+    }
+
+    #[test]
+    fn test_create_tx_summary() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let notes: Vec<(u64, Note<_>)> = [1u64, 2]
+            .iter()
+            .map(|&index| {
+                let (d, p_d) = acc.generate_address_components();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(index * 10)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = [Num::ZERO]
+            .iter()
+            .copied()
+            .chain(notes.iter().map(|(_, note)| note.hash(&acc.params)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+
+        let tx = TxType::Transfer {
+            fee: BoundedNum::new(Num::from(3u64)),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::from(5u64)),
+            }],
+        };
+        let tx = acc.create_tx(tx, Some(128), None).unwrap();
+
+        // 10 * (128 - 1) + 20 * (128 - 2)
+        let input_energy = Num::from(3790u64);
+        assert_eq!(
+            tx.summary,
+            Some(TxSummary {
+                input_value: Num::from(30u64),
+                output_value: Num::from(5u64),
+                fee: Num::from(3u64),
+                delta_value: -Num::from(3u64),
+                input_energy,
+                remaining_energy: input_energy,
+                spent_note_indices: vec![1, 2],
+            })
+        );
+        assert_eq!(tx.secret.tx.output.0.e.to_num(), input_energy);
+        assert_eq!(tx.secret.tx.output.0.b.to_num(), Num::from(22u64));
+
+        let decoded = TransactionData::<Fr>::from_bytes(&tx.to_bytes()).unwrap();
+        assert!(decoded.summary.is_none());
+    }
+
+    #[test]
+    fn test_energy_estimate_matches_create_tx() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+
+        let (d, p_d) = acc.generate_address_components();
+        let account = Account {
+            d,
+            p_d,
+            i: BoundedNum::new(Num::ZERO),
+            b: BoundedNum::new(Num::from(7u64)),
+            e: BoundedNum::new(Num::from(4u64)),
+        };
+        let note = |b: u64| {
+            let (d, p_d) = acc.generate_address_components();
+            Note {
+                d,
+                p_d,
+                b: BoundedNum::new(Num::from(b)),
+                t: CustomRng.gen(),
+            }
+        };
+        let first_notes = vec![(1, note(10)), (2, note(20))];
+        let second_notes = vec![(129, note(5))];
+        let hashes = |notes: &[(u64, Note<Fr>)]| {
+            [Num::ZERO]
+                .iter()
+                .copied()
+                .chain(notes.iter().map(|(_, note)| note.hash(&*POOL_PARAMS)))
+                .collect::<Vec<_>>()
+        };
+        acc.state
+            .add_full_tx(0, &hashes(&first_notes), Some(account), &first_notes);
+        acc.state
+            .add_full_tx(128, &hashes(&second_notes), None, &second_notes);
+
+        let tx = TxType::Transfer {
+            fee: BoundedNum::new(Num::from(3u64)),
+            outputs: vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::from(5u64)),
+            }],
+        };
+        let tx = acc.create_tx(tx, Some(256), None).unwrap();
+
+        // 4 + 7 * 256 + 10 * (256 - 1) + 20 * (256 - 2) + 5 * (256 - 129)
+        let expected = Num::from(10061u64);
+        assert_eq!(tx.summary.unwrap().input_energy, expected);
+        assert_eq!(acc.state.energy_estimate(256), expected);
+
+        let breakdown = acc.state.balance_breakdown();
+        let energies: Vec<_> = breakdown
+            .iter()
+            .map(|info| (info.index, info.amount, info.energy))
+            .collect();
+        assert_eq!(
+            energies,
+            vec![
+                (1, Num::from(10u64), Num::from(2550u64)),
+                (2, Num::from(20u64), Num::from(5080u64)),
+                (129, Num::from(5u64), Num::from(635u64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_account_nullifier_matches_create_tx() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+
+        let tx = acc.create_tx(deposit(), None, None).unwrap();
+        assert_eq!(
+            acc.account_nullifier(&tx.secret.tx.input.0, 0),
+            tx.public.nullifier
+        );
+
+        let out_account = tx.secret.tx.output.0;
+        acc.state
+            .add_full_tx(0, tx.out_hashes.as_slice(), Some(out_account), &[]);
+
+        let tx = acc.create_tx(deposit(), None, None).unwrap();
+        let nullifiers = acc.nullifiers_for_accounts(&[(0, out_account), (128, out_account)]);
+        assert_eq!(nullifiers[0], tx.public.nullifier);
+        assert_ne!(nullifiers[1], tx.public.nullifier);
+    }
+
+    #[test]
+    fn test_create_tx_nullifier_ledger() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+
+        let tx = acc.create_tx(deposit(), None, None).unwrap();
+        let nullifier = tx.public.nullifier;
+        acc.state
+            .record_pending_nullifier(nullifier, "job-1".to_string());
+        assert!(matches!(
+            acc.create_tx(deposit(), None, None),
+            Err(CreateTxError::NullifierAlreadyUsed { pending: true })
+        ));
+
+        acc.override_double_spend_check = true;
+        assert_eq!(
+            acc.create_tx(deposit(), None, None)
+                .unwrap()
+                .public
+                .nullifier,
+            nullifier
+        );
+        acc.override_double_spend_check = false;
+
+        acc.state.mark_nullifier_spent(nullifier);
+        assert_eq!(
+            acc.state.nullifier_record(nullifier),
+            Some(NullifierRecord {
+                job_ref: Some("job-1".to_string()),
+                spent: true,
+            })
+        );
+        assert!(matches!(
+            acc.create_tx(deposit(), None, None),
+            Err(CreateTxError::NullifierAlreadyUsed { pending: false })
+        ));
+
+        // Once the mined transaction is synced, the next one spends the new account
+        let out_account = tx.secret.tx.output.0;
+        acc.state
+            .add_full_tx(0, tx.out_hashes.as_slice(), Some(out_account), &[]);
+        let next = acc.create_tx(deposit(), None, None).unwrap();
+        assert_ne!(next.public.nullifier, nullifier);
+        assert!(acc.state.nullifier_record(next.public.nullifier).is_none());
+    }
+
+    #[test]
+    fn test_create_tx_pool_id() {
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+        let account = |pool_id: u64| {
+            UserAccount::new_with_pool(
+                Num::from(1u64),
+                BoundedNum::new(Num::from(pool_id)),
+                State::init_test(POOL_PARAMS.clone()),
+                POOL_PARAMS.clone(),
+            )
+        };
+        let first = account(1);
+        let second = account(2);
+
+        let first_tx = first.create_tx(deposit(), None, None).unwrap();
+        let second_tx = second.create_tx(deposit(), None, None).unwrap();
+
+        assert_ne!(first_tx.public.delta, second_tx.public.delta);
+        assert_eq!(first_tx.secret.tx.input.0.d.to_num(), Num::from(1u64));
+        assert_eq!(second_tx.secret.tx.input.0.d.to_num(), Num::from(2u64));
+
+        let delta = first.check_delta_pool_id(first_tx.public.delta).unwrap();
+        assert_eq!(delta.pool_id, Num::from(1u64));
+        assert_eq!(delta.v, 1);
+        assert!(matches!(
+            first.check_delta_pool_id(second_tx.public.delta),
+            Err(DeltaError::PoolIdMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_user_account_builder() {
+        let acc = UserAccountBuilder::new()
+            .pool_id(BoundedNum::new(Num::from(7u64)))
+            .address_length(32)
+            .track_outgoing(true)
+            .build(
+                Num::from(1u64),
+                State::init_test(POOL_PARAMS.clone()),
+                POOL_PARAMS.clone(),
+            );
+
+        let tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::ONE),
+                    outputs: vec![TxOutput {
+                        to: acc.generate_address(),
+                        amount: BoundedNum::new(Num::ONE),
+                    }],
+                },
+                None,
+                None,
+            )
+            .unwrap();
+        let delta = acc.check_delta_pool_id(tx.public.delta).unwrap();
+        assert_eq!(delta.pool_id, Num::from(7u64));
+        assert_eq!(acc.state.list_outgoing_addresses().len(), 1);
+
+        let withdraw = |to: Vec<u8>| TxType::Withdraw {
+            fee: BoundedNum::new(Num::ZERO),
+            withdraw_amount: BoundedNum::new(Num::ZERO),
+            to,
+            native_amount: BoundedNum::new(Num::ZERO),
+            energy_amount: BoundedNum::new(Num::ZERO),
+        };
+        assert!(matches!(
+            acc.create_tx(withdraw(vec![0; EVM_ADDRESS_LENGTH]), None, None),
+            Err(CreateTxError::InvalidWithdrawAddress {
+                expected: 32,
+                got: EVM_ADDRESS_LENGTH
+            })
+        ));
+        assert!(acc.create_tx(withdraw(vec![0; 32]), None, None).is_ok());
+    }
+
+    #[test]
+    fn test_user_account_builder_defaults() {
+        let legacy = UserAccount::new(
+            Num::from(1u64),
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let built = UserAccountBuilder::new().build(
+            Num::from(1u64),
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+
+        assert_eq!(built.pool_id, legacy.pool_id);
+        assert_eq!(built.keys.eta, legacy.keys.eta);
+        assert_eq!(built.address_length, legacy.address_length);
+        assert_eq!(built.allow_fresh_account, legacy.allow_fresh_account);
+        assert_eq!(built.track_outgoing, legacy.track_outgoing);
+        assert_eq!(
+            built.override_double_spend_check,
+            legacy.override_double_spend_check
+        );
+        assert_eq!(built.fast_padding, legacy.fast_padding);
+        assert!(built.sign_callback.is_none());
+
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+        let a = legacy
+            .create_tx_with_rng(deposit(), None, None, &mut SeededRng(1))
+            .unwrap();
+        let b = built
+            .create_tx_with_rng(deposit(), None, None, &mut SeededRng(1))
+            .unwrap();
+        assert_transaction_data_eq(&a, &b);
+    }
+
+    #[test]
+    fn test_create_tx_after_restore() {
+        use crate::tx_parser::{parse_txs, IndexedTx};
+
+        let sk = Num::from(1u64);
+        let deposit = || TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+
+        let mut acc = UserAccount::new(
+            sk,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let mut txs = vec![];
+        for index in [0u64, 128] {
+            let tx = acc.create_tx(deposit(), Some(index), None).unwrap();
+            let indexed_tx = IndexedTx {
+                index,
+                memo: tx.ciphertext,
+                commitment: tx.public.out_commit,
+            };
+            let result = parse_txs(acc.keys.eta, &[indexed_tx.clone()], &*POOL_PARAMS);
+            acc.state
+                .apply_update(result.state_update.into(), index + 128);
+            txs.push(indexed_tx);
+        }
+
+        // The local tree is behind the transaction
+        assert!(matches!(
+            acc.create_tx(deposit(), Some(384), None),
+            Err(CreateTxError::StateNotSynced)
+        ));
+
+        // Restored from the seed, nothing is synced yet
+        let mut restored = UserAccount::new(
+            sk,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        assert!(!restored.state.is_bootstrapped());
+        assert!(matches!(
+            restored.create_tx(deposit(), Some(256), None),
+            Err(CreateTxError::StateNotSynced)
+        ));
+
+        let result = parse_txs(restored.keys.eta, &txs, &*POOL_PARAMS);
+        let (latest_index, latest_account) = result.latest_own_account.unwrap();
+        assert_eq!(latest_index, 128);
+        restored.state.apply_update(result.state_update.into(), 256);
+        assert!(restored.state.is_bootstrapped());
+
+        let tx = restored.create_tx(deposit(), Some(256), None).unwrap();
+        assert_eq!(
+            tx.secret.tx.input.0.hash(&*POOL_PARAMS),
+            latest_account.hash(&*POOL_PARAMS)
+        );
+        assert_eq!(
+            restored.account_nullifier(&latest_account, latest_index),
+            tx.public.nullifier
+        );
+    }
+
+    fn assert_transaction_data_eq(a: &TransactionData<Fr>, b: &TransactionData<Fr>) {
+        let params = &*POOL_PARAMS;
+
+        assert_eq!(a.public.root, b.public.root);
+        assert_eq!(a.public.nullifier, b.public.nullifier);
+        assert_eq!(a.public.out_commit, b.public.out_commit);
+        assert_eq!(a.public.delta, b.public.delta);
+        assert_eq!(a.public.memo, b.public.memo);
+
+        let (a_tx, b_tx) = (&a.secret.tx, &b.secret.tx);
+        assert_eq!(a_tx.input.0.hash(params), b_tx.input.0.hash(params));
+        assert_eq!(a_tx.output.0.hash(params), b_tx.output.0.hash(params));
+        let note_hashes =
+            |notes: &[Note<Fr>]| -> Vec<_> { notes.iter().map(|note| note.hash(params)).collect() };
+        assert_eq!(
+            note_hashes(a_tx.input.1.as_slice()),
+            note_hashes(b_tx.input.1.as_slice())
+        );
+        assert_eq!(
+            note_hashes(a_tx.output.1.as_slice()),
+            note_hashes(b_tx.output.1.as_slice())
+        );
+
+        let proofs = |data: &TransactionData<Fr>| -> Vec<(Vec<Num<Fr>>, Vec<bool>)> {
+            std::iter::once(&data.secret.in_proof.0)
+                .chain(data.secret.in_proof.1.iter())
+                .map(|proof| {
+                    (
+                        proof.sibling.as_slice().to_vec(),
+                        proof.path.as_slice().to_vec(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(proofs(a), proofs(b));
+        assert_eq!(a.secret.eddsa_s, b.secret.eddsa_s);
+        assert_eq!(a.secret.eddsa_r, b.secret.eddsa_r);
+        assert_eq!(a.secret.eddsa_a, b.secret.eddsa_a);
+
+        assert_eq!(a.ciphertext, b.ciphertext);
+        assert_eq!(a.memo, b.memo);
+        assert_eq!(a.commitment_root, b.commitment_root);
+        assert_eq!(a.out_hashes.as_slice(), b.out_hashes.as_slice());
+    }
+
+    #[test]
+    fn test_transaction_data_bytes_roundtrip() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let outputs = || {
+            vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::ZERO),
+            }]
+        };
+
+        let txs = vec![
+            TxType::Deposit {
+                fee: BoundedNum::new(Num::ZERO),
+                deposit_amount: BoundedNum::new(Num::ONE),
+                outputs: outputs(),
+            },
+            TxType::DepositPermittable {
+                fee: BoundedNum::new(Num::ZERO),
+                deposit_amount: BoundedNum::new(Num::ONE),
+                deadline: 1_000_000,
+                holder: vec![1; 20],
+                outputs: outputs(),
+            },
+            TxType::Transfer {
+                fee: BoundedNum::new(Num::ZERO),
+                outputs: outputs(),
+            },
+            TxType::Withdraw {
+                fee: BoundedNum::new(Num::ZERO),
+                withdraw_amount: BoundedNum::new(Num::ZERO),
+                to: vec![2; 20],
+                native_amount: BoundedNum::new(Num::ZERO),
+                energy_amount: BoundedNum::new(Num::ZERO),
+            },
+        ];
+
+        for tx in txs {
+            let data = acc.create_tx(tx, None, None).unwrap();
+            let bytes = data.to_bytes();
+            let decoded = TransactionData::from_bytes(&bytes).unwrap();
+
+            assert_transaction_data_eq(&data, &decoded);
+            assert_eq!(decoded.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_verify_out_commitment() {
+        let acc = UserAccount::new(
+            Num::ZERO,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let mut tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::ONE),
+                    outputs: vec![TxOutput {
+                        to: acc.generate_address(),
+                        amount: BoundedNum::new(Num::ONE),
+                    }],
+                },
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(tx.verify_out_commitment(&*POOL_PARAMS));
+        assert_eq!(
+            crate::utils::commitment_from_leaves(&tx.out_hashes.as_slice()[..2], &*POOL_PARAMS),
+            tx.commitment_root
+        );
+
+        tx.commitment_root += Num::ONE;
+        assert!(!tx.verify_out_commitment(&*POOL_PARAMS));
+    }
+
+    #[test]
+    fn test_transaction_data_from_bytes_errors() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let tx = TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+        let mut bytes = acc.create_tx(tx, None, None).unwrap().to_bytes();
+
+        assert!(TransactionData::<Fr>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        bytes[0] = TRANSACTION_DATA_VERSION + 1;
+        assert!(TransactionData::<Fr>::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_create_tx_withdraw_validation() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let withdraw = |to: Vec<u8>, withdraw_amount, native_amount| TxType::Withdraw {
+            fee: BoundedNum::new(Num::ZERO),
+            withdraw_amount: BoundedNum::new(withdraw_amount),
+            to,
+            native_amount: BoundedNum::new(native_amount),
+            energy_amount: BoundedNum::new(Num::ZERO),
+        };
+
+        let res = acc.create_tx(withdraw(vec![2; 19], Num::ZERO, Num::ZERO), None, None);
+        assert!(matches!(
+            res,
+            Err(CreateTxError::InvalidWithdrawAddress {
+                expected: 20,
+                got: 19
+            })
+        ));
+
+        let res = acc.create_tx(withdraw(vec![2; 20], Num::ONE, Num::from(2u64)), None, None);
+        assert!(matches!(
+            res,
+            Err(CreateTxError::NativeAmountExceedsWithdraw(..))
+        ));
+
+        acc.address_length = 32;
+        let res = acc.create_tx(withdraw(vec![2; 20], Num::ZERO, Num::ZERO), None, None);
+        assert!(matches!(
+            res,
+            Err(CreateTxError::InvalidWithdrawAddress {
+                expected: 32,
+                got: 20
+            })
+        ));
+        assert!(acc
+            .create_tx(withdraw(vec![2; 32], Num::ZERO, Num::ZERO), None, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_create_tx_deposit_permittable_holder_validation() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let tx = TxType::DepositPermittable {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            deadline: 1_000_000,
+            holder: vec![1; 19],
+            outputs: vec![],
+        };
+
+        assert!(matches!(
+            acc.create_tx(tx, None, None),
+            Err(CreateTxError::InvalidHolderAddress {
+                expected: 20,
+                got: 19
+            })
+        ));
+    }
+
+    #[test]
+    fn test_create_tx_overflow() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        acc.allow_fresh_account = true;
+        let pow2 = |n: u32| (0..n).fold(Num::<Fr>::ONE, |acc, _| acc + acc);
+
+        let new_note = |amount: Num<Fr>| {
+            let (d, p_d) = acc.generate_address_components();
+            Note {
+                d,
+                p_d,
+                b: BoundedNum::new(amount),
+                t: CustomRng.gen(),
+            }
+        };
+        let max_note = new_note(pow2(constants::BALANCE_SIZE_BITS as u32) - Num::ONE);
+        let far_note = new_note(Num::ONE);
+
+        // Note index beyond the delta index wraps the energy around the field
+        let extra_state = StateFragment {
+            new_notes: vec![(1 << 40, far_note)],
+            ..Default::default()
+        };
+        let transfer = TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![],
+        };
+        assert!(matches!(
+            acc.create_tx(transfer, Some(128), Some(extra_state)),
+            Err(CreateTxError::EnergyOverflow(_))
+        ));
+
+        acc.state.add_full_tx(
+            0,
+            &[Num::ZERO, max_note.hash(&acc.params)],
+            None,
+            &[(1, max_note)],
+        );
+        let deposit = TxType::Deposit {
+            fee: BoundedNum::new(Num::ZERO),
+            deposit_amount: BoundedNum::new(Num::ONE),
+            outputs: vec![],
+        };
+        assert!(matches!(
+            acc.create_tx(deposit, None, None),
+            Err(CreateTxError::BalanceOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_num_to_u128_overflow() {
+        assert_eq!(num_to_u128(Num::<Fr>::from(42u64)).unwrap(), 42);
+        assert!(matches!(
+            num_to_u128(-Num::<Fr>::ONE),
+            Err(CreateTxError::AmountOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_tx_error_serialize() {
+        use serde_json::json;
+
+        let cases = vec![
+            (
+                CreateTxError::TooManyOutputs { max: 127, got: 128 },
+                json!({ "code": "TOO_MANY_OUTPUTS", "max": 127, "got": 128 }),
+            ),
+            (
+                CreateTxError::ProofNotFound(5),
+                json!({ "code": "PROOF_NOT_FOUND", "index": 5 }),
+            ),
+            (
+                CreateTxError::AddressParseError(AddressParseError::InvalidChecksum),
+                json!({ "code": "INVALID_ADDRESS" }),
+            ),
+            (
+                CreateTxError::InsufficientBalance {
+                    required: u128::MAX,
+                    available: 10,
+                },
+                json!({
+                    "code": "INSUFFICIENT_BALANCE",
+                    "required": u128::MAX.to_string(),
+                    "available": "10",
+                }),
+            ),
+            (
+                CreateTxError::InsufficientEnergy {
+                    required: 20,
+                    available: 10,
+                },
+                json!({ "code": "INSUFFICIENT_ENERGY", "required": "20", "available": "10" }),
+            ),
+            (
+                CreateTxError::InvalidNoteIndex(3),
+                json!({ "code": "INVALID_NOTE_INDEX", "index": 3 }),
+            ),
+            (
+                CreateTxError::TooManyAggregationTxs {
+                    max: 1,
+                    required: 2,
+                },
+                json!({ "code": "TOO_MANY_AGGREGATION_TXS", "max": 1, "required": 2 }),
+            ),
+            (
+                CreateTxError::InvalidWithdrawAddress {
+                    expected: 20,
+                    got: 19,
+                },
+                json!({ "code": "INVALID_WITHDRAW_ADDRESS", "expected": 20, "got": 19 }),
+            ),
+            (
+                CreateTxError::InvalidHolderAddress {
+                    expected: 20,
+                    got: 0,
+                },
+                json!({ "code": "INVALID_HOLDER_ADDRESS", "expected": 20, "got": 0 }),
+            ),
+            (
+                CreateTxError::NativeAmountExceedsWithdraw("2".to_owned(), "1".to_owned()),
+                json!({
+                    "code": "NATIVE_AMOUNT_EXCEEDS_WITHDRAW",
+                    "nativeAmount": "2",
+                    "withdrawAmount": "1",
+                }),
+            ),
+            (
+                CreateTxError::EnergyAmountTooLarge("1".to_owned()),
+                json!({ "code": "ENERGY_AMOUNT_TOO_LARGE", "amount": "1" }),
+            ),
+            (
+                CreateTxError::BalanceOverflow("1".to_owned()),
+                json!({ "code": "BALANCE_OVERFLOW", "balance": "1" }),
+            ),
+            (
+                CreateTxError::EnergyOverflow("1".to_owned()),
+                json!({ "code": "ENERGY_OVERFLOW", "energy": "1" }),
+            ),
+            (
+                CreateTxError::AmountOverflow("1".to_owned()),
+                json!({ "code": "AMOUNT_OVERFLOW", "amount": "1" }),
+            ),
+            (CreateTxError::WatchOnly, json!({ "code": "WATCH_ONLY" })),
+            (
+                CreateTxError::StateNotSynced,
+                json!({ "code": "STATE_NOT_SYNCED" }),
+            ),
+        ];
+
+        for (err, mut expected) in cases {
+            expected["message"] = json!(err.to_string());
+            assert_eq!(serde_json::to_value(&err).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fits_in_bits() {
+        let pow2 = |n: u32| (0..n).fold(Num::<Fr>::ONE, |acc, _| acc + acc);
+
+        assert!(fits_in_bits(Num::<Fr>::ZERO, 64));
+        assert!(fits_in_bits(pow2(64) - Num::ONE, 64));
+        assert!(!fits_in_bits(pow2(64), 64));
+        assert!(fits_in_bits(pow2(4) - Num::ONE, 4));
+        assert!(!fits_in_bits(pow2(4), 4));
+    }
+
+    #[test]
+    fn test_address_info() {
+        let acc = UserAccount::new(
+            Num::ZERO,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let foreign_acc = UserAccount::new(
+            Num::ONE,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+
+        let address = acc.generate_address();
+        let (d, p_d) = match acc.address_info(&address) {
+            AddressInfo::Own { d, p_d } => (d, p_d),
+            info => panic!("Unexpected address info: {:?}", info),
+        };
+        assert_eq!(format_address::<PoolBN256>(d, p_d), address);
+
+        // Re-derivation from the stored diversifier
+        let derived = acc.derive_address_for_d(&d.to_num().to_string()).unwrap();
+        assert_eq!(derived, address);
+        assert!(acc.derive_address_for_d("not a number").is_err());
+        let too_large =
+            (0..constants::DIVERSIFIER_SIZE_BITS).fold(Num::<Fr>::ONE, |acc, _| acc + acc);
+        assert!(matches!(
+            acc.derive_address_for_d(&too_large.to_string()),
+            Err(AddressParseError::InvalidDiversifier(_))
+        ));
+
+        assert!(matches!(
+            acc.address_info(&foreign_acc.generate_address()),
+            AddressInfo::Foreign { .. }
+        ));
+
+        // Malformed checksum
+        let mut bytes = bs58::decode(&address).into_vec().unwrap();
+        bytes[45] ^= 1;
+        let malformed = bs58::encode(bytes).into_string();
+        assert!(matches!(
+            acc.address_info(&malformed),
+            AddressInfo::Invalid(AddressParseError::InvalidChecksum)
+        ));
+        assert!(!acc.is_own_address(&malformed));
+    }
+
+    #[test]
+    fn test_user_account_is_own_address() {
+        let acc_1 = UserAccount::new(
+            Num::ZERO,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let acc_2 = UserAccount::new(
+            Num::ONE,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+
+        let address_1 = acc_1.generate_address();
+        let address_2 = acc_2.generate_address();
+
+        assert!(acc_1.is_own_address(&address_1));
+        assert!(acc_2.is_own_address(&address_2));
+
+        assert!(!acc_1.is_own_address(&address_2));
+        assert!(!acc_2.is_own_address(&address_1));
+    }
+
+    #[test]
+    fn test_generate_address_deterministic() {
+        let acc_1 = UserAccount::new(
+            Num::from(42),
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let acc_2 = UserAccount::new(
+            Num::from(42),
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+
+        let (address_1, d_1) = acc_1.generate_address_deterministic(7);
+        let (address_2, d_2) = acc_2.generate_address_deterministic(7);
+        assert_eq!(address_1, address_2);
+        assert_eq!(d_1.to_num(), d_2.to_num());
+        let derived = acc_1
+            .derive_address_for_d(&d_1.to_num().to_string())
+            .unwrap();
+        assert_eq!(derived, address_1);
+
+        let (address_3, d_3) = acc_1.generate_address_deterministic(8);
+        assert_ne!(address_1, address_3);
+        assert_ne!(d_1.to_num(), d_3.to_num());
+
+        let addresses = acc_2.list_deterministic_addresses(5..10);
+        assert_eq!(addresses.len(), 5);
+        assert_eq!(addresses[2].0, address_1);
+        assert_eq!(addresses[2].1.to_num(), d_1.to_num());
+        assert_eq!(addresses[3].0, address_3);
+        for (address, _) in &addresses {
+            assert!(acc_1.is_own_address(address));
+            assert!(acc_2.is_own_address(address));
+        }
+
+        let foreign = UserAccount::new(
+            Num::ONE,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        assert!(!foreign.is_own_address(&addresses[0].0));
+        assert_ne!(foreign.generate_address_deterministic(7).0, addresses[2].0);
+    }
+
+    #[test]
+    fn test_watch_only_account() {
+        let acc = UserAccount::new(
+            Num::ONE,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let mut watch = UserAccount::watch_only(
+            acc.keys.eta,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        assert!(watch.keys.is_watch_only());
+        assert!(!acc.keys.is_watch_only());
+
+        let address = acc.generate_address();
+        assert!(watch.is_own_address(&address));
+        assert!(acc.is_own_address(&watch.generate_address()));
+
+        let tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::from(2u64)),
+                    outputs: vec![TxOutput {
+                        to: address.clone(),
+                        amount: BoundedNum::new(Num::ONE),
+                    }],
+                },
+                None,
+                None,
+            )
+            .unwrap();
+
+        let (account, notes) = watch.decrypt_pair(&tx.ciphertext).unwrap();
+        let params = &*POOL_PARAMS;
+        assert_eq!(account.hash(params), tx.out_hashes.as_slice()[0]);
+        assert_eq!(notes[0].hash(params), tx.out_hashes.as_slice()[1]);
+
+        let in_notes = watch.decrypt_notes(&tx.ciphertext);
+        assert_eq!(
+            in_notes[0].map(|note| note.hash(params)),
+            Some(tx.out_hashes.as_slice()[1])
+        );
+
+        let own_notes = vec![(1, notes[0])];
+        watch
+            .state
+            .add_full_tx(0, tx.out_hashes.as_slice(), Some(account), &own_notes);
+        assert_eq!(watch.state.total_balance(), Num::from(2u64));
+
+        let res = watch.create_tx(
+            TxType::Transfer {
+                fee: BoundedNum::new(Num::ZERO),
+                outputs: vec![TxOutput {
+                    to: address,
+                    amount: BoundedNum::new(Num::ONE),
+                }],
+            },
+            None,
+            None,
+        );
+        assert!(matches!(res, Err(CreateTxError::WatchOnly)));
+    }
+
+    #[test]
+    fn test_memo_size_hint() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ONE, state, POOL_PARAMS.clone());
+        let zero = || BoundedNum::new(Num::ZERO);
+
+        for num_outputs in 0..4 {
+            let outputs = || {
+                (0..num_outputs)
+                    .map(|_| TxOutput {
+                        to: acc.generate_address(),
+                        amount: zero(),
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let mut txs = vec![
+                TxType::Deposit {
+                    fee: zero(),
+                    deposit_amount: zero(),
+                    outputs: outputs(),
+                },
+                TxType::DepositPermittable {
+                    fee: zero(),
+                    deposit_amount: zero(),
+                    deadline: 1_700_000_000,
+                    holder: vec![1; EVM_ADDRESS_LENGTH],
+                    outputs: outputs(),
+                },
+                TxType::Transfer {
+                    fee: zero(),
+                    outputs: outputs(),
+                },
+            ];
+            if num_outputs == 0 {
+                txs.push(TxType::Withdraw {
+                    fee: zero(),
+                    withdraw_amount: zero(),
+                    to: vec![2; EVM_ADDRESS_LENGTH],
+                    native_amount: zero(),
+                    energy_amount: zero(),
+                });
+            }
+
+            for tx in txs {
+                let hint = tx.memo_size_hint(num_outputs);
+                let data = acc.create_tx(tx, None, None).unwrap();
+                assert_eq!(data.memo.len(), hint);
+            }
+        }
+    }
+
+    #[test]
+    fn test_address_reuse_warning() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ONE, state, POOL_PARAMS.clone());
+        let receiver = UserAccount::new(
+            Num::from(2u64),
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let address = receiver.generate_address();
+        let tx = || TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![TxOutput {
+                to: address.clone(),
+                amount: BoundedNum::new(Num::ZERO),
+            }],
+        };
+
+        let untracked = acc.create_tx(tx(), None, None).unwrap();
+        assert!(untracked.warnings.is_empty());
+        acc.record_outgoing_addresses(&untracked.destinations);
+        assert!(acc.state.list_outgoing_addresses().is_empty());
+
+        acc.track_outgoing = true;
+        let first = acc.create_tx(tx(), None, None).unwrap();
+        assert!(first.warnings.is_empty());
+        // building alone doesn't record the destinations
+        assert!(acc.state.list_outgoing_addresses().is_empty());
+        let retry = acc.create_tx(tx(), None, None).unwrap();
+        assert!(retry.warnings.is_empty());
+
+        acc.record_outgoing_addresses(&first.destinations);
+        acc.record_outgoing_addresses(&retry.destinations);
+        let second = acc.create_tx(tx(), None, None).unwrap();
+        assert_eq!(
+            second.warnings,
+            vec![TxWarning::AddressReuse {
+                to: address.clone()
+            }]
+        );
+
+        let (d, p_d) = parse_address::<PoolBN256>(&address).unwrap();
+        assert_eq!(acc.state.outgoing_address_seen(d, p_d), Some(2));
+        assert_eq!(acc.state.outgoing_address_seen(d, p_d + Num::ONE), None);
+
+        acc.state
+            .record_outgoing_address(d, p_d, Some("friend".to_string()));
+        acc.state.record_outgoing_address(d, p_d, None);
+        let addresses = acc.state.list_outgoing_addresses();
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].p_d, p_d);
+        assert_eq!(addresses[0].count, 4);
+        assert_eq!(addresses[0].label.as_deref(), Some("friend"));
+    }
+
+    #[test]
+    fn test_forget_sk() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let mut acc = UserAccount::new(Num::ONE, state, POOL_PARAMS.clone());
+        let address = acc.generate_address();
+        let tx = || TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![],
+        };
+        assert!(acc.create_tx(tx(), None, None).is_ok());
+
+        acc.forget_sk();
+        assert!(acc.keys.is_watch_only());
+        assert!(acc.is_own_address(&address));
+        assert!(matches!(
+            acc.create_tx(tx(), None, None),
+            Err(CreateTxError::WatchOnly)
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_notes_iter() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ONE, state, POOL_PARAMS.clone());
+        let address = acc.generate_address();
+
+        let tx = acc
+            .create_tx(
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::ZERO),
+                    deposit_amount: BoundedNum::new(Num::ZERO),
+                    outputs: (0..100)
+                        .map(|_| TxOutput {
+                            to: address.clone(),
+                            amount: BoundedNum::new(Num::ZERO),
+                        })
+                        .collect(),
+                },
+                None,
+                None,
+            )
+            .unwrap();
+
+        let params = &*POOL_PARAMS;
+        let hash_all = |notes: Vec<Option<Note<Fr>>>| -> Vec<_> {
+            notes
+                .into_iter()
+                .map(|note| note.map(|note| note.hash(params)))
+                .collect()
+        };
+
+        let notes = acc.decrypt_notes(&tx.ciphertext);
+        let notes_iter: Vec<_> = acc.decrypt_notes_iter(&tx.ciphertext).collect();
+        assert_eq!(notes.len(), 100);
+        assert!(notes.iter().all(Option::is_some));
+        assert_eq!(hash_all(notes_iter), hash_all(notes));
+
+        // The last note is cut off
+        let truncated = &tx.ciphertext[..tx.ciphertext.len() - 10];
+        let notes_iter: Vec<_> = acc.decrypt_notes_iter(truncated).collect();
+        assert_eq!(notes_iter.len(), 100);
+        assert!(notes_iter[99].is_none());
+
+        // Only a part of the header is left
+        assert_eq!(acc.decrypt_notes_iter(&tx.ciphertext[..10]).count(), 0);
+    }
+
+    #[test]
+    fn test_padding_notes() {
+        let mut rng = CustomRng;
+        let params = &*POOL_PARAMS;
+        let eta = Num::from(7u64);
+        let derive = |d| derive_key_p_d::<PoolBN256, Fr>(d, eta, params).x;
+
+        for &shared in &[false, true] {
+            let notes = padding_notes::<Fr, _>(constants::IN - 1, shared, &mut rng, derive);
+            assert_eq!(notes.len(), constants::IN - 1);
+            for note in notes.iter() {
+                assert_eq!(note.b.to_num(), Num::ZERO);
+                assert_eq!(note.p_d, derive_key_p_d(note.d.to_num(), eta, params).x);
+            }
+
+            let hashes: std::collections::HashSet<_> =
+                notes.iter().map(|note| note.hash(params)).collect();
+            assert_eq!(hashes.len(), notes.len());
+
+            let diversifiers: std::collections::HashSet<_> =
+                notes.iter().map(|note| note.d.to_num()).collect();
+            assert_eq!(diversifiers.len(), if shared { 1 } else { notes.len() });
+        }
+
+        assert!(padding_notes::<Fr, _>(0, false, &mut rng, derive).is_empty());
+    }
+
+    /// Counts the uses of the `hash` Poseidon parameters, which hash the diversifier of every
+    /// derived address.
+    #[derive(Clone)]
+    struct CountingParams {
+        inner: PoolBN256,
+        hashes: Arc<AtomicUsize>,
+    }
+
+    impl PoolParams for CountingParams {
+        type Fr = <PoolBN256 as PoolParams>::Fr;
+        type Fs = <PoolBN256 as PoolParams>::Fs;
+        type J = <PoolBN256 as PoolParams>::J;
+
+        fn jubjub(&self) -> &Self::J {
+            self.inner.jubjub()
+        }
+
+        fn hash(&self) -> &PoseidonParams<Self::Fr> {
+            self.hashes.fetch_add(1, Ordering::SeqCst);
+            self.inner.hash()
+        }
+
+        fn compress(&self) -> &PoseidonParams<Self::Fr> {
+            self.inner.compress()
+        }
+
+        fn sponge(&self) -> &PoseidonParams<Self::Fr> {
+            self.inner.sponge()
+        }
+
+        fn note(&self) -> &PoseidonParams<Self::Fr> {
+            self.inner.note()
+        }
+
+        fn account(&self) -> &PoseidonParams<Self::Fr> {
+            self.inner.account()
+        }
+
+        fn eddsa(&self) -> &PoseidonParams<Self::Fr> {
+            self.inner.eddsa()
+        }
+    }
+
+    #[test]
+    fn test_fast_padding_derives_once() {
+        let hashes = Arc::new(AtomicUsize::new(0));
+        let params = CountingParams {
+            inner: POOL_PARAMS.clone(),
+            hashes: hashes.clone(),
+        };
+        let mut acc = UserAccount::new(Num::ZERO, State::init_test(params.clone()), params.clone());
+
+        let (d, p_d) = acc.generate_address_components();
+        let note = Note {
+            d,
+            p_d,
+            b: BoundedNum::new(Num::from(10u64)),
+            t: CustomRng.gen(),
+        };
+        acc.state
+            .add_full_tx(0, &[Num::ZERO, note.hash(&params)], None, &[(1, note)]);
+
+        let count_hashes = |f: &dyn Fn()| {
+            hashes.store(0, Ordering::SeqCst);
+            f();
+            hashes.load(Ordering::SeqCst)
+        };
+        let per_address = count_hashes(&|| {
+            derive_key_p_d::<CountingParams, Fr>(Num::ONE, acc.keys.eta, &params);
+        });
+        assert!(per_address > 0);
+
+        let transfer = || TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![],
+        };
+        let distinct = count_hashes(&|| {
+            acc.create_tx(transfer(), None, None).unwrap();
+        });
+        acc.fast_padding = true;
+        let fast = count_hashes(&|| {
+            acc.create_tx(transfer(), None, None).unwrap();
+        });
+
+        // One real note, the remaining slots are padding
+        let padding = constants::IN - 1;
+        assert_eq!(distinct - fast, (padding - 1) * per_address);
+    }
+
+    fn zero_account() -> Account<Fr> {
+        Account {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            i: BoundedNum::new(Num::ZERO),
+            b: BoundedNum::new(Num::ZERO),
+            e: BoundedNum::new(Num::ZERO),
+        }
+    }
+
+    #[test]
+    fn test_state_fragment_merge() {
+        let hash = |n: u64| Num::<Fr>::from(n);
+
+        let left = StateFragment {
+            new_leafs: vec![(256, vec![hash(3), hash(4)])],
+            new_commitments: vec![(0, hash(1))],
+            new_accounts: vec![(256, zero_account())],
+            new_notes: vec![],
+        };
+        let right = StateFragment {
+            new_leafs: vec![(128, vec![hash(2)])],
+            new_commitments: vec![],
+            new_accounts: vec![(128, zero_account())],
+            new_notes: vec![(257, zero_note())],
+        };
+        assert!(StateFragment::<Fr>::default().is_empty());
+        assert!(!right.is_empty());
+
+        let merged = left.merge(right).unwrap();
+        assert_eq!(
+            merged
+                .new_leafs
+                .iter()
+                .map(|(index, _)| *index)
+                .collect::<Vec<_>>(),
+            vec![128, 256]
+        );
+        assert_eq!(
+            merged
+                .new_accounts
+                .iter()
+                .map(|(index, _)| *index)
+                .collect::<Vec<_>>(),
+            vec![128, 256]
+        );
+        assert_eq!(merged.max_index(), Some(257));
+    }
+
+    #[test]
+    fn test_state_fragment_merge_duplicate_account() {
+        let account = || vec![(128, zero_account())];
+        let left = StateFragment::<Fr> {
+            new_accounts: account(),
+            ..Default::default()
+        };
+        let right = StateFragment {
+            new_accounts: account(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            left.merge(right),
+            Err(MergeError::Conflict {
+                kind: FragmentItemKind::Account,
+                index: 128
+            })
+        ));
+    }
+
+    #[test]
+    fn test_state_fragment_merge_leaf_commitment_overlap() {
+        let left = StateFragment::<Fr> {
+            new_commitments: vec![(128, Num::ONE)],
+            ..Default::default()
+        };
+        let right = StateFragment {
+            new_leafs: vec![(129, vec![Num::ONE, Num::ONE])],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            left.merge(right),
+            Err(MergeError::Conflict {
+                kind: FragmentItemKind::Leaf,
+                index: 129
+            })
+        ));
+
+        let left = StateFragment::<Fr> {
+            new_leafs: vec![(0, vec![Num::ONE; 3])],
+            ..Default::default()
+        };
+        let right = StateFragment {
+            new_leafs: vec![(2, vec![Num::ONE])],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            left.merge(right),
+            Err(MergeError::Conflict {
+                kind: FragmentItemKind::Leaf,
+                index: 2
+            })
+        ));
     }
 }
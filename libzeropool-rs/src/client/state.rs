@@ -1,7 +1,14 @@
-use std::{convert::TryInto, marker::PhantomData};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    marker::PhantomData,
+    ops::Range,
+};
 
 use kvdb::KeyValueDB;
 use kvdb_memorydb::InMemory as MemoryDatabase;
+#[cfg(feature = "native")]
+use kvdb_persy::{OpenMode, PersyDatabase as NativeDatabase};
 #[cfg(feature = "web")]
 use kvdb_web::Database as WebDatabase;
 use libzeropool::{
@@ -12,25 +19,273 @@ use libzeropool::{
     },
     native::{
         account::{Account, Account as NativeAccount},
+        boundednum::BoundedNum,
+        cipher,
+        key::derive_key_p_d,
         note::{Note, Note as NativeNote},
         params::PoolParams,
     },
 };
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::{merkle::MerkleTree, sparse_array::SparseArray};
+use super::{StateFragment, TokenAmount};
+use crate::{
+    keys::Keys,
+    memo::split_memo_text,
+    merkle::{MerkleTree, Node},
+    sparse_array::SparseArray,
+};
 
 pub type TxStorage<D, Fr> = SparseArray<D, Transaction<Fr>>;
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
+/// Every backend creates the missing columns when a database is opened, so new columns don't
+/// need a migration.
+const RECORD_COLUMNS: u32 = 2;
+
+enum RecordCols {
+    /// Outgoing addresses, see [`State::record_outgoing_address`].
+    AddressBook = 0,
+    /// Nullifier ledger, see [`State::record_pending_nullifier`].
+    Nullifiers = 1,
+}
+
+/// What the user has recorded about their own transactions. Unlike the tree and the cached
+/// accounts and notes it can't be synced from the pool again, so it's kept in a database of its
+/// own that [`State::restore`] and [`State::rollback`] don't touch.
+pub struct RecordStorage<D: KeyValueDB> {
+    pub db: D,
+}
+
+#[cfg(feature = "web")]
+impl RecordStorage<WebDatabase> {
+    pub async fn new_web(name: &str) -> RecordStorage<WebDatabase> {
+        let db = WebDatabase::open(name.to_owned(), RECORD_COLUMNS)
+            .await
+            .unwrap();
+
+        RecordStorage { db }
+    }
+}
+
+#[cfg(feature = "native")]
+impl RecordStorage<NativeDatabase> {
+    pub fn new_native(path: &str) -> std::io::Result<RecordStorage<NativeDatabase>> {
+        let db = NativeDatabase::open(path, RECORD_COLUMNS, &[], OpenMode::ReadWrite)?;
+
+        Ok(RecordStorage { db })
+    }
+}
+
+impl RecordStorage<MemoryDatabase> {
+    pub fn new_test() -> RecordStorage<MemoryDatabase> {
+        RecordStorage {
+            db: kvdb_memorydb::create(RECORD_COLUMNS),
+        }
+    }
+}
+
+impl<D: KeyValueDB> RecordStorage<D> {
+    pub fn new(db: D) -> RecordStorage<D> {
+        RecordStorage { db }
+    }
+
+    fn get(&self, col: RecordCols, key: &[u8]) -> Option<Vec<u8>> {
+        self.db.get(col as u32, key).unwrap()
+    }
+
+    fn set(&self, col: RecordCols, key: &[u8], value: &[u8]) {
+        let mut batch = self.db.transaction();
+        batch.put(col as u32, key, value);
+        self.db.write(batch).unwrap();
+    }
+
+    fn iter(&self, col: RecordCols) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.db.iter(col as u32).map(|res| {
+            let (key, value) = res.unwrap();
+            (key.to_vec(), value)
+        })
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(bound(serialize = "", deserialize = ""))]
 pub enum Transaction<Fr: PrimeField> {
     Account(NativeAccount<Fr>),
     Note(NativeNote<Fr>),
 }
 
+impl<Fr: PrimeField> Transaction<Fr> {
+    /// Whether `data` is a serialized [`Transaction::Note`], only the variant tag is checked.
+    fn is_serialized_note(data: &[u8]) -> bool {
+        data.first() == Some(&1)
+    }
+}
+
+/// Summary of a [`State::redecrypt_range`] run.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedecryptReport {
+    /// Number of memos that were provided by the source and decrypted.
+    pub scanned: u64,
+    /// Indices of memos the source could not provide.
+    pub missing: Vec<u64>,
+    pub new_accounts: u64,
+    pub new_notes: u64,
+    /// Indices at which a different entry is already cached. Existing entries are never replaced.
+    pub conflicts: Vec<u64>,
+}
+
+/// Summary of a [`State::rollback`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollbackReport {
+    /// Number of cached accounts and notes that were removed.
+    pub removed_txs: u64,
+    /// Index starting from which the tree nodes must be re-requested, see [`MerkleTree::rollback`].
+    pub nodes_request_index: Option<u64>,
+}
+
+/// User-facing event reconstructed from the cached accounts and notes, see [`State::history`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    bound(serialize = "", deserialize = "")
+)]
+pub enum HistoryEntry<Fr: PrimeField> {
+    /// Tokens entered the account from outside of the pool.
+    Deposit {
+        index: u64,
+        amount: Num<Fr>,
+        spent_notes: Vec<u64>,
+    },
+    /// Note received from another user, `diversifier` is the one of the receiving address.
+    TransferIn {
+        index: u64,
+        amount: Num<Fr>,
+        diversifier: Num<Fr>,
+    },
+    /// Tokens left the account, fee included. Memos are not cached in the state, so withdrawals
+    /// are reported as outgoing transfers too.
+    TransferOut {
+        index: u64,
+        amount: Num<Fr>,
+        spent_notes: Vec<u64>,
+    },
+}
+
+/// Strategy of picking the input notes among the usable ones, see
+/// [`State::usable_notes_with_strategy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoteSelection {
+    /// Notes with the lowest indices.
+    OldestFirst,
+    /// Notes with the largest amounts, the lowest index wins a tie.
+    LargestFirst,
+    /// Usable notes with the specified indices, the other indices are ignored.
+    Indices(Vec<u64>),
+}
+
+/// Whether a cached note can be spent, see [`State::note_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "accountIndex", rename_all = "camelCase")]
+pub enum NoteStatus {
+    /// The note can be spent by the next transaction.
+    Spendable,
+    /// The note is below the earliest usable index of the account at the specified index, so it
+    /// can't be spent unless that account is rolled back, e.g. when the transaction that created
+    /// it is dropped by the relayer.
+    LockedUntil(u64),
+    /// There is no own note at the index.
+    Unknown,
+}
+
+/// Usable note together with the energy it would yield, see [`State::balance_breakdown`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteInfo<Fr: PrimeField> {
+    pub index: u64,
+    pub amount: Num<Fr>,
+    pub energy: Num<Fr>,
+}
+
+/// The consolidation is recommended only if the total fee is at most `1 / CONSOLIDATION_FEE_RATIO`
+/// of the usable notes balance, see [`State::consolidation_advice`].
+pub const CONSOLIDATION_FEE_RATIO: u64 = 100;
+
+/// Whether it's worth to merge the usable notes into the account now, see
+/// [`State::consolidation_advice`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", bound(serialize = "", deserialize = ""))]
+pub struct ConsolidationAdvice<Fr: PrimeField> {
+    pub usable_notes: u64,
+    /// Number of transactions spending all the usable notes, at most [`constants::IN`] notes each.
+    pub txs_needed: u64,
+    /// Fee of all the consolidation transactions.
+    pub total_fee: Num<Fr>,
+    /// Fee the next outgoing transfer saves if the notes are consolidated in advance: it doesn't
+    /// have to be preceded by the extra transactions spending the notes that don't fit into it.
+    pub fee_saved: Num<Fr>,
+    pub recommended: bool,
+}
+
+/// Address the user has sent notes to, see [`State::record_outgoing_address`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = ""))]
+pub struct OutgoingAddress<Fr: PrimeField> {
+    pub d: BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    pub p_d: Num<Fr>,
+    /// Number of recorded out notes sent to the address.
+    pub count: u64,
+    pub label: Option<String>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct OutgoingAddressEntry {
+    count: u64,
+    label: Option<String>,
+}
+
+/// Nullifier of a submitted transaction, see [`State::record_pending_nullifier`].
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NullifierRecord {
+    /// Relayer job the transaction was submitted with, `None` if the nullifier was only marked
+    /// as spent.
+    pub job_ref: Option<String>,
+    /// Whether the transaction was mined.
+    pub spent: bool,
+}
+
+/// Current version of the [`StateSnapshot`] format.
+pub const STATE_SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StateSnapshotError {
+    #[error("Unsupported state snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("State snapshot latest indices don't match its transactions")]
+    InconsistentIndices,
+    #[error("Failed to open the tree database: {0}")]
+    Database(String),
+}
+
+/// Storage independent copy of a [`State`], see [`State::export`] and [`State::import`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+#[serde(bound(serialize = "", deserialize = ""))]
+pub struct StateSnapshot<Fr: PrimeField> {
+    pub version: u32,
+    pub next_index: u64,
+    /// All stored tree nodes.
+    pub nodes: Vec<Node<Fr>>,
+    pub txs: Vec<(u64, Transaction<Fr>)>,
+    pub latest_account_index: Option<u64>,
+    pub latest_note_index: u64,
+}
+
 pub struct State<D: KeyValueDB, P: PoolParams> {
     pub tree: MerkleTree<D, P>,
     /// Stores only usable (own) accounts and notes
     pub(crate) txs: TxStorage<D, P::Fr>,
+    pub(crate) records: RecordStorage<D>,
     pub(crate) latest_account: Option<NativeAccount<P::Fr>>,
     pub latest_account_index: Option<u64>,
     /// Latest owned note index
@@ -44,13 +299,55 @@ where
     P: PoolParams,
     P::Fr: 'static,
 {
-    pub async fn init_web(db_id: String, params: P) -> Self {
+    pub async fn init_web(db_id: String, params: P) -> std::io::Result<Self> {
         let merkle_db_name = format!("zeropool.{}.smt", &db_id);
         let tx_db_name = format!("zeropool.{}.txs", &db_id);
-        let tree = MerkleTree::new_web(&merkle_db_name, params.clone()).await;
+        let records_db_name = format!("zeropool.{}.records", &db_id);
+        let tree = MerkleTree::new_web(&merkle_db_name, params.clone()).await?;
         let txs = TxStorage::new_web(&tx_db_name).await;
+        let records = RecordStorage::new_web(&records_db_name).await;
+
+        Ok(Self::new(tree, txs, records))
+    }
+
+    /// Number of writes to the tree, the transaction storage and the records that are not
+    /// committed to IndexedDB yet. Reads always reflect them regardless.
+    pub fn pending_writes(&self) -> usize {
+        self.tree.pending_writes() + self.txs.db.pending_writes() + self.records.db.pending_writes()
+    }
+
+    /// Resolves once all the writes made before the call are committed to IndexedDB.
+    pub fn flush(&self) -> impl std::future::Future<Output = ()> {
+        let tree = self.tree.flush();
+        let txs = self.txs.db.flush();
+        let records = self.records.db.flush();
+
+        async move {
+            tree.await;
+            txs.await;
+            records.await;
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+pub type NativeState<P> = State<NativeDatabase, P>;
+
+#[cfg(feature = "native")]
+impl<P> State<NativeDatabase, P>
+where
+    P: PoolParams,
+    P::Fr: 'static,
+{
+    pub fn init_native(path: &str, params: P) -> std::io::Result<Self> {
+        let merkle_db_path = format!("{}.smt", path);
+        let tx_db_path = format!("{}.txs", path);
+        let records_db_path = format!("{}.records", path);
+        let tree = MerkleTree::new_native(&merkle_db_path, params)?;
+        let txs = TxStorage::new_native(&tx_db_path)?;
+        let records = RecordStorage::new_native(&records_db_path)?;
 
-        Self::new(tree, txs)
+        Ok(Self::new(tree, txs, records))
     }
 }
 
@@ -62,8 +359,9 @@ where
     pub fn init_test(params: P) -> Self {
         let tree = MerkleTree::new_test(params);
         let txs = TxStorage::new_test();
+        let records = RecordStorage::new_test();
 
-        Self::new(tree, txs)
+        Self::new(tree, txs, records)
     }
 }
 
@@ -73,14 +371,18 @@ where
     P: PoolParams,
     P::Fr: 'static,
 {
-    pub fn new(tree: MerkleTree<D, P>, txs: TxStorage<D, P::Fr>) -> Self {
+    pub fn new(
+        tree: MerkleTree<D, P>,
+        txs: TxStorage<D, P::Fr>,
+        records: RecordStorage<D>,
+    ) -> Self {
         // TODO: Cache
-        let (latest_account_index, latest_note_index, latest_account) =
-            latest_indices::<D, P>(&txs);
+        let (latest_account_index, latest_note_index, latest_account) = latest_indices(txs.iter());
 
         State {
             tree,
             txs,
+            records,
             latest_account_index,
             latest_note_index,
             latest_account,
@@ -121,6 +423,60 @@ where
         }
     }
 
+    /// Whether the state has been synced at least once. An empty state is indistinguishable from
+    /// a restored one that hasn't fetched its own transactions yet, so until then the latest
+    /// account of the user is unknown.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.latest_account_index.is_some() || self.tree.next_index() > 0 || self.sync_cursor() > 0
+    }
+
+    /// Index up to which the transactions were fetched and applied, see [`State::apply_update`].
+    pub fn sync_cursor(&self) -> u64 {
+        self.tree.sync_cursor()
+    }
+
+    pub fn set_sync_cursor(&mut self, index: u64) {
+        self.tree.set_sync_cursor(index)
+    }
+
+    /// Applies the fetched transactions and moves the sync cursor to `cursor`. The accounts and
+    /// notes are cached first, the tree nodes and the cursor are then written at once, so an
+    /// interrupted update is applied again from the previous cursor.
+    pub fn apply_update(&mut self, update: StateFragment<P::Fr>, cursor: u64) {
+        self.apply_update_inner(update, cursor, false);
+    }
+
+    /// Same as [`State::apply_update`], but returns the tree nodes written by the update, see
+    /// [`MerkleTree::add_leafs_and_commitments_with_changes`].
+    pub fn apply_update_with_changes(
+        &mut self,
+        update: StateFragment<P::Fr>,
+        cursor: u64,
+    ) -> Vec<Node<P::Fr>> {
+        self.apply_update_inner(update, cursor, true)
+    }
+
+    fn apply_update_inner(
+        &mut self,
+        update: StateFragment<P::Fr>,
+        cursor: u64,
+        with_changes: bool,
+    ) -> Vec<Node<P::Fr>> {
+        for (at_index, account) in update.new_accounts {
+            self.add_account(at_index, account);
+        }
+        for (at_index, note) in update.new_notes {
+            self.add_note(at_index, note);
+        }
+
+        self.tree.add_leafs_and_commitments_with_cursor(
+            update.new_leafs,
+            update.new_commitments,
+            cursor,
+            with_changes,
+        )
+    }
+
     /// Cache account at specified index.
     pub fn add_account(&mut self, at_index: u64, account: Account<P::Fr>) {
         // Update tx storage
@@ -149,17 +505,99 @@ where
         self.txs.iter().collect()
     }
 
+    /// Returns all usable notes sorted by index.
     pub fn get_usable_notes(&self) -> Vec<(u64, Note<P::Fr>)> {
-        let next_usable_index = self.earliest_usable_index();
+        self.notes_from(self.earliest_usable_index(), usize::MAX)
+    }
 
-        // Fetch all usable notes from the state
-        self.txs
-            .iter_slice(next_usable_index..=self.latest_note_index)
-            .filter_map(|(index, tx)| match tx {
-                Transaction::Note(note) => Some((index, note)),
+    /// Returns a page of the usable notes sorted by index and the total number of usable notes.
+    /// The storage is scanned without deserializing the notes and only the indices of the first
+    /// `offset + limit` notes are kept, then the notes of the page are read. The pages are stable
+    /// as long as the state doesn't change.
+    pub fn usable_notes_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<(u64, Note<P::Fr>)>, usize) {
+        let capacity = offset.saturating_add(limit);
+        let mut indices = BTreeSet::new();
+        let mut total = 0;
+
+        for (index, data) in self
+            .txs
+            .iter_slice_raw(self.earliest_usable_index()..=self.latest_note_index)
+        {
+            if !Transaction::<P::Fr>::is_serialized_note(&data) {
+                continue;
+            }
+
+            total += 1;
+            indices.insert(index);
+            if indices.len() > capacity {
+                let last = *indices.iter().next_back().unwrap();
+                indices.remove(&last);
+            }
+        }
+
+        let notes = indices
+            .into_iter()
+            .skip(offset)
+            .filter_map(|index| match self.txs.get(index) {
+                Some(Transaction::Note(note)) => Some((index, note)),
                 _ => None,
             })
-            .collect()
+            .collect();
+
+        (notes, total)
+    }
+
+    /// Returns the notes a transaction would spend by default: at most [`constants::IN`] usable
+    /// notes with the lowest indices, sorted by index.
+    pub fn usable_notes(&self) -> Vec<(u64, Note<P::Fr>)> {
+        self.usable_notes_with_strategy(&NoteSelection::OldestFirst)
+    }
+
+    /// Picks at most [`constants::IN`] usable notes according to the strategy. The result is
+    /// sorted by index, so it can be passed to `UserAccount::create_tx_with_notes`.
+    pub fn usable_notes_with_strategy(&self, strategy: &NoteSelection) -> Vec<(u64, Note<P::Fr>)> {
+        let mut notes = self.get_usable_notes();
+
+        match strategy {
+            NoteSelection::OldestFirst => {}
+            NoteSelection::LargestFirst => {
+                notes.sort_by(|(a_index, a), (b_index, b)| {
+                    b.b.to_num()
+                        .to_uint()
+                        .cmp(&a.b.to_num().to_uint())
+                        .then(a_index.cmp(b_index))
+                });
+            }
+            NoteSelection::Indices(indices) => {
+                notes.retain(|(index, _)| indices.contains(index));
+            }
+        }
+
+        notes.truncate(constants::IN);
+        notes.sort_by_key(|(index, _)| *index);
+        notes
+    }
+
+    /// Returns at most `limit` notes with the lowest indices starting from the index, sorted by
+    /// index. The storage doesn't guarantee the iteration order, so the lowest ones are selected
+    /// while iterating and only `limit` notes are kept in memory.
+    pub(crate) fn notes_from(&self, from_index: u64, limit: usize) -> Vec<(u64, Note<P::Fr>)> {
+        let mut notes = BTreeMap::new();
+        for (index, tx) in self.txs.iter_slice(from_index..=self.latest_note_index) {
+            if let Transaction::Note(note) = tx {
+                notes.insert(index, note);
+                if notes.len() > limit {
+                    let last = *notes.keys().next_back().unwrap();
+                    notes.remove(&last);
+                }
+            }
+        }
+
+        notes.into_iter().collect()
     }
 
     /// Return an index of a earliest usable note.
@@ -219,6 +657,20 @@ where
             .unwrap_or(latest_account_index)
     }
 
+    pub fn note_status(&self, index: u64) -> NoteStatus {
+        match self.txs.get(index) {
+            Some(Transaction::Note(_)) => {}
+            _ => return NoteStatus::Unknown,
+        }
+
+        match self.latest_account_index {
+            Some(account_index) if index < self.earliest_usable_index() => {
+                NoteStatus::LockedUntil(account_index)
+            }
+            _ => NoteStatus::Spendable,
+        }
+    }
+
     /// Returns user's total balance (account + available notes).
     pub fn total_balance(&self) -> Num<P::Fr> {
         self.account_balance() + self.note_balance()
@@ -245,29 +697,457 @@ where
         note_balance
     }
 
-    pub fn rollback(&mut self, to_index: u64) {
-        self.txs.remove_all_after(to_index);
-        self.tree.rollback(to_index);
+    /// Returns the balance that can be spent by a single transaction: the account balance plus the
+    /// usable notes that `create_tx` would pick (at most [`constants::IN`]), excluding the notes
+    /// below `min_note_amount`.
+    pub fn spendable_balance(&self, min_note_amount: TokenAmount<P::Fr>) -> Num<P::Fr> {
+        let min_note_amount = min_note_amount.to_num().to_uint();
+
+        self.usable_notes()
+            .into_iter()
+            .map(|(_, note)| note.b.to_num())
+            .filter(|amount| amount.to_uint() >= min_note_amount)
+            .fold(self.account_balance(), |balance, amount| balance + amount)
+    }
+
+    /// Returns the usable notes below `min_note_amount`.
+    pub fn dust_notes(&self, min_note_amount: TokenAmount<P::Fr>) -> Vec<(u64, Note<P::Fr>)> {
+        let min_note_amount = min_note_amount.to_num().to_uint();
+
+        self.get_usable_notes()
+            .into_iter()
+            .filter(|(_, note)| note.b.to_num().to_uint() < min_note_amount)
+            .collect()
+    }
+
+    /// Estimates the consolidation of the usable notes at the specified fee per transaction. The
+    /// consolidation is recommended if the next outgoing transfer can't spend all the notes by
+    /// itself, i.e. there are more than [`constants::IN`] of them, and the total fee is at most
+    /// `1 / CONSOLIDATION_FEE_RATIO` of their balance.
+    pub fn consolidation_advice(&self, fee: TokenAmount<P::Fr>) -> ConsolidationAdvice<P::Fr> {
+        let notes = self.get_usable_notes();
+        let usable_notes = notes.len() as u64;
+        let txs_needed = (usable_notes + constants::IN as u64 - 1) / constants::IN as u64;
+
+        let fee = fee.to_num();
+        let total_fee = fee * Num::from(txs_needed);
+        let fee_saved = fee * Num::from(txs_needed.saturating_sub(1));
+
+        let notes_balance = notes
+            .iter()
+            .fold(Num::ZERO, |balance, (_, note)| balance + note.b.to_num());
+        let recommended = txs_needed > 1
+            && (total_fee * Num::from(CONSOLIDATION_FEE_RATIO)).to_uint()
+                <= notes_balance.to_uint();
+
+        ConsolidationAdvice {
+            usable_notes,
+            txs_needed,
+            total_fee,
+            fee_saved,
+            recommended,
+        }
+    }
+
+    /// Returns the energy a transaction with the specified delta index would receive: the energy
+    /// of the account plus the energy accrued by its balance and by the notes that `create_tx`
+    /// would spend.
+    pub fn energy_estimate(&self, at_index: u64) -> Num<P::Fr> {
+        let at_index = Num::from(at_index);
+        let account_index = Num::from(self.latest_account_index.unwrap_or(0));
+        let account_energy = self
+            .latest_account
+            .map(|acc| acc.e.to_num() + acc.b.to_num() * (at_index - account_index))
+            .unwrap_or(Num::ZERO);
+
+        self.usable_notes()
+            .into_iter()
+            .fold(account_energy, |energy, (index, note)| {
+                energy + note.b.to_num() * (at_index - Num::from(index))
+            })
+    }
+
+    /// Lists the usable notes with the energy they would yield if spent at the next index of the
+    /// tree.
+    pub fn balance_breakdown(&self) -> Vec<NoteInfo<P::Fr>> {
+        let at_index = Num::from(self.tree.next_index());
+
+        self.get_usable_notes()
+            .into_iter()
+            .map(|(index, note)| NoteInfo {
+                index,
+                amount: note.b.to_num(),
+                energy: note.b.to_num() * (at_index - Num::from(index)),
+            })
+            .collect()
+    }
+
+    /// Reconstructs the user-facing history ordered by index. The value change of every own
+    /// account relative to the previous one, the notes it spent and the notes sent to self within
+    /// the same transaction becomes a deposit or an outgoing transfer. Other notes are incoming
+    /// transfers.
+    pub fn history(&self) -> Vec<HistoryEntry<P::Fr>> {
+        let slot = |index: u64| index >> constants::OUTPLUSONELOG;
+
+        let mut txs = self.get_all_txs();
+        txs.sort_by_key(|(index, _)| *index);
+
+        let notes: Vec<(u64, Note<P::Fr>)> = txs
+            .iter()
+            .filter_map(|(index, tx)| match tx {
+                Transaction::Note(note) => Some((*index, *note)),
+                _ => None,
+            })
+            .collect();
+        let account_slots: Vec<u64> = txs
+            .iter()
+            .filter_map(|(index, tx)| match tx {
+                Transaction::Account(_) => Some(slot(*index)),
+                _ => None,
+            })
+            .collect();
+
+        let mut history = Vec::new();
+        let mut prev_balance = Num::ZERO;
+        let mut prev_spend_index = 0;
+        for (index, tx) in txs {
+            let account = match tx {
+                Transaction::Account(account) => account,
+                Transaction::Note(note) => {
+                    if !account_slots.contains(&slot(index)) {
+                        history.push(HistoryEntry::TransferIn {
+                            index,
+                            amount: note.b.to_num(),
+                            diversifier: note.d.to_num(),
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            let spend_index: u64 = account.i.to_num().try_into().unwrap();
+            let spent_notes: Vec<(u64, Note<P::Fr>)> = notes
+                .iter()
+                .filter(|(note_index, _)| (prev_spend_index..spend_index).contains(note_index))
+                .copied()
+                .collect();
+            let self_sent = notes
+                .iter()
+                .filter(|(note_index, _)| *note_index > index && slot(*note_index) == slot(index))
+                .fold(Num::ZERO, |sum, (_, note)| sum + note.b.to_num());
+
+            let before = spent_notes
+                .iter()
+                .fold(prev_balance, |sum, (_, note)| sum + note.b.to_num());
+            let after = account.b.to_num() + self_sent;
+            let spent_notes = spent_notes.into_iter().map(|(index, _)| index).collect();
+
+            if after.to_uint() > before.to_uint() {
+                history.push(HistoryEntry::Deposit {
+                    index,
+                    amount: after - before,
+                    spent_notes,
+                });
+            } else if after.to_uint() < before.to_uint() {
+                history.push(HistoryEntry::TransferOut {
+                    index,
+                    amount: before - after,
+                    spent_notes,
+                });
+            }
+
+            prev_balance = account.b.to_num();
+            prev_spend_index = spend_index;
+        }
+
+        history
+    }
+
+    /// Re-runs decryption of raw memos (ciphertexts) over the specified index range and caches
+    /// newly discovered accounts and notes. Already cached entries are kept as is and reported as
+    /// conflicts if the decrypted data differs. The merkle tree is not modified.
+    pub fn redecrypt_range(
+        &mut self,
+        range: Range<u64>,
+        keys: &Keys<P>,
+        raw_memo_source: &dyn Fn(u64) -> Option<Vec<u8>>,
+    ) -> RedecryptReport {
+        let step = constants::OUT as u64 + 1;
+        let mut report = RedecryptReport::default();
+
+        // Memos are only located at the commitment boundaries
+        let mut index = (range.start + step - 1) / step * step;
+        while index < range.end {
+            match raw_memo_source(index) {
+                Some(memo) => {
+                    report.scanned += 1;
+                    self.redecrypt_memo(index, &memo, keys, &mut report);
+                }
+                None => report.missing.push(index),
+            }
+
+            index += step;
+        }
+
+        report
+    }
+
+    fn redecrypt_memo(
+        &mut self,
+        index: u64,
+        memo: &[u8],
+        keys: &Keys<P>,
+        report: &mut RedecryptReport,
+    ) {
+        let params = self.tree.params().clone();
+        let is_own_note =
+            |note: &Note<P::Fr>| note.p_d == derive_key_p_d(note.d.to_num(), keys.eta, &params).x;
+        let (memo, _) = split_memo_text::<P::Fr>(memo);
+
+        let (account, notes): (_, Vec<_>) = match cipher::decrypt_out(keys.eta, memo, &params) {
+            Some((account, notes)) => (Some(account), notes.into_iter().map(Some).collect()),
+            None => (None, cipher::decrypt_in(keys.eta, memo, &params)),
+        };
+
+        if let Some(account) = account {
+            match self.txs.get(index) {
+                None => {
+                    self.add_account(index, account);
+                    report.new_accounts += 1;
+                }
+                Some(Transaction::Account(existing)) if existing == account => {}
+                Some(_) => report.conflicts.push(index),
+            }
+        }
+
+        for (i, note) in notes.into_iter().enumerate() {
+            let note = match note {
+                Some(note) if is_own_note(&note) => note,
+                _ => continue,
+            };
+
+            let note_index = index + 1 + i as u64;
+            match self.txs.get(note_index) {
+                None => {
+                    self.add_note(note_index, note);
+                    report.new_notes += 1;
+                }
+                Some(Transaction::Note(existing)) if existing == note => {}
+                Some(_) => report.conflicts.push(note_index),
+            }
+        }
+    }
+
+    /// Dumps the tree nodes and the cached accounts and notes.
+    pub fn export(&self) -> StateSnapshot<P::Fr> {
+        StateSnapshot {
+            version: STATE_SNAPSHOT_VERSION,
+            next_index: self.tree.next_index(),
+            nodes: self.tree.get_all_nodes(),
+            txs: self.get_all_txs(),
+            latest_account_index: self.latest_account_index,
+            latest_note_index: self.latest_note_index,
+        }
+    }
+
+    /// Creates a state from a snapshot. The databases must be opened the same way as for
+    /// [`MerkleTree::new`] and [`TxStorage::new`], their previous content is replaced as in
+    /// [`State::restore`].
+    pub fn import(
+        tree_db: D,
+        txs_db: D,
+        records_db: D,
+        snapshot: StateSnapshot<P::Fr>,
+        params: P,
+    ) -> Result<Self, StateSnapshotError> {
+        let tree = MerkleTree::new(tree_db, params)
+            .map_err(|err| StateSnapshotError::Database(err.to_string()))?;
+        let mut state = Self::new(tree, TxStorage::new(txs_db), RecordStorage::new(records_db));
+        state.restore(&snapshot)?;
+
+        Ok(state)
+    }
+
+    /// Replaces the tree and the cached accounts and notes with the snapshot. The sync cursor is
+    /// reset, the nullifier ledger and the address book are kept: they record what the user has
+    /// submitted, which the snapshot doesn't cover.
+    pub fn restore(&mut self, snapshot: &StateSnapshot<P::Fr>) -> Result<(), StateSnapshotError> {
+        if snapshot.version != STATE_SNAPSHOT_VERSION {
+            return Err(StateSnapshotError::UnsupportedVersion(snapshot.version));
+        }
+
         let (latest_account_index, latest_note_index, latest_account) =
-            latest_indices::<D, P>(&self.txs);
+            latest_indices(snapshot.txs.iter().cloned());
+        if latest_account_index != snapshot.latest_account_index
+            || latest_note_index != snapshot.latest_note_index
+        {
+            return Err(StateSnapshotError::InconsistentIndices);
+        }
+
+        self.tree.restore(&snapshot.nodes, snapshot.next_index);
+        self.txs.restore(&snapshot.txs);
         self.latest_account_index = latest_account_index;
         self.latest_note_index = latest_note_index;
         self.latest_account = latest_account;
+
+        Ok(())
+    }
+
+    /// Removes everything at or after `to_index` from both the tree and the cached accounts and
+    /// notes, then recalculates the latest account and note from the remaining entries. The sync
+    /// cursor is lowered to `to_index` if it's ahead.
+    pub fn rollback(&mut self, to_index: u64) -> RollbackReport {
+        let removed_txs = self.txs.remove_range(to_index..=u64::MAX);
+        let nodes_request_index = self.tree.rollback(to_index);
+        if self.sync_cursor() > to_index {
+            self.set_sync_cursor(to_index);
+        }
+
+        let (latest_account_index, latest_note_index, latest_account) =
+            latest_indices(self.txs.iter());
+        self.latest_account_index = latest_account_index;
+        self.latest_note_index = latest_note_index;
+        self.latest_account = latest_account;
+
+        RollbackReport {
+            removed_txs,
+            nodes_request_index,
+        }
+    }
+
+    /// Removes the cached accounts and notes below `before_index`. The latest account and the
+    /// usable notes are always kept, so the index is lowered to the earliest of them if needed.
+    /// Returns the number of removed entries.
+    pub fn prune_history(&mut self, before_index: u64) -> u64 {
+        let before_index = before_index
+            .min(self.latest_account_index.unwrap_or(u64::MAX))
+            .min(self.earliest_usable_index());
+
+        self.txs.compact_before(before_index)
+    }
+
+    /// Records an out note sent to the address in the address book. The label replaces the
+    /// existing one if specified.
+    pub fn record_outgoing_address(
+        &mut self,
+        d: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        p_d: Num<P::Fr>,
+        label: Option<String>,
+    ) {
+        let key = address_book_key(d, p_d);
+        let mut entry = self
+            .records
+            .get(RecordCols::AddressBook, &key)
+            .map(|value| OutgoingAddressEntry::try_from_slice(&value).unwrap())
+            .unwrap_or(OutgoingAddressEntry {
+                count: 0,
+                label: None,
+            });
+
+        entry.count += 1;
+        if label.is_some() {
+            entry.label = label;
+        }
+
+        self.records
+            .set(RecordCols::AddressBook, &key, &entry.try_to_vec().unwrap());
+    }
+
+    /// Returns the number of out notes sent to the address, `None` if there were none.
+    pub fn outgoing_address_seen(
+        &self,
+        d: BoundedNum<P::Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+        p_d: Num<P::Fr>,
+    ) -> Option<u64> {
+        self.records
+            .get(RecordCols::AddressBook, &address_book_key(d, p_d))
+            .map(|value| OutgoingAddressEntry::try_from_slice(&value).unwrap().count)
+    }
+
+    /// Records the nullifier of a transaction submitted to the relayer, so that the transaction
+    /// isn't built again from the same account state (see
+    /// [`crate::client::CreateTxError::NullifierAlreadyUsed`]). The job reference replaces the
+    /// existing one, a spent nullifier stays spent.
+    pub fn record_pending_nullifier(&mut self, nullifier: Num<P::Fr>, job_ref: String) {
+        let job_ref = Some(job_ref);
+        let record = match self.nullifier_record(nullifier) {
+            Some(record) => NullifierRecord { job_ref, ..record },
+            None => NullifierRecord {
+                job_ref,
+                spent: false,
+            },
+        };
+
+        self.records.set(
+            RecordCols::Nullifiers,
+            &nullifier_key(nullifier),
+            &record.try_to_vec().unwrap(),
+        );
+    }
+
+    /// Marks the nullifier as spent once its transaction is mined, recording it if needed.
+    pub fn mark_nullifier_spent(&mut self, nullifier: Num<P::Fr>) {
+        let record = match self.nullifier_record(nullifier) {
+            Some(record) => NullifierRecord {
+                spent: true,
+                ..record
+            },
+            None => NullifierRecord {
+                job_ref: None,
+                spent: true,
+            },
+        };
+
+        self.records.set(
+            RecordCols::Nullifiers,
+            &nullifier_key(nullifier),
+            &record.try_to_vec().unwrap(),
+        );
+    }
+
+    /// Returns the ledger record of the nullifier, `None` if it wasn't recorded.
+    pub fn nullifier_record(&self, nullifier: Num<P::Fr>) -> Option<NullifierRecord> {
+        self.records
+            .get(RecordCols::Nullifiers, &nullifier_key(nullifier))
+            .map(|value| NullifierRecord::try_from_slice(&value).unwrap())
+    }
+
+    pub fn list_outgoing_addresses(&self) -> Vec<OutgoingAddress<P::Fr>> {
+        self.records
+            .iter(RecordCols::AddressBook)
+            .map(|(key, value)| {
+                let (d, p_d) = BorshDeserialize::try_from_slice(&key).unwrap();
+                let entry = OutgoingAddressEntry::try_from_slice(&value).unwrap();
+
+                OutgoingAddress {
+                    d,
+                    p_d,
+                    count: entry.count,
+                    label: entry.label,
+                }
+            })
+            .collect()
     }
 }
 
-fn latest_indices<D, P>(
-    txs: &TxStorage<D, P::Fr>,
-) -> (Option<u64>, u64, Option<NativeAccount<P::Fr>>)
-where
-    D: KeyValueDB,
-    P: PoolParams,
-    P::Fr: 'static,
-{
+fn nullifier_key<Fr: PrimeField>(nullifier: Num<Fr>) -> Vec<u8> {
+    nullifier.try_to_vec().unwrap()
+}
+
+fn address_book_key<Fr: PrimeField>(
+    d: BoundedNum<Fr, { constants::DIVERSIFIER_SIZE_BITS }>,
+    p_d: Num<Fr>,
+) -> Vec<u8> {
+    (d, p_d).try_to_vec().unwrap()
+}
+
+fn latest_indices<Fr: PrimeField>(
+    txs: impl Iterator<Item = (u64, Transaction<Fr>)>,
+) -> (Option<u64>, u64, Option<NativeAccount<Fr>>) {
     let mut latest_account_index = None;
     let mut latest_note_index = 0;
     let mut latest_account = None;
-    for (index, tx) in txs.iter() {
+    for (index, tx) in txs {
         match tx {
             Transaction::Account(acc) => {
                 if index >= latest_account_index.unwrap_or(0) {
@@ -285,3 +1165,597 @@ where
 
     (latest_account_index, latest_note_index, latest_account)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use libzeropool::{
+        fawkes_crypto::{ff_uint::Num, rand::Rng},
+        native::{boundednum::BoundedNum, params::PoolBN256},
+        POOL_PARAMS,
+    };
+
+    use super::*;
+    use crate::{
+        address::parse_address,
+        client::{TxType, UserAccount},
+        keys::SecretKey,
+        merkle,
+        random::CustomRng,
+    };
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    fn account(i: u64, b: u64) -> Account<Fr> {
+        Account {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            i: BoundedNum::new(Num::from(i)),
+            b: BoundedNum::new(Num::from(b)),
+            e: BoundedNum::new(Num::ZERO),
+        }
+    }
+
+    fn note(b: u64) -> Note<Fr> {
+        Note {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            b: BoundedNum::new(Num::from(b)),
+            t: CustomRng.gen(),
+        }
+    }
+
+    fn hashes(n: u64) -> Vec<Num<Fr>> {
+        (1..=n).map(Num::from).collect()
+    }
+
+    #[test]
+    fn test_redecrypt_range_finds_missed_note() {
+        let mut rng = CustomRng;
+        let acc = UserAccount::new(
+            Num::ZERO,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+        let sender = Keys::derive(SecretKey::new(Num::ONE), &*POOL_PARAMS);
+
+        let (d, p_d) = parse_address::<PoolBN256>(&acc.generate_address()).unwrap();
+        let note = Note {
+            d,
+            p_d,
+            b: BoundedNum::new(Num::from(10)),
+            t: rng.gen(),
+        };
+        let sender_account = Account {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            i: BoundedNum::new(Num::ZERO),
+            b: BoundedNum::new(Num::ZERO),
+            e: BoundedNum::new(Num::ZERO),
+        };
+        let entropy: [u8; 32] = rng.gen();
+        let memo = cipher::encrypt(&entropy, sender.eta, sender_account, &[note], &*POOL_PARAMS);
+
+        // The memo at 128 was skipped during sync because of a late birthday
+        let mut state = acc.state;
+        assert_eq!(state.note_balance(), Num::ZERO);
+        let root_before = state.tree.get_root();
+
+        let source = |index: u64| {
+            if index == 128 {
+                Some(memo.clone())
+            } else {
+                None
+            }
+        };
+        let report = state.redecrypt_range(0..256, &acc.keys, &source);
+
+        assert_eq!(report.scanned, 1);
+        assert_eq!(report.missing, vec![0]);
+        assert_eq!(report.new_accounts, 0);
+        assert_eq!(report.new_notes, 1);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(state.txs.get(129), Some(Transaction::Note(note)));
+        assert_eq!(state.latest_note_index, 129);
+        assert_eq!(state.note_balance(), Num::from(10));
+        assert_eq!(state.tree.get_root(), root_before);
+        assert_eq!(state.tree.next_index(), 0);
+
+        // Running it again must not produce duplicates
+        let report = state.redecrypt_range(0..256, &acc.keys, &source);
+        assert_eq!(report.new_notes, 0);
+        assert!(report.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_rollback_reverts_to_previous_account() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+
+        let first_account = account(0, 5);
+        state.add_full_tx(
+            0,
+            &hashes(3),
+            Some(first_account),
+            &[(1, note(10)), (2, note(10))],
+        );
+        state.add_full_tx(128, &hashes(2), Some(account(3, 25)), &[(129, note(7))]);
+
+        assert_eq!(state.latest_account_index, Some(128));
+        assert_eq!(state.latest_note_index, 129);
+        assert_eq!(state.total_balance(), Num::from(32u64));
+
+        let report = state.rollback(128);
+
+        assert_eq!(report.removed_txs, 2);
+        assert_eq!(report.nodes_request_index, None);
+        assert_eq!(state.tree.next_index(), 128);
+        assert_eq!(state.latest_account, Some(first_account));
+        assert_eq!(state.latest_account_index, Some(0));
+        assert_eq!(state.latest_note_index, 2);
+        assert_eq!(state.earliest_usable_index(), 1);
+        assert_eq!(state.account_balance(), Num::from(5u64));
+        assert_eq!(state.note_balance(), Num::from(20u64));
+        assert_eq!(state.get_usable_notes().len(), 2);
+        assert!(state.txs.get(128).is_none());
+        assert!(state.txs.get(129).is_none());
+    }
+
+    #[test]
+    fn test_sync_cursor_persisted_and_clamped() {
+        let mut rng = CustomRng;
+        let mut state = State::init_test(POOL_PARAMS.clone());
+        let note = Note {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            b: BoundedNum::new(Num::from(10)),
+            t: rng.gen(),
+        };
+
+        assert_eq!(state.sync_cursor(), 0);
+
+        state.apply_update(
+            StateFragment {
+                new_leafs: vec![(0, hashes(2))],
+                new_notes: vec![(1, note)],
+                ..Default::default()
+            },
+            128,
+        );
+        let changes = state.apply_update_with_changes(
+            StateFragment {
+                new_commitments: vec![(128, rng.gen())],
+                ..Default::default()
+            },
+            256,
+        );
+        assert!(!changes.is_empty());
+        assert_eq!(state.sync_cursor(), 256);
+        assert_eq!(state.tree.next_index(), 256);
+
+        let tree = MerkleTree::new(state.tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        let mut state = State::new(tree, state.txs, state.records);
+        assert_eq!(state.sync_cursor(), 256);
+        assert_eq!(state.get_usable_notes().len(), 1);
+
+        state.rollback(128);
+        assert_eq!(state.sync_cursor(), 128);
+
+        // rolling back past the cursor leaves it untouched
+        state.set_sync_cursor(64);
+        state.rollback(128);
+        assert_eq!(state.sync_cursor(), 64);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut rng = CustomRng;
+        let mut state = State::init_test(POOL_PARAMS.clone());
+
+        let mut note = |d: u64, b: u64| Note {
+            d: BoundedNum::new(Num::from(d)),
+            p_d: Num::ZERO,
+            b: BoundedNum::new(Num::from(b)),
+            t: rng.gen(),
+        };
+
+        // deposit of 10
+        state.add_full_tx(0, &hashes(1), Some(account(0, 10)), &[]);
+        // incoming note of 5 from someone else's transaction
+        state.add_full_tx(128, &hashes(2), None, &[(129, note(7, 5))]);
+        // spends the note, sends 2 to self and 4 + fee 1 to someone else
+        state.add_full_tx(256, &hashes(3), Some(account(130, 8)), &[(257, note(0, 2))]);
+
+        let history = state.history();
+        assert_eq!(
+            history,
+            vec![
+                HistoryEntry::Deposit {
+                    index: 0,
+                    amount: Num::from(10u64),
+                    spent_notes: vec![],
+                },
+                HistoryEntry::TransferIn {
+                    index: 129,
+                    amount: Num::from(5u64),
+                    diversifier: Num::from(7u64),
+                },
+                HistoryEntry::TransferOut {
+                    index: 256,
+                    amount: Num::from(5u64),
+                    spent_notes: vec![129],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prune_history_keeps_usable_notes() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+
+        state.add_full_tx(
+            0,
+            &hashes(3),
+            Some(account(0, 5)),
+            &[(1, note(10)), (2, note(10))],
+        );
+        state.add_full_tx(128, &hashes(2), Some(account(3, 25)), &[(129, note(7))]);
+        state.add_full_tx(256, &hashes(2), None, &[(257, note(3))]);
+
+        let usable_notes = state.get_usable_notes();
+        assert_eq!(state.earliest_usable_index(), 129);
+        assert_eq!(state.total_balance(), Num::from(35u64));
+
+        // Limited by the latest account
+        assert_eq!(state.prune_history(300), 3);
+        assert_eq!(state.txs.count(), 3);
+        assert!(state.txs.iter_slice(0..128).next().is_none());
+        assert_eq!(state.prune_history(50), 0);
+
+        assert_eq!(state.earliest_usable_index(), 129);
+        assert_eq!(state.get_usable_notes(), usable_notes);
+        assert_eq!(state.total_balance(), Num::from(35u64));
+        assert_eq!(state.latest_account_index, Some(128));
+    }
+
+    #[test]
+    fn test_note_status() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+        let note = |b: u64| {
+            let mut note = crate::utils::zero_note();
+            note.b = BoundedNum::new(Num::from(b));
+            note
+        };
+        let account = |i: u64| Account {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            i: BoundedNum::new(Num::from(i)),
+            b: BoundedNum::new(Num::ZERO),
+            e: BoundedNum::new(Num::ZERO),
+        };
+
+        state.add_note(128, note(1));
+        state.add_note(384, note(2));
+        assert_eq!(state.note_status(128), NoteStatus::Spendable);
+        assert_eq!(state.note_status(256), NoteStatus::Unknown);
+
+        state.add_account(256, account(256));
+        assert_eq!(state.earliest_usable_index(), 384);
+        assert_eq!(state.note_status(128), NoteStatus::LockedUntil(256));
+        assert_eq!(state.note_status(384), NoteStatus::Spendable);
+
+        state.rollback(256);
+        assert_eq!(state.note_status(128), NoteStatus::Spendable);
+        assert_eq!(state.note_status(384), NoteStatus::Unknown);
+    }
+
+    #[test]
+    fn test_usable_notes_page() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+        for i in 0..1000u64 {
+            let mut note = crate::utils::zero_note();
+            note.b = BoundedNum::new(Num::from(i + 1));
+            state.add_note((1000 - i) * 128, note);
+        }
+
+        let mut indices = vec![];
+        for page in 0..3 {
+            let (notes, total) = state.usable_notes_page(page * 400, 400);
+            assert_eq!(total, 1000);
+            assert_eq!(notes.len(), if page < 2 { 400 } else { 200 });
+            indices.extend(notes.into_iter().map(|(index, _)| index));
+        }
+
+        let expected: Vec<u64> = state
+            .get_usable_notes()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(indices, expected);
+        assert_eq!(indices, (1..=1000).map(|i| i * 128).collect::<Vec<_>>());
+
+        let (notes, total) = state.usable_notes_page(1000, 10);
+        assert!(notes.is_empty());
+        assert_eq!(total, 1000);
+    }
+
+    /// Counts the reads of single values.
+    struct CountingDatabase {
+        inner: MemoryDatabase,
+        gets: Arc<AtomicUsize>,
+    }
+
+    impl CountingDatabase {
+        fn new(columns: u32, gets: Arc<AtomicUsize>) -> Self {
+            CountingDatabase {
+                inner: kvdb_memorydb::create(columns),
+                gets,
+            }
+        }
+    }
+
+    impl KeyValueDB for CountingDatabase {
+        fn get(&self, col: u32, key: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(col, key)
+        }
+
+        fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            self.inner.get_by_prefix(col, prefix)
+        }
+
+        fn write(&self, transaction: kvdb::DBTransaction) -> std::io::Result<()> {
+            self.inner.write(transaction)
+        }
+
+        fn iter<'a>(
+            &'a self,
+            col: u32,
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.inner.iter(col)
+        }
+
+        fn iter_with_prefix<'a>(
+            &'a self,
+            col: u32,
+            prefix: &'a [u8],
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.inner.iter_with_prefix(col, prefix)
+        }
+    }
+
+    #[test]
+    fn test_usable_notes_page_reads_only_page() {
+        let gets = Arc::new(AtomicUsize::new(0));
+        let tree = MerkleTree::new(
+            CountingDatabase::new(merkle::NUM_COLUMNS, Arc::default()),
+            POOL_PARAMS.clone(),
+        )
+        .unwrap();
+        let txs = TxStorage::new(CountingDatabase::new(1, gets.clone()));
+        let mut state = State::new(tree, txs, RecordStorage::new_test());
+        for i in 1..=10_000u64 {
+            state.add_note(i * 128, note(i));
+        }
+
+        gets.store(0, Ordering::SeqCst);
+        let (notes, total) = state.usable_notes_page(5_000, 20);
+
+        assert_eq!(total, 10_000);
+        assert_eq!(
+            notes.iter().map(|(index, _)| *index).collect::<Vec<_>>(),
+            (5_001..=5_020).map(|i| i * 128).collect::<Vec<_>>()
+        );
+        assert_eq!(gets.load(Ordering::SeqCst), notes.len());
+    }
+
+    /// Account with own notes of the specified amounts at indices 1, 2, ... of the first
+    /// transaction.
+    fn account_with_notes(amounts: &[u64]) -> UserAccount<MemoryDatabase, PoolBN256> {
+        let mut acc = UserAccount::new(
+            Num::ZERO,
+            State::init_test(POOL_PARAMS.clone()),
+            POOL_PARAMS.clone(),
+        );
+
+        let notes: Vec<_> = (1..)
+            .zip(amounts)
+            .map(|(index, &amount)| {
+                let (d, p_d) = parse_address::<PoolBN256>(&acc.generate_address()).unwrap();
+                let note = Note {
+                    d,
+                    p_d,
+                    b: BoundedNum::new(Num::from(amount)),
+                    t: CustomRng.gen(),
+                };
+                (index, note)
+            })
+            .collect();
+        let hashes: Vec<_> = std::iter::once(Num::ZERO)
+            .chain(notes.iter().map(|(_, note)| note.hash(&*POOL_PARAMS)))
+            .collect();
+        acc.state.add_full_tx(0, &hashes, None, &notes);
+
+        acc
+    }
+
+    #[test]
+    fn test_notes_from_keeps_lowest_indices() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+        for index in [900u64, 5, 700, 3, 1000, 129] {
+            state.add_note(index, note(index));
+        }
+
+        let indices = |notes: Vec<(u64, Note<Fr>)>| -> Vec<u64> {
+            notes.into_iter().map(|(index, _)| index).collect()
+        };
+        assert_eq!(indices(state.notes_from(0, 3)), vec![3, 5, 129]);
+        assert_eq!(indices(state.notes_from(6, 2)), vec![129, 700]);
+        assert_eq!(
+            indices(state.notes_from(0, usize::MAX)),
+            vec![3, 5, 129, 700, 900, 1000]
+        );
+        assert!(state.notes_from(0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_consolidation_advice() {
+        let in_count = constants::IN as u64;
+
+        for (num_notes, txs_needed) in [(in_count, 1), (in_count + 1, 2), (3 * in_count, 3)] {
+            let acc = account_with_notes(&vec![1_000_000; num_notes as usize]);
+
+            let advice = acc
+                .state
+                .consolidation_advice(BoundedNum::new(Num::from(10u64)));
+            assert_eq!(advice.usable_notes, num_notes);
+            assert_eq!(advice.txs_needed, txs_needed);
+            assert_eq!(advice.total_fee, Num::from(10 * txs_needed));
+            assert_eq!(advice.fee_saved, Num::from(10 * (txs_needed - 1)));
+            assert_eq!(advice.recommended, num_notes > in_count);
+
+            let advice = acc
+                .state
+                .consolidation_advice(BoundedNum::new(Num::from(1_000_000u64)));
+            assert_eq!(advice.txs_needed, txs_needed);
+            assert!(!advice.recommended);
+        }
+    }
+
+    #[test]
+    fn test_usable_notes_with_strategy() {
+        let num_notes = constants::IN as u64 + 5;
+        let amount = |index: u64| (index * 7) % 10 + 1;
+        let amounts: Vec<_> = (1..=num_notes).map(amount).collect();
+        let acc = account_with_notes(&amounts);
+
+        let selected = |strategy: NoteSelection| -> Vec<u64> {
+            acc.state
+                .usable_notes_with_strategy(&strategy)
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect()
+        };
+
+        let oldest: Vec<u64> = (1..=constants::IN as u64).collect();
+        assert_eq!(selected(NoteSelection::OldestFirst), oldest);
+        let usable: Vec<u64> = acc
+            .state
+            .usable_notes()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(usable, oldest);
+
+        let mut largest: Vec<u64> = (1..=num_notes).collect();
+        largest.sort_by_key(|&index| std::cmp::Reverse(amount(index)));
+        largest.truncate(constants::IN);
+        largest.sort_unstable();
+        assert_eq!(selected(NoteSelection::LargestFirst), largest);
+
+        assert_eq!(
+            selected(NoteSelection::Indices(vec![5, 2, 1000])),
+            vec![2, 5]
+        );
+        assert_eq!(
+            selected(NoteSelection::Indices((1..=num_notes).rev().collect())).len(),
+            constants::IN
+        );
+
+        for strategy in [
+            NoteSelection::OldestFirst,
+            NoteSelection::LargestFirst,
+            NoteSelection::Indices(vec![5, 2, 1000]),
+        ] {
+            let indices = selected(strategy);
+            let tx = acc
+                .create_tx_with_notes(
+                    TxType::Transfer {
+                        fee: BoundedNum::new(Num::ZERO),
+                        outputs: vec![],
+                    },
+                    None,
+                    None,
+                    &indices,
+                )
+                .unwrap();
+            assert_eq!(tx.summary.unwrap().spent_note_indices, indices);
+        }
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+
+        state.add_full_tx(0, &hashes(3), Some(account(0, 5)), &[(1, note(10))]);
+        state.add_full_tx(128, &hashes(2), Some(account(1, 15)), &[(129, note(7))]);
+        state.add_hashes(256, &hashes(1));
+        state.add_note(385, note(3));
+
+        let snapshot = state.export();
+        let bytes = snapshot.try_to_vec().unwrap();
+        let snapshot = StateSnapshot::try_from_slice(&bytes).unwrap();
+
+        let imported = State::import(
+            kvdb_memorydb::create(merkle::NUM_COLUMNS),
+            kvdb_memorydb::create(1),
+            kvdb_memorydb::create(RECORD_COLUMNS),
+            snapshot,
+            POOL_PARAMS.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(imported.tree.get_root(), state.tree.get_root());
+        assert_eq!(imported.tree.next_index(), state.tree.next_index());
+        assert_eq!(imported.get_all_txs(), state.get_all_txs());
+        assert_eq!(imported.latest_account, state.latest_account);
+        assert_eq!(imported.latest_account_index, Some(128));
+        assert_eq!(imported.latest_note_index, 385);
+        assert_eq!(imported.total_balance(), state.total_balance());
+        assert_eq!(imported.total_balance(), Num::from(35u64));
+    }
+
+    #[test]
+    fn test_restore_keeps_ledger_and_address_book() {
+        let mut state = State::init_test(POOL_PARAMS.clone());
+        let snapshot = state.export();
+
+        state.add_hashes(0, &[Num::ONE, Num::from(2u64)]);
+        state.set_sync_cursor(128);
+        state.record_pending_nullifier(Num::from(7u64), "job".to_string());
+        state.record_outgoing_address(BoundedNum::new(Num::ONE), Num::from(2u64), None);
+
+        state.restore(&snapshot).unwrap();
+        assert_eq!(state.tree.next_index(), 0);
+        assert_eq!(state.sync_cursor(), 0);
+        assert!(state.nullifier_record(Num::from(7u64)).is_some());
+        assert_eq!(
+            state.outgoing_address_seen(BoundedNum::new(Num::ONE), Num::from(2u64)),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_import_rejects_unknown_version() {
+        let mut snapshot = State::init_test(POOL_PARAMS.clone()).export();
+        snapshot.version += 1;
+
+        let result = State::import(
+            kvdb_memorydb::create(merkle::NUM_COLUMNS),
+            kvdb_memorydb::create(1),
+            kvdb_memorydb::create(RECORD_COLUMNS),
+            snapshot,
+            POOL_PARAMS.clone(),
+        );
+
+        assert_eq!(
+            result.err(),
+            Some(StateSnapshotError::UnsupportedVersion(
+                STATE_SNAPSHOT_VERSION + 1
+            ))
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::convert::TryInto;
+
+use libzeropool::{
+    fawkes_crypto::ff_uint::{Num, PrimeField, Uint},
+    native::tx::parse_delta,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DeltaError {
+    #[error("Value {0} is out of the encodable range")]
+    OutOfRange(String),
+    #[error("Pool id mismatch: expected {expected}, got {got}")]
+    PoolIdMismatch { expected: String, got: String },
+}
+
+/// Decoded transaction delta with signed amounts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta<Fr: PrimeField> {
+    pub v: i128,
+    /// Energy can exceed the i64 range since it's accumulated as balance × index.
+    pub e: i128,
+    pub index: u64,
+    pub pool_id: Num<Fr>,
+}
+
+/// Decodes a delta produced by `make_delta`. Unlike a plain `parse_delta` followed by integer
+/// conversions, never panics.
+pub fn decode_delta<Fr: PrimeField>(delta: Num<Fr>) -> Result<Delta<Fr>, DeltaError> {
+    let (v, e, index, pool_id) = parse_delta(delta);
+
+    Ok(Delta {
+        v: num_to_i128(v)?,
+        e: num_to_i128(e)?,
+        index: index
+            .try_into()
+            .map_err(|_| DeltaError::OutOfRange(index.to_string()))?,
+        pool_id,
+    })
+}
+
+/// Checks that the delta was made for the expected pool.
+pub fn check_pool_id<Fr: PrimeField>(
+    delta: &Delta<Fr>,
+    expected_pool_id: Num<Fr>,
+) -> Result<(), DeltaError> {
+    if delta.pool_id != expected_pool_id {
+        return Err(DeltaError::PoolIdMismatch {
+            expected: expected_pool_id.to_string(),
+            got: delta.pool_id.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Converts a field element to a signed integer, treating the upper half of the field as
+/// negative numbers.
+fn num_to_i128<Fr: PrimeField>(num: Num<Fr>) -> Result<i128, DeltaError> {
+    if let Some(value) = num_to_u127(num) {
+        return Ok(value as i128);
+    }
+
+    if let Some(value) = num_to_u127(-num) {
+        return Ok(-(value as i128));
+    }
+
+    Err(DeltaError::OutOfRange(num.to_string()))
+}
+
+fn num_to_u127<Fr: PrimeField>(num: Num<Fr>) -> Option<u128> {
+    let bytes = num.to_uint().0.to_little_endian();
+    let (low, high) = bytes.split_at(16);
+
+    if high.iter().any(|&b| b != 0) || low[15] & 0x80 != 0 {
+        return None;
+    }
+
+    Some(u128::from_le_bytes(low.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::native::{
+        params::{PoolBN256, PoolParams},
+        tx::make_delta,
+    };
+
+    use super::*;
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    fn pow2(n: u32) -> Num<Fr> {
+        (0..n).fold(Num::ONE, |acc, _| acc + acc)
+    }
+
+    #[test]
+    fn test_decode_delta_large_energy() {
+        let delta = make_delta::<Fr>(
+            -Num::from(5u64),
+            pow2(100),
+            Num::from(128u64),
+            Num::from(7u64),
+        );
+
+        let decoded = decode_delta(delta).unwrap();
+        assert_eq!(decoded.v, -5);
+        assert_eq!(decoded.e, 1i128 << 100);
+        assert!(decoded.e > i64::MAX as i128);
+        assert_eq!(decoded.index, 128);
+        assert_eq!(decoded.pool_id, Num::from(7u64));
+    }
+
+    #[test]
+    fn test_decode_delta_negative_amounts() {
+        let delta = make_delta::<Fr>(
+            -Num::from(1_000_000_000u64),
+            -pow2(80),
+            Num::from(256u64),
+            Num::ZERO,
+        );
+
+        let decoded = decode_delta(delta).unwrap();
+        assert_eq!(decoded.v, -1_000_000_000);
+        assert_eq!(decoded.e, -(1i128 << 80));
+        assert_eq!(decoded.index, 256);
+    }
+
+    #[test]
+    fn test_num_to_i128_out_of_range() {
+        assert_eq!(num_to_i128(pow2(126)), Ok(1i128 << 126));
+        assert_eq!(num_to_i128(-pow2(126)), Ok(-(1i128 << 126)));
+        assert_eq!(
+            num_to_i128(pow2(200)),
+            Err(DeltaError::OutOfRange(pow2(200).to_string()))
+        );
+    }
+
+    #[test]
+    fn test_check_pool_id() {
+        let delta = make_delta::<Fr>(Num::ZERO, Num::ZERO, Num::ZERO, Num::from(7u64));
+        let decoded = decode_delta(delta).unwrap();
+
+        assert!(check_pool_id(&decoded, Num::from(7u64)).is_ok());
+        assert_eq!(
+            check_pool_id(&decoded, Num::from(8u64)),
+            Err(DeltaError::PoolIdMismatch {
+                expected: "8".to_string(),
+                got: "7".to_string(),
+            })
+        );
+    }
+}
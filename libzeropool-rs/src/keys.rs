@@ -1,3 +1,9 @@
+use std::{
+    ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use bip39::{Language, Mnemonic};
 use libzeropool::{
     fawkes_crypto::ff_uint::{Num, NumRepr, PrimeField, Uint},
     native::{
@@ -5,23 +11,231 @@ use libzeropool::{
         params::PoolParams,
     },
 };
+use thiserror::Error;
+
+use crate::utils::keccak256;
+
+/// Domain separator of the spending keys derived from a mnemonic.
+const MNEMONIC_SK_DOMAIN: &[u8] = b"zeropool-sk";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum KeyError {
+    #[error("Invalid mnemonic: {0}")]
+    InvalidMnemonic(String),
+}
 
 pub fn reduce_sk<Fs: PrimeField>(seed: &[u8]) -> Num<Fs> {
     Num::<Fs>::from_uint_reduced(NumRepr(Uint::from_little_endian(seed)))
 }
 
-#[derive(Clone)]
+/// Derives the spending key of the account `account_index` from an English BIP-39 mnemonic
+/// (empty passphrase): `reduce_sk(keccak256("zeropool-sk" || seed || account_index as u32 LE))`.
+pub fn derive_from_mnemonic<Fs: PrimeField>(
+    phrase: &str,
+    account_index: u32,
+) -> Result<Num<Fs>, KeyError> {
+    let mnemonic = Mnemonic::parse_in(Language::English, phrase)
+        .map_err(|err| KeyError::InvalidMnemonic(err.to_string()))?;
+    let seed = mnemonic.to_seed("");
+    let data = [MNEMONIC_SK_DOMAIN, &seed, &account_index.to_le_bytes()].concat();
+
+    Ok(reduce_sk(&keccak256(&data)))
+}
+
+/// Overwrites the bytes with zeros in a way that is not optimized out by the compiler.
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, aligned reference.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+fn zeroize_num<F: PrimeField>(num: &mut Num<F>) {
+    // SAFETY: `num` is a valid, aligned reference.
+    unsafe { ptr::write_volatile(num, Num::ZERO) };
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Spending key that is overwritten with zeros on drop. It is intentionally not `Clone` and
+/// not `Copy`, and is consumed by [`Keys::derive`].
+pub struct SecretKey<Fs: PrimeField>(Num<Fs>);
+
+impl<Fs: PrimeField> SecretKey<Fs> {
+    pub fn new(sk: Num<Fs>) -> Self {
+        SecretKey(sk)
+    }
+
+    /// Parses a little endian spending key, `None` if it's not an element of `Fs`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Num::from_uint(NumRepr(Uint::from_little_endian(bytes))).map(SecretKey)
+    }
+
+    pub fn expose(&self) -> Num<Fs> {
+        self.0
+    }
+}
+
+impl<Fs: PrimeField> From<Num<Fs>> for SecretKey<Fs> {
+    fn from(sk: Num<Fs>) -> Self {
+        SecretKey::new(sk)
+    }
+}
+
+impl<Fs: PrimeField> Drop for SecretKey<Fs> {
+    fn drop(&mut self) {
+        zeroize_num(&mut self.0);
+    }
+}
+
+/// Keys of an account. The spending key is wiped on drop and the keys can't be cloned, so they
+/// should be borrowed instead:
+///
+/// ```compile_fail
+/// use libzeropool_rs::{keys::Keys, libzeropool::native::params::PoolBN256};
+///
+/// fn assert_clone<T: Clone>() {}
+/// assert_clone::<Keys<PoolBN256>>();
+/// ```
 pub struct Keys<P: PoolParams> {
-    pub sk: Num<P::Fs>,
-    pub a: Num<P::Fr>,
+    /// Spending key, `None` for watch-only keys.
+    pub sk: Option<Num<P::Fs>>,
+    /// Public authorization key, `None` for watch-only keys.
+    pub a: Option<Num<P::Fr>>,
     pub eta: Num<P::Fr>,
 }
 
 impl<P: PoolParams> Keys<P> {
-    pub fn derive(sk: Num<P::Fs>, params: &P) -> Self {
-        let a = derive_key_a(sk, params).x;
+    pub fn derive(sk: SecretKey<P::Fs>, params: &P) -> Self {
+        let a = derive_key_a(sk.expose(), params).x;
         let eta = derive_key_eta(a, params);
 
-        Keys { sk, a, eta }
+        Keys {
+            sk: Some(sk.expose()),
+            a: Some(a),
+            eta,
+        }
+    }
+
+    /// Keys that can only decrypt notes and derive addresses.
+    pub fn watch_only(eta: Num<P::Fr>) -> Self {
+        Keys {
+            sk: None,
+            a: None,
+            eta,
+        }
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.sk.is_none()
+    }
+
+    /// Wipes the spending key, turning the keys into watch-only ones.
+    pub fn forget_sk(&mut self) {
+        if let Some(sk) = self.sk.as_mut() {
+            zeroize_num(sk);
+        }
+        self.sk = None;
+        self.a = None;
+    }
+}
+
+impl<P: PoolParams> Drop for Keys<P> {
+    fn drop(&mut self) {
+        self.forget_sk();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use libzeropool::{native::params::PoolBN256, POOL_PARAMS};
+
+    use super::*;
+
+    type Fs = <PoolBN256 as PoolParams>::Fs;
+
+    const ABANDON: &str = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                           abandon abandon abandon about";
+    const LEGAL: &str =
+        "legal winner thank year wave sausage worth useful legal winner thank yellow";
+
+    #[test]
+    fn test_derive_from_mnemonic_known_answers() {
+        let vectors = vec![
+            (
+                ABANDON,
+                0,
+                "488204640081067322571857209681136132298434973598022343941844795121058490736",
+            ),
+            (
+                ABANDON,
+                1,
+                "2318307560511513714786826262087080689275019613275871874613639641033838213612",
+            ),
+            (
+                LEGAL,
+                0,
+                "3547721434727224979302371407702308389879939809087429045381195052197252037528",
+            ),
+            (
+                LEGAL,
+                1,
+                "4658310284382659260762594543385391802634470691155653304856555966334284363547",
+            ),
+        ];
+
+        for (phrase, account_index, expected) in vectors {
+            let sk = derive_from_mnemonic::<Fs>(phrase, account_index).unwrap();
+            assert_eq!(sk, Num::from_str(expected).unwrap());
+        }
+
+        assert_ne!(
+            derive_from_mnemonic::<Fs>(ABANDON, 0).unwrap(),
+            derive_from_mnemonic::<Fs>(ABANDON, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_derive_from_mnemonic_invalid() {
+        // Wrong checksum word
+        let bad_checksum = ABANDON.replace("about", "abandon");
+        assert!(matches!(
+            derive_from_mnemonic::<Fs>(&bad_checksum, 0),
+            Err(KeyError::InvalidMnemonic(_))
+        ));
+        assert!(matches!(
+            derive_from_mnemonic::<Fs>("not a mnemonic", 0),
+            Err(KeyError::InvalidMnemonic(_))
+        ));
+        assert!(matches!(
+            derive_from_mnemonic::<Fs>("", 0),
+            Err(KeyError::InvalidMnemonic(_))
+        ));
+    }
+
+    #[test]
+    fn test_forget_sk() {
+        let mut keys = Keys::derive(SecretKey::new(Num::ONE), &*POOL_PARAMS);
+        let eta = keys.eta;
+        assert!(!keys.is_watch_only());
+
+        keys.forget_sk();
+        assert!(keys.is_watch_only());
+        assert!(keys.a.is_none());
+        assert_eq!(keys.eta, eta);
+    }
+
+    #[test]
+    fn test_secret_key_from_bytes() {
+        let mut bytes = Num::<Fs>::from(5u64).to_uint().0.to_little_endian();
+        let sk = SecretKey::<Fs>::from_bytes(&bytes).unwrap();
+        assert_eq!(sk.expose(), Num::from(5u64));
+
+        zeroize(&mut bytes);
+        assert!(bytes.iter().all(|byte| *byte == 0));
+
+        assert!(SecretKey::<Fs>::from_bytes(&[0xff; 32]).is_none());
     }
 }
@@ -2,7 +2,9 @@ pub use libzeropool;
 
 pub mod address;
 pub mod client;
+pub mod delta;
 pub mod keys;
+pub mod memo;
 pub mod merkle;
 #[cfg(feature = "groth16")]
 pub mod proof_groth16;
@@ -11,7 +13,11 @@ pub mod proof_plonk;
 pub mod random;
 pub mod sparse_array;
 pub mod store;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_vectors;
+pub mod tx_parser;
 pub mod utils;
+pub mod witness;
 
 pub mod proof {
     #[doc(inline)]
@@ -0,0 +1,527 @@
+use std::{convert::TryInto, ops::Range};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use libzeropool::{
+    fawkes_crypto::{
+        borsh::{BorshDeserialize, BorshSerialize},
+        ff_uint::{Num, NumRepr, PrimeField, Uint},
+        native::ecc::EdwardsPoint,
+        rand::{Rng, RngCore},
+    },
+    native::{
+        account::Account, boundednum::BoundedNum, key::derive_key_p_d, note::Note,
+        params::PoolParams,
+    },
+};
+use thiserror::Error;
+
+use crate::{
+    client::TxType,
+    utils::{keccak256, zero_note},
+};
+
+/// Size of the addresses (withdrawal receiver, permittable deposit holder) stored in memos.
+pub const ADDRESS_SIZE: usize = 20;
+
+const FEE_SIZE: usize = 8;
+const NATIVE_AMOUNT_SIZE: usize = 8;
+const DEADLINE_SIZE: usize = 8;
+const NUM_ITEMS_SIZE: usize = 4;
+const HASH_SIZE: usize = 32;
+/// Ephemeral public key of the ciphertext (x coordinate of a point).
+const EPK_SIZE: usize = 32;
+const SYMMETRIC_KEY_SIZE: usize = 32;
+/// Authentication tag appended by the symmetric cipher.
+const MAC_SIZE: usize = 16;
+
+/// Maximum size of the text attached to a transfer, see [`encrypt_memo_text`].
+pub const MAX_MEMO_TEXT_SIZE: usize = u8::MAX as usize;
+/// Ends a memo that carries an encrypted text.
+const MEMO_TEXT_MAGIC: &[u8] = b"zpmt";
+/// Layout version of the encrypted text, the first byte of the trailer.
+const MEMO_TEXT_VERSION: u8 = 1;
+/// Version, number of recipients, size of the text and the magic.
+const MEMO_TEXT_TRAILER_SIZE: usize = 3 + MEMO_TEXT_MAGIC.len();
+/// Ephemeral key and the encrypted content key of a recipient.
+const MEMO_TEXT_RECIPIENT_SIZE: usize = EPK_SIZE + SYMMETRIC_KEY_SIZE + MAC_SIZE;
+const MEMO_TEXT_KEY_DOMAIN: &[u8] = b"zp_memo_text";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MemoTextError {
+    #[error("Encrypted data is too long: expected {max} bytes max, got {got}")]
+    TooLong { max: usize, got: usize },
+    #[error("Encrypted data requires at least one output")]
+    NoRecipients,
+    #[error("Invalid public key of the recipient: {0}")]
+    InvalidRecipientKey(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MemoParseError {
+    #[error("Memo is truncated: expected at least {expected} bytes, got {got}")]
+    Truncated { expected: usize, got: usize },
+}
+
+/// Transaction type without the transaction data, determines the layout of the memo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxTypeId {
+    Deposit,
+    DepositPermittable,
+    Transfer,
+    Withdraw,
+}
+
+impl<Fr: PrimeField> From<&TxType<Fr>> for TxTypeId {
+    fn from(tx: &TxType<Fr>) -> Self {
+        match tx {
+            TxType::Deposit { .. } => TxTypeId::Deposit,
+            TxType::DepositPermittable { .. } => TxTypeId::DepositPermittable,
+            TxType::Transfer { .. } | TxType::TransferV2 { .. } => TxTypeId::Transfer,
+            TxType::Withdraw { .. } => TxTypeId::Withdraw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedMemo<Fr: PrimeField> {
+    /// Fee, absent if the memo contains only the ciphertext.
+    pub fee: Option<u64>,
+    /// Withdrawals only.
+    pub native_amount: Option<u64>,
+    /// Withdrawal receiver.
+    pub receiver: Option<Vec<u8>>,
+    /// Permittable deposits only.
+    pub deadline: Option<u64>,
+    /// Permittable deposit holder.
+    pub holder: Option<Vec<u8>>,
+    /// Position of the encrypted account and notes within the memo.
+    pub ciphertext: Range<usize>,
+    /// Hashes of the account and the notes from the beginning of the ciphertext.
+    pub hashes: Vec<Num<Fr>>,
+}
+
+impl<Fr: PrimeField> ParsedMemo<Fr> {
+    /// Returns the ciphertext part of the memo this was parsed from.
+    pub fn ciphertext<'a>(&self, memo: &'a [u8]) -> &'a [u8] {
+        &memo[self.ciphertext.clone()]
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], MemoParseError> {
+        let end = self
+            .pos
+            .checked_add(size)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(MemoParseError::Truncated {
+                expected: self.pos.saturating_add(size),
+                got: self.data.len(),
+            })?;
+
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn take_u64(&mut self) -> Result<u64, MemoParseError> {
+        Ok(BigEndian::read_u64(self.take(FEE_SIZE)?))
+    }
+}
+
+/// Splits a memo produced by [`crate::client::UserAccount::create_tx`] into its parts.
+/// The layout of the transaction specific data depends on the transaction type. If `tx_type_hint`
+/// is `None`, the memo is expected to contain only the ciphertext.
+pub fn parse_memo<Fr: PrimeField>(
+    memo: &[u8],
+    tx_type_hint: Option<TxTypeId>,
+) -> Result<ParsedMemo<Fr>, MemoParseError> {
+    let mut reader = Reader { data: memo, pos: 0 };
+
+    let mut parsed = ParsedMemo {
+        fee: None,
+        native_amount: None,
+        receiver: None,
+        deadline: None,
+        holder: None,
+        ciphertext: 0..0,
+        hashes: vec![],
+    };
+
+    match tx_type_hint {
+        Some(TxTypeId::Deposit) | Some(TxTypeId::Transfer) => {
+            parsed.fee = Some(reader.take_u64()?);
+        }
+        Some(TxTypeId::DepositPermittable) => {
+            parsed.fee = Some(reader.take_u64()?);
+            parsed.deadline = Some(reader.take_u64()?);
+            parsed.holder = Some(reader.take(ADDRESS_SIZE)?.to_vec());
+        }
+        Some(TxTypeId::Withdraw) => {
+            parsed.fee = Some(reader.take_u64()?);
+            parsed.native_amount = Some(reader.take_u64()?);
+            parsed.receiver = Some(reader.take(ADDRESS_SIZE)?.to_vec());
+        }
+        None => {}
+    }
+
+    let ciphertext_start = reader.pos;
+    let num_items = LittleEndian::read_u32(reader.take(NUM_ITEMS_SIZE)?);
+    let hashes_size =
+        (num_items as usize)
+            .checked_mul(HASH_SIZE)
+            .ok_or(MemoParseError::Truncated {
+                expected: usize::MAX,
+                got: memo.len(),
+            })?;
+    let hashes = reader.take(hashes_size)?;
+
+    parsed.hashes = hashes
+        .chunks(HASH_SIZE)
+        .map(|bytes| Num::from_uint_reduced(NumRepr(Uint::from_little_endian(bytes))))
+        .collect();
+    parsed.ciphertext = ciphertext_start..memo.len();
+
+    Ok(parsed)
+}
+
+/// Size of the transaction specific data that precedes the ciphertext in a memo.
+pub fn tx_data_size(tx_type: TxTypeId) -> usize {
+    match tx_type {
+        TxTypeId::Deposit | TxTypeId::Transfer => FEE_SIZE,
+        TxTypeId::DepositPermittable => FEE_SIZE + DEADLINE_SIZE + ADDRESS_SIZE,
+        TxTypeId::Withdraw => FEE_SIZE + NATIVE_AMOUNT_SIZE + ADDRESS_SIZE,
+    }
+}
+
+/// Size of the ciphertext of an account and `num_notes` notes: the number of items and their
+/// hashes, the ephemeral key followed by the encrypted symmetric keys of the items, the encrypted
+/// account, and an ephemeral key with the encrypted note for every note.
+pub fn ciphertext_size<Fr: PrimeField>(num_notes: usize) -> usize {
+    let num_items = num_notes + 1;
+    let account = Account::<Fr> {
+        d: BoundedNum::new(Num::ZERO),
+        p_d: Num::ZERO,
+        i: BoundedNum::new(Num::ZERO),
+        b: BoundedNum::new(Num::ZERO),
+        e: BoundedNum::new(Num::ZERO),
+    };
+    let account_size = account.try_to_vec().unwrap().len();
+    let note_size = zero_note::<Fr>().try_to_vec().unwrap().len();
+
+    NUM_ITEMS_SIZE
+        + num_items * HASH_SIZE
+        + EPK_SIZE
+        + num_items * SYMMETRIC_KEY_SIZE
+        + MAC_SIZE
+        + account_size
+        + MAC_SIZE
+        + num_notes * (EPK_SIZE + note_size + MAC_SIZE)
+}
+
+/// Encrypts `text` for the owners of `notes`, the result is appended to the ciphertext of the memo.
+///
+/// The text is encrypted with a random content key, which is encrypted for every note with the
+/// key agreed with its owner in the same way the notes are: for an ephemeral `esk` the memo carries
+/// `epk = G_d * esk`, and both `p_d * esk` (sender) and `epk * eta` (owner) give the shared point.
+/// Memos without a text keep the original layout.
+pub fn encrypt_memo_text<P: PoolParams, R: RngCore>(
+    text: &[u8],
+    notes: &[Note<P::Fr>],
+    rng: &mut R,
+    params: &P,
+) -> Result<Vec<u8>, MemoTextError> {
+    if text.len() > MAX_MEMO_TEXT_SIZE {
+        return Err(MemoTextError::TooLong {
+            max: MAX_MEMO_TEXT_SIZE,
+            got: text.len(),
+        });
+    }
+    if notes.is_empty() {
+        return Err(MemoTextError::NoRecipients);
+    }
+
+    let content_key: [u8; 32] = rng.gen();
+    let mut data = Vec::with_capacity(
+        notes.len() * MEMO_TEXT_RECIPIENT_SIZE + text.len() + MAC_SIZE + MEMO_TEXT_TRAILER_SIZE,
+    );
+
+    for note in notes {
+        let p_d = EdwardsPoint::subgroup_decompress(note.p_d, params.jubjub())
+            .ok_or_else(|| MemoTextError::InvalidRecipientKey(note.p_d.to_string()))?;
+        let esk: Num<P::Fr> = rng.gen();
+        let epk = derive_key_p_d(note.d.to_num(), esk, params).x;
+        let shared = p_d.mul(esk.to_other_reduced(), params.jubjub());
+
+        data.extend(epk.try_to_vec().unwrap());
+        data.extend(symmetric_encrypt(&memo_text_key(shared.x), &content_key));
+    }
+
+    data.extend(symmetric_encrypt(&content_key, text));
+    data.push(MEMO_TEXT_VERSION);
+    data.push(notes.len() as u8);
+    data.push(text.len() as u8);
+    data.extend(MEMO_TEXT_MAGIC);
+
+    Ok(data)
+}
+
+/// Splits the ciphertext of a memo into the part produced by `cipher::encrypt` and the encrypted
+/// text appended by [`encrypt_memo_text`], if any. The text is only split off if the trailer has
+/// a known version, there's a recipient for every note, and the rest is exactly
+/// [`ciphertext_size`] of the notes declared in the header, so legacy ciphertexts are never cut.
+pub fn split_memo_text<Fr: PrimeField>(memo: &[u8]) -> (&[u8], Option<&[u8]>) {
+    if memo.len() < MEMO_TEXT_TRAILER_SIZE || !memo.ends_with(MEMO_TEXT_MAGIC) {
+        return (memo, None);
+    }
+
+    let trailer = &memo[memo.len() - MEMO_TEXT_TRAILER_SIZE..];
+    let (version, num_recipients, text_size) =
+        (trailer[0], trailer[1] as usize, trailer[2] as usize);
+    let size =
+        num_recipients * MEMO_TEXT_RECIPIENT_SIZE + text_size + MAC_SIZE + MEMO_TEXT_TRAILER_SIZE;
+    if version != MEMO_TEXT_VERSION || num_recipients == 0 || size > memo.len() {
+        return (memo, None);
+    }
+
+    let (ciphertext, memo_text) = memo.split_at(memo.len() - size);
+    let num_notes = match parse_memo::<Fr>(ciphertext, None) {
+        Ok(parsed) => parsed.hashes.len().saturating_sub(1),
+        Err(_) => return (memo, None),
+    };
+    if num_notes != num_recipients || ciphertext.len() != ciphertext_size::<Fr>(num_notes) {
+        return (memo, None);
+    }
+
+    (ciphertext, Some(memo_text))
+}
+
+/// Decrypts the text split off by [`split_memo_text`] as the owner of the note at `position` among
+/// the notes of the memo. Returns `None` if the note doesn't belong to the owner of `eta`.
+pub fn decrypt_memo_text<P: PoolParams>(
+    memo_text: &[u8],
+    position: usize,
+    eta: Num<P::Fr>,
+    params: &P,
+) -> Option<Vec<u8>> {
+    let num_recipients = memo_text[memo_text.len() - MEMO_TEXT_TRAILER_SIZE + 1] as usize;
+    if position >= num_recipients {
+        return None;
+    }
+
+    let recipient =
+        &memo_text[position * MEMO_TEXT_RECIPIENT_SIZE..(position + 1) * MEMO_TEXT_RECIPIENT_SIZE];
+    let epk = Num::<P::Fr>::try_from_slice(&recipient[..EPK_SIZE]).ok()?;
+    let epk = EdwardsPoint::subgroup_decompress(epk, params.jubjub())?;
+    let shared = epk.mul(eta.to_other_reduced(), params.jubjub());
+
+    let content_key = symmetric_decrypt(&memo_text_key(shared.x), &recipient[EPK_SIZE..])?;
+    let content_key: [u8; 32] = content_key.try_into().ok()?;
+    let text = &memo_text
+        [num_recipients * MEMO_TEXT_RECIPIENT_SIZE..memo_text.len() - MEMO_TEXT_TRAILER_SIZE];
+
+    symmetric_decrypt(&content_key, text)
+}
+
+fn memo_text_key<Fr: PrimeField>(shared_x: Num<Fr>) -> [u8; 32] {
+    keccak256(&[&shared_x.try_to_vec().unwrap()[..], MEMO_TEXT_KEY_DOMAIN].concat())
+}
+
+// Every key encrypts a single message, so the nonce can be constant.
+fn symmetric_encrypt(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&[0; 12]), plaintext)
+        .unwrap()
+}
+
+fn symmetric_decrypt(key: &[u8; 32], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(&[0; 12]), ciphertext)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::{
+        fawkes_crypto::ff_uint::Num,
+        native::{boundednum::BoundedNum, params::PoolBN256},
+        POOL_PARAMS,
+    };
+
+    use super::*;
+    use crate::client::{state::State, TxOutput, UserAccount};
+
+    type Fr = <PoolBN256 as libzeropool::native::params::PoolParams>::Fr;
+
+    #[test]
+    fn test_parse_memo_roundtrip() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let outputs = || {
+            vec![TxOutput {
+                to: acc.generate_address(),
+                amount: BoundedNum::new(Num::ZERO),
+            }]
+        };
+
+        let txs = vec![
+            (
+                3,
+                TxType::Deposit {
+                    fee: BoundedNum::new(Num::from(3u64)),
+                    deposit_amount: BoundedNum::new(Num::from(5u64)),
+                    outputs: outputs(),
+                },
+            ),
+            (
+                3,
+                TxType::DepositPermittable {
+                    fee: BoundedNum::new(Num::from(3u64)),
+                    deposit_amount: BoundedNum::new(Num::from(5u64)),
+                    deadline: 1_000_000,
+                    holder: vec![1; ADDRESS_SIZE],
+                    outputs: outputs(),
+                },
+            ),
+            (
+                0,
+                TxType::Transfer {
+                    fee: BoundedNum::new(Num::ZERO),
+                    outputs: outputs(),
+                },
+            ),
+            (
+                0,
+                TxType::Withdraw {
+                    fee: BoundedNum::new(Num::ZERO),
+                    withdraw_amount: BoundedNum::new(Num::ZERO),
+                    to: vec![2; ADDRESS_SIZE],
+                    native_amount: BoundedNum::new(Num::from(7u64)),
+                    energy_amount: BoundedNum::new(Num::ZERO),
+                },
+            ),
+        ];
+
+        for (expected_fee, tx) in txs {
+            let tx_type = TxTypeId::from(&tx);
+            let data = acc.create_tx(tx, None, None).unwrap();
+
+            let parsed = parse_memo::<Fr>(&data.memo, Some(tx_type)).unwrap();
+            assert_eq!(parsed.fee, Some(expected_fee));
+            assert_eq!(parsed.ciphertext.start, tx_data_size(tx_type));
+            assert_eq!(parsed.ciphertext(&data.memo), data.ciphertext.as_slice());
+
+            // Withdrawals have no output notes, only the account is encrypted
+            let num_hashes = if tx_type == TxTypeId::Withdraw { 1 } else { 2 };
+            assert_eq!(
+                parsed.hashes,
+                data.out_hashes.as_slice()[..num_hashes].to_vec()
+            );
+
+            match tx_type {
+                TxTypeId::DepositPermittable => {
+                    assert_eq!(parsed.deadline, Some(1_000_000));
+                    assert_eq!(parsed.holder, Some(vec![1; ADDRESS_SIZE]));
+                }
+                TxTypeId::Withdraw => {
+                    assert_eq!(parsed.native_amount, Some(7));
+                    assert_eq!(parsed.receiver, Some(vec![2; ADDRESS_SIZE]));
+                }
+                _ => {
+                    assert_eq!(parsed.deadline, None);
+                    assert_eq!(parsed.receiver, None);
+                }
+            }
+
+            let ciphertext_only = parse_memo::<Fr>(&data.ciphertext, None).unwrap();
+            assert_eq!(ciphertext_only.fee, None);
+            assert_eq!(ciphertext_only.hashes, parsed.hashes);
+        }
+    }
+
+    #[test]
+    fn test_split_memo_text() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let outputs = vec![TxOutput {
+            to: acc.generate_address(),
+            amount: BoundedNum::new(Num::ZERO),
+        }];
+        let transfer = |encrypted_data: Option<Vec<u8>>| match encrypted_data {
+            Some(encrypted_data) => TxType::TransferV2 {
+                fee: BoundedNum::new(Num::ZERO),
+                outputs: outputs.clone(),
+                encrypted_data,
+            },
+            None => TxType::Transfer {
+                fee: BoundedNum::new(Num::ZERO),
+                outputs: outputs.clone(),
+            },
+        };
+
+        let data = acc
+            .create_tx(transfer(Some(b"hello".to_vec())), None, None)
+            .unwrap();
+        let (ciphertext, memo_text) = split_memo_text::<Fr>(&data.ciphertext);
+        assert_eq!(ciphertext.len(), ciphertext_size::<Fr>(1));
+        assert_eq!(
+            memo_text.unwrap().len(),
+            MEMO_TEXT_RECIPIENT_SIZE + 5 + MAC_SIZE + MEMO_TEXT_TRAILER_SIZE
+        );
+
+        // Unknown version
+        let mut unknown = data.ciphertext.clone();
+        let version_pos = unknown.len() - MEMO_TEXT_TRAILER_SIZE;
+        unknown[version_pos] = MEMO_TEXT_VERSION + 1;
+        assert_eq!(split_memo_text::<Fr>(&unknown), (&unknown[..], None));
+
+        // A legacy ciphertext that happens to end with a trailer is not cut
+        let mut legacy = acc
+            .create_tx(transfer(None), None, None)
+            .unwrap()
+            .ciphertext;
+        assert_eq!(split_memo_text::<Fr>(&legacy), (&legacy[..], None));
+        legacy.extend([MEMO_TEXT_VERSION, 1, 0].iter());
+        legacy.extend(MEMO_TEXT_MAGIC);
+        assert_eq!(split_memo_text::<Fr>(&legacy), (&legacy[..], None));
+    }
+
+    #[test]
+    fn test_parse_memo_truncated() {
+        let memo = [0u8; FEE_SIZE + NATIVE_AMOUNT_SIZE + 4];
+        assert_eq!(
+            parse_memo::<Fr>(&memo, Some(TxTypeId::Withdraw)),
+            Err(MemoParseError::Truncated {
+                expected: FEE_SIZE + NATIVE_AMOUNT_SIZE + ADDRESS_SIZE,
+                got: memo.len(),
+            })
+        );
+
+        let mut memo = vec![0u8; FEE_SIZE];
+        memo.extend_from_slice(&2u32.to_le_bytes());
+        memo.extend_from_slice(&[0u8; HASH_SIZE]);
+        assert_eq!(
+            parse_memo::<Fr>(&memo, Some(TxTypeId::Transfer)),
+            Err(MemoParseError::Truncated {
+                expected: FEE_SIZE + NUM_ITEMS_SIZE + 2 * HASH_SIZE,
+                got: memo.len(),
+            })
+        );
+
+        let mut memo = vec![0u8; FEE_SIZE];
+        memo.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            parse_memo::<Fr>(&memo, Some(TxTypeId::Transfer)),
+            Err(MemoParseError::Truncated { got, .. }) if got == memo.len()
+        ));
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use kvdb::{DBTransaction, KeyValueDB};
+use kvdb::{DBOp, DBTransaction, KeyValueDB};
 use kvdb_memorydb::InMemory as MemoryDatabase;
 #[cfg(feature = "native")]
-use kvdb_persy::PersyDatabase as NativeDatabase;
+use kvdb_persy::{OpenMode, PersyDatabase as NativeDatabase};
 #[cfg(feature = "web")]
 use kvdb_web::Database as WebDatabase;
 use libzeropool::{
@@ -15,21 +18,91 @@ use libzeropool::{
         ff_uint::{Num, PrimeField},
         native::poseidon::{poseidon, MerkleProof},
     },
-    native::params::PoolParams,
+    native::{
+        params::PoolParams,
+        tree::{TreePub, TreeSec},
+    },
 };
+#[cfg(feature = "multicore")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::utils::zero_note;
+use crate::utils::{commitment_from_leaves, zero_note};
 
 pub type Hash<F> = Num<F>;
 
-const NUM_COLUMNS: u32 = 4;
+pub(crate) const NUM_COLUMNS: u32 = 4;
+/// Standalone next index record written by older versions, only used to rebuild the metadata.
 const NEXT_INDEX_KEY: &[u8] = br"next_index";
+const METADATA_KEY: &[u8] = br"metadata";
+const SYNC_CURSOR_KEY: &str = "sync_cursor";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const METADATA_VERSION: u8 = 1;
+/// Current layout of the tree database, see [`migrate`].
+pub const SCHEMA_VERSION: u64 = 2;
+/// Node key prefixes for every height of the tree, so that prefix iterators can borrow them.
+const HEIGHT_PREFIXES: [[u8; 4]; constants::HEIGHT + 1] = {
+    let mut prefixes = [[0u8; 4]; constants::HEIGHT + 1];
+    let mut height = 0;
+    while height <= constants::HEIGHT {
+        prefixes[height] = (height as u32).to_be_bytes();
+        height += 1;
+    }
+    prefixes
+};
 enum DbCols {
     Leaves = 0,
     TempLeaves = 1,
     NamedIndex = 2,
-    NextIndex = 3,
+    Metadata = 3,
+}
+
+#[derive(Debug, Error)]
+pub enum TreeMetadataError {
+    #[error("Tree metadata is missing")]
+    Missing,
+    #[error("Tree metadata is malformed")]
+    Malformed,
+    #[error("Unsupported tree metadata version {0}")]
+    UnsupportedVersion(u8),
+    #[error(
+        "Tree database schema version {0} is newer than the supported version {}",
+        SCHEMA_VERSION
+    )]
+    UnsupportedSchemaVersion(u64),
+    #[error("Stored root {stored} does not match the root of the tree {actual}")]
+    RootMismatch { stored: String, actual: String },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TreeMismatch {
+    #[error(
+        "Leafs at index {index} don't match the commitment: expected {expected}, got {computed}"
+    )]
+    Commitment {
+        index: u64,
+        expected: String,
+        computed: String,
+    },
+    #[error("No leafs provided for the commitment at index {0}")]
+    MissingLeafs(u64),
+    #[error("Too many leafs for a single commitment: expected {max} max, got {got}")]
+    TooManyLeafs { max: usize, got: usize },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum VirtualUpdateError {
+    #[error("Index {0} is not a multiple of the commitment size")]
+    UnalignedIndex(u64),
+    #[error("Index {index} is below the next index of the tree {next_index}")]
+    IndexBelowNextIndex { index: u64, next_index: u64 },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CommitmentError {
+    #[error("Commitment index {0} is out of range")]
+    IndexOutOfRange(u64),
 }
 
 pub struct MerkleTree<D: KeyValueDB, P: PoolParams> {
@@ -48,57 +121,218 @@ pub type WebMerkleTree<P> = MerkleTree<WebDatabase, P>;
 
 #[cfg(feature = "web")]
 impl<P: PoolParams> MerkleTree<WebDatabase, P> {
-    pub async fn new_web(name: &str, params: P) -> MerkleTree<WebDatabase, P> {
+    pub async fn new_web(name: &str, params: P) -> std::io::Result<MerkleTree<WebDatabase, P>> {
         let db = WebDatabase::open(name.to_owned(), NUM_COLUMNS)
             .await
             .unwrap();
 
         Self::new(db, params)
     }
+
+    /// Number of writes that are not committed to IndexedDB yet.
+    pub fn pending_writes(&self) -> usize {
+        self.db.pending_writes()
+    }
+
+    /// Resolves once all the previous writes are committed to IndexedDB.
+    pub fn flush(&self) -> impl std::future::Future<Output = ()> {
+        self.db.flush()
+    }
 }
 
 #[cfg(feature = "native")]
 impl<P: PoolParams> MerkleTree<NativeDatabase, P> {
     pub fn new_native(path: &str, params: P) -> std::io::Result<MerkleTree<NativeDatabase, P>> {
         let prefix = (0u32).to_be_bytes();
-        let db = NativeDatabase::open(path, 4, &[&prefix])?;
+        let db = NativeDatabase::open(path, NUM_COLUMNS, &[&prefix], OpenMode::ReadWrite)?;
 
-        Ok(Self::new(db, params))
+        Self::new(db, params)
     }
 }
 
 impl<P: PoolParams> MerkleTree<MemoryDatabase, P> {
     pub fn new_test(params: P) -> MerkleTree<MemoryDatabase, P> {
         Self::new(kvdb_memorydb::create(NUM_COLUMNS), params)
+            .expect("a new database is always up to date")
+    }
+}
+
+/// Upgrades the tree database to [`SCHEMA_VERSION`] in place, does nothing if it's up to date.
+/// Fails if the database has a newer schema version.
+///
+/// Version 1 databases have neither the schema version nor the metadata record, only the legacy
+/// next index record. Their nodes already have the current layout, so the migration only moves
+/// next_index into the metadata record. Unversioned databases with the metadata record only get
+/// the version record.
+pub fn migrate<D: KeyValueDB, P: PoolParams>(db: &D, params: &P) -> std::io::Result<()> {
+    let version = match db.get(DbCols::NamedIndex as u32, SCHEMA_VERSION_KEY.as_bytes())? {
+        Some(data) => Some((&data[..]).read_u64::<BigEndian>()?),
+        None if db.has_key(DbCols::Metadata as u32, METADATA_KEY)? => None,
+        None => Some(1),
+    };
+
+    let mut batch = db.transaction();
+    match version {
+        Some(SCHEMA_VERSION) => return Ok(()),
+        Some(version) if version > SCHEMA_VERSION => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                TreeMetadataError::UnsupportedSchemaVersion(version),
+            ))
+        }
+        Some(_) => MerkleTree::<D, P>::migrate_v1_batched(db, params, &mut batch)?,
+        None => {}
     }
+    batch.put(
+        DbCols::NamedIndex as u32,
+        SCHEMA_VERSION_KEY.as_bytes(),
+        &SCHEMA_VERSION.to_be_bytes(),
+    );
+
+    db.write(batch)
+}
+
+/// Root of the tree that has `leaf` at the position given by `proof`. A proof of height `H`
+/// starts at height `HEIGHT - H`, e.g. commitment proofs take the commitment as the leaf.
+pub fn merkle_proof_root<P: PoolParams, const H: usize>(
+    leaf: Hash<P::Fr>,
+    proof: &MerkleProof<P::Fr, { H }>,
+    params: &P,
+) -> Hash<P::Fr> {
+    proof
+        .sibling
+        .iter()
+        .zip(proof.path.iter())
+        .fold(leaf, |node, (&sibling, &is_right)| {
+            let pair = if is_right {
+                [sibling, node]
+            } else {
+                [node, sibling]
+            };
+            poseidon(pair.as_ref(), params.compress())
+        })
 }
 
 // TODO: Proper error handling.
 impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
-    pub fn new(db: D, params: P) -> Self {
-        let db_next_index = db.get(DbCols::NextIndex as u32, NEXT_INDEX_KEY);
-        let next_index = match db_next_index {
-            Ok(Some(next_index)) => next_index.as_slice().read_u64::<BigEndian>().unwrap(),
-            _ => {
-                let mut cur_next_index = 0;
-                for (k, _v) in db.iter(0).map(|res| res.unwrap()) {
-                    let (height, index) = Self::parse_node_key(&k);
-
-                    if height == 0 && index >= cur_next_index {
-                        cur_next_index = Self::calc_next_index(index);
-                    }
-                }
-                cur_next_index
+    /// Opens the tree, see [`MerkleTree::open_or_rebuild`].
+    pub fn new(db: D, params: P) -> std::io::Result<Self> {
+        Self::open_or_rebuild(db, params)
+    }
+
+    /// Opens the tree using the metadata record without scanning the stored nodes.
+    /// Fails if the metadata is missing or its root doesn't match the stored root node.
+    pub fn open(db: D, params: P) -> Result<Self, TreeMetadataError> {
+        if let Ok(Some(data)) = db.get(DbCols::NamedIndex as u32, SCHEMA_VERSION_KEY.as_bytes()) {
+            let version = (&data[..])
+                .read_u64::<BigEndian>()
+                .map_err(|_| TreeMetadataError::Malformed)?;
+            if version > SCHEMA_VERSION {
+                return Err(TreeMetadataError::UnsupportedSchemaVersion(version));
             }
-        };
+        }
+
+        let mut tree = Self::unloaded(db, params);
+        tree.load_metadata()?;
+
+        Ok(tree)
+    }
+
+    /// Same as [`MerkleTree::open`], but if the metadata is missing or inconsistent,
+    /// next_index is recovered by scanning the stored nodes and the metadata is rewritten.
+    /// Older databases are migrated first, see [`migrate`]. Fails if the database has a newer
+    /// schema version or can't be migrated.
+    pub fn open_or_rebuild(db: D, params: P) -> std::io::Result<Self> {
+        migrate(&db, &params)?;
+
+        let mut tree = Self::unloaded(db, params);
+        if tree.load_metadata().is_err() {
+            tree.rebuild_metadata();
+        }
+
+        Ok(tree)
+    }
 
+    fn unloaded(db: D, params: P) -> Self {
         MerkleTree {
             db,
             default_hashes: Self::gen_default_hashes(&params),
             zero_note_hashes: Self::gen_empty_note_hashes(&params),
             params,
-            next_index,
+            next_index: 0,
+        }
+    }
+
+    fn load_metadata(&mut self) -> Result<(), TreeMetadataError> {
+        let data = match self.db.get(DbCols::Metadata as u32, METADATA_KEY) {
+            Ok(Some(data)) => data,
+            _ => return Err(TreeMetadataError::Missing),
+        };
+        let (next_index, stored_root) = Self::parse_metadata(&data)?;
+
+        self.next_index = next_index;
+        let actual_root = self.get_root();
+        if actual_root != stored_root {
+            self.next_index = 0;
+            return Err(TreeMetadataError::RootMismatch {
+                stored: stored_root.to_string(),
+                actual: actual_root.to_string(),
+            });
         }
+
+        Ok(())
+    }
+
+    fn rebuild_metadata(&mut self) {
+        let legacy_next_index = match self.db.get(DbCols::Metadata as u32, NEXT_INDEX_KEY) {
+            Ok(Some(data)) => (&data[..]).read_u64::<BigEndian>().unwrap_or(0),
+            _ => 0,
+        };
+
+        // Nodes up to the commitment height belong to a single OUT+1 slot, so they pin
+        // next_index exactly, even if the leaves of the slot are not stored.
+        let tree = &*self;
+        let next_index = (0..=constants::OUTPLUSONELOG as u32)
+            .flat_map(move |height| {
+                tree.iter_nodes_in(height, 0..u64::MAX)
+                    .map(move |node| Self::calc_next_index(node.index << height))
+            })
+            .fold(legacy_next_index, u64::max);
+
+        self.next_index = next_index;
+
+        let mut batch = self.db.transaction();
+        batch.delete(DbCols::Metadata as u32, NEXT_INDEX_KEY);
+        self.write_batch(batch);
+    }
+
+    /// Moves next_index of a version 1 database from the legacy record (or the stored nodes, if
+    /// it's missing) into the metadata record. The nodes are left as is.
+    fn migrate_v1_batched(db: &D, params: &P, batch: &mut DBTransaction) -> std::io::Result<()> {
+        let nodes = db
+            .iter(DbCols::Leaves as u32)
+            .map(|res| res.map(|(key, value)| Self::build_node(&key, &value)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let legacy_next_index = match db.get(DbCols::Metadata as u32, NEXT_INDEX_KEY)? {
+            Some(data) => (&data[..]).read_u64::<BigEndian>().unwrap_or(0),
+            None => 0,
+        };
+        let next_index = nodes
+            .iter()
+            .filter(|node| node.height <= constants::OUTPLUSONELOG as u32)
+            .map(|node| Self::calc_next_index(node.index << node.height))
+            .fold(legacy_next_index, u64::max);
+
+        let root = nodes
+            .iter()
+            .find(|node| node.height == constants::HEIGHT as u32)
+            .map(|node| node.value)
+            .unwrap_or_else(|| Self::gen_default_hashes(params)[constants::HEIGHT]);
+        batch.delete(DbCols::Metadata as u32, NEXT_INDEX_KEY);
+        Self::set_metadata_batched(batch, next_index, root);
+
+        Ok(())
     }
 
     /// Add hash for an element with a certain index at a certain height
@@ -127,7 +361,7 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         // update inner nodes
         self.update_path_batched(&mut batch, height, index, hash, temporary_leaves_count);
 
-        self.db.write(batch).unwrap();
+        self.write_batch(batch);
     }
 
     pub fn add_hash(&mut self, index: u64, hash: Hash<P::Fr>, temporary: bool) {
@@ -140,6 +374,90 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         index
     }
 
+    /// Adds the commitment of the transaction occupying the commitment slot `commitment_index`,
+    /// i.e. the leafs starting at `commitment_index * (OUT + 1)`. The next index is moved past the
+    /// slot if it's not there yet.
+    pub fn add_commitment(
+        &mut self,
+        commitment_index: u64,
+        hash: Hash<P::Fr>,
+    ) -> Result<(), CommitmentError> {
+        if commitment_index >= 1 << (constants::HEIGHT - constants::OUTPLUSONELOG) {
+            return Err(CommitmentError::IndexOutOfRange(commitment_index));
+        }
+
+        self.add_hash_at_height(
+            constants::OUTPLUSONELOG as u32,
+            commitment_index,
+            hash,
+            false,
+        );
+
+        Ok(())
+    }
+
+    /// Imports the siblings of the path from the node `index` at height `HEIGHT - H` to the root,
+    /// e.g. a proof obtained from a server, so that a light client can build proofs for its own
+    /// leafs without having the full tree. The node itself should already be in the tree.
+    ///
+    /// The siblings don't affect the next index. They are stored as permanent nodes, so that
+    /// [`MerkleTree::clean`] never removes them.
+    pub fn add_proof<const H: usize>(
+        &mut self,
+        index: u64,
+        siblings: &SizedVec<Hash<P::Fr>, { H }>,
+    ) {
+        let start_height = (constants::HEIGHT - H) as u32;
+        let mut batch = self.db.transaction();
+
+        let mut node_index = index;
+        let mut node_hash = self.get(start_height, index);
+        let mut temporary_leaves_count = self.get_temporary_count(start_height, index);
+
+        for (height, &sibling_hash) in (start_height..).zip(siblings.iter()) {
+            let sibling_index = node_index ^ 1;
+            if self.get(height, sibling_index) == sibling_hash {
+                temporary_leaves_count += self.get_temporary_count(height, sibling_index);
+            } else {
+                self.set_batched(&mut batch, height, sibling_index, sibling_hash, 0);
+            }
+
+            let pair = if node_index % 2 == 0 {
+                [node_hash, sibling_hash]
+            } else {
+                [sibling_hash, node_hash]
+            };
+            node_hash = poseidon(pair.as_ref(), self.params.compress());
+            node_index /= 2;
+
+            self.set_batched(
+                &mut batch,
+                height + 1,
+                node_index,
+                node_hash,
+                temporary_leaves_count,
+            );
+        }
+
+        self.write_batch(batch);
+    }
+
+    /// Checks whether all the nodes required by [`MerkleTree::get_leaf_proof`] are known: the leaf
+    /// is stored and every sibling on its path is either stored, an empty subtree after the next
+    /// index or a zero note subtree inside of a transaction slot.
+    pub fn has_full_path(&self, index: u64) -> bool {
+        if self.get_opt(0, index).is_none() {
+            return false;
+        }
+
+        (0..constants::HEIGHT as u32).all(|height| {
+            let sibling_index = (index >> height) ^ 1;
+            height < constants::OUTPLUSONELOG as u32
+                || sibling_index << height >= self.next_index
+                || self.get_opt(height, sibling_index).is_some()
+        })
+    }
+
     pub fn add_leafs_and_commitments(
         &mut self,
         leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
@@ -149,41 +467,103 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             return;
         }
 
-        let mut next_index: u64 = 0;
-        let mut start_index: u64 = u64::MAX;
-        let mut virtual_nodes: HashMap<(u32, u64), Hash<P::Fr>> = commitments
-            .into_iter()
-            .map(|(index, hash)| {
-                assert_eq!(index & ((1 << constants::OUTPLUSONELOG) - 1), 0);
-                start_index = start_index.min(index);
-                next_index = next_index.max(index + 1);
-                (
-                    (
-                        constants::OUTPLUSONELOG as u32,
-                        index >> constants::OUTPLUSONELOG,
-                    ),
-                    hash,
-                )
-            })
-            .collect();
+        let virtual_nodes = self.calc_leafs_and_commitments(leafs, commitments);
+        self.put_hashes(virtual_nodes);
+    }
 
-        leafs.into_iter().for_each(|(index, leafs)| {
-            assert_eq!(index & ((1 << constants::OUTPLUSONELOG) - 1), 0);
-            start_index = start_index.min(index);
-            next_index = next_index.max(index + leafs.len() as u64);
-            (0..constants::OUTPLUSONELOG).for_each(|height| {
-                virtual_nodes.insert(
-                    (
-                        height as u32,
-                        ((index + leafs.len() as u64 - 1) >> height) + 1,
-                    ),
-                    self.zero_note_hashes[height],
-                );
-            });
-            leafs.into_iter().enumerate().for_each(|(i, leaf)| {
-                virtual_nodes.insert((0_u32, index + i as u64), leaf);
-            });
-        });
+    /// Same as [`MerkleTree::add_leafs_and_commitments`], but returns every node written in the
+    /// batch, including the recalculated ancestors up to the root, ordered by (height, index).
+    /// Unfilled nodes of the added subtrees have zero note hashes and are not stored.
+    pub fn add_leafs_and_commitments_with_changes(
+        &mut self,
+        leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
+        commitments: Vec<(u64, Hash<P::Fr>)>,
+    ) -> Vec<Node<P::Fr>> {
+        if leafs.is_empty() && commitments.is_empty() {
+            return vec![];
+        }
+
+        let virtual_nodes = self.calc_leafs_and_commitments(leafs, commitments);
+        let changes = Self::sorted_nodes(&virtual_nodes);
+        self.put_hashes(virtual_nodes);
+
+        changes
+    }
+
+    /// Same as [`MerkleTree::add_leafs_and_commitments`], but also sets the sync cursor in the
+    /// same write. Returns the written nodes if `with_changes` is set, see
+    /// [`MerkleTree::add_leafs_and_commitments_with_changes`].
+    pub(crate) fn add_leafs_and_commitments_with_cursor(
+        &mut self,
+        leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
+        commitments: Vec<(u64, Hash<P::Fr>)>,
+        sync_cursor: u64,
+        with_changes: bool,
+    ) -> Vec<Node<P::Fr>> {
+        let virtual_nodes = if leafs.is_empty() && commitments.is_empty() {
+            HashMap::new()
+        } else {
+            self.calc_leafs_and_commitments(leafs, commitments)
+        };
+        let changes = if with_changes {
+            Self::sorted_nodes(&virtual_nodes)
+        } else {
+            vec![]
+        };
+
+        let mut batch = self.db.transaction();
+        for ((height, index), value) in virtual_nodes {
+            self.set_batched(&mut batch, height, index, value, 0);
+        }
+        self.set_named_index_batched(&mut batch, SYNC_CURSOR_KEY, sync_cursor);
+        self.write_batch(batch);
+
+        changes
+    }
+
+    /// Index up to which the transactions were fetched and applied, see
+    /// [`crate::client::state::State::apply_update`]. Independent from the next index, which is
+    /// also moved by the optimistic updates.
+    pub fn sync_cursor(&self) -> u64 {
+        self.get_named_index_opt(SYNC_CURSOR_KEY).unwrap_or(0)
+    }
+
+    pub fn set_sync_cursor(&mut self, sync_cursor: u64) {
+        let mut batch = self.db.transaction();
+        self.set_named_index_batched(&mut batch, SYNC_CURSOR_KEY, sync_cursor);
+        self.write_batch(batch);
+    }
+
+    fn calc_leafs_and_commitments(
+        &mut self,
+        leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
+        commitments: Vec<(u64, Hash<P::Fr>)>,
+    ) -> HashMap<(u32, u64), Hash<P::Fr>> {
+        let (mut virtual_nodes, update_boundaries) =
+            self.prepare_leafs_and_commitments(leafs, commitments);
+
+        // calculate new hashes
+        self.get_virtual_node_full(
+            constants::HEIGHT as u32,
+            0,
+            &mut virtual_nodes,
+            &update_boundaries,
+        );
+
+        virtual_nodes
+    }
+
+    /// Updates next_index and returns the new nodes together with the boundaries of the update.
+    fn prepare_leafs_and_commitments(
+        &mut self,
+        leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
+        commitments: Vec<(u64, Hash<P::Fr>)>,
+    ) -> (HashMap<(u32, u64), Hash<P::Fr>>, UpdateBoundaries) {
+        let (virtual_nodes, new_range) = self.virtual_nodes_from_state(leafs, commitments);
+        let Range {
+            start: start_index,
+            end: next_index,
+        } = new_range.unwrap_or(self.next_index..self.next_index);
 
         let original_next_index = self.next_index;
         self.update_next_index_from_node(0, next_index);
@@ -195,6 +575,114 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             new_hashes_right_index: next_index,
         };
 
+        (virtual_nodes, update_boundaries)
+    }
+
+    pub fn add_hashes<I>(&mut self, start_index: u64, hashes: I)
+    where
+        I: IntoIterator<Item = Hash<P::Fr>>,
+    {
+        let virtual_nodes = self.calc_hashes(start_index, hashes);
+        self.put_hashes(virtual_nodes);
+    }
+
+    /// Same as [`MerkleTree::add_hashes`], but the hashes of a single transaction are only added if
+    /// they match its on-chain commitment.
+    pub fn add_hashes_checked<I>(
+        &mut self,
+        start_index: u64,
+        hashes: I,
+        expected_commitment: Hash<P::Fr>,
+    ) -> Result<(), TreeMismatch>
+    where
+        I: IntoIterator<Item = Hash<P::Fr>>,
+    {
+        let hashes: Vec<_> = hashes.into_iter().collect();
+        self.check_commitment(start_index, &hashes, expected_commitment)?;
+        self.add_hashes(start_index, hashes);
+
+        Ok(())
+    }
+
+    /// Checks that the leafs of every transaction with an expected commitment hash to it. Leaf
+    /// batches are keyed by their first index and can span several transactions.
+    pub fn check_leafs(
+        &self,
+        leafs: &[(u64, Vec<Hash<P::Fr>>)],
+        expected_commitments: &[(u64, Hash<P::Fr>)],
+    ) -> Result<(), TreeMismatch> {
+        let slot_size = constants::OUT as u64 + 1;
+
+        for &(index, expected_commitment) in expected_commitments {
+            let slot = index..index + slot_size;
+            let mut slot_leafs = vec![None; constants::OUT + 1];
+            for (start_index, hashes) in leafs {
+                for (leaf_index, hash) in (*start_index..).zip(hashes) {
+                    if slot.contains(&leaf_index) {
+                        slot_leafs[(leaf_index - index) as usize] = Some(*hash);
+                    }
+                }
+            }
+
+            if slot_leafs.iter().all(Option::is_none) {
+                return Err(TreeMismatch::MissingLeafs(index));
+            }
+
+            let slot_leafs: Vec<_> = slot_leafs
+                .into_iter()
+                .map(|hash| hash.unwrap_or(self.zero_note_hashes[0]))
+                .collect();
+            self.check_commitment(index, &slot_leafs, expected_commitment)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_commitment(
+        &self,
+        index: u64,
+        hashes: &[Hash<P::Fr>],
+        expected_commitment: Hash<P::Fr>,
+    ) -> Result<(), TreeMismatch> {
+        if hashes.len() > constants::OUT + 1 {
+            return Err(TreeMismatch::TooManyLeafs {
+                max: constants::OUT + 1,
+                got: hashes.len(),
+            });
+        }
+
+        let computed = commitment_from_leaves(hashes, &self.params);
+
+        if computed != expected_commitment {
+            return Err(TreeMismatch::Commitment {
+                index,
+                expected: expected_commitment.to_string(),
+                computed: computed.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`MerkleTree::add_hashes`], but returns every node written in the batch, including
+    /// the recalculated ancestors up to the root, ordered by (height, index).
+    pub fn add_hashes_with_changes<I>(&mut self, start_index: u64, hashes: I) -> Vec<Node<P::Fr>>
+    where
+        I: IntoIterator<Item = Hash<P::Fr>>,
+    {
+        let virtual_nodes = self.calc_hashes(start_index, hashes);
+        let changes = Self::sorted_nodes(&virtual_nodes);
+        self.put_hashes(virtual_nodes);
+
+        changes
+    }
+
+    fn calc_hashes<I>(&mut self, start_index: u64, hashes: I) -> HashMap<(u32, u64), Hash<P::Fr>>
+    where
+        I: IntoIterator<Item = Hash<P::Fr>>,
+    {
+        let (mut virtual_nodes, update_boundaries) = self.prepare_hashes(start_index, hashes);
+
         // calculate new hashes
         self.get_virtual_node_full(
             constants::HEIGHT as u32,
@@ -203,18 +691,22 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             &update_boundaries,
         );
 
-        // add new hashes to tree
-        self.put_hashes(virtual_nodes);
+        virtual_nodes
     }
 
-    pub fn add_hashes<I>(&mut self, start_index: u64, hashes: I)
+    /// Updates next_index and returns the new leafs together with the boundaries of the update.
+    fn prepare_hashes<I>(
+        &mut self,
+        start_index: u64,
+        hashes: I,
+    ) -> (HashMap<(u32, u64), Hash<P::Fr>>, UpdateBoundaries)
     where
         I: IntoIterator<Item = Hash<P::Fr>>,
     {
         // check that index is correct
         assert_eq!(start_index & ((1 << constants::OUTPLUSONELOG) - 1), 0);
 
-        let mut virtual_nodes: HashMap<(u32, u64), Hash<P::Fr>> = hashes
+        let virtual_nodes: HashMap<(u32, u64), Hash<P::Fr>> = hashes
             .into_iter()
             // todo: check that there are no zero holes?
             .filter(|hash| *hash != self.zero_note_hashes[0])
@@ -228,25 +720,88 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         let original_next_index = self.next_index;
         self.update_next_index_from_node(0, start_index);
 
+        // Leaves can arrive after the commitment of their subtree has already been added,
+        // in that case only the subtree itself has to be recalculated.
+        let (updated_range_left_index, updated_range_right_index) =
+            if start_index < original_next_index {
+                (start_index, start_index + (1 << constants::OUTPLUSONELOG))
+            } else {
+                (original_next_index, self.next_index)
+            };
+
         let update_boundaries = UpdateBoundaries {
-            updated_range_left_index: original_next_index,
-            updated_range_right_index: self.next_index,
+            updated_range_left_index,
+            updated_range_right_index,
             new_hashes_left_index: start_index,
             new_hashes_right_index: start_index + new_hashes_count,
         };
 
+        (virtual_nodes, update_boundaries)
+    }
+
+    /// Appends commitments at the next OUT+1-aligned slots of the tree in a single transaction.
+    /// Returns the new root and the leaf index of the first appended commitment.
+    pub fn append_commitments(&mut self, commitments: &[Hash<P::Fr>]) -> (Hash<P::Fr>, u64) {
+        let first_index = self.next_index;
+        if commitments.is_empty() {
+            return (self.get_root(), first_index);
+        }
+
+        let first_commitment_index = first_index >> constants::OUTPLUSONELOG;
+        let mut virtual_nodes: HashMap<(u32, u64), Hash<P::Fr>> = commitments
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| {
+                (
+                    (
+                        constants::OUTPLUSONELOG as u32,
+                        first_commitment_index + i as u64,
+                    ),
+                    *hash,
+                )
+            })
+            .collect();
+
+        let next_index = first_index + ((commitments.len() as u64) << constants::OUTPLUSONELOG);
+        let update_boundaries = UpdateBoundaries {
+            updated_range_left_index: first_index,
+            updated_range_right_index: next_index,
+            new_hashes_left_index: first_index,
+            new_hashes_right_index: next_index,
+        };
+
         // calculate new hashes
-        self.get_virtual_node_full(
+        let root = self.get_virtual_node_full(
             constants::HEIGHT as u32,
             0,
             &mut virtual_nodes,
             &update_boundaries,
         );
 
-        // add new hashes to tree
-        self.put_hashes(virtual_nodes);
-    }
-
+        let mut batch = self.db.transaction();
+        for ((height, index), value) in virtual_nodes {
+            self.set_batched(&mut batch, height, index, value, 0);
+        }
+        self.next_index = next_index;
+        self.write_batch(batch);
+
+        (root, first_index)
+    }
+
+    fn sorted_nodes(virtual_nodes: &HashMap<(u32, u64), Hash<P::Fr>>) -> Vec<Node<P::Fr>> {
+        let mut nodes: Vec<_> = virtual_nodes
+            .iter()
+            .map(|(&(height, index), &value)| Node {
+                index,
+                height,
+                value,
+            })
+            .collect();
+        nodes.sort_by_key(|node| (node.height, node.index));
+
+        nodes
+    }
+
     fn put_hashes(&mut self, virtual_nodes: HashMap<(u32, u64), Hash<<P as PoolParams>::Fr>>) {
         let mut batch = self.db.transaction();
 
@@ -254,7 +809,7 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             self.set_batched(&mut batch, height, index, value, 0);
         }
 
-        self.db.write(batch).unwrap();
+        self.write_batch(batch);
     }
 
     // This method is used in tests.
@@ -270,7 +825,7 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         // update path
         self.update_path_batched(&mut batch, height, index, hash, 1 << height);
 
-        self.db.write(batch).unwrap();
+        self.write_batch(batch);
     }
 
     pub fn get(&self, height: u32, index: u64) -> Hash<P::Fr> {
@@ -280,14 +835,17 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
     fn get_with_next_index(&self, height: u32, index: u64, next_index: u64) -> Hash<P::Fr> {
         match self.get_opt(height, index) {
             Some(val) => val,
-            _ => {
-                let next_leave_index = u64::pow(2, height) * (index + 1);
-                if next_leave_index <= next_index {
-                    self.zero_note_hashes[height as usize]
-                } else {
-                    self.default_hashes[height as usize]
-                }
-            }
+            _ => self.missing_node_hash(height, index, next_index),
+        }
+    }
+
+    /// Hash of a node that is not stored: zero notes before next_index, empty leaves after it.
+    fn missing_node_hash(&self, height: u32, index: u64, next_index: u64) -> Hash<P::Fr> {
+        let next_leave_index = u64::pow(2, height) * (index + 1);
+        if next_leave_index <= next_index {
+            self.zero_note_hashes[height as usize]
+        } else {
+            self.default_hashes[height as usize]
         }
     }
 
@@ -303,6 +861,28 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         self.get(constants::HEIGHT as u32, 0)
     }
 
+    /// Root of the tree as it was when the next index was `index`, i.e. with all leafs starting
+    /// from `index` removed. Nodes to the left of `index` are read as is, so the result is only
+    /// correct if they were not removed by [`MerkleTree::clean`].
+    pub fn get_root_at_index(&self, index: u64) -> Hash<P::Fr> {
+        self.get_node_at_index(constants::HEIGHT as u32, 0, index)
+    }
+
+    fn get_node_at_index(&self, height: u32, index: u64, next_index: u64) -> Hash<P::Fr> {
+        let first_leaf_index = index << height;
+        let end_leaf_index = (index + 1) << height;
+        if end_leaf_index <= next_index {
+            return self.get_with_next_index(height, index, next_index);
+        }
+        if first_leaf_index >= next_index {
+            return self.default_hashes[height as usize];
+        }
+
+        let left = self.get_node_at_index(height - 1, 2 * index, next_index);
+        let right = self.get_node_at_index(height - 1, 2 * index + 1, next_index);
+        poseidon([left, right].as_ref(), self.params.compress())
+    }
+
     pub fn get_root_after_virtual<I>(&self, new_commitments: I) -> Hash<P::Fr>
     where
         I: IntoIterator<Item = Hash<P::Fr>>,
@@ -335,6 +915,84 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         )
     }
 
+    /// Assembles the inputs of the tree update circuit for adding `commitment` at the
+    /// commitment slot `commitment_index` without modifying the tree. The filled proof points
+    /// at the previous slot, for the first slot both proofs point at the free slot.
+    pub fn prepare_tree_update(
+        &self,
+        commitment: Hash<P::Fr>,
+        commitment_index: u64,
+    ) -> (TreePub<P::Fr>, TreeSec<P::Fr>) {
+        let height = constants::OUTPLUSONELOG as u32;
+        let left_index = commitment_index << constants::OUTPLUSONELOG;
+
+        let mut virtual_nodes = HashMap::new();
+        virtual_nodes.insert((height, commitment_index), commitment);
+        let root_after = self.get_virtual_node(
+            constants::HEIGHT as u32,
+            0,
+            &mut virtual_nodes,
+            left_index,
+            left_index + constants::OUT as u64 + 1,
+        );
+
+        let proof_free = self.get_proof_unchecked(commitment_index);
+        let (prev_leaf, proof_filled) = match commitment_index.checked_sub(1) {
+            Some(prev_index) => (
+                self.get(height, prev_index),
+                self.get_proof_unchecked(prev_index),
+            ),
+            None => (self.get(height, commitment_index), proof_free.clone()),
+        };
+
+        let public = TreePub {
+            root_before: self.get_root(),
+            root_after,
+            leaf: commitment,
+        };
+        let secret = TreeSec {
+            proof_filled,
+            proof_free,
+            prev_leaf,
+        };
+
+        (public, secret)
+    }
+
+    /// Returns the root the tree would have after adding the leafs and commitments, without
+    /// modifying the tree. Both are keyed by the index of their first leaf, which can't be below
+    /// the next index, so that the existing nodes are never shadowed.
+    pub fn get_root_after_state(
+        &self,
+        leafs: &[(u64, Vec<Hash<P::Fr>>)],
+        commitments: &[(u64, Hash<P::Fr>)],
+    ) -> Result<Hash<P::Fr>, VirtualUpdateError> {
+        let indices = leafs
+            .iter()
+            .map(|(index, _)| *index)
+            .chain(commitments.iter().map(|(index, _)| *index));
+        for index in indices {
+            if index & ((1 << constants::OUTPLUSONELOG) - 1) != 0 {
+                return Err(VirtualUpdateError::UnalignedIndex(index));
+            }
+            if index < self.next_index {
+                return Err(VirtualUpdateError::IndexBelowNextIndex {
+                    index,
+                    next_index: self.next_index,
+                });
+            }
+        }
+
+        if leafs.is_empty() && commitments.is_empty() {
+            return Ok(self.get_root());
+        }
+
+        let (mut virtual_nodes, update_boundaries) =
+            self.get_virtual_subtree(leafs.iter().cloned(), commitments.iter().cloned());
+
+        Ok(self.get_root_optimistic(&mut virtual_nodes, &update_boundaries))
+    }
+
     pub fn get_root_optimistic(
         &self,
         virtual_nodes: &mut HashMap<(u32, u64), Hash<P::Fr>>,
@@ -360,7 +1018,62 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         }
     }
 
+    /// Reads multiple nodes at once. Every distinct node is read only once, in key order, and
+    /// the results are returned in the order of `keys`. Missing nodes are `None`.
+    pub fn get_many(&self, keys: &[(u32, u64)]) -> Vec<Option<Hash<P::Fr>>> {
+        let mut unique_keys = keys.to_vec();
+        unique_keys.sort_unstable();
+        unique_keys.dedup();
+
+        let nodes: HashMap<_, _> = unique_keys
+            .into_iter()
+            .map(|(height, index)| ((height, index), self.get_opt(height, index)))
+            .collect();
+
+        keys.iter().map(|key| nodes[key]).collect()
+    }
+
     pub fn get_proof_unchecked<const H: usize>(&self, index: u64) -> MerkleProof<P::Fr, { H }> {
+        Self::build_proof(index, |height, index| self.get(height, index))
+    }
+
+    /// Batched version of [`MerkleTree::get_leaf_proof`]: the siblings shared by the proofs
+    /// (e.g. the upper part of the path) are read once, see [`MerkleTree::get_many`].
+    pub fn get_proofs(
+        &self,
+        indices: &[u64],
+    ) -> Vec<Option<MerkleProof<P::Fr, { constants::HEIGHT }>>> {
+        let keys: Vec<_> = indices
+            .iter()
+            .flat_map(|&index| {
+                let siblings = (0..constants::HEIGHT as u32).map(move |height| {
+                    let node_index = index >> height;
+                    (height, node_index ^ 1)
+                });
+                std::iter::once((0, index)).chain(siblings)
+            })
+            .collect();
+        let nodes: HashMap<_, _> = keys.iter().copied().zip(self.get_many(&keys)).collect();
+
+        indices
+            .iter()
+            .map(|&index| {
+                nodes[&(0, index)]?;
+
+                Some(Self::build_proof(index, |height, index| {
+                    nodes[&(height, index)]
+                        .unwrap_or_else(|| self.missing_node_hash(height, index, self.next_index))
+                }))
+            })
+            .collect()
+    }
+
+    /// Collects the siblings of the path from the leaf `index` to the root, starting at height
+    /// `HEIGHT - H`.
+    fn build_proof<const H: usize>(
+        index: u64,
+        mut get: impl FnMut(u32, u64) -> Hash<P::Fr>,
+    ) -> MerkleProof<P::Fr, { H }> {
         let mut sibling: SizedVec<_, { H }> = (0..H).map(|_| Num::ZERO).collect();
         let mut path: SizedVec<_, { H }> = (0..H).map(|_| false).collect();
 
@@ -371,7 +1084,7 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             |x, (h, (sibling, is_right))| {
                 let cur_height = (start_height + h) as u32;
                 *is_right = x % 2 == 1;
-                *sibling = self.get(cur_height, x ^ 1);
+                *sibling = get(cur_height, x ^ 1);
 
                 x / 2
             },
@@ -380,6 +1093,19 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         MerkleProof { sibling, path }
     }
 
+    /// Returns the proof of the commitment at the commitment slot `commitment_index`, or `None` if
+    /// the slot is not below the next index.
+    pub fn get_commitment_proof(
+        &self,
+        commitment_index: u64,
+    ) -> Option<MerkleProof<P::Fr, { constants::HEIGHT - constants::OUTPLUSONELOG }>> {
+        if commitment_index >= self.next_index >> constants::OUTPLUSONELOG {
+            return None;
+        }
+
+        Some(self.get_proof_unchecked(commitment_index))
+    }
+
     pub fn get_leaf_proof(&self, index: u64) -> Option<MerkleProof<P::Fr, { constants::HEIGHT }>> {
         let key = Self::node_key(0, index);
         let node_present = self.db.get(0, &key).map_or(false, |value| value.is_some());
@@ -482,6 +1208,72 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         new_hashes: I1,
         new_commitments: I2,
     ) -> (HashMap<(u32, u64), Hash<P::Fr>>, UpdateBoundaries)
+    where
+        I1: IntoIterator<Item = (u64, Vec<Hash<P::Fr>>)>,
+        I2: IntoIterator<Item = (u64, Hash<P::Fr>)>,
+    {
+        let (mut virtual_nodes, new_range) =
+            self.virtual_nodes_from_state(new_hashes, new_commitments);
+
+        let update_boundaries = {
+            if let Some(Range {
+                start: start_index,
+                end: next_index,
+            }) = new_range
+            {
+                UpdateBoundaries {
+                    updated_range_left_index: self.next_index,
+                    updated_range_right_index: Self::calc_next_index(next_index),
+                    new_hashes_left_index: start_index,
+                    new_hashes_right_index: next_index,
+                }
+            } else {
+                UpdateBoundaries {
+                    updated_range_left_index: self.next_index,
+                    updated_range_right_index: self.next_index,
+                    new_hashes_left_index: self.next_index,
+                    new_hashes_right_index: self.next_index,
+                }
+            }
+        };
+
+        // calculate new hashes
+        self.get_virtual_node_full(
+            constants::HEIGHT as u32,
+            0,
+            &mut virtual_nodes,
+            &update_boundaries,
+        );
+
+        (virtual_nodes, update_boundaries)
+    }
+
+    /// Returns proofs for the leaves at the specified indices as if the optimistic leaves and
+    /// commitments were added to the tree. The proofs are valid against the root returned by
+    /// [`Self::get_root_optimistic`] for the same state.
+    pub fn get_proof_after_state(
+        &self,
+        new_leafs: &[(u64, Vec<Hash<P::Fr>>)],
+        new_commitments: &[(u64, Hash<P::Fr>)],
+        target_indices: &[u64],
+    ) -> Vec<MerkleProof<P::Fr, { constants::HEIGHT }>> {
+        let (mut virtual_nodes, update_boundaries) =
+            self.get_virtual_subtree(new_leafs.iter().cloned(), new_commitments.iter().copied());
+
+        target_indices
+            .iter()
+            .map(|&index| self.get_proof_virtual(index, &mut virtual_nodes, &update_boundaries))
+            .collect()
+    }
+
+    /// Places the optimistic leaves and commitments into a map of virtual nodes, filling the rest
+    /// of the leaf subtrees with zero note hashes. Returns the map and the range of leaf indices
+    /// covered by the new hashes, if any.
+    fn virtual_nodes_from_state<I1, I2>(
+        &self,
+        new_leafs: I1,
+        new_commitments: I2,
+    ) -> (HashMap<(u32, u64), Hash<P::Fr>>, Option<Range<u64>>)
     where
         I1: IntoIterator<Item = (u64, Vec<Hash<P::Fr>>)>,
         I2: IntoIterator<Item = (u64, Hash<P::Fr>)>,
@@ -504,7 +1296,7 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             })
             .collect();
 
-        new_hashes.into_iter().for_each(|(index, leafs)| {
+        new_leafs.into_iter().for_each(|(index, leafs)| {
             assert_eq!(index & ((1 << constants::OUTPLUSONELOG) - 1), 0);
             start_index = Some(start_index.unwrap_or(u64::MAX).min(index));
             next_index = Some(next_index.unwrap_or(0).max(index + leafs.len() as u64));
@@ -522,33 +1314,12 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             });
         });
 
-        let update_boundaries = {
-            if let (Some(start_index), Some(next_index)) = (start_index, next_index) {
-                UpdateBoundaries {
-                    updated_range_left_index: self.next_index,
-                    updated_range_right_index: Self::calc_next_index(next_index),
-                    new_hashes_left_index: start_index,
-                    new_hashes_right_index: next_index,
-                }
-            } else {
-                UpdateBoundaries {
-                    updated_range_left_index: self.next_index,
-                    updated_range_right_index: self.next_index,
-                    new_hashes_left_index: self.next_index,
-                    new_hashes_right_index: self.next_index,
-                }
-            }
+        let new_range = match (start_index, next_index) {
+            (Some(start_index), Some(next_index)) => Some(start_index..next_index),
+            _ => None,
         };
 
-        // calculate new hashes
-        self.get_virtual_node_full(
-            constants::HEIGHT as u32,
-            0,
-            &mut virtual_nodes,
-            &update_boundaries,
-        );
-
-        (virtual_nodes, update_boundaries)
+        (virtual_nodes, new_range)
     }
 
     pub fn get_proof_optimistic_index(
@@ -560,30 +1331,37 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         Some(self.get_proof_virtual(index, virtual_nodes, update_boundaries))
     }
 
+    /// Same as [`MerkleTree::get_proof_optimistic_index`] for multiple indices, the siblings
+    /// shared by the proofs are resolved once.
+    pub fn get_proofs_optimistic_index(
+        &self,
+        indices: &[u64],
+        virtual_nodes: &mut HashMap<(u32, u64), Hash<P::Fr>>,
+        update_boundaries: &UpdateBoundaries,
+    ) -> Vec<MerkleProof<P::Fr, { constants::HEIGHT }>> {
+        let mut siblings = HashMap::new();
+
+        indices
+            .iter()
+            .map(|&index| {
+                Self::build_proof(index, |height, index| {
+                    *siblings.entry((height, index)).or_insert_with(|| {
+                        self.get_virtual_node_full(height, index, virtual_nodes, update_boundaries)
+                    })
+                })
+            })
+            .collect()
+    }
+
     fn get_proof_virtual<const H: usize>(
         &self,
         index: u64,
         virtual_nodes: &mut HashMap<(u32, u64), Hash<P::Fr>>,
         update_boundaries: &UpdateBoundaries,
     ) -> MerkleProof<P::Fr, { H }> {
-        let mut sibling: SizedVec<_, { H }> = (0..H).map(|_| Num::ZERO).collect();
-        let mut path: SizedVec<_, { H }> = (0..H).map(|_| false).collect();
-
-        let start_height = constants::HEIGHT - H;
-
-        sibling.iter_mut().zip(path.iter_mut()).enumerate().fold(
-            index,
-            |x, (h, (sibling, is_right))| {
-                let cur_height = (start_height + h) as u32;
-                *is_right = x % 2 == 1;
-                *sibling =
-                    self.get_virtual_node_full(cur_height, x ^ 1, virtual_nodes, update_boundaries);
-
-                x / 2
-            },
-        );
-
-        MerkleProof { sibling, path }
+        Self::build_proof(index, |height, index| {
+            self.get_virtual_node_full(height, index, virtual_nodes, update_boundaries)
+        })
     }
 
     pub fn get_virtual_node(
@@ -611,44 +1389,48 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         virtual_nodes: &mut HashMap<(u32, u64), Hash<P::Fr>>,
         update_boundaries: &UpdateBoundaries,
     ) -> Hash<P::Fr> {
+        if let Some(hash) =
+            self.resolve_virtual_node(height, index, virtual_nodes, update_boundaries)
+        {
+            return hash;
+        }
+
+        let left_child =
+            self.get_virtual_node_full(height - 1, 2 * index, virtual_nodes, update_boundaries);
+        let right_child =
+            self.get_virtual_node_full(height - 1, 2 * index + 1, virtual_nodes, update_boundaries);
+        let pair = [left_child, right_child];
+        let hash = poseidon(pair.as_ref(), self.params.compress());
+        virtual_nodes.insert((height, index), hash);
+
+        hash
+    }
+
+    /// Returns the node if it doesn't have to be recalculated from its children: it's outside of
+    /// the updated range, consists of zero notes only or is already known.
+    fn resolve_virtual_node(
+        &self,
+        height: u32,
+        index: u64,
+        virtual_nodes: &HashMap<(u32, u64), Hash<P::Fr>>,
+        update_boundaries: &UpdateBoundaries,
+    ) -> Option<Hash<P::Fr>> {
         let node_left = index * (1 << height);
         let node_right = (index + 1) * (1 << height);
         if node_right <= update_boundaries.updated_range_left_index
             || update_boundaries.updated_range_right_index <= node_left
         {
-            return self.get(height, index);
+            return Some(self.get(height, index));
         }
         if (node_right <= update_boundaries.new_hashes_left_index
             || update_boundaries.new_hashes_right_index <= node_left)
             && update_boundaries.updated_range_left_index <= node_left
             && node_right <= update_boundaries.updated_range_right_index
         {
-            return self.zero_note_hashes[height as usize];
-        }
-
-        let key = (height, index);
-        match virtual_nodes.get(&key) {
-            Some(hash) => *hash,
-            None => {
-                let left_child = self.get_virtual_node_full(
-                    height - 1,
-                    2 * index,
-                    virtual_nodes,
-                    update_boundaries,
-                );
-                let right_child = self.get_virtual_node_full(
-                    height - 1,
-                    2 * index + 1,
-                    virtual_nodes,
-                    update_boundaries,
-                );
-                let pair = [left_child, right_child];
-                let hash = poseidon(pair.as_ref(), self.params.compress());
-                virtual_nodes.insert(key, hash);
-
-                hash
-            }
+            return Some(self.zero_note_hashes[height as usize]);
         }
+
+        virtual_nodes.get(&(height, index)).copied()
     }
 
     pub fn clean(&mut self) -> u64 {
@@ -686,11 +1468,144 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
 
         self.set_clean_index_batched(&mut batch, clean_before_index);
 
-        self.db.write(batch).unwrap();
+        self.write_batch(batch);
 
         self.next_index
     }
 
+    /// Recomputes the temporary leaf counts bottom-up and returns the nodes whose stored count
+    /// differs from the recomputed one, ordered by height and index.
+    ///
+    /// Stored counts are only trusted for nodes without stored children, i.e. temporary leaves and
+    /// subtree roots left by [`clean`](Self::clean), and only if the node itself is still present.
+    /// Counts left behind by removed nodes are reported as orphans.
+    pub fn audit_temporary_counts(&self) -> Vec<(u32, u64)> {
+        let (stored, expected) = self.expected_temporary_counts();
+
+        stored
+            .keys()
+            .chain(expected.keys())
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|key| stored.get(key) != expected.get(key))
+            .collect()
+    }
+
+    /// Rewrites all temporary leaf counts with the values recomputed by
+    /// [`audit_temporary_counts`](Self::audit_temporary_counts) in a single batch.
+    pub fn rebuild_temporary_counts(&mut self) {
+        let (stored, expected) = self.expected_temporary_counts();
+
+        let mut batch = self.db.transaction();
+        for &(height, index) in stored.keys() {
+            batch.delete(DbCols::TempLeaves as u32, &Self::node_key(height, index));
+        }
+        for ((height, index), count) in expected {
+            batch.put(
+                DbCols::TempLeaves as u32,
+                &Self::node_key(height, index),
+                &count.to_be_bytes(),
+            );
+        }
+
+        self.write_batch(batch);
+    }
+
+    /// Returns the stored and the recomputed temporary leaf counts.
+    fn expected_temporary_counts(&self) -> (BTreeMap<(u32, u64), u64>, BTreeMap<(u32, u64), u64>) {
+        let nodes: BTreeSet<(u32, u64)> = self
+            .db
+            .iter(DbCols::Leaves as u32)
+            .map(|res| Self::parse_node_key(&res.unwrap().0))
+            .collect();
+        let stored: BTreeMap<(u32, u64), u64> = self
+            .db
+            .iter(DbCols::TempLeaves as u32)
+            .map(|res| {
+                let (key, value) = res.unwrap();
+                let count = (&value[..]).read_u64::<BigEndian>().unwrap();
+                (Self::parse_node_key(&key), count)
+            })
+            .collect();
+
+        let mut expected = BTreeMap::new();
+        for height in 0..=constants::HEIGHT as u32 {
+            let level = (height, 0)..=(height, u64::MAX);
+            let mut indices: BTreeSet<u64> = nodes
+                .range(level.clone())
+                .map(|&(_, index)| index)
+                .chain(stored.range(level).map(|(&(_, index), _)| index))
+                .collect();
+            if height > 0 {
+                // parents of zero-valued nodes are not necessarily stored
+                indices.extend(
+                    expected
+                        .range((height - 1, 0)..=(height - 1, u64::MAX))
+                        .map(|(&(_, index), _)| index / 2),
+                );
+            }
+
+            for index in indices {
+                let children = [
+                    (height.wrapping_sub(1), 2 * index),
+                    (height.wrapping_sub(1), 2 * index + 1),
+                ];
+                let has_children = height > 0
+                    && children
+                        .iter()
+                        .any(|child| nodes.contains(child) || expected.contains_key(child));
+
+                let count = if has_children {
+                    children
+                        .iter()
+                        .map(|child| expected.get(child).copied().unwrap_or(0))
+                        .sum()
+                } else if nodes.contains(&(height, index)) {
+                    stored.get(&(height, index)).copied().unwrap_or(0)
+                } else {
+                    0
+                };
+
+                if count > 0 {
+                    expected.insert((height, index), count);
+                }
+            }
+        }
+
+        (stored, expected)
+    }
+
+    /// Searches backwards over at most `max_lookback` commitment boundaries, starting from the
+    /// current next index, for the state in which the root was `expected_root`, and rolls the tree
+    /// back to it. Returns the new next index, or `None` if no such state was found, in which case
+    /// the tree is left untouched.
+    pub fn rollback_to_root(
+        &mut self,
+        expected_root: Hash<P::Fr>,
+        max_lookback: u64,
+    ) -> Option<u64> {
+        if self.get_root() == expected_root {
+            return Some(self.next_index);
+        }
+
+        let slot_size = 1u64 << constants::OUTPLUSONELOG;
+        let mut index = self.next_index / slot_size * slot_size;
+        if index == self.next_index {
+            index = index.checked_sub(slot_size)?;
+        }
+
+        for _ in 0..max_lookback {
+            if self.get_root_at_index(index) == expected_root {
+                self.rollback(index);
+                return Some(index);
+            }
+            index = index.checked_sub(slot_size)?;
+        }
+
+        None
+    }
+
     pub fn rollback(&mut self, rollback_index: u64) -> Option<u64> {
         let mut result: Option<u64> = None;
 
@@ -717,62 +1632,211 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
             }
         }
 
-        // Update next_index.
+        // Update next_index and remove the leaves in a single transaction
         let original_next_index = self.next_index;
         self.next_index = if rollback_index > 0 {
             Self::calc_next_index(rollback_index - 1)
         } else {
             0
         };
-        // remove leaves
-        for index in (rollback_index..original_next_index).rev() {
-            self.remove_leaf(index);
-        }
+        let mut batch = self.db.transaction();
+        self.remove_leaves_batched(&mut batch, rollback_index..original_next_index);
+        self.write_batch(batch);
 
         result
     }
 
-    pub fn get_all_nodes(&self) -> Vec<Node<P::Fr>> {
-        self.db
-            .iter(0)
-            .map(|res| {
-                let (key, value) = res.unwrap();
-                Self::build_node(&key, &value)
+    /// Verifies that every stored inner node is equal to the hash of its children.
+    /// Returns positions (height, index) of inconsistent nodes ordered from bottom to top.
+    pub fn check_integrity(&self) -> Result<(), Vec<(u32, u64)>> {
+        let mut candidates = BTreeSet::new();
+        for node in self.iter_nodes() {
+            if node.height > 0 {
+                candidates.insert((node.height, node.index));
+            }
+            if node.height < constants::HEIGHT as u32 {
+                candidates.insert((node.height + 1, node.index / 2));
+            }
+        }
+
+        let bad: Vec<_> = candidates
+            .into_iter()
+            .filter(|&(height, index)| {
+                // Subtree roots without stored children (commitments, cleaned subtrees)
+                // cannot be verified.
+                let has_children = self.get_opt(height - 1, 2 * index).is_some()
+                    || self.get_opt(height - 1, 2 * index + 1).is_some();
+
+                has_children
+                    && self.get(height, index) != self.hash_children(height, index, &HashMap::new())
             })
-            .collect()
-    }
+            .collect();
 
-    pub fn get_leaves(&self) -> Vec<Node<P::Fr>> {
-        self.get_leaves_after(0)
+        if bad.is_empty() {
+            Ok(())
+        } else {
+            Err(bad)
+        }
     }
 
-    pub fn get_leaves_after(&self, index: u64) -> Vec<Node<P::Fr>> {
-        let prefix = (0u32).to_be_bytes();
-        self.db
-            .iter_with_prefix(0, &prefix)
-            .map(|res| {
-                let (key, value) = res.unwrap();
-                Self::build_node(&key, &value)
-            })
-            .filter(|node| node.index >= index)
-            .collect()
+    /// Recomputes the specified nodes and all of their ancestors from their children.
+    /// All changes are written in a single transaction.
+    pub fn repair(&mut self, bad: &[(u32, u64)]) {
+        let mut nodes = BTreeSet::new();
+        for &(height, index) in bad {
+            // leaves cannot be recomputed, start from their parents
+            let (mut height, mut index) = if height == 0 {
+                (1, index / 2)
+            } else {
+                (height, index)
+            };
+
+            while height <= constants::HEIGHT as u32 {
+                nodes.insert((height, index));
+                height += 1;
+                index /= 2;
+            }
+        }
+
+        // BTreeSet is ordered by height, so children are always repaired before their parents
+        let mut repaired = HashMap::new();
+        for (height, index) in nodes {
+            let hash = self.hash_children(height, index, &repaired);
+            repaired.insert((height, index), hash);
+        }
+
+        let mut batch = self.db.transaction();
+        for ((height, index), hash) in repaired {
+            let temporary_leaves_count = self.get_temporary_count(height, index);
+            self.set_batched(&mut batch, height, index, hash, temporary_leaves_count);
+        }
+        self.write_batch(batch);
+    }
+
+    fn hash_children(
+        &self,
+        height: u32,
+        index: u64,
+        overrides: &HashMap<(u32, u64), Hash<P::Fr>>,
+    ) -> Hash<P::Fr> {
+        let child = |index| {
+            overrides
+                .get(&(height - 1, index))
+                .copied()
+                .unwrap_or_else(|| self.get(height - 1, index))
+        };
+        let pair = [child(2 * index), child(2 * index + 1)];
+
+        poseidon(pair.as_ref(), self.params.compress())
+    }
+
+    pub fn get_all_nodes(&self) -> Vec<Node<P::Fr>> {
+        self.iter_nodes().collect()
+    }
+
+    /// Lazily iterates over all stored nodes without loading the whole tree into memory.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = Node<P::Fr>> + '_ {
+        self.db.iter(0).map(|res| {
+            let (key, value) = res.unwrap();
+            Self::build_node(&key, &value)
+        })
+    }
+
+    /// Lazily iterates over stored nodes at the specified height whose indices are within the range.
+    /// Only the nodes of that height are scanned.
+    pub fn iter_nodes_in(
+        &self,
+        height: u32,
+        index_range: Range<u64>,
+    ) -> impl Iterator<Item = Node<P::Fr>> + '_ {
+        HEIGHT_PREFIXES
+            .get(height as usize)
+            .into_iter()
+            .flat_map(move |prefix| self.db.iter_with_prefix(0, prefix))
+            .map(|res| {
+                let (key, value) = res.unwrap();
+                Self::build_node(&key, &value)
+            })
+            .filter(move |node| index_range.contains(&node.index))
+    }
+
+    pub fn get_leaves(&self) -> Vec<Node<P::Fr>> {
+        self.get_leaves_after(0)
+    }
+
+    pub fn get_leaves_after(&self, index: u64) -> Vec<Node<P::Fr>> {
+        self.iter_nodes_in(0, index..u64::MAX).collect()
     }
 
     pub fn next_index(&self) -> u64 {
         self.next_index
     }
 
-    fn update_next_index(&mut self, next_index: u64) -> bool {
-        if next_index >= self.next_index {
-            let mut transaction = self.db.transaction();
-            let mut data = [0u8; 8];
-            {
-                let mut bytes = &mut data[..];
-                let _ = bytes.write_u64::<BigEndian>(next_index);
+    /// Collects storage statistics of the tree in a single pass over the database.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            nodes_per_height: vec![0; constants::HEIGHT + 1],
+            temporary_subtrees: 0,
+            next_index: self.next_index,
+            clean_index: self.get_clean_index(),
+            approximate_size: 0,
+        };
+
+        for col in 0..NUM_COLUMNS {
+            for res in self.db.iter(col) {
+                let (key, value) = res.unwrap();
+                stats.approximate_size += (key.len() + value.len()) as u64;
+
+                if col == DbCols::Leaves as u32 {
+                    let (height, _) = Self::parse_node_key(&key);
+                    if let Some(count) = stats.nodes_per_height.get_mut(height as usize) {
+                        *count += 1;
+                    }
+                } else if col == DbCols::TempLeaves as u32 {
+                    stats.temporary_subtrees += 1;
+                }
             }
-            transaction.put(DbCols::NextIndex as u32, NEXT_INDEX_KEY, &data);
-            self.db.write(transaction).unwrap();
+        }
+
+        stats
+    }
+
+    /// Replaces the nodes of the tree with the specified ones. Temporary leaf marks are not
+    /// preserved. The sync cursor and the clean index describe the replaced nodes and are reset,
+    /// the schema version is kept.
+    pub(crate) fn restore(&mut self, nodes: &[Node<P::Fr>], next_index: u64) {
+        let mut batch = self.db.transaction();
+        batch.delete_prefix(DbCols::Leaves as u32, &[]);
+        batch.delete_prefix(DbCols::TempLeaves as u32, &[]);
+        batch.delete_prefix(DbCols::Metadata as u32, &[]);
+        batch.delete(DbCols::NamedIndex as u32, SYNC_CURSOR_KEY.as_bytes());
+        batch.delete(DbCols::NamedIndex as u32, b"clean_index");
+
+        for node in nodes {
+            batch.put(
+                DbCols::Leaves as u32,
+                &Self::node_key(node.height, node.index),
+                &node.value.try_to_vec().unwrap(),
+            );
+        }
+
+        self.next_index = next_index;
+        self.write_batch(batch);
+    }
+
+    pub(crate) fn params(&self) -> &P {
+        &self.params
+    }
+
+    /// Consumes the tree, returning the underlying database.
+    pub fn into_db(self) -> D {
+        self.db
+    }
 
+    /// Moves next_index forward. It's only persisted by the next [`MerkleTree::write_batch`],
+    /// together with the nodes that moved it.
+    fn update_next_index(&mut self, next_index: u64) -> bool {
+        if next_index >= self.next_index {
             self.next_index = next_index;
             true
         } else {
@@ -780,6 +1844,60 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         }
     }
 
+    /// Writes the batch together with the metadata record matching the resulting tree.
+    /// next_index must already be updated.
+    fn write_batch(&self, mut batch: DBTransaction) {
+        let root = self.root_after_batch(&batch);
+        Self::set_metadata_batched(&mut batch, self.next_index, root);
+
+        self.db.write(batch).unwrap();
+    }
+
+    fn root_after_batch(&self, batch: &DBTransaction) -> Hash<P::Fr> {
+        let root_key = Self::node_key(constants::HEIGHT as u32, 0);
+        let is_root = |col: u32, key: &[u8]| col == DbCols::Leaves as u32 && key == &root_key[..];
+
+        // The last operation on the root node wins
+        let pending_root = batch.ops.iter().rev().find_map(|op| match op {
+            DBOp::Insert { col, key, value } if is_root(*col, &key[..]) => {
+                Some(Some(Hash::try_from_slice(value).unwrap()))
+            }
+            DBOp::Delete { col, key } if is_root(*col, &key[..]) => Some(None),
+            DBOp::DeletePrefix { col, prefix }
+                if *col == DbCols::Leaves as u32 && root_key.starts_with(&prefix[..]) =>
+            {
+                Some(None)
+            }
+            _ => None,
+        });
+
+        pending_root
+            .unwrap_or_else(|| self.get_opt(constants::HEIGHT as u32, 0))
+            .unwrap_or_else(|| self.missing_node_hash(constants::HEIGHT as u32, 0, self.next_index))
+    }
+
+    fn set_metadata_batched(batch: &mut DBTransaction, next_index: u64, root: Hash<P::Fr>) {
+        let mut data = vec![METADATA_VERSION];
+        data.extend_from_slice(&next_index.to_be_bytes());
+        data.extend_from_slice(&root.try_to_vec().unwrap());
+
+        batch.put(DbCols::Metadata as u32, METADATA_KEY, &data);
+    }
+
+    fn parse_metadata(data: &[u8]) -> Result<(u64, Hash<P::Fr>), TreeMetadataError> {
+        let (&version, mut bytes) = data.split_first().ok_or(TreeMetadataError::Malformed)?;
+        if version != METADATA_VERSION {
+            return Err(TreeMetadataError::UnsupportedVersion(version));
+        }
+
+        let next_index = bytes
+            .read_u64::<BigEndian>()
+            .map_err(|_| TreeMetadataError::Malformed)?;
+        let root = Hash::try_from_slice(bytes).map_err(|_| TreeMetadataError::Malformed)?;
+
+        Ok((next_index, root))
+    }
+
     fn update_next_index_from_node(&mut self, height: u32, index: u64) -> bool {
         let leaf_index = u64::pow(2, height) * (index + 1) - 1;
         self.update_next_index(Self::calc_next_index(leaf_index))
@@ -883,7 +2001,41 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
         self.remove_batched(&mut batch, 0, index);
         self.update_path_batched(&mut batch, 0, index, self.default_hashes[0], 0);
 
-        self.db.write(batch).unwrap();
+        self.write_batch(batch);
+    }
+
+    /// Removes the leaves starting from the last one and updates their paths. The paths are
+    /// computed over the pending changes, so that all of them fit in a single batch.
+    fn remove_leaves_batched(&mut self, batch: &mut DBTransaction, leaves: Range<u64>) {
+        let mut pending: HashMap<(u32, u64), (Hash<P::Fr>, u64)> = HashMap::new();
+        for index in leaves.rev() {
+            self.remove_batched(batch, 0, index);
+            pending.insert((0, index), (self.default_hashes[0], 0));
+
+            let mut child_index = index;
+            for height in 1..=constants::HEIGHT as u32 {
+                let parent_index = child_index / 2;
+                let child = |index: u64| {
+                    pending
+                        .get(&(height - 1, index))
+                        .copied()
+                        .unwrap_or_else(|| {
+                            (
+                                self.get(height - 1, index),
+                                self.get_temporary_count(height - 1, index),
+                            )
+                        })
+                };
+                let (left, left_count) = child(2 * parent_index);
+                let (right, right_count) = child(2 * parent_index + 1);
+                let hash = poseidon([left, right].as_ref(), self.params.compress());
+                let temporary_leaves_count = left_count + right_count;
+
+                self.set_batched(batch, height, parent_index, hash, temporary_leaves_count);
+                pending.insert((height, parent_index), (hash, temporary_leaves_count));
+                child_index = parent_index;
+            }
+        }
     }
 
     fn get_clean_index(&self) -> u64 {
@@ -993,7 +2145,93 @@ impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P> {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+impl<D: KeyValueDB, P: PoolParams> MerkleTree<D, P>
+where
+    P::Fr: Send + Sync,
+{
+    /// Same as [`MerkleTree::add_hashes`], but the new nodes are hashed level by level, in
+    /// parallel if the `multicore` feature is enabled. The result is identical.
+    pub fn add_hashes_par<I>(&mut self, start_index: u64, hashes: I)
+    where
+        I: IntoIterator<Item = Hash<P::Fr>>,
+    {
+        let (mut virtual_nodes, update_boundaries) = self.prepare_hashes(start_index, hashes);
+        self.hash_virtual_nodes_by_level(&mut virtual_nodes, &update_boundaries);
+        self.put_hashes(virtual_nodes);
+    }
+
+    /// Same as [`MerkleTree::add_leafs_and_commitments`], but the new nodes are hashed level by
+    /// level, in parallel if the `multicore` feature is enabled. The result is identical.
+    pub fn add_leafs_and_commitments_par(
+        &mut self,
+        leafs: Vec<(u64, Vec<Hash<P::Fr>>)>,
+        commitments: Vec<(u64, Hash<P::Fr>)>,
+    ) {
+        if leafs.is_empty() && commitments.is_empty() {
+            return;
+        }
+
+        let (mut virtual_nodes, update_boundaries) =
+            self.prepare_leafs_and_commitments(leafs, commitments);
+        self.hash_virtual_nodes_by_level(&mut virtual_nodes, &update_boundaries);
+        self.put_hashes(virtual_nodes);
+    }
+
+    /// Computes the same nodes as [`MerkleTree::get_virtual_node_full`] called for the root.
+    /// Nodes at the same height don't depend on each other, so every level is hashed at once.
+    fn hash_virtual_nodes_by_level(
+        &self,
+        virtual_nodes: &mut HashMap<(u32, u64), Hash<P::Fr>>,
+        update_boundaries: &UpdateBoundaries,
+    ) {
+        // Walk down from the root the same way the recursion does to find the nodes to hash
+        let mut levels = vec![vec![]; constants::HEIGHT + 1];
+        let mut current = vec![0u64];
+        for height in (1..=constants::HEIGHT as u32).rev() {
+            let mut children = vec![];
+            for index in current {
+                if self
+                    .resolve_virtual_node(height, index, virtual_nodes, update_boundaries)
+                    .is_none()
+                {
+                    levels[height as usize].push(index);
+                    children.push(2 * index);
+                    children.push(2 * index + 1);
+                }
+            }
+            current = children;
+        }
+
+        let compress = self.params.compress();
+        for height in 1..=constants::HEIGHT as u32 {
+            // Storage lookups stay sequential, only hashing is parallel
+            let known_nodes = &*virtual_nodes;
+            let pairs: Vec<_> = levels[height as usize]
+                .iter()
+                .map(|&index| {
+                    let child = |index| {
+                        self.resolve_virtual_node(height - 1, index, known_nodes, update_boundaries)
+                            .expect("children are hashed before their parents")
+                    };
+
+                    (index, [child(2 * index), child(2 * index + 1)])
+                })
+                .collect();
+
+            #[cfg(feature = "multicore")]
+            let pairs = pairs.into_par_iter();
+            #[cfg(not(feature = "multicore"))]
+            let pairs = pairs.into_iter();
+
+            let hashes: Vec<_> = pairs
+                .map(|(index, pair)| ((height, index), poseidon(pair.as_ref(), compress)))
+                .collect();
+            virtual_nodes.extend(hashes);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, BorshSerialize, BorshDeserialize, Eq, PartialEq)]
 pub struct Node<F: PrimeField> {
     pub index: u64,
     pub height: u32,
@@ -1001,6 +2239,18 @@ pub struct Node<F: PrimeField> {
     pub value: Num<F>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct TreeStats {
+    /// Number of stored nodes for every height of the tree.
+    pub nodes_per_height: Vec<u64>,
+    /// Number of nodes marked as roots of subtrees with temporary leaves.
+    pub temporary_subtrees: u64,
+    pub next_index: u64,
+    pub clean_index: u64,
+    /// Sum of the key and value lengths of all database entries of the tree.
+    pub approximate_size: u64,
+}
+
 pub struct UpdateBoundaries {
     updated_range_left_index: u64,
     updated_range_right_index: u64,
@@ -1010,14 +2260,17 @@ pub struct UpdateBoundaries {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::atomic::AtomicUsize;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
     #[cfg(not(feature = "native"))]
     use kvdb_memorydb::InMemory as Database;
     #[cfg(feature = "native")]
     use kvdb_persy::PersyDatabase as Database;
     use libzeropool::{
-        fawkes_crypto::ff_uint::rand::Rng,
+        fawkes_crypto::{ff_uint::rand::Rng, native::poseidon::poseidon_merkle_proof_root},
         native::{params::PoolBN256, tx},
         POOL_PARAMS,
     };
@@ -1037,6 +2290,7 @@ mod tests {
     impl Drop for TestContext {
         fn drop(&mut self) {
             std::fs::remove_file(&self.db_path).unwrap();
+            let _ = std::fs::remove_file(format!("{}.lock", self.db_path));
         }
     }
 
@@ -1053,12 +2307,26 @@ mod tests {
         }
     }
 
+    /// Opens a tree over a database without any registered prefixes.
+    #[cfg(feature = "native")]
+    fn init_unregistered() -> TestContext {
+        static FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let file_counter = FILE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let path = format!("merkle-test-unregistered-{}.persy", file_counter);
+        let db = Database::open(&path, NUM_COLUMNS, &[], OpenMode::ReadWrite).unwrap();
+
+        TestContext {
+            tree: MerkleTree::new(db, POOL_PARAMS.clone()).unwrap(),
+            db_path: path,
+        }
+    }
+
     #[cfg(not(feature = "native"))]
     fn init() -> TestContext {
         let db = kvdb_memorydb::create(3);
 
         TestContext {
-            tree: MerkleTree::new(db, POOL_PARAMS.clone()),
+            tree: MerkleTree::new(db, POOL_PARAMS.clone()).unwrap(),
         }
     }
 
@@ -1203,150 +2471,858 @@ mod tests {
     // }
 
     #[test]
-    fn test_get_leaf_proof() {
+    fn test_get_leaf_proof() {
+        let mut rng = CustomRng;
+        let mut tree = &mut init().tree;
+        let proof = tree.get_leaf_proof(123);
+
+        assert!(proof.is_none());
+
+        tree.add_hash(123, rng.gen(), false);
+        let proof = tree.get_leaf_proof(123).unwrap();
+
+        assert_eq!(proof.sibling.as_slice().len(), constants::HEIGHT);
+        assert_eq!(proof.path.as_slice().len(), constants::HEIGHT);
+    }
+
+    #[test]
+    fn test_get_proof_unchecked() {
+        let mut rng = CustomRng;
+        let mut tree = &mut init().tree;
+
+        // Get proof for the right child of the root of the tree
+        const SUBROOT_HEIGHT: usize = 1;
+        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
+        assert_eq!(
+            proof.sibling[SUBROOT_HEIGHT - 1],
+            tree.default_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
+        );
+
+        assert_eq!(proof.sibling.as_slice().len(), SUBROOT_HEIGHT);
+        assert_eq!(proof.path.as_slice().len(), SUBROOT_HEIGHT);
+
+        // If we add leaf to the right branch,
+        // then left child of the root should not be affected directly
+        tree.add_hash(1 << 47, rng.gen(), false);
+        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
+        assert_eq!(
+            proof.sibling[SUBROOT_HEIGHT - 1],
+            tree.zero_note_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
+        );
+
+        // But if we add leaf to the left branch, then left child of the root should change
+        tree.add_hash((1 << 47) - 1, rng.gen(), false);
+        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
+        assert_ne!(
+            proof.sibling[SUBROOT_HEIGHT - 1],
+            tree.zero_note_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
+        );
+    }
+
+    #[test]
+    fn test_temporary_nodes_are_used_to_calculate_hashes_first() {
+        let mut rng = CustomRng;
+        let mut tree = &mut init().tree;
+
+        let hash0: Hash<_> = rng.gen();
+        let hash1: Hash<_> = rng.gen();
+
+        // add hash for index 0
+        tree.add_hash(0, hash0, true);
+
+        // add hash for index 1
+        tree.add_hash(1, hash1, false);
+
+        let parent_hash = tree.get(1, 0);
+        let expected_parent_hash = poseidon([hash0, hash1].as_ref(), POOL_PARAMS.compress());
+
+        assert_eq!(parent_hash, expected_parent_hash);
+    }
+
+    #[test_case(0, 5)]
+    #[test_case(1, 5)]
+    #[test_case(2, 5)]
+    #[test_case(4, 5)]
+    #[test_case(5, 5)]
+    #[test_case(5, 8)]
+    #[test_case(10, 15)]
+    #[test_case(12, 15)]
+    fn test_all_temporary_nodes_in_subtree_are_removed(subtree_height: u32, full_height: usize) {
+        let mut rng = CustomRng;
+
+        let subtree_size = 1 << subtree_height;
+        let subtrees_count = (1 << full_height) / subtree_size;
+        let start_index = 1 << 12;
+        let mut subtree_indexes: Vec<_> = (0..subtrees_count).map(|i| start_index + i).collect();
+        subtree_indexes.shuffle(&mut thread_rng());
+
+        let mut tree = &mut init().tree;
+        for subtree_index in subtree_indexes {
+            tree.add_subtree_root(subtree_height, subtree_index, rng.gen());
+        }
+
+        tree.clean();
+
+        let tree_nodes = tree.get_all_nodes();
+        assert_eq!(
+            tree_nodes.len(),
+            constants::HEIGHT - full_height + 1,
+            "Some temporary subtree nodes were not removed."
+        );
+    }
+
+    #[test]
+    fn test_stats_after_clean() {
+        let mut rng = CustomRng;
+        let tree = &mut init().tree;
+
+        for index in 0..300 {
+            tree.add_hash(index, rng.gen(), true);
+        }
+
+        let before = tree.stats();
+        assert_eq!(before.next_index, 300);
+        assert_eq!(before.clean_index, 0);
+        assert_eq!(before.nodes_per_height.len(), constants::HEIGHT + 1);
+        assert_eq!(before.nodes_per_height[0], 300);
+        assert_eq!(
+            before.nodes_per_height.iter().sum::<u64>(),
+            tree.get_all_nodes().len() as u64
+        );
+        assert!(before.temporary_subtrees > 0);
+
+        tree.clean();
+
+        let after = tree.stats();
+        assert_eq!(after.next_index, 300);
+        assert_eq!(after.clean_index, u64::MAX);
+        for height in 0..constants::OUTPLUSONELOG {
+            assert!(after.nodes_per_height[height] < before.nodes_per_height[height]);
+        }
+        assert!(after.temporary_subtrees < before.temporary_subtrees);
+        assert!(after.approximate_size < before.approximate_size);
+    }
+
+    #[test]
+    fn test_rebuild_temporary_counts_after_lost_leaf() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        for index in 0..4 {
+            tree.add_hash(index, rng.gen(), true);
+        }
+        for index in 4..6 {
+            tree.add_hash(index, rng.gen(), false);
+        }
+        assert!(tree.audit_temporary_counts().is_empty());
+
+        // lose a temporary leaf while keeping its temporary marker
+        let mut batch = tree.db.transaction();
+        batch.delete(
+            DbCols::Leaves as u32,
+            &MerkleTree::<MemoryDatabase, PoolBN256>::node_key(0, 1),
+        );
+        tree.db.write(batch).unwrap();
+
+        let mismatches = tree.audit_temporary_counts();
+        let expected: Vec<(u32, u64)> = std::iter::once((0, 1))
+            .chain((1..=constants::HEIGHT as u32).map(|height| (height, 0)))
+            .collect();
+        assert_eq!(mismatches, expected);
+
+        tree.rebuild_temporary_counts();
+        assert!(tree.audit_temporary_counts().is_empty());
+
+        let node_keys = |tree: &MerkleTree<MemoryDatabase, PoolBN256>| -> BTreeSet<(u32, u64)> {
+            tree.get_all_nodes()
+                .iter()
+                .map(|node| (node.height, node.index))
+                .collect()
+        };
+        let before = node_keys(&tree);
+        tree.clean();
+        let after = node_keys(&tree);
+
+        let removed: Vec<_> = before.difference(&after).copied().collect();
+        assert_eq!(removed, vec![(0, 2), (0, 3)]);
+        assert!(tree.audit_temporary_counts().is_empty());
+    }
+
+    #[test]
+    fn test_add_commitment_and_proof() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        let commitments: Vec<Hash<_>> = (0..3).map(|_| rng.gen()).collect();
+        for (index, commitment) in commitments.iter().enumerate() {
+            tree.add_commitment(index as u64, *commitment).unwrap();
+        }
+        assert_eq!(tree.next_index(), 3 << constants::OUTPLUSONELOG);
+
+        for (index, commitment) in commitments.iter().enumerate() {
+            let proof = tree.get_commitment_proof(index as u64).unwrap();
+            let root = poseidon_merkle_proof_root(*commitment, &proof, POOL_PARAMS.compress());
+            assert_eq!(root, tree.get_root());
+        }
+        assert!(tree.get_commitment_proof(3).is_none());
+
+        let max_index = 1 << (constants::HEIGHT - constants::OUTPLUSONELOG);
+        assert_eq!(
+            tree.add_commitment(max_index, rng.gen()),
+            Err(CommitmentError::IndexOutOfRange(max_index))
+        );
+        assert_eq!(tree.next_index(), 3 << constants::OUTPLUSONELOG);
+    }
+
+    #[test]
+    fn test_rollback_all_works_correctly() {
+        let remove_size: u64 = 24;
+
+        let mut rng = CustomRng;
+        let mut tree = &mut init().tree;
+
+        let original_root = tree.get_root();
+
+        for index in 0..remove_size {
+            let leaf = rng.gen();
+            tree.add_hash(index, leaf, false);
+        }
+
+        let rollback_result = tree.rollback(0);
+        assert!(rollback_result.is_none());
+        debug_assert!(tree.audit_temporary_counts().is_empty());
+        let rollback_root = tree.get_root();
+        assert_eq!(rollback_root, original_root);
+        assert_eq!(tree.next_index, 0);
+    }
+
+    #[test_case(32, 16)]
+    #[test_case(16, 0)]
+    #[test_case(11, 7)]
+    fn test_rollback_removes_nodes_correctly(keep_size: u64, remove_size: u64) {
+        let mut rng = CustomRng;
+        let mut tree = &mut init().tree;
+
+        for index in 0..keep_size {
+            let leaf = rng.gen();
+            tree.add_hash(index, leaf, false);
+        }
+        let original_root = tree.get_root();
+
+        for index in 0..remove_size {
+            let leaf = rng.gen();
+            tree.add_hash(128 + index, leaf, false);
+        }
+
+        let rollback_result = tree.rollback(128);
+        assert!(rollback_result.is_none());
+        debug_assert!(tree.audit_temporary_counts().is_empty());
+        let rollback_root = tree.get_root();
+        assert_eq!(rollback_root, original_root);
+        assert_eq!(tree.next_index, 128);
+    }
+
+    #[test]
+    fn test_rollback_persists_next_index() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let mut add_subtree = |tree: &mut MerkleTree<MemoryDatabase, PoolBN256>, index: u64| {
+            let hashes: Vec<_> = (0..constants::OUT + 1).map(|_| rng.gen()).collect();
+            tree.add_hashes(index, hashes);
+        };
+
+        add_subtree(&mut tree, 0);
+        let original_root = tree.get_root();
+        add_subtree(&mut tree, 128);
+        add_subtree(&mut tree, 256);
+        assert_eq!(tree.next_index(), 384);
+
+        assert!(tree.rollback(128).is_none());
+
+        let tree = MerkleTree::new(tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        assert_eq!(tree.next_index(), 128);
+        assert_eq!(tree.get_root(), original_root);
+    }
+
+    #[test]
+    fn test_rollback_single_write() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        let original_root = tree.get_root();
+        tree.add_hashes(128, (0..5).map(|_| rng.gen()).collect::<Vec<_>>());
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let db = CountingDatabase {
+            inner: tree.into_db(),
+            node_iterations: Default::default(),
+            node_gets: Default::default(),
+            writes: writes.clone(),
+        };
+        let mut tree = MerkleTree::new(db, POOL_PARAMS.clone()).unwrap();
+        writes.store(0, Ordering::SeqCst);
+
+        assert!(tree.rollback(128).is_none());
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        assert_eq!(tree.next_index(), 128);
+        assert_eq!(tree.get_root(), original_root);
+        assert!(tree.audit_temporary_counts().is_empty());
+    }
+
+    #[test]
+    fn test_add_hashes_single_write() {
+        let mut rng = CustomRng;
+        let writes = Arc::new(AtomicUsize::new(0));
+        let db = CountingDatabase {
+            inner: kvdb_memorydb::create(NUM_COLUMNS),
+            node_iterations: Default::default(),
+            node_gets: Default::default(),
+            writes: writes.clone(),
+        };
+        let mut tree = MerkleTree::new(db, POOL_PARAMS.clone()).unwrap();
+
+        writes.store(0, Ordering::SeqCst);
+        tree.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        writes.store(0, Ordering::SeqCst);
+        tree.add_hash(128, rng.gen(), false);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        writes.store(0, Ordering::SeqCst);
+        tree.add_leafs_and_commitments(vec![], vec![(2, rng.gen())]);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        let tree = MerkleTree::new(tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        assert_eq!(tree.next_index(), 3 << constants::OUTPLUSONELOG);
+    }
+
+    #[test]
+    fn test_add_leafs_and_commitments_equals_loop() {
+        let mut rng = CustomRng;
+        let mut bulk_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let mut loop_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        let leafs: Vec<(u64, Vec<Hash<_>>)> = vec![
+            (0, (0..3).map(|_| rng.gen()).collect()),
+            (
+                3 << constants::OUTPLUSONELOG,
+                (0..2).map(|_| rng.gen()).collect(),
+            ),
+        ];
+        let commitments: Vec<(u64, Hash<_>)> = (1..3u64)
+            .map(|i| (i << constants::OUTPLUSONELOG, rng.gen()))
+            .collect();
+
+        bulk_tree.add_leafs_and_commitments(leafs.clone(), commitments.clone());
+
+        for (index, hashes) in leafs {
+            for (i, hash) in hashes.into_iter().enumerate() {
+                loop_tree.add_hash(index + i as u64, hash, false);
+            }
+        }
+        for (index, hash) in commitments {
+            loop_tree.add_hash_at_height(
+                constants::OUTPLUSONELOG as u32,
+                index >> constants::OUTPLUSONELOG,
+                hash,
+                false,
+            );
+        }
+
+        assert_eq!(bulk_tree.get_root(), loop_tree.get_root());
+        assert_eq!(bulk_tree.next_index(), loop_tree.next_index());
+    }
+
+    #[test]
+    fn test_rollback_to_root() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+
+        tree.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        let commitments: Vec<Hash<_>> = (0..2).map(|_| rng.gen()).collect();
+        let (expected_root, _) = tree.append_commitments(&commitments);
+        assert_eq!(tree.next_index(), 384);
+        assert_eq!(tree.get_root_at_index(384), expected_root);
+
+        let garbage: Vec<Hash<_>> = (0..3).map(|_| rng.gen()).collect();
+        tree.append_commitments(&garbage);
+        assert_eq!(tree.next_index(), 768);
+
+        assert_eq!(tree.rollback_to_root(expected_root, 2), None);
+        assert_eq!(tree.next_index(), 768);
+
+        assert_eq!(tree.rollback_to_root(expected_root, 3), Some(384));
+        assert_eq!(tree.next_index(), 384);
+        assert_eq!(tree.get_root(), expected_root);
+
+        assert_eq!(tree.rollback_to_root(expected_root, 0), Some(384));
+        assert_eq!(tree.rollback_to_root(rng.gen(), 10), None);
+        assert_eq!(tree.next_index(), 384);
+    }
+
+    /// Counts full and prefix iterations over the nodes column.
+    struct CountingDatabase {
+        inner: MemoryDatabase,
+        node_iterations: Arc<AtomicUsize>,
+        node_gets: Arc<AtomicUsize>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl CountingDatabase {
+        fn count(&self, col: u32) {
+            if col == DbCols::Leaves as u32 {
+                self.node_iterations.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl KeyValueDB for CountingDatabase {
+        fn get(&self, col: u32, key: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            if col == DbCols::Leaves as u32 {
+                self.node_gets.fetch_add(1, Ordering::SeqCst);
+            }
+            self.inner.get(col, key)
+        }
+
+        fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            self.inner.get_by_prefix(col, prefix)
+        }
+
+        fn write(&self, transaction: DBTransaction) -> std::io::Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.write(transaction)
+        }
+
+        fn iter<'a>(
+            &'a self,
+            col: u32,
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.count(col);
+            self.inner.iter(col)
+        }
+
+        fn iter_with_prefix<'a>(
+            &'a self,
+            col: u32,
+            prefix: &'a [u8],
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.count(col);
+            self.inner.iter_with_prefix(col, prefix)
+        }
+    }
+
+    #[test]
+    fn test_open_with_metadata() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        for slot in 0..80u64 {
+            let hashes: Vec<_> = (0..constants::OUT + 1).map(|_| rng.gen()).collect();
+            tree.add_hashes(slot << constants::OUTPLUSONELOG, hashes);
+        }
+        let root = tree.get_root();
+        let next_index = tree.next_index();
+        assert!(next_index >= 10_000);
+
+        let node_iterations = Arc::new(AtomicUsize::new(0));
+        let db = CountingDatabase {
+            inner: tree.into_db(),
+            node_iterations: node_iterations.clone(),
+            node_gets: Default::default(),
+            writes: Default::default(),
+        };
+        let tree = MerkleTree::open(db, POOL_PARAMS.clone()).unwrap();
+        assert_eq!(node_iterations.load(Ordering::SeqCst), 0);
+        assert_eq!(tree.next_index(), next_index);
+        assert_eq!(tree.get_root(), root);
+
+        let tree = MerkleTree::new(tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        assert_eq!(node_iterations.load(Ordering::SeqCst), 0);
+        assert_eq!(tree.next_index(), next_index);
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let mut rng = CustomRng;
+        let leaves: Vec<Hash<_>> = (0..3).map(|_| rng.gen()).collect();
+
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_hashes(0, leaves.clone());
+        let root = tree.get_root();
+        let next_index = tree.next_index();
+        let proofs: Vec<_> = (0..leaves.len() as u64)
+            .map(|index| tree.get_leaf_proof(index).unwrap())
+            .collect();
+
+        // Version 1 layout: the same nodes, but only the legacy next index record
+        let db = tree.into_db();
+        let mut batch = db.transaction();
+        batch.delete(DbCols::NamedIndex as u32, SCHEMA_VERSION_KEY.as_bytes());
+        batch.delete(DbCols::Metadata as u32, METADATA_KEY);
+        batch.put(
+            DbCols::Metadata as u32,
+            NEXT_INDEX_KEY,
+            &next_index.to_be_bytes(),
+        );
+        db.write(batch).unwrap();
+
+        migrate(&db, &*POOL_PARAMS).unwrap();
+        let tree = MerkleTree::open(db, POOL_PARAMS.clone()).unwrap();
+        assert_eq!(tree.next_index(), next_index);
+        assert_eq!(tree.get_root(), root);
+        for (index, proof) in proofs.iter().enumerate() {
+            let migrated = tree.get_leaf_proof(index as u64).unwrap();
+            assert_eq!(migrated.sibling.as_slice(), proof.sibling.as_slice());
+            assert_eq!(migrated.path.as_slice(), proof.path.as_slice());
+            let proof_root =
+                poseidon_merkle_proof_root(leaves[index], &migrated, POOL_PARAMS.compress());
+            assert_eq!(proof_root, root);
+        }
+
+        // the migration is applied once
+        let db = tree.into_db();
+        migrate(&db, &*POOL_PARAMS).unwrap();
+        let tree = MerkleTree::new(db, POOL_PARAMS.clone()).unwrap();
+        assert_eq!(tree.get_root(), root);
+
+        let db = tree.into_db();
+        let mut batch = db.transaction();
+        let newer_version = (SCHEMA_VERSION + 1).to_be_bytes();
+        batch.put(
+            DbCols::NamedIndex as u32,
+            SCHEMA_VERSION_KEY.as_bytes(),
+            &newer_version,
+        );
+        db.write(batch).unwrap();
+        assert!(migrate(&db, &*POOL_PARAMS).is_err());
+        assert!(matches!(
+            MerkleTree::open(db, POOL_PARAMS.clone()),
+            Err(TreeMetadataError::UnsupportedSchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_restore_keeps_schema_version() {
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_hashes(0, vec![Num::ONE]);
+        tree.restore(&[], 0);
+
+        let db = tree.into_db();
+        let version = db
+            .get(DbCols::NamedIndex as u32, SCHEMA_VERSION_KEY.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(&version[..], &SCHEMA_VERSION.to_be_bytes()[..]);
+        assert_eq!(
+            MerkleTree::open(db, POOL_PARAMS.clone())
+                .unwrap()
+                .next_index(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_new_newer_schema_version() {
+        let db = MerkleTree::new_test(POOL_PARAMS.clone()).into_db();
+        let mut batch = db.transaction();
+        batch.put(
+            DbCols::NamedIndex as u32,
+            SCHEMA_VERSION_KEY.as_bytes(),
+            &(SCHEMA_VERSION + 1).to_be_bytes(),
+        );
+        db.write(batch).unwrap();
+
+        let err = MerkleTree::new(db, POOL_PARAMS.clone()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_new_tree_has_schema_version() {
+        let tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let db = tree.into_db();
+        let version = db
+            .get(DbCols::NamedIndex as u32, SCHEMA_VERSION_KEY.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(&version[..], &SCHEMA_VERSION.to_be_bytes()[..]);
+    }
+
+    #[test]
+    fn test_add_proof() {
         let mut rng = CustomRng;
-        let mut tree = &mut init().tree;
-        let proof = tree.get_leaf_proof(123);
+        let my_index = 1_000_000;
+        let my_slot = my_index >> constants::OUTPLUSONELOG << constants::OUTPLUSONELOG;
 
-        assert!(proof.is_none());
+        let mut server_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        for start_index in [0, 128, my_slot - 128, my_slot, 2_000_000 >> 7 << 7] {
+            let hashes: Vec<_> = (0..constants::OUT + 1).map(|_| rng.gen()).collect();
+            server_tree.add_hashes(start_index, hashes);
+        }
+        let server_root = server_tree.get_root();
+        let my_leaf = server_tree.get(0, my_index);
+        let server_proof = server_tree.get_leaf_proof(my_index).unwrap();
+
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_hash(my_index, my_leaf, false);
+        let next_index = tree.next_index();
+        assert!(!tree.has_full_path(my_index));
+
+        tree.add_proof(my_index, &server_proof.sibling);
+        assert_eq!(tree.next_index(), next_index);
+        assert!(tree.has_full_path(my_index));
+        assert!(!tree.has_full_path(my_index + 1));
+        assert_eq!(tree.get_root(), server_root);
+
+        let check_proof = |tree: &MerkleTree<_, _>| {
+            let proof = tree.get_leaf_proof(my_index).unwrap();
+            let root = poseidon_merkle_proof_root(my_leaf, &proof, POOL_PARAMS.compress());
+            assert_eq!(root, server_root);
+        };
+        check_proof(&tree);
 
-        tree.add_hash(123, rng.gen(), false);
-        let proof = tree.get_leaf_proof(123).unwrap();
+        tree.clean();
+        assert!(tree.has_full_path(my_index));
+        check_proof(&tree);
 
-        assert_eq!(proof.sibling.as_slice().len(), constants::HEIGHT);
-        assert_eq!(proof.path.as_slice().len(), constants::HEIGHT);
+        // reopening keeps the imported nodes
+        let tree = MerkleTree::open(tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        assert_eq!(tree.next_index(), next_index);
+        check_proof(&tree);
     }
 
     #[test]
-    fn test_get_proof_unchecked() {
+    fn test_get_proofs() {
         let mut rng = CustomRng;
-        let mut tree = &mut init().tree;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        // two subtrees far apart
+        let second_start = 1 << 20;
+        tree.add_hashes(0, (0..8).map(|_| rng.gen()).collect::<Vec<_>>());
+        tree.add_hashes(second_start, (0..8).map(|_| rng.gen()).collect::<Vec<_>>());
+
+        let node_gets = Arc::new(AtomicUsize::new(0));
+        let db = CountingDatabase {
+            inner: tree.into_db(),
+            node_iterations: Default::default(),
+            node_gets: node_gets.clone(),
+            writes: Default::default(),
+        };
+        let tree = MerkleTree::open(db, POOL_PARAMS.clone()).unwrap();
 
-        // Get proof for the right child of the root of the tree
-        const SUBROOT_HEIGHT: usize = 1;
-        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
-        assert_eq!(
-            proof.sibling[SUBROOT_HEIGHT - 1],
-            tree.default_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
-        );
+        let indices: Vec<u64> = (0..8).chain(second_start..second_start + 8).collect();
+        let missing_index = second_start + 100;
 
-        assert_eq!(proof.sibling.as_slice().len(), SUBROOT_HEIGHT);
-        assert_eq!(proof.path.as_slice().len(), SUBROOT_HEIGHT);
+        node_gets.store(0, Ordering::SeqCst);
+        let expected: Vec<_> = indices
+            .iter()
+            .map(|&index| tree.get_leaf_proof(index).unwrap())
+            .collect();
+        let single_gets = node_gets.load(Ordering::SeqCst);
+
+        node_gets.store(0, Ordering::SeqCst);
+        let proofs = tree.get_proofs(&indices);
+        let batched_gets = node_gets.load(Ordering::SeqCst);
+
+        assert!(batched_gets < single_gets);
+        assert_eq!(proofs.len(), indices.len());
+        for (proof, expected) in proofs.iter().zip(expected) {
+            let proof = proof.as_ref().unwrap();
+            assert_eq!(proof.sibling.as_slice(), expected.sibling.as_slice());
+            assert_eq!(proof.path.as_slice(), expected.path.as_slice());
+        }
 
-        // If we add leaf to the right branch,
-        // then left child of the root should not be affected directly
-        tree.add_hash(1 << 47, rng.gen(), false);
-        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
-        assert_eq!(
-            proof.sibling[SUBROOT_HEIGHT - 1],
-            tree.zero_note_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
-        );
+        assert!(tree.get_leaf_proof(missing_index).is_none());
+        let proofs = tree.get_proofs(&[missing_index, 3]);
+        assert!(proofs[0].is_none());
+        assert!(proofs[1].is_some());
 
-        // But if we add leaf to the left branch, then left child of the root should change
-        tree.add_hash((1 << 47) - 1, rng.gen(), false);
-        let proof = tree.get_proof_unchecked::<SUBROOT_HEIGHT>(1);
-        assert_ne!(
-            proof.sibling[SUBROOT_HEIGHT - 1],
-            tree.zero_note_hashes[constants::HEIGHT - SUBROOT_HEIGHT]
+        assert_eq!(
+            tree.get_many(&[(0, 3), (0, missing_index), (0, 3)]),
+            vec![Some(tree.get(0, 3)), None, Some(tree.get(0, 3))]
         );
     }
 
     #[test]
-    fn test_temporary_nodes_are_used_to_calculate_hashes_first() {
+    fn test_open_or_rebuild_inconsistent_metadata() {
         let mut rng = CustomRng;
-        let mut tree = &mut init().tree;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        tree.add_hash_at_height(constants::OUTPLUSONELOG as u32, 1, rng.gen(), false);
+        let root = tree.get_root();
+        assert_eq!(tree.next_index(), 256);
 
-        let hash0: Hash<_> = rng.gen();
-        let hash1: Hash<_> = rng.gen();
+        let corrupt_metadata = |db: &CountingDatabase| {
+            let mut batch = db.transaction();
+            let bad_root = CustomRng.gen();
+            MerkleTree::<CountingDatabase, PoolBN256>::set_metadata_batched(
+                &mut batch, 256, bad_root,
+            );
+            db.write(batch).unwrap();
+        };
 
-        // add hash for index 0
-        tree.add_hash(0, hash0, true);
+        let node_iterations = Arc::new(AtomicUsize::new(0));
+        let db = CountingDatabase {
+            inner: tree.into_db(),
+            node_iterations: node_iterations.clone(),
+            node_gets: Default::default(),
+            writes: Default::default(),
+        };
+        corrupt_metadata(&db);
+
+        let tree = MerkleTree::open_or_rebuild(db, POOL_PARAMS.clone()).unwrap();
+        assert!(node_iterations.load(Ordering::SeqCst) > 0);
+        assert_eq!(tree.next_index(), 256);
+        assert_eq!(tree.get_root(), root);
+
+        // the rebuilt metadata is used on the next open
+        node_iterations.store(0, Ordering::SeqCst);
+        let tree = MerkleTree::open(tree.into_db(), POOL_PARAMS.clone()).unwrap();
+        assert_eq!(node_iterations.load(Ordering::SeqCst), 0);
+
+        let db = tree.into_db();
+        corrupt_metadata(&db);
+        assert!(matches!(
+            MerkleTree::open(db, POOL_PARAMS.clone()),
+            Err(TreeMetadataError::RootMismatch { .. })
+        ));
+        assert!(matches!(
+            MerkleTree::open(kvdb_memorydb::create(NUM_COLUMNS), POOL_PARAMS.clone()),
+            Err(TreeMetadataError::Missing)
+        ));
+    }
 
-        // add hash for index 1
-        tree.add_hash(1, hash1, false);
+    #[test]
+    fn test_add_hashes_checked() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let first_hashes: Vec<Hash<_>> = (0..3).map(|_| rng.gen()).collect();
+        tree.add_hashes(0, first_hashes.clone());
+        let root = tree.get_root();
 
-        let parent_hash = tree.get(1, 0);
-        let expected_parent_hash = poseidon([hash0, hash1].as_ref(), POOL_PARAMS.compress());
+        let hashes: Vec<Hash<_>> = (0..5).map(|_| rng.gen()).collect();
+        let mut reference_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        reference_tree.add_hashes(128, hashes.clone());
+        let commitment = reference_tree.get(constants::OUTPLUSONELOG as u32, 1);
+
+        assert!(matches!(
+            tree.add_hashes_checked(128, hashes.clone(), rng.gen()),
+            Err(TreeMismatch::Commitment { index: 128, .. })
+        ));
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.next_index(), 128);
+        assert!(tree.get_opt(0, 128).is_none());
+
+        // a merged batch spanning two transactions
+        let merged: Vec<_> = first_hashes
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(tree.zero_note_hashes[0]))
+            .take(constants::OUT + 1)
+            .chain(hashes.iter().copied())
+            .collect();
+        assert_eq!(
+            tree.check_leafs(&[(0, merged)], &[(128, commitment)]),
+            Ok(())
+        );
+        assert_eq!(
+            tree.check_leafs(&[(128, hashes.clone())], &[(256, commitment)]),
+            Err(TreeMismatch::MissingLeafs(256))
+        );
 
-        assert_eq!(parent_hash, expected_parent_hash);
+        tree.add_hashes_checked(128, hashes, commitment).unwrap();
+        assert_eq!(tree.next_index(), 256);
+        assert_eq!(
+            tree.get(constants::OUTPLUSONELOG as u32, 1),
+            reference_tree.get(constants::OUTPLUSONELOG as u32, 1)
+        );
     }
 
-    #[test_case(0, 5)]
-    #[test_case(1, 5)]
-    #[test_case(2, 5)]
-    #[test_case(4, 5)]
-    #[test_case(5, 5)]
-    #[test_case(5, 8)]
-    #[test_case(10, 15)]
-    #[test_case(12, 15)]
-    fn test_all_temporary_nodes_in_subtree_are_removed(subtree_height: u32, full_height: usize) {
+    #[test]
+    fn test_add_leafs_and_commitments_par() {
         let mut rng = CustomRng;
+        let mut sequential = MerkleTree::new_test(POOL_PARAMS.clone());
+        let mut parallel = MerkleTree::new_test(POOL_PARAMS.clone());
 
-        let subtree_size = 1 << subtree_height;
-        let subtrees_count = (1 << full_height) / subtree_size;
-        let start_index = 1 << 12;
-        let mut subtree_indexes: Vec<_> = (0..subtrees_count).map(|i| start_index + i).collect();
-        subtree_indexes.shuffle(&mut thread_rng());
+        sequential.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        parallel.add_hashes_par(
+            0,
+            sequential.get_leaves().into_iter().map(|node| node.value),
+        );
+        assert_eq!(parallel.get_root(), sequential.get_root());
 
-        let mut tree = &mut init().tree;
-        for subtree_index in subtree_indexes {
-            tree.add_subtree_root(subtree_height, subtree_index, rng.gen());
-        }
+        let commitments: Vec<(u64, Hash<_>)> = (1..=64u64)
+            .map(|i| (i << constants::OUTPLUSONELOG, rng.gen()))
+            .collect();
+        let leafs: Vec<(u64, Vec<Hash<_>>)> = vec![(
+            65 << constants::OUTPLUSONELOG,
+            (0..5).map(|_| rng.gen()).collect(),
+        )];
 
-        tree.clean();
+        sequential.add_leafs_and_commitments(leafs.clone(), commitments.clone());
+        parallel.add_leafs_and_commitments_par(leafs, commitments);
 
-        let tree_nodes = tree.get_all_nodes();
-        assert_eq!(
-            tree_nodes.len(),
-            constants::HEIGHT - full_height + 1,
-            "Some temporary subtree nodes were not removed."
-        );
+        assert_eq!(parallel.get_root(), sequential.get_root());
+        assert_eq!(parallel.next_index(), sequential.next_index());
+        assert_eq!(parallel.get_all_nodes(), sequential.get_all_nodes());
     }
 
     #[test]
-    fn test_rollback_all_works_correctly() {
-        let remove_size: u64 = 24;
-
+    fn test_append_commitments() {
         let mut rng = CustomRng;
-        let mut tree = &mut init().tree;
-
-        let original_root = tree.get_root();
-
-        for index in 0..remove_size {
-            let leaf = rng.gen();
-            tree.add_hash(index, leaf, false);
+        let leafs: Vec<Vec<Hash<_>>> = vec![
+            (0..3).map(|_| rng.gen()).collect(),
+            (0..5).map(|_| rng.gen()).collect(),
+        ];
+
+        let mut reference_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        for (i, leafs) in leafs.iter().enumerate() {
+            reference_tree.add_hashes(i as u64 * 128, leafs.clone());
         }
+        let commitments: Vec<_> = (0..leafs.len() as u64)
+            .map(|i| reference_tree.get(constants::OUTPLUSONELOG as u32, i))
+            .collect();
 
-        let rollback_result = tree.rollback(0);
-        assert!(rollback_result.is_none());
-        let rollback_root = tree.get_root();
-        assert_eq!(rollback_root, original_root);
-        assert_eq!(tree.next_index, 0);
+        let tree = &mut init().tree;
+        let (root, first_index) = tree.append_commitments(&commitments);
+        assert_eq!(first_index, 0);
+        assert_eq!(root, reference_tree.get_root());
+        assert_eq!(tree.get_root(), reference_tree.get_root());
+        assert_eq!(tree.next_index(), reference_tree.next_index());
+
+        // leafs arrive after their commitment
+        tree.add_hashes(128, leafs[1].clone());
+        assert_eq!(tree.get_root(), reference_tree.get_root());
+        assert_eq!(tree.get(1, 65), reference_tree.get(1, 65));
+        assert_eq!(tree.next_index(), 256);
+        assert!(tree.check_integrity().is_ok());
     }
 
-    #[test_case(32, 16)]
-    #[test_case(16, 0)]
-    #[test_case(11, 7)]
-    fn test_rollback_removes_nodes_correctly(keep_size: u64, remove_size: u64) {
+    #[test]
+    fn test_check_integrity_and_repair() {
         let mut rng = CustomRng;
-        let mut tree = &mut init().tree;
+        let tree = &mut init().tree;
+
+        tree.add_hashes(0, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        tree.add_hash_at_height(constants::OUTPLUSONELOG as u32, 1, rng.gen(), false);
+        tree.add_hashes(384, (0..5).map(|_| rng.gen()).collect::<Vec<_>>());
+        assert!(tree.check_integrity().is_ok());
 
-        for index in 0..keep_size {
-            let leaf = rng.gen();
-            tree.add_hash(index, leaf, false);
-        }
         let original_root = tree.get_root();
 
-        for index in 0..remove_size {
-            let leaf = rng.gen();
-            tree.add_hash(128 + index, leaf, false);
-        }
+        let mut batch = tree.db.transaction();
+        let corrupted: Hash<_> = rng.gen();
+        batch.put(
+            DbCols::Leaves as u32,
+            &MerkleTree::<Database, PoolBN256>::node_key(3, 48),
+            &corrupted.try_to_vec().unwrap(),
+        );
+        tree.db.write(batch).unwrap();
 
-        let rollback_result = tree.rollback(128);
-        assert!(rollback_result.is_none());
-        let rollback_root = tree.get_root();
-        assert_eq!(rollback_root, original_root);
-        assert_eq!(tree.next_index, 128);
+        let bad = tree.check_integrity().unwrap_err();
+        assert_eq!(bad[0], (3, 48));
+
+        tree.repair(&bad);
+        assert!(tree.check_integrity().is_ok());
+        assert_eq!(tree.get_root(), original_root);
     }
 
     // #[test]
@@ -1454,6 +3430,107 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_get_leaves_unregistered_prefix() {
+        let mut rng = CustomRng;
+        let ctx = &mut init_unregistered();
+        let tree = &mut ctx.tree;
+
+        for index in (0..6).chain(126..131) {
+            tree.add_hash(index, rng.gen(), false);
+        }
+
+        let mut leaves: Vec<_> = tree.get_leaves().iter().map(|node| node.index).collect();
+        leaves.sort();
+        assert_eq!(leaves, (0..6).chain(126..131).collect::<Vec<_>>());
+
+        let mut leaves: Vec<_> = tree
+            .get_leaves_after(4)
+            .iter()
+            .map(|node| node.index)
+            .collect();
+        leaves.sort();
+        assert_eq!(leaves, vec![4, 5, 126, 127, 128, 129, 130]);
+
+        let mut nodes: Vec<_> = tree
+            .iter_nodes_in(1, 0..64)
+            .map(|node| (node.index, node.value))
+            .collect();
+        nodes.sort_by_key(|(index, _)| *index);
+        let mut expected: Vec<_> = tree
+            .get_all_nodes()
+            .into_iter()
+            .filter(|node| node.height == 1 && node.index < 64)
+            .map(|node| (node.index, node.value))
+            .collect();
+        expected.sort_by_key(|(index, _)| *index);
+        assert!(!nodes.is_empty());
+        assert_eq!(nodes, expected);
+    }
+
+    #[test]
+    fn test_iter_nodes_returns_stored_nodes() {
+        let mut rng = CustomRng;
+        let tree = &mut init().tree;
+
+        assert_eq!(tree.iter_nodes().count(), 0);
+        assert_eq!(tree.iter_nodes_in(0, 0..u64::MAX).count(), 0);
+
+        let leaves: Vec<(u64, Hash<_>)> = (0..6)
+            .chain(126..131)
+            .map(|index| (index, rng.gen()))
+            .collect();
+        for &(index, leaf) in &leaves {
+            tree.add_hash(index, leaf, false);
+        }
+
+        // The leaves and all of their ancestors
+        let expected: BTreeSet<(u32, u64)> = leaves
+            .iter()
+            .flat_map(|&(index, _)| {
+                (0..=constants::HEIGHT as u32).map(move |height| (height, index >> height))
+            })
+            .collect();
+        let mut all_nodes = tree.get_all_nodes();
+        all_nodes.sort_by_key(|node| (node.height, node.index));
+        let positions: BTreeSet<_> = all_nodes
+            .iter()
+            .map(|node| (node.height, node.index))
+            .collect();
+        assert_eq!(all_nodes.len(), expected.len());
+        assert_eq!(positions, expected);
+
+        let stored_leaves: Vec<_> = all_nodes
+            .iter()
+            .filter(|node| node.height == 0)
+            .map(|node| (node.index, node.value))
+            .collect();
+        assert_eq!(stored_leaves, leaves);
+
+        let mut nodes_by_height: Vec<_> = (0..=constants::HEIGHT as u32)
+            .flat_map(|height| tree.iter_nodes_in(height, 0..u64::MAX))
+            .collect();
+        nodes_by_height.sort_by_key(|node| (node.height, node.index));
+        assert_eq!(all_nodes, nodes_by_height);
+
+        // Height filter that matches nothing
+        assert_eq!(
+            tree.iter_nodes_in(constants::HEIGHT as u32 + 1, 0..u64::MAX)
+                .count(),
+            0
+        );
+
+        // Index range crossing a power of two
+        let mut indices: Vec<_> = tree.iter_nodes_in(0, 127..129).map(|n| n.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, vec![127, 128]);
+
+        let mut indices: Vec<_> = tree.iter_nodes_in(1, 63..65).map(|n| n.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, vec![63, 64]);
+    }
+
     #[test]
     fn test_get_proof_after() {
         let mut rng = CustomRng;
@@ -1519,6 +3596,147 @@ mod tests {
         }
     }
 
+    fn assert_changes_match_diff(
+        tree: &MerkleTree<Database, PoolBN256>,
+        before: &[Node<<PoolBN256 as PoolParams>::Fr>],
+        changes: &[Node<<PoolBN256 as PoolParams>::Fr>],
+    ) {
+        let mut sorted = changes.to_vec();
+        sorted.sort_by_key(|node| (node.height, node.index));
+        assert_eq!(changes, sorted.as_slice());
+
+        let after = tree.get_all_nodes();
+        let mut diff: Vec<_> = after.iter().filter(|node| !before.contains(node)).collect();
+        diff.sort_by_key(|node| (node.height, node.index));
+        let stored: Vec<_> = changes
+            .iter()
+            .filter(|node| node.value != tree.zero_note_hashes[node.height as usize])
+            .collect();
+        assert_eq!(stored, diff);
+
+        for node in changes {
+            assert_eq!(tree.get(node.height, node.index), node.value);
+        }
+        assert!(changes
+            .iter()
+            .any(|node| node.height == constants::HEIGHT as u32 && node.value == tree.get_root()));
+    }
+
+    #[test]
+    fn test_prepare_tree_update() {
+        let mut rng = CustomRng;
+        let tree = &mut init().tree;
+        let compress = POOL_PARAMS.compress();
+        let height = constants::OUTPLUSONELOG as u32;
+
+        // The first slot has no filled predecessor
+        let first_commitment = rng.gen();
+        let (public, secret) = tree.prepare_tree_update(first_commitment, 0);
+        assert_eq!(public.root_before, tree.get_root());
+        assert_eq!(
+            poseidon_merkle_proof_root(secret.prev_leaf, &secret.proof_free, compress),
+            public.root_before
+        );
+        assert_eq!(
+            poseidon_merkle_proof_root(first_commitment, &secret.proof_free, compress),
+            public.root_after
+        );
+        tree.append_commitments(&[first_commitment]);
+        assert_eq!(tree.get_root(), public.root_after);
+
+        tree.add_hashes(128, (0..3).map(|_| rng.gen()).collect::<Vec<_>>());
+        let prev_commitment = tree.get(height, 1);
+
+        let commitment = rng.gen();
+        let (public, secret) = tree.prepare_tree_update(commitment, 2);
+        assert_eq!(public.root_before, tree.get_root());
+        assert_eq!(public.leaf, commitment);
+        assert_eq!(secret.prev_leaf, prev_commitment);
+        assert_eq!(
+            poseidon_merkle_proof_root(secret.prev_leaf, &secret.proof_filled, compress),
+            public.root_before
+        );
+        assert_eq!(
+            poseidon_merkle_proof_root(tree.get(height, 2), &secret.proof_free, compress),
+            public.root_before
+        );
+        assert_eq!(
+            poseidon_merkle_proof_root(commitment, &secret.proof_free, compress),
+            public.root_after
+        );
+
+        // The tree itself is not modified
+        assert_eq!(tree.get_root(), public.root_before);
+
+        tree.append_commitments(&[commitment]);
+        assert_eq!(tree.get_root(), public.root_after);
+    }
+
+    #[test]
+    fn test_get_proof_after_state() {
+        let mut rng = CustomRng;
+        let tree = &mut init().tree;
+
+        let hashes: Vec<_> = (0..3).map(|_| rng.gen()).collect();
+        tree.add_hashes(0, hashes.clone());
+
+        // A foreign tx known only by its commitment followed by our own leaves
+        let new_commitments = vec![(128, rng.gen())];
+        let leafs: Vec<Hash<_>> = (0..3).map(|_| rng.gen()).collect();
+        let new_leafs = vec![(256, leafs.clone())];
+
+        let targets = [1, 256, 257, 258];
+        let proofs = tree.get_proof_after_state(&new_leafs, &new_commitments, &targets);
+
+        let (mut virtual_nodes, update_boundaries) =
+            tree.get_virtual_subtree(new_leafs.iter().cloned(), new_commitments.iter().copied());
+        let root = tree.get_root_optimistic(&mut virtual_nodes, &update_boundaries);
+
+        let target_leafs = [hashes[1], leafs[0], leafs[1], leafs[2]];
+        for (leaf, proof) in target_leafs.iter().zip(proofs.iter()) {
+            let proof_root = proof.sibling.iter().zip(proof.path.iter()).fold(
+                *leaf,
+                |node, (sibling, is_right)| {
+                    let pair = if *is_right {
+                        [*sibling, node]
+                    } else {
+                        [node, *sibling]
+                    };
+                    poseidon(pair.as_ref(), POOL_PARAMS.compress())
+                },
+            );
+            assert_eq!(proof_root, root);
+        }
+
+        // The tree itself is not modified
+        assert_eq!(tree.next_index(), 128);
+
+        tree.add_leafs_and_commitments(new_leafs, new_commitments);
+        assert_eq!(tree.get_root(), root);
+    }
+
+    #[test]
+    fn test_add_with_changes_matches_diff() {
+        let mut rng = CustomRng;
+        let tree = &mut init().tree;
+
+        let before = tree.get_all_nodes();
+        let hashes: Vec<_> = (0..5).map(|_| rng.gen()).collect();
+        let changes = tree.add_hashes_with_changes(0, hashes);
+        assert_changes_match_diff(tree, &before, &changes);
+
+        let before = tree.get_all_nodes();
+        let leafs = vec![(128, (0..3).map(|_| rng.gen()).collect())];
+        let commitments = vec![(256, rng.gen()), (384, rng.gen())];
+        let changes = tree.add_leafs_and_commitments_with_changes(leafs, commitments);
+        assert_changes_match_diff(tree, &before, &changes);
+        assert_eq!(tree.next_index(), 512);
+
+        assert!(tree
+            .add_leafs_and_commitments_with_changes(vec![], vec![])
+            .is_empty());
+    }
+
     #[test]
     fn test_default_hashes_are_added_correctly() {
         let mut rng = CustomRng;
@@ -1697,4 +3915,40 @@ mod tests {
 
         assert_eq!(optimistic_root.to_string(), root.to_string());
     }
+
+    #[test]
+    fn test_get_root_after_state() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let mut applied = MerkleTree::new_test(POOL_PARAMS.clone());
+        let existing: Vec<_> = (0..3).map(|_| rng.gen()).collect();
+        tree.add_hashes(0, existing.clone());
+        applied.add_hashes(0, existing);
+
+        let root = tree.get_root();
+        assert_eq!(tree.get_root_after_state(&[], &[]), Ok(root));
+
+        let leafs = vec![(128, (0..5).map(|_| rng.gen()).collect::<Vec<_>>())];
+        let commitments = vec![(256, rng.gen())];
+        let root_after = tree.get_root_after_state(&leafs, &commitments).unwrap();
+
+        // the tree is left untouched
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.next_index(), 128);
+
+        applied.add_leafs_and_commitments(leafs, commitments);
+        assert_eq!(root_after, applied.get_root());
+
+        assert_eq!(
+            tree.get_root_after_state(&[], &[(0, rng.gen())]),
+            Err(VirtualUpdateError::IndexBelowNextIndex {
+                index: 0,
+                next_index: 128
+            })
+        );
+        assert_eq!(
+            tree.get_root_after_state(&[(130, vec![rng.gen()])], &[]),
+            Err(VirtualUpdateError::UnalignedIndex(130))
+        );
+    }
 }
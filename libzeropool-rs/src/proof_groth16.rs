@@ -1,12 +1,20 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bellman::pairing::{
+    ff::{Field, PrimeField as _},
+    CurveAffine, CurveProjective, Engine as PairingEngine,
+};
 use libzeropool::{
     circuit::{tree::tree_update, tx::c_transfer},
     fawkes_crypto::{
         backend::bellman_groth16::{
             engines::Engine,
+            num_to_bellman_fp,
             prover::{prove, Proof},
+            verifier::VK,
             Parameters,
         },
-        ff_uint::Num,
+        ff_uint::{Num, NumRepr, Uint},
     },
     native::{
         params::PoolParams,
@@ -14,6 +22,26 @@ use libzeropool::{
         tx::{TransferPub, TransferSec},
     },
 };
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::utils::keccak256;
+
+type BellmanFr<E> = <<E as Engine>::BE as PairingEngine>::Fr;
+type BellmanG1<E> = <<E as Engine>::BE as PairingEngine>::G1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProofStage {
+    WitnessGeneration,
+    Proving,
+    Done,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProveError {
+    #[error("Proving was cancelled")]
+    Cancelled,
+}
 
 pub fn prove_tx<P, E>(
     params: &Parameters<E>,
@@ -48,3 +76,274 @@ where
 
     prove(params, &tree_pub, &tree_sec, circuit)
 }
+
+/// Verifies a batch of proofs at once using a random linear combination of their verification
+/// equations, so that all the pairings share a single Miller loop and final exponentiation.
+/// The randomizers are derived from `rng_seed` and the whole batch. The batch is only sound if
+/// `rng_seed` can't be predicted by whoever produced the proofs, so it must come from a secure
+/// RNG at verification time. Returns `false` if the batch is empty or any of the proofs is invalid.
+pub fn verify_batch<E: Engine>(
+    vk: &VK<E>,
+    proofs_and_inputs: &[(&Proof<E>, &[Num<E::Fr>])],
+    rng_seed: [u8; 32],
+) -> bool {
+    if proofs_and_inputs.is_empty() {
+        return false;
+    }
+
+    let vk = vk.to_bellman();
+    let transcript = batch_transcript(&rng_seed, proofs_and_inputs);
+
+    let mut r_sum = BellmanFr::<E>::zero();
+    let mut acc_ic = BellmanG1::<E>::zero();
+    let mut acc_c = BellmanG1::<E>::zero();
+    let mut terms = Vec::with_capacity(proofs_and_inputs.len() + 3);
+
+    for (i, (proof, inputs)) in proofs_and_inputs.iter().enumerate() {
+        if inputs.len() + 1 != vk.ic.len() {
+            return false;
+        }
+
+        let proof = proof.to_bellman();
+        let r = batch_randomizer::<E>(&transcript, i as u64);
+
+        let mut ic = vk.ic[0].into_projective();
+        for (input, base) in inputs.iter().zip(vk.ic.iter().skip(1)) {
+            let input: BellmanFr<E> = num_to_bellman_fp(*input);
+            ic.add_assign(&base.mul(input.into_repr()));
+        }
+        ic.mul_assign(r.into_repr());
+        acc_ic.add_assign(&ic);
+
+        acc_c.add_assign(&proof.c.mul(r.into_repr()));
+        terms.push((
+            proof.a.mul(r.into_repr()).into_affine().prepare(),
+            proof.b.prepare(),
+        ));
+        r_sum.add_assign(&r);
+    }
+
+    let mut acc_alpha = vk.alpha_g1.mul(r_sum.into_repr());
+    acc_alpha.negate();
+    acc_ic.negate();
+    acc_c.negate();
+
+    terms.push((acc_alpha.into_affine().prepare(), vk.beta_g2.prepare()));
+    terms.push((acc_ic.into_affine().prepare(), vk.gamma_g2.prepare()));
+    terms.push((acc_c.into_affine().prepare(), vk.delta_g2.prepare()));
+
+    let terms: Vec<_> = terms.iter().map(|(a, b)| (a, b)).collect();
+    let result = E::BE::final_exponentiation(&E::BE::miller_loop(terms.iter()));
+
+    result == Some(<E::BE as PairingEngine>::Fqk::one())
+}
+
+/// Hash of the seed and every proof with its inputs, so that the randomizers depend on the batch.
+fn batch_transcript<E: Engine>(
+    seed: &[u8; 32],
+    proofs_and_inputs: &[(&Proof<E>, &[Num<E::Fr>])],
+) -> [u8; 32] {
+    let mut data = seed.to_vec();
+    for (proof, inputs) in proofs_and_inputs {
+        let proof = proof.to_bellman();
+        data.extend_from_slice(proof.a.into_uncompressed().as_ref());
+        data.extend_from_slice(proof.b.into_uncompressed().as_ref());
+        data.extend_from_slice(proof.c.into_uncompressed().as_ref());
+        for input in inputs.iter() {
+            data.extend(input.to_uint().0.to_little_endian());
+        }
+    }
+
+    keccak256(&data)
+}
+
+/// 128-bit randomizer for the `index`-th proof of a batch.
+fn batch_randomizer<E: Engine>(transcript: &[u8; 32], index: u64) -> BellmanFr<E> {
+    let mut data = transcript.to_vec();
+    data.extend_from_slice(&index.to_le_bytes());
+
+    let mut bytes = [0u8; 32];
+    bytes[..16].copy_from_slice(&keccak256(&data)[..16]);
+
+    num_to_bellman_fp(Num::<E::Fr>::from_uint_reduced(NumRepr(
+        Uint::from_little_endian(&bytes),
+    )))
+}
+
+/// Same as [`prove_tx`], but reports the current stage through `progress` and checks `cancel`
+/// between stages. The prover can't be interrupted once the witness generation has started, so if
+/// `cancel` is set after that, the proof is discarded when it's ready and
+/// [`ProveError::Cancelled`] is returned.
+pub fn prove_tx_with_progress<P, E, F>(
+    params: &Parameters<E>,
+    pool_params: &P,
+    transfer_pub: TransferPub<E::Fr>,
+    transfer_sec: TransferSec<E::Fr>,
+    progress: F,
+    cancel: &AtomicBool,
+) -> Result<(Vec<Num<E::Fr>>, Proof<E>), ProveError>
+where
+    P: PoolParams<Fr = E::Fr>,
+    E: Engine,
+    F: Fn(ProofStage),
+{
+    with_progress(progress, cancel, |witness_ready| {
+        let circuit = |public, secret| {
+            c_transfer(&public, &secret, pool_params);
+            witness_ready();
+        };
+
+        prove(params, &transfer_pub, &transfer_sec, circuit)
+    })
+}
+
+/// Runs `prove`, which must call the passed hook once the circuit is synthesized.
+fn with_progress<T, F, G>(progress: F, cancel: &AtomicBool, prove: G) -> Result<T, ProveError>
+where
+    F: Fn(ProofStage),
+    G: FnOnce(&dyn Fn()) -> T,
+{
+    if cancel.load(Ordering::Relaxed) {
+        return Err(ProveError::Cancelled);
+    }
+
+    progress(ProofStage::WitnessGeneration);
+
+    let witness_ready = || progress(ProofStage::Proving);
+    let result = prove(&witness_ready);
+
+    if cancel.load(Ordering::Relaxed) {
+        return Err(ProveError::Cancelled);
+    }
+
+    progress(ProofStage::Done);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use libzeropool::fawkes_crypto::{
+        backend::bellman_groth16::{engines::Bn256, setup::setup},
+        circuit::{cs::CS, num::CNum},
+    };
+
+    use super::*;
+
+    fn square<C: CS>(public: CNum<C>, secret: CNum<C>) {
+        (&secret * &secret).assert_eq(&public);
+    }
+
+    fn square_proofs(
+        n: u64,
+    ) -> (
+        VK<Bn256>,
+        Vec<(Vec<Num<<Bn256 as Engine>::Fr>>, Proof<Bn256>)>,
+    ) {
+        let params = setup::<Bn256, _, _, _>(square);
+        let proofs = (1..=n)
+            .map(|x| {
+                let x = Num::from(x);
+                prove(&params, &(x * x), &x, square)
+            })
+            .collect();
+
+        (params.get_vk(), proofs)
+    }
+
+    #[test]
+    fn test_verify_batch_valid() {
+        let (vk, proofs) = square_proofs(5);
+        let batch: Vec<_> = proofs
+            .iter()
+            .map(|(inputs, proof)| (proof, inputs.as_slice()))
+            .collect();
+
+        assert!(verify_batch(&vk, &batch, [1; 32]));
+        assert!(verify_batch(&vk, &batch, [2; 32]));
+    }
+
+    #[test]
+    fn test_verify_batch_empty() {
+        let (vk, _) = square_proofs(0);
+
+        assert!(!verify_batch(&vk, &[], [1; 32]));
+    }
+
+    #[test]
+    fn test_verify_batch_corrupted() {
+        let (vk, mut proofs) = square_proofs(5);
+        proofs[3].1.a = proofs[0].1.a.clone();
+        let batch: Vec<_> = proofs
+            .iter()
+            .map(|(inputs, proof)| (proof, inputs.as_slice()))
+            .collect();
+
+        assert!(!verify_batch(&vk, &batch, [1; 32]));
+        assert!(!verify_batch(&vk, &batch, [2; 32]));
+    }
+
+    #[test]
+    fn test_with_progress_reports_stages() {
+        let stages = RefCell::new(Vec::new());
+        let cancel = AtomicBool::new(false);
+
+        let result = with_progress(
+            |stage| stages.borrow_mut().push(stage),
+            &cancel,
+            |witness_ready| {
+                witness_ready();
+                42
+            },
+        );
+
+        assert_eq!(result, Ok(42));
+        assert_eq!(
+            stages.into_inner(),
+            vec![
+                ProofStage::WitnessGeneration,
+                ProofStage::Proving,
+                ProofStage::Done
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_progress_cancelled_before_start() {
+        let stages = RefCell::new(Vec::new());
+        let cancel = AtomicBool::new(true);
+
+        let result = with_progress(
+            |stage| stages.borrow_mut().push(stage),
+            &cancel,
+            |_| unreachable!("proving must not start"),
+        );
+
+        assert_eq!(result, Err::<(), _>(ProveError::Cancelled));
+        assert!(stages.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_with_progress_cancelled_while_proving() {
+        let stages = RefCell::new(Vec::new());
+        let cancel = AtomicBool::new(false);
+
+        let result = with_progress(
+            |stage| stages.borrow_mut().push(stage),
+            &cancel,
+            |witness_ready| {
+                witness_ready();
+                cancel.store(true, Ordering::Relaxed);
+                42
+            },
+        );
+
+        assert_eq!(result, Err(ProveError::Cancelled));
+        assert_eq!(
+            stages.into_inner(),
+            vec![ProofStage::WitnessGeneration, ProofStage::Proving]
+        );
+    }
+}
@@ -37,3 +37,35 @@ impl RngCore for CustomRng {
         getrandom(dest).map_err(|err| RandError::new(ErrorWrapper(err)))
     }
 }
+
+/// Deterministic splitmix64 generator, for reproducible transactions in tests, see
+/// [`crate::client::UserAccount::create_tx_with_rng`].
+#[cfg(any(test, feature = "test-utils"))]
+pub struct SeededRng(pub u64);
+
+#[cfg(any(test, feature = "test-utils"))]
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), RandError> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
-use std::{convert::TryFrom, marker::PhantomData, ops::RangeBounds};
+use std::{
+    convert::TryFrom,
+    marker::PhantomData,
+    ops::{RangeBounds, RangeInclusive},
+};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use kvdb::{DBTransaction, KeyValueDB};
 use kvdb_memorydb::InMemory as MemoryDatabase;
 #[cfg(feature = "native")]
-use kvdb_persy::PersyDatabase as NativeDatabase;
+use kvdb_persy::{OpenMode, PersyDatabase as NativeDatabase};
 #[cfg(feature = "web")]
 use kvdb_web::Database as WebDatabase;
 
@@ -41,13 +45,27 @@ where
     T: BorshSerialize + BorshDeserialize,
 {
     pub fn new_native(path: &str) -> std::io::Result<SparseArray<NativeDatabase, T>> {
-        let db = NativeDatabase::open(path, 1, &[])?;
+        let db = NativeDatabase::open(path, 1, &[], OpenMode::ReadWrite)?;
 
         Ok(SparseArray {
             db,
             _phantom: Default::default(),
         })
     }
+
+    /// Iterates over the items starting from `from` in index order. Only the consumed items are
+    /// read, see [`NativeDatabase::iter_from`].
+    pub fn iter_from(&self, from: u64) -> impl Iterator<Item = (u64, T)> + '_ {
+        self.db
+            .iter_from(0, &from.to_be_bytes())
+            .unwrap()
+            .map(|res| {
+                let (key, value) = res.unwrap();
+                let index = u64::from_be_bytes(TryFrom::try_from(key.as_ref()).unwrap());
+
+                (index, T::try_from_slice(&value).unwrap())
+            })
+    }
 }
 
 impl<T> SparseArray<MemoryDatabase, T>
@@ -99,6 +117,23 @@ where
         self.iter().filter(move |(index, _)| range.contains(index))
     }
 
+    /// Same as [`SparseArray::iter_slice`], but yields the serialized items.
+    pub fn iter_slice_raw<R>(&self, range: R) -> impl Iterator<Item = (u64, Vec<u8>)> + '_
+    where
+        R: RangeBounds<u64> + 'static,
+    {
+        self.db.iter(0).filter_map(move |res| {
+            let (key, value) = res.unwrap();
+            let index = u64::from_be_bytes(TryFrom::try_from(key.as_ref()).unwrap());
+
+            if range.contains(&index) {
+                Some((index, value))
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn set(&self, index: u64, data: &T) {
         let mut batch = self.db.transaction();
         self.set_batched(index, data, &mut batch);
@@ -113,14 +148,38 @@ where
     }
 
     pub fn remove_all_after(&self, index: u64) {
+        self.remove_range(index..=u64::MAX);
+    }
+
+    /// Removes all entries within the range in a single transaction. Returns the number of removed
+    /// entries.
+    pub fn remove_range(&self, range: RangeInclusive<u64>) -> u64 {
+        self.try_remove_range(range).unwrap()
+    }
+
+    /// Same as [`SparseArray::remove_range`], but returns the write error instead of panicking.
+    /// Nothing is removed if the write fails.
+    pub fn try_remove_range(&self, range: RangeInclusive<u64>) -> std::io::Result<u64> {
         let mut batch = self.db.transaction();
+        let mut removed = 0;
 
-        for (index, _) in self.iter_slice(index..) {
+        for (index, _) in self.iter_slice(range) {
             let key = index.to_be_bytes();
             batch.delete(0, &key);
+            removed += 1;
         }
 
-        self.db.write(batch).unwrap();
+        self.db.write(batch)?;
+
+        Ok(removed)
+    }
+
+    /// Removes all entries below the index. Returns the number of removed entries.
+    pub fn compact_before(&self, index: u64) -> u64 {
+        match index.checked_sub(1) {
+            Some(last) => self.remove_range(0..=last),
+            None => 0,
+        }
     }
 
     // FIXME: Crazy inefficient, replace or improve kvdb
@@ -129,6 +188,15 @@ where
     }
 
     pub fn set_multiple<'a, I>(&self, items: I)
+    where
+        I: IntoIterator<Item = &'a (u64, T)>,
+    {
+        self.try_set_multiple(items).unwrap()
+    }
+
+    /// Same as [`SparseArray::set_multiple`], but returns the write error instead of panicking.
+    /// All items are written in a single transaction, so nothing is stored if the write fails.
+    pub fn try_set_multiple<'a, I>(&self, items: I) -> std::io::Result<()>
     where
         I: IntoIterator<Item = &'a (u64, T)>,
     {
@@ -138,6 +206,21 @@ where
             self.set_batched(*index, item, &mut batch);
         }
 
+        self.db.write(batch)
+    }
+
+    /// Replaces the whole content of the array with the specified items.
+    pub fn restore<'a, I>(&self, items: I)
+    where
+        I: IntoIterator<Item = &'a (u64, T)>,
+    {
+        let mut batch = self.db.transaction();
+        batch.delete_prefix(0, &[]);
+
+        for (index, item) in items {
+            self.set_batched(*index, item, &mut batch);
+        }
+
         self.db.write(batch).unwrap();
     }
 
@@ -170,8 +253,65 @@ impl<'a, T: BorshDeserialize> Iterator for SparseArrayIter<'a, T> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use super::*;
 
+    /// Applies the operations of a write one by one and fails once `remaining_ops` of them have
+    /// been applied, like a write interrupted halfway. The operations of a write are staged until
+    /// all of them are applied, so an interrupted write is rolled back as a whole, but the writes
+    /// before it stay committed.
+    struct FailingDatabase {
+        inner: MemoryDatabase,
+        remaining_ops: AtomicUsize,
+    }
+
+    impl KeyValueDB for FailingDatabase {
+        fn get(&self, col: u32, key: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            self.inner.get(col, key)
+        }
+
+        fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> std::io::Result<Option<kvdb::DBValue>> {
+            self.inner.get_by_prefix(col, prefix)
+        }
+
+        fn write(&self, transaction: DBTransaction) -> std::io::Result<()> {
+            let mut staged = DBTransaction::new();
+            for op in transaction.ops {
+                let applied = self.remaining_ops.fetch_update(
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                    |remaining| remaining.checked_sub(1),
+                );
+                if applied.is_err() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "Injected write failure",
+                    ));
+                }
+
+                staged.ops.push(op);
+            }
+
+            self.inner.write(staged)
+        }
+
+        fn iter<'a>(
+            &'a self,
+            col: u32,
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.inner.iter(col)
+        }
+
+        fn iter_with_prefix<'a>(
+            &'a self,
+            col: u32,
+            prefix: &'a [u8],
+        ) -> Box<dyn Iterator<Item = std::io::Result<kvdb::DBKeyValue>> + 'a> {
+            self.inner.iter_with_prefix(col, prefix)
+        }
+    }
+
     #[test]
     fn test_sparse_array_iter_slice() {
         let a = SparseArray::new_test();
@@ -187,4 +327,52 @@ mod tests {
         assert_eq!(a.iter_slice(2..=412345).count(), 2, "from 2");
         assert_eq!(a.iter_slice(2..=412344).count(), 1, "from 2 except last");
     }
+
+    #[test]
+    fn test_sparse_array_remove_range() {
+        let a = SparseArray::new_test();
+        let items: Vec<_> = (0..=512u64).map(|index| (index, index as u32)).collect();
+        a.set_multiple(&items);
+
+        assert_eq!(a.remove_range(100..=200), 101);
+        assert_eq!(a.remove_range(100..=200), 0);
+        assert_eq!(a.count(), 412);
+        assert_eq!(a.iter_slice(90..=210).count(), 20);
+        assert!(a.iter_slice(100..=200).next().is_none());
+        assert_eq!(a.get(99), Some(99));
+        assert_eq!(a.get(100), None);
+        assert_eq!(a.get(201), Some(201));
+
+        assert_eq!(a.compact_before(300), 199);
+        assert_eq!(a.compact_before(0), 0);
+        assert_eq!(a.count(), 213);
+        assert_eq!(a.iter().map(|(index, _)| index).min(), Some(300));
+    }
+
+    #[test]
+    fn test_sparse_array_batch_atomicity() {
+        let a = SparseArray::new(FailingDatabase {
+            inner: kvdb_memorydb::create(1),
+            remaining_ops: AtomicUsize::new(15),
+        });
+        let items: Vec<_> = (0..10u64).map(|index| (index, index as u32)).collect();
+        a.try_set_multiple(&items).unwrap();
+
+        // The database fails after applying 5 of the 10 ops
+        let more: Vec<_> = (10..20u64).map(|index| (index, index as u32)).collect();
+        assert!(a.try_set_multiple(&more).is_err());
+        assert_eq!(a.db.remaining_ops.load(Ordering::SeqCst), 0);
+        assert_eq!(a.count(), 10);
+        assert!((10..20).all(|index| a.get(index).is_none()));
+
+        a.db.remaining_ops.store(5, Ordering::SeqCst);
+        assert!(a.try_remove_range(0..=9).is_err());
+        assert_eq!(a.db.remaining_ops.load(Ordering::SeqCst), 0);
+        assert_eq!(a.count(), 10);
+        assert!((0..10).all(|index| a.get(index) == Some(index as u32)));
+
+        a.db.remaining_ops.store(5, Ordering::SeqCst);
+        assert_eq!(a.try_remove_range(0..=4).unwrap(), 5);
+        assert_eq!(a.count(), 5);
+    }
 }
@@ -0,0 +1,192 @@
+//! Deterministic transaction vectors for the ports of the client. Every [`Scenario`] builds an
+//! account from a fixed seed, fills its state and creates a transaction using
+//! [`SeededRng`], so the resulting public inputs, memo and ciphertext only change if hashing or
+//! encoding does.
+//!
+//! Ports are expected to compare their output against [`to_json`] of [`generate_all`].
+
+use libzeropool::{
+    fawkes_crypto::{ff_uint::Num, rand::Rng},
+    native::{
+        account::Account,
+        boundednum::BoundedNum,
+        note::Note,
+        params::{PoolBN256, PoolParams},
+        tx::TransferPub,
+    },
+    POOL_PARAMS,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    address::parse_address,
+    client::{state::State, TxOutput, TxType, UserAccount},
+    random::SeededRng,
+};
+
+pub type Fr = <PoolBN256 as PoolParams>::Fr;
+pub type Fs = <PoolBN256 as PoolParams>::Fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scenario {
+    /// Deposit of a fresh account with a single output note.
+    FirstDeposit,
+    /// Transfer spending two notes, no input account.
+    TransferTwoNotes,
+    /// Withdrawal from an account that has accrued energy, part of which is withdrawn too.
+    WithdrawWithEnergy,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 3] = [
+        Scenario::FirstDeposit,
+        Scenario::TransferTwoNotes,
+        Scenario::WithdrawWithEnergy,
+    ];
+
+    fn seed(self) -> u64 {
+        match self {
+            Scenario::FirstDeposit => 1,
+            Scenario::TransferTwoNotes => 2,
+            Scenario::WithdrawWithEnergy => 3,
+        }
+    }
+}
+
+/// Inputs and expected outputs of a transaction. Field order is part of the JSON format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = ""))]
+pub struct TxVector {
+    pub scenario: Scenario,
+    /// Seed of the [`SeededRng`] that produced the spending key, the state and the transaction.
+    pub seed: u64,
+    pub sk: Num<Fs>,
+    pub tx: TxType<Fr>,
+    pub public: TransferPub<Fr>,
+    /// Hex encoded.
+    pub memo: String,
+    /// Hex encoded.
+    pub ciphertext: String,
+}
+
+pub fn generate(scenario: Scenario) -> TxVector {
+    let seed = scenario.seed();
+    let mut rng = SeededRng(seed);
+
+    let sk: Num<Fs> = rng.gen();
+    let mut acc = UserAccount::new(
+        sk,
+        State::init_test(POOL_PARAMS.clone()),
+        POOL_PARAMS.clone(),
+    );
+    let own_address = |index: u64| acc.generate_address_deterministic(index).0;
+    let recipient = UserAccount::new(
+        rng.gen(),
+        State::init_test(POOL_PARAMS.clone()),
+        POOL_PARAMS.clone(),
+    )
+    .generate_address_deterministic(0)
+    .0;
+    let amount = |value: u64| BoundedNum::new(Num::from(value));
+
+    let tx = match scenario {
+        Scenario::FirstDeposit => TxType::Deposit {
+            fee: amount(100),
+            deposit_amount: amount(10_000),
+            outputs: vec![TxOutput {
+                to: recipient,
+                amount: amount(1_000),
+            }],
+        },
+        Scenario::TransferTwoNotes => {
+            let notes: Vec<(u64, Note<Fr>)> = [(1, 5_000), (2, 7_000)]
+                .iter()
+                .map(|&(index, value)| {
+                    let (d, p_d) = parse_address::<PoolBN256>(&own_address(index)).unwrap();
+                    let note = Note {
+                        d,
+                        p_d,
+                        b: amount(value),
+                        t: rng.gen(),
+                    };
+                    (index, note)
+                })
+                .collect();
+            let hashes: Vec<_> = std::iter::once(Num::ZERO)
+                .chain(notes.iter().map(|(_, note)| note.hash(&*POOL_PARAMS)))
+                .collect();
+            acc.state.add_full_tx(0, &hashes, None, &notes);
+
+            TxType::Transfer {
+                fee: amount(100),
+                outputs: vec![TxOutput {
+                    to: recipient,
+                    amount: amount(10_000),
+                }],
+            }
+        }
+        Scenario::WithdrawWithEnergy => {
+            let (d, p_d) = parse_address::<PoolBN256>(&own_address(0)).unwrap();
+            let account = Account {
+                d,
+                p_d,
+                i: BoundedNum::new(Num::ZERO),
+                b: amount(20_000),
+                e: BoundedNum::new(Num::ZERO),
+            };
+            let hashes = [account.hash(&*POOL_PARAMS)];
+            acc.state.add_full_tx(0, &hashes, Some(account), &[]);
+
+            TxType::Withdraw {
+                fee: amount(100),
+                withdraw_amount: amount(15_000),
+                to: vec![0x42; acc.address_length],
+                native_amount: amount(1_000),
+                energy_amount: amount(1_000_000),
+            }
+        }
+    };
+
+    let tx_data = acc
+        .create_tx_with_rng(tx.clone(), None, None, &mut rng)
+        .unwrap();
+
+    TxVector {
+        scenario,
+        seed,
+        sk,
+        tx,
+        public: tx_data.public,
+        memo: hex::encode(&tx_data.memo),
+        ciphertext: hex::encode(&tx_data.ciphertext),
+    }
+}
+
+pub fn generate_all() -> Vec<TxVector> {
+    Scenario::ALL
+        .iter()
+        .map(|&scenario| generate(scenario))
+        .collect()
+}
+
+/// Serializes the vectors as pretty-printed JSON.
+pub fn to_json(vectors: &[TxVector]) -> String {
+    let mut json = serde_json::to_string_pretty(vectors).unwrap();
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors_are_deterministic() {
+        let vectors = to_json(&generate_all());
+        assert_eq!(to_json(&generate_all()), vectors);
+
+        let parsed: Vec<TxVector> = serde_json::from_str(&vectors).unwrap();
+        assert_eq!(to_json(&parsed), vectors);
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::HashSet;
+
+use libzeropool::{
+    constants,
+    fawkes_crypto::ff_uint::{Num, PrimeField},
+    native::{account::Account, cipher, key, note::Note, params::PoolParams},
+};
+#[cfg(feature = "multicore")]
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memo::{decrypt_memo_text, parse_memo, split_memo_text},
+    merkle::Hash,
+    utils::zero_note,
+};
+
+/// Transaction from the pool contract, `memo` contains only the ciphertext.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedTx<Fr: PrimeField> {
+    pub index: u64,
+    pub memo: Vec<u8>,
+    pub commitment: Hash<Fr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedNote<Fr: PrimeField> {
+    pub index: u64,
+    pub note: Note<Fr>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateUpdate<Fr: PrimeField> {
+    #[serde(rename = "newLeafs")]
+    pub new_leafs: Vec<(u64, Vec<Hash<Fr>>)>,
+    #[serde(rename = "newCommitments")]
+    pub new_commitments: Vec<(u64, Hash<Fr>)>,
+    #[serde(rename = "newAccounts")]
+    pub new_accounts: Vec<(u64, Account<Fr>)>,
+    #[serde(rename = "newNotes")]
+    pub new_notes: Vec<Vec<(u64, Note<Fr>)>>,
+    /// On-chain commitments (by the index of their first leaf) to check the leafs against before
+    /// they are added, see [`crate::merkle::MerkleTree::check_leafs`]. Never set by the parser.
+    #[serde(rename = "expectedCommitments", default)]
+    pub expected_commitments: Vec<(u64, Hash<Fr>)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecMemo<Fr: PrimeField> {
+    pub index: u64,
+    pub acc: Option<Account<Fr>>,
+    #[serde(rename = "inNotes")]
+    pub in_notes: Vec<IndexedNote<Fr>>,
+    #[serde(rename = "outNotes")]
+    pub out_notes: Vec<IndexedNote<Fr>>,
+    #[serde(rename = "txHash")]
+    pub tx_hash: Option<String>,
+    /// Data attached to the transaction for the owners of its notes, only decrypted if one of
+    /// them is the scanner.
+    #[serde(rename = "memoText", default, skip_serializing_if = "Option::is_none")]
+    pub memo_text: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParseErrorCode {
+    /// Memo or commitment is not a valid hex string.
+    BadHex,
+    /// Memo is too short to contain the hashes of the account and the notes.
+    TruncatedMemo,
+}
+
+/// Transaction that could not be parsed. The rest of the batch is parsed as usual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParseError {
+    pub index: u64,
+    pub code: ParseErrorCode,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ParseResult<Fr: PrimeField> {
+    #[serde(rename = "decryptedMemos")]
+    pub decrypted_memos: Vec<DecMemo<Fr>>,
+    #[serde(rename = "stateUpdate")]
+    pub state_update: StateUpdate<Fr>,
+    /// The most recent own account among the parsed transactions. After restoring from the seed
+    /// it's the account the next transaction has to spend.
+    #[serde(rename = "latestOwnAccount", default)]
+    pub latest_own_account: Option<(u64, Account<Fr>)>,
+    /// Transactions that could not be parsed, ordered by index.
+    #[serde(default)]
+    pub errors: Vec<ParseError>,
+}
+
+impl<Fr: PrimeField> ParseResult<Fr> {
+    fn append(&mut self, other: ParseResult<Fr>) {
+        self.decrypted_memos.extend(other.decrypted_memos);
+        self.errors.extend(other.errors);
+        if let Some((index, account)) = other.latest_own_account {
+            if self
+                .latest_own_account
+                .map_or(true, |(latest, _)| index > latest)
+            {
+                self.latest_own_account = Some((index, account));
+            }
+        }
+        self.state_update
+            .new_leafs
+            .extend(other.state_update.new_leafs);
+        self.state_update
+            .new_commitments
+            .extend(other.state_update.new_commitments);
+        self.state_update
+            .new_accounts
+            .extend(other.state_update.new_accounts);
+        self.state_update
+            .new_notes
+            .extend(other.state_update.new_notes);
+    }
+}
+
+/// Decrypts the memos that belong to the owner of `eta` and collects the state update.
+/// Memos that cannot be decrypted only contribute their commitments, malformed memos are
+/// additionally reported in [`ParseResult::errors`]. Uses rayon if the `multicore` feature is
+/// enabled.
+pub fn parse_txs<P>(eta: Num<P::Fr>, txs: &[IndexedTx<P::Fr>], params: &P) -> ParseResult<P::Fr>
+where
+    P: PoolParams + Sync,
+    P::Fr: Send + Sync,
+{
+    #[cfg(feature = "multicore")]
+    let parse_results: Vec<_> = txs.par_iter().map(|tx| parse_tx(eta, tx, params)).collect();
+    #[cfg(not(feature = "multicore"))]
+    let parse_results: Vec<_> = txs.iter().map(|tx| parse_tx(eta, tx, params)).collect();
+
+    let mut parse_result =
+        parse_results
+            .into_iter()
+            .fold(ParseResult::default(), |mut acc, parse_result| {
+                acc.append(parse_result);
+                acc
+            });
+
+    parse_result
+        .decrypted_memos
+        .sort_by(|a, b| a.index.cmp(&b.index));
+    parse_result.errors.sort_by_key(|err| err.index);
+
+    parse_result
+}
+
+/// Parses transactions fetched in pages and accumulates a single state update. Transactions
+/// with indices that were already processed are skipped, so pages may overlap.
+#[derive(Debug, Clone)]
+pub struct ParseSession<Fr: PrimeField> {
+    zero_note_hash: Hash<Fr>,
+    last_index: Option<u64>,
+    seen_indices: HashSet<u64>,
+    state_update: StateUpdate<Fr>,
+}
+
+impl<Fr: PrimeField> ParseSession<Fr> {
+    pub fn new<P: PoolParams<Fr = Fr>>(params: &P) -> Self {
+        ParseSession {
+            zero_note_hash: zero_note().hash(params),
+            last_index: None,
+            seen_indices: HashSet::new(),
+            state_update: StateUpdate::default(),
+        }
+    }
+
+    /// Highest transaction index processed so far.
+    pub fn last_index(&self) -> Option<u64> {
+        self.last_index
+    }
+
+    /// Parses the transactions that haven't been processed yet and returns their result.
+    pub fn feed<P>(&mut self, eta: Num<Fr>, txs: &[IndexedTx<Fr>], params: &P) -> ParseResult<Fr>
+    where
+        P: PoolParams<Fr = Fr> + Sync,
+        Fr: Send + Sync,
+    {
+        let new_txs: Vec<_> = txs
+            .iter()
+            .filter(|tx| self.seen_indices.insert(tx.index))
+            .cloned()
+            .collect();
+
+        if let Some(max_index) = new_txs.iter().map(|tx| tx.index).max() {
+            self.last_index = Some(self.last_index.map_or(max_index, |i| i.max(max_index)));
+        }
+
+        let parse_result = parse_txs(eta, &new_txs, params);
+
+        let update = parse_result.state_update.clone();
+        self.state_update.new_leafs.extend(update.new_leafs);
+        self.state_update
+            .new_commitments
+            .extend(update.new_commitments);
+        self.state_update.new_accounts.extend(update.new_accounts);
+        self.state_update.new_notes.extend(update.new_notes);
+
+        parse_result
+    }
+
+    /// Returns the cumulative state update ordered by index. Leaf batches of adjacent
+    /// transactions are merged into a single batch padded with zero note hashes.
+    pub fn finalize(&self) -> StateUpdate<Fr> {
+        let mut update = self.state_update.clone();
+
+        update.new_leafs.sort_by_key(|(index, _)| *index);
+        update.new_commitments.sort_by_key(|(index, _)| *index);
+        update.new_accounts.sort_by_key(|(index, _)| *index);
+        update
+            .new_notes
+            .sort_by_key(|notes| notes.first().map(|(index, _)| *index));
+        update.new_leafs = coalesce_leafs(update.new_leafs, self.zero_note_hash);
+
+        update
+    }
+}
+
+/// Merges sorted leaf batches that occupy adjacent transaction slots.
+fn coalesce_leafs<Fr: PrimeField>(
+    new_leafs: Vec<(u64, Vec<Hash<Fr>>)>,
+    zero_note_hash: Hash<Fr>,
+) -> Vec<(u64, Vec<Hash<Fr>>)> {
+    let mut runs: Vec<(u64, Vec<Hash<Fr>>)> = Vec::new();
+
+    for (index, leafs) in new_leafs {
+        if let Some((run_start, run)) = runs.last_mut() {
+            let run_end = *run_start + run.len() as u64;
+            let slot_size = 1u64 << constants::OUTPLUSONELOG;
+            let next_slot = (run_end + slot_size - 1) / slot_size * slot_size;
+
+            if next_slot == index {
+                run.resize((index - *run_start) as usize, zero_note_hash);
+                run.extend(leafs);
+                continue;
+            }
+        }
+
+        runs.push((index, leafs));
+    }
+
+    runs
+}
+
+fn parse_tx<P: PoolParams>(
+    eta: Num<P::Fr>,
+    tx: &IndexedTx<P::Fr>,
+    params: &P,
+) -> ParseResult<P::Fr> {
+    let IndexedTx {
+        index,
+        ref memo,
+        commitment,
+    } = *tx;
+    let (memo, memo_text) = split_memo_text::<P::Fr>(memo);
+
+    let hashes = match parse_memo::<P::Fr>(memo, None) {
+        Ok(parsed) => parsed.hashes,
+        Err(_) => {
+            let mut parse_result = foreign_tx(index, commitment);
+            parse_result.errors.push(ParseError {
+                index,
+                code: ParseErrorCode::TruncatedMemo,
+            });
+            return parse_result;
+        }
+    };
+
+    let is_own_note =
+        |note: &Note<P::Fr>| note.p_d == key::derive_key_p_d(note.d.to_num(), eta, params).x;
+    // Tried with every own note, since the sender may be one of the recipients too
+    let decrypt_text = |in_notes: &[(u64, Note<P::Fr>)]| {
+        let memo_text = memo_text?;
+        in_notes.iter().find_map(|(note_index, _)| {
+            let position = (note_index - index - 1) as usize;
+            decrypt_memo_text(memo_text, position, eta, params)
+        })
+    };
+
+    match cipher::decrypt_out(eta, memo, params) {
+        Some((account, notes)) => {
+            let mut in_notes = Vec::new();
+            let mut out_notes = Vec::new();
+            notes.into_iter().enumerate().for_each(|(i, note)| {
+                out_notes.push((index + 1 + (i as u64), note));
+
+                if is_own_note(&note) {
+                    in_notes.push((index + 1 + (i as u64), note));
+                }
+            });
+
+            ParseResult {
+                decrypted_memos: vec![DecMemo {
+                    index,
+                    acc: Some(account),
+                    in_notes: indexed_notes(&in_notes),
+                    out_notes: indexed_notes(&out_notes),
+                    memo_text: decrypt_text(&in_notes),
+                    ..Default::default()
+                }],
+                state_update: StateUpdate {
+                    new_leafs: vec![(index, hashes)],
+                    new_accounts: vec![(index, account)],
+                    new_notes: vec![in_notes],
+                    ..Default::default()
+                },
+                latest_own_account: Some((index, account)),
+                ..Default::default()
+            }
+        }
+        None => {
+            let in_notes: Vec<(_, _)> = cipher::decrypt_in(eta, memo, params)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, note)| match note {
+                    Some(note) if is_own_note(&note) => Some((index + 1 + (i as u64), note)),
+                    _ => None,
+                })
+                .collect();
+
+            if in_notes.is_empty() {
+                return foreign_tx(index, commitment);
+            }
+
+            ParseResult {
+                decrypted_memos: vec![DecMemo {
+                    index,
+                    in_notes: indexed_notes(&in_notes),
+                    memo_text: decrypt_text(&in_notes),
+                    ..Default::default()
+                }],
+                state_update: StateUpdate {
+                    new_leafs: vec![(index, hashes)],
+                    new_notes: vec![in_notes],
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        }
+    }
+}
+
+fn foreign_tx<Fr: PrimeField>(index: u64, commitment: Hash<Fr>) -> ParseResult<Fr> {
+    ParseResult {
+        state_update: StateUpdate {
+            new_commitments: vec![(index, commitment)],
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn indexed_notes<Fr: PrimeField>(notes: &[(u64, Note<Fr>)]) -> Vec<IndexedNote<Fr>> {
+    notes
+        .iter()
+        .map(|&(index, note)| IndexedNote { index, note })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::{
+        fawkes_crypto::ff_uint::Num,
+        native::{boundednum::BoundedNum, params::PoolBN256},
+        POOL_PARAMS,
+    };
+
+    use super::*;
+    use crate::{
+        client::{state::State, TxOutput, TxType, UserAccount},
+        keys::{Keys, SecretKey},
+        merkle::MerkleTree,
+    };
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    fn eta(sk: u64) -> Num<Fr> {
+        Keys::derive(SecretKey::new(Num::from(sk)), &*POOL_PARAMS).eta
+    }
+
+    fn transfer_to(
+        acc: &UserAccount<kvdb_memorydb::InMemory, PoolBN256>,
+        to: String,
+    ) -> IndexedTx<Fr> {
+        transfer_with_data(acc, to, None)
+    }
+
+    fn transfer_with_data(
+        acc: &UserAccount<kvdb_memorydb::InMemory, PoolBN256>,
+        to: String,
+        encrypted_data: Option<Vec<u8>>,
+    ) -> IndexedTx<Fr> {
+        let fee = BoundedNum::new(Num::ZERO);
+        let outputs = vec![TxOutput {
+            to,
+            amount: BoundedNum::new(Num::ZERO),
+        }];
+        let tx = match encrypted_data {
+            Some(encrypted_data) => TxType::TransferV2 {
+                fee,
+                outputs,
+                encrypted_data,
+            },
+            None => TxType::Transfer { fee, outputs },
+        };
+        let data = acc.create_tx(tx, None, None).unwrap();
+
+        IndexedTx {
+            index: 128,
+            memo: data.ciphertext,
+            commitment: data.public.out_commit,
+        }
+    }
+
+    #[test]
+    fn test_parse_txs_owner() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::from(1u64), state, POOL_PARAMS.clone());
+        let tx = transfer_to(&acc, acc.generate_address());
+
+        let result = parse_txs(eta(1), &[tx], &*POOL_PARAMS);
+
+        assert_eq!(result.decrypted_memos.len(), 1);
+        let memo = &result.decrypted_memos[0];
+        assert_eq!(memo.index, 128);
+        assert!(memo.acc.is_some());
+        assert_eq!(memo.out_notes.len(), 1);
+        assert_eq!(memo.out_notes[0].index, 129);
+        assert_eq!(memo.in_notes.len(), 1);
+
+        let update = &result.state_update;
+        assert_eq!(update.new_leafs.len(), 1);
+        assert_eq!(update.new_leafs[0].1.len(), 2);
+        assert_eq!(update.new_accounts.len(), 1);
+        assert!(update.new_commitments.is_empty());
+        assert_eq!(result.latest_own_account.map(|(index, _)| index), Some(128));
+    }
+
+    #[test]
+    fn test_parse_txs_stranger() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::from(1u64), state, POOL_PARAMS.clone());
+        let tx = transfer_to(&acc, acc.generate_address());
+        let commitment = tx.commitment;
+
+        let result = parse_txs(eta(2), &[tx], &*POOL_PARAMS);
+
+        assert!(result.decrypted_memos.is_empty());
+        assert!(result.state_update.new_leafs.is_empty());
+        assert!(result.state_update.new_accounts.is_empty());
+        assert_eq!(result.state_update.new_commitments, vec![(128, commitment)]);
+    }
+
+    #[test]
+    fn test_parse_txs_incoming_note() {
+        let sender_state = State::init_test(POOL_PARAMS.clone());
+        let sender = UserAccount::new(Num::from(1u64), sender_state, POOL_PARAMS.clone());
+        let receiver_state = State::init_test(POOL_PARAMS.clone());
+        let receiver = UserAccount::new(Num::from(2u64), receiver_state, POOL_PARAMS.clone());
+        let tx = transfer_to(&sender, receiver.generate_address());
+
+        let result = parse_txs(eta(2), &[tx], &*POOL_PARAMS);
+
+        assert_eq!(result.decrypted_memos.len(), 1);
+        let memo = &result.decrypted_memos[0];
+        assert!(memo.acc.is_none());
+        assert!(memo.out_notes.is_empty());
+        assert_eq!(memo.in_notes.len(), 1);
+        assert_eq!(memo.in_notes[0].index, 129);
+        assert!(result.state_update.new_accounts.is_empty());
+        assert_eq!(result.state_update.new_notes.len(), 1);
+        assert!(result.latest_own_account.is_none());
+    }
+
+    #[test]
+    fn test_parse_txs_memo_text() {
+        let sender_state = State::init_test(POOL_PARAMS.clone());
+        let sender = UserAccount::new(Num::from(1u64), sender_state, POOL_PARAMS.clone());
+        let receiver_state = State::init_test(POOL_PARAMS.clone());
+        let receiver = UserAccount::new(Num::from(2u64), receiver_state, POOL_PARAMS.clone());
+        let text = b"invoice #42".to_vec();
+        let tx = transfer_with_data(&sender, receiver.generate_address(), Some(text.clone()));
+
+        let result = parse_txs(eta(2), &[tx.clone()], &*POOL_PARAMS);
+        assert_eq!(result.decrypted_memos.len(), 1);
+        assert_eq!(result.decrypted_memos[0].in_notes.len(), 1);
+        assert_eq!(result.decrypted_memos[0].memo_text, Some(text));
+
+        // The sender still sees its account, but isn't a recipient of the text
+        let result = parse_txs(eta(1), &[tx.clone()], &*POOL_PARAMS);
+        assert_eq!(result.decrypted_memos.len(), 1);
+        assert!(result.decrypted_memos[0].acc.is_some());
+        assert_eq!(result.decrypted_memos[0].memo_text, None);
+
+        let result = parse_txs(eta(3), &[tx], &*POOL_PARAMS);
+        assert!(result.decrypted_memos.is_empty());
+        assert_eq!(result.state_update.new_commitments.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_txs_truncated_memo() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::from(1u64), state, POOL_PARAMS.clone());
+        let tx = transfer_to(&acc, acc.generate_address());
+        let truncated = IndexedTx {
+            index: 256,
+            memo: tx.memo[..8].to_vec(),
+            commitment: tx.commitment,
+        };
+
+        let result = parse_txs(eta(1), &[truncated, tx.clone()], &*POOL_PARAMS);
+
+        assert_eq!(
+            result.errors,
+            vec![ParseError {
+                index: 256,
+                code: ParseErrorCode::TruncatedMemo,
+            }]
+        );
+        assert_eq!(result.decrypted_memos.len(), 1);
+        assert_eq!(result.decrypted_memos[0].index, 128);
+        assert_eq!(result.state_update.new_accounts.len(), 1);
+        assert_eq!(
+            result.state_update.new_commitments,
+            vec![(256, tx.commitment)]
+        );
+    }
+
+    #[test]
+    fn test_parse_session_overlapping_pages() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::from(1u64), state, POOL_PARAMS.clone());
+        let own_tx = transfer_to(&acc, acc.generate_address());
+
+        // Every 10th transaction is foreign and only contributes its commitment
+        let txs: Vec<_> = (0..150u64)
+            .map(|i| IndexedTx {
+                index: i << constants::OUTPLUSONELOG,
+                memo: if i % 10 == 9 {
+                    vec![]
+                } else {
+                    own_tx.memo.clone()
+                },
+                commitment: own_tx.commitment,
+            })
+            .collect();
+
+        let mut session = ParseSession::new(&*POOL_PARAMS);
+        let first = session.feed(eta(1), &txs[0..100], &*POOL_PARAMS);
+        let second = session.feed(eta(1), &txs[50..150], &*POOL_PARAMS);
+
+        assert_eq!(first.decrypted_memos.len(), 90);
+        assert_eq!(second.decrypted_memos.len(), 45);
+        assert_eq!(session.last_index(), Some(149 << constants::OUTPLUSONELOG));
+
+        let update = session.finalize();
+
+        let account_indices: HashSet<_> = update.new_accounts.iter().map(|(i, _)| *i).collect();
+        assert_eq!(update.new_accounts.len(), 135);
+        assert_eq!(account_indices.len(), 135);
+
+        let note_indices: HashSet<_> = update.new_notes.iter().flatten().map(|(i, _)| *i).collect();
+        assert_eq!(update.new_notes.iter().flatten().count(), 135);
+        assert_eq!(note_indices.len(), 135);
+
+        assert_eq!(update.new_commitments.len(), 15);
+        assert_eq!(update.new_leafs.len(), 15);
+        for (run, (index, leafs)) in update.new_leafs.iter().enumerate() {
+            assert_eq!(*index, (run as u64 * 10) << constants::OUTPLUSONELOG);
+            assert_eq!(leafs.len(), (8 << constants::OUTPLUSONELOG) + 2);
+        }
+
+        // Coalesced runs produce the same tree as the separate batches
+        let separate = parse_txs(eta(1), &txs, &*POOL_PARAMS).state_update;
+        let mut expected_tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        expected_tree.add_leafs_and_commitments(separate.new_leafs, separate.new_commitments);
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        tree.add_leafs_and_commitments(update.new_leafs, update.new_commitments);
+
+        assert_eq!(tree.get_root(), expected_tree.get_root());
+        assert_eq!(tree.next_index(), expected_tree.next_index());
+    }
+}
@@ -4,9 +4,11 @@ use libzeropool::{
         ff_uint::{Num, PrimeField},
         native::poseidon::MerkleProof,
     },
-    native::{boundednum::BoundedNum, note::Note},
+    native::{boundednum::BoundedNum, note::Note, params::PoolParams, tx::out_commitment_hash},
 };
 
+use crate::merkle::merkle_proof_root;
+
 pub fn keccak256(data: &[u8]) -> [u8; 32] {
     use sha3::Digest;
 
@@ -28,9 +30,250 @@ pub fn zero_note<Fr: PrimeField>() -> Note<Fr> {
     }
 }
 
+/// Calculates the out commitment of a transaction from its leafs (the account hash followed by
+/// the note hashes). The unused leafs are filled with zero note hashes, the same way the
+/// transactions are built.
+///
+/// # Panics
+/// If there are more than `constants::OUT + 1` leafs.
+pub fn commitment_from_leaves<P: PoolParams>(leaves: &[Num<P::Fr>], params: &P) -> Num<P::Fr> {
+    assert!(
+        leaves.len() <= constants::OUT + 1,
+        "too many leafs: {}",
+        leaves.len()
+    );
+
+    let zero_note_hash = if leaves.len() <= constants::OUT {
+        zero_note().hash(params)
+    } else {
+        Num::ZERO
+    };
+    let leaves: Vec<_> = leaves
+        .iter()
+        .copied()
+        .chain(std::iter::repeat(zero_note_hash))
+        .take(constants::OUT + 1)
+        .collect();
+
+    out_commitment_hash(&leaves, params)
+}
+
+/// Checks that `proof` leads from `leaf` to `expected_root`, see [`merkle_proof_root`].
+pub fn verify_merkle_proof<P: PoolParams, const H: usize>(
+    leaf: Num<P::Fr>,
+    proof: &MerkleProof<P::Fr, { H }>,
+    expected_root: Num<P::Fr>,
+    params: &P,
+) -> bool {
+    merkle_proof_root(leaf, proof, params) == expected_root
+}
+
+/// [`verify_merkle_proof`] for proofs of a height only known at runtime, such as the ones passed
+/// from JS. Accepts leaf and commitment proofs, any other height fails the check.
+pub fn verify_merkle_proof_parts<P: PoolParams>(
+    leaf: Num<P::Fr>,
+    sibling: &[Num<P::Fr>],
+    path: &[bool],
+    expected_root: Num<P::Fr>,
+    params: &P,
+) -> bool {
+    fn verify<P: PoolParams, const H: usize>(
+        leaf: Num<P::Fr>,
+        sibling: &[Num<P::Fr>],
+        path: &[bool],
+        expected_root: Num<P::Fr>,
+        params: &P,
+    ) -> bool {
+        let proof = MerkleProof::<P::Fr, { H }> {
+            sibling: sibling.iter().copied().collect(),
+            path: path.iter().copied().collect(),
+        };
+        verify_merkle_proof(leaf, &proof, expected_root, params)
+    }
+
+    if sibling.len() != path.len() {
+        return false;
+    }
+
+    match sibling.len() {
+        constants::HEIGHT => {
+            verify::<P, { constants::HEIGHT }>(leaf, sibling, path, expected_root, params)
+        }
+        len if len == constants::HEIGHT - constants::OUTPLUSONELOG => {
+            verify::<P, { constants::HEIGHT - constants::OUTPLUSONELOG }>(
+                leaf,
+                sibling,
+                path,
+                expected_root,
+                params,
+            )
+        }
+        _ => false,
+    }
+}
+
 pub fn zero_proof<Fr: PrimeField>() -> MerkleProof<Fr, { constants::HEIGHT }> {
     MerkleProof {
         sibling: (0..constants::HEIGHT).map(|_| Num::ZERO).collect(),
         path: (0..constants::HEIGHT).map(|_| false).collect(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::{
+        fawkes_crypto::rand::Rng,
+        native::{account::Account, params::PoolBN256},
+        POOL_PARAMS,
+    };
+
+    use super::*;
+    use crate::{merkle::MerkleTree, random::CustomRng};
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    fn padded(leaves: &[Num<Fr>]) -> Num<Fr> {
+        let mut leaves = leaves.to_vec();
+        leaves.resize(constants::OUT + 1, zero_note::<Fr>().hash(&*POOL_PARAMS));
+        out_commitment_hash(&leaves, &*POOL_PARAMS)
+    }
+
+    #[test]
+    fn test_commitment_from_leaves() {
+        let mut rng = CustomRng;
+        let account_hash: Num<Fr> = rng.gen();
+
+        // no leafs at all, only the padding
+        assert_eq!(commitment_from_leaves(&[], &*POOL_PARAMS), padded(&[]));
+
+        // account without notes
+        assert_eq!(
+            commitment_from_leaves(&[account_hash], &*POOL_PARAMS),
+            padded(&[account_hash])
+        );
+
+        // account and constants::OUT notes, no padding
+        let leaves: Vec<Num<Fr>> = (0..constants::OUT + 1).map(|_| rng.gen()).collect();
+        assert_eq!(
+            commitment_from_leaves(&leaves, &*POOL_PARAMS),
+            out_commitment_hash(&leaves, &*POOL_PARAMS)
+        );
+
+        // delegated deposits output a zero account followed by the deposit notes
+        let zero_account = Account::<Fr> {
+            d: BoundedNum::new(Num::ZERO),
+            p_d: Num::ZERO,
+            i: BoundedNum::new(Num::ZERO),
+            b: BoundedNum::new(Num::ZERO),
+            e: BoundedNum::new(Num::ZERO),
+        }
+        .hash(&*POOL_PARAMS);
+        let note_hash: Num<Fr> = rng.gen();
+        assert_eq!(
+            commitment_from_leaves(&[zero_account, note_hash], &*POOL_PARAMS),
+            padded(&[zero_account, note_hash])
+        );
+        assert_ne!(
+            commitment_from_leaves(&[zero_account, note_hash], &*POOL_PARAMS),
+            commitment_from_leaves(&[note_hash], &*POOL_PARAMS)
+        );
+    }
+
+    #[test]
+    fn test_verify_merkle_proof() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let leaves: Vec<Num<Fr>> = (0..5).map(|_| rng.gen()).collect();
+        for (index, leaf) in leaves.iter().enumerate() {
+            tree.add_hash(index as u64, *leaf, false);
+        }
+        let root = tree.get_root();
+
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree.get_leaf_proof(index as u64).unwrap();
+            assert!(verify_merkle_proof(*leaf, &proof, root, &*POOL_PARAMS));
+            assert!(verify_merkle_proof_parts(
+                *leaf,
+                proof.sibling.as_slice(),
+                proof.path.as_slice(),
+                root,
+                &*POOL_PARAMS
+            ));
+            assert!(!verify_merkle_proof(rng.gen(), &proof, root, &*POOL_PARAMS));
+            assert!(!verify_merkle_proof(
+                *leaf,
+                &proof,
+                rng.gen(),
+                &*POOL_PARAMS
+            ));
+
+            let mut bad_sibling = tree.get_leaf_proof(index as u64).unwrap();
+            *bad_sibling.sibling.iter_mut().next().unwrap() = rng.gen();
+            assert!(!verify_merkle_proof(
+                *leaf,
+                &bad_sibling,
+                root,
+                &*POOL_PARAMS
+            ));
+
+            let mut bad_path = tree.get_leaf_proof(index as u64).unwrap();
+            let bit = bad_path.path.iter_mut().next().unwrap();
+            *bit = !*bit;
+            assert!(!verify_merkle_proof(*leaf, &bad_path, root, &*POOL_PARAMS));
+        }
+
+        let proof = tree.get_leaf_proof(0).unwrap();
+        assert!(!verify_merkle_proof_parts(
+            leaves[0],
+            &proof.sibling.as_slice()[1..],
+            &proof.path.as_slice()[1..],
+            root,
+            &*POOL_PARAMS
+        ));
+    }
+
+    #[test]
+    fn test_verify_commitment_merkle_proof() {
+        let mut rng = CustomRng;
+        let mut tree = MerkleTree::new_test(POOL_PARAMS.clone());
+        let commitments: Vec<Num<Fr>> = (0..3).map(|_| rng.gen()).collect();
+        for (index, commitment) in commitments.iter().enumerate() {
+            tree.add_commitment(index as u64, *commitment).unwrap();
+        }
+        let root = tree.get_root();
+
+        for (index, commitment) in commitments.iter().enumerate() {
+            let proof = tree.get_commitment_proof(index as u64).unwrap();
+            assert!(verify_merkle_proof(
+                *commitment,
+                &proof,
+                root,
+                &*POOL_PARAMS
+            ));
+            assert!(verify_merkle_proof_parts(
+                *commitment,
+                proof.sibling.as_slice(),
+                proof.path.as_slice(),
+                root,
+                &*POOL_PARAMS
+            ));
+
+            let mut bad_path = tree.get_commitment_proof(index as u64).unwrap();
+            let bit = bad_path.path.iter_mut().last().unwrap();
+            *bit = !*bit;
+            assert!(!verify_merkle_proof(
+                *commitment,
+                &bad_path,
+                root,
+                &*POOL_PARAMS
+            ));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_commitment_from_leaves_too_many() {
+        let leaves = vec![Num::<Fr>::ZERO; constants::OUT + 2];
+        commitment_from_leaves(&leaves, &*POOL_PARAMS);
+    }
+}
@@ -0,0 +1,263 @@
+//! Compact binary encoding of the transaction witness, meant for passing [`TransferPub`] and
+//! [`TransferSec`] between threads (e.g. from a wallet worker to a prover worker) instead of
+//! their serde representation, where every field element is a decimal string.
+//!
+//! Field elements are encoded as 32 bytes little-endian, merkle proof paths are packed into bits.
+//! Both encodings start with a version byte.
+
+use std::io::{self, Write};
+
+use libzeropool::{
+    constants,
+    fawkes_crypto::{
+        borsh::{BorshDeserialize, BorshSerialize},
+        core::sizedvec::SizedVec,
+        ff_uint::{Num, PrimeField},
+        native::poseidon::MerkleProof,
+    },
+    native::{
+        account::Account,
+        tx::{TransferPub, TransferSec, Tx},
+    },
+};
+
+use crate::client::{read_sized_vec, write_sized_vec};
+
+const WITNESS_VERSION: u8 = 1;
+
+/// Encodes the public inputs of a transaction.
+pub fn encode_pub<Fr: PrimeField>(public: &TransferPub<Fr>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_pub(public, &mut buf).unwrap();
+    buf
+}
+
+/// Decodes the public inputs produced by [`encode_pub`].
+pub fn decode_pub<Fr: PrimeField>(bytes: &[u8]) -> io::Result<TransferPub<Fr>> {
+    let buf = &mut &bytes[..];
+    read_version(buf)?;
+
+    let public = TransferPub {
+        root: Num::deserialize(buf)?,
+        nullifier: Num::deserialize(buf)?,
+        out_commit: Num::deserialize(buf)?,
+        delta: Num::deserialize(buf)?,
+        memo: Num::deserialize(buf)?,
+    };
+
+    check_consumed(buf)?;
+    Ok(public)
+}
+
+/// Encodes the secret inputs of a transaction.
+pub fn encode<Fr: PrimeField>(secret: &TransferSec<Fr>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_sec(secret, &mut buf).unwrap();
+    buf
+}
+
+/// Decodes the secret inputs produced by [`encode`].
+pub fn decode<Fr: PrimeField>(bytes: &[u8]) -> io::Result<TransferSec<Fr>> {
+    let buf = &mut &bytes[..];
+    read_version(buf)?;
+
+    let tx = Tx {
+        input: (Account::deserialize(buf)?, read_sized_vec(buf)?),
+        output: (Account::deserialize(buf)?, read_sized_vec(buf)?),
+    };
+    let account_proof = read_merkle_proof(buf)?;
+    let note_proofs = (0..constants::IN)
+        .map(|_| read_merkle_proof(buf))
+        .collect::<io::Result<_>>()?;
+    let secret = TransferSec {
+        tx,
+        in_proof: (account_proof, note_proofs),
+        eddsa_s: Num::deserialize(buf)?,
+        eddsa_r: Num::deserialize(buf)?,
+        eddsa_a: Num::deserialize(buf)?,
+    };
+
+    check_consumed(buf)?;
+    Ok(secret)
+}
+
+fn write_pub<Fr: PrimeField, W: Write>(public: &TransferPub<Fr>, w: &mut W) -> io::Result<()> {
+    WITNESS_VERSION.serialize(w)?;
+    public.root.serialize(w)?;
+    public.nullifier.serialize(w)?;
+    public.out_commit.serialize(w)?;
+    public.delta.serialize(w)?;
+    public.memo.serialize(w)
+}
+
+fn write_sec<Fr: PrimeField, W: Write>(secret: &TransferSec<Fr>, w: &mut W) -> io::Result<()> {
+    WITNESS_VERSION.serialize(w)?;
+    secret.tx.input.0.serialize(w)?;
+    write_sized_vec(&secret.tx.input.1, w)?;
+    secret.tx.output.0.serialize(w)?;
+    write_sized_vec(&secret.tx.output.1, w)?;
+    write_merkle_proof(&secret.in_proof.0, w)?;
+    for proof in secret.in_proof.1.iter() {
+        write_merkle_proof(proof, w)?;
+    }
+    secret.eddsa_s.serialize(w)?;
+    secret.eddsa_r.serialize(w)?;
+    secret.eddsa_a.serialize(w)
+}
+
+fn read_version(buf: &mut &[u8]) -> io::Result<()> {
+    let version = u8::deserialize(buf)?;
+    if version != WITNESS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported witness version: {}", version),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_consumed(buf: &[u8]) -> io::Result<()> {
+    if !buf.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected trailing bytes",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Siblings are written as is, the path is packed into `ceil(H / 8)` bytes, least significant
+/// bit first.
+fn write_merkle_proof<Fr: PrimeField, W: Write, const H: usize>(
+    proof: &MerkleProof<Fr, H>,
+    w: &mut W,
+) -> io::Result<()> {
+    write_sized_vec(&proof.sibling, w)?;
+
+    let mut packed = vec![0u8; (H + 7) / 8];
+    for (i, _) in proof.path.iter().enumerate().filter(|(_, bit)| **bit) {
+        packed[i / 8] |= 1 << (i % 8);
+    }
+
+    w.write_all(&packed)
+}
+
+fn read_merkle_proof<Fr: PrimeField, const H: usize>(
+    buf: &mut &[u8],
+) -> io::Result<MerkleProof<Fr, H>> {
+    let sibling = read_sized_vec(buf)?;
+
+    let packed_len = (H + 7) / 8;
+    if buf.len() < packed_len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Merkle proof path is truncated",
+        ));
+    }
+    let (packed, rest) = buf.split_at(packed_len);
+    *buf = rest;
+
+    let path: SizedVec<bool, H> = (0..H)
+        .map(|i| packed[i / 8] & (1 << (i % 8)) != 0)
+        .collect();
+
+    Ok(MerkleProof { sibling, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool::{
+        native::{
+            boundednum::BoundedNum,
+            params::{PoolBN256, PoolParams},
+        },
+        POOL_PARAMS,
+    };
+
+    use super::*;
+    use crate::client::{state::State, TxOutput, TxType, UserAccount};
+
+    type Fr = <PoolBN256 as PoolParams>::Fr;
+
+    #[test]
+    fn test_witness_roundtrip() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::from(1u64), state, POOL_PARAMS.clone());
+        let zero = || BoundedNum::new(Num::ZERO);
+        let outputs = || {
+            vec![TxOutput {
+                to: acc.generate_address(),
+                amount: zero(),
+            }]
+        };
+
+        let txs: Vec<TxType<Fr>> = vec![
+            TxType::Deposit {
+                fee: zero(),
+                deposit_amount: BoundedNum::new(Num::ONE),
+                outputs: outputs(),
+            },
+            TxType::DepositPermittable {
+                fee: zero(),
+                deposit_amount: BoundedNum::new(Num::ONE),
+                deadline: 1_700_000_000,
+                holder: vec![1; 20],
+                outputs: outputs(),
+            },
+            TxType::Transfer {
+                fee: zero(),
+                outputs: outputs(),
+            },
+            TxType::Withdraw {
+                fee: zero(),
+                withdraw_amount: zero(),
+                to: vec![2; 20],
+                native_amount: zero(),
+                energy_amount: zero(),
+            },
+        ];
+
+        for tx in txs {
+            let data = acc.create_tx(tx, None, None).unwrap();
+
+            let pub_bytes = encode_pub(&data.public);
+            let public = decode_pub::<Fr>(&pub_bytes).unwrap();
+            assert_eq!(
+                serde_json::to_value(&public).unwrap(),
+                serde_json::to_value(&data.public).unwrap()
+            );
+
+            let sec_bytes = encode(&data.secret);
+            let secret = decode::<Fr>(&sec_bytes).unwrap();
+            let json = serde_json::to_string(&data.secret).unwrap();
+            assert_eq!(serde_json::to_string(&secret).unwrap(), json);
+            assert_eq!(encode(&secret), sec_bytes);
+
+            assert!(sec_bytes.len() * 100 < json.len() * 40);
+        }
+    }
+
+    #[test]
+    fn test_witness_rejects_invalid() {
+        let state = State::init_test(POOL_PARAMS.clone());
+        let acc = UserAccount::new(Num::ZERO, state, POOL_PARAMS.clone());
+        let tx = TxType::Transfer {
+            fee: BoundedNum::new(Num::ZERO),
+            outputs: vec![],
+        };
+        let data = acc.create_tx(tx, None, None).unwrap();
+        let bytes = encode(&data.secret);
+
+        assert!(decode::<Fr>(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode::<Fr>(&trailing).is_err());
+
+        let mut version = bytes;
+        version[0] = WITNESS_VERSION + 1;
+        assert!(decode::<Fr>(&version).is_err());
+    }
+}